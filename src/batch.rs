@@ -0,0 +1,168 @@
+//! A module for grouping related lines sent to a client into batches.
+//!
+//! A batch is opened with `BATCH +reference type params...`, each line inside
+//! it is given a `batch=reference` tag, and it is closed with
+//! `BATCH -reference`. Batches may be nested, in which case the lines which
+//! open and close the inner batch are tagged with the reference of the outer
+//! one. Clients which have not enabled `batch` are sent the same lines with
+//! no batches around them.
+
+use crate::{outgoing, tags};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A batch being built for a single client.
+pub struct Batch {
+    /// The reference which identifies this batch to the client.
+    reference: Vec<u8>,
+    /// The message which opens this batch, without its reference.
+    start: outgoing::Message,
+    /// The lines and nested batches inside this batch, in order.
+    items: Vec<Item>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Something sent inside a batch.
+enum Item {
+    /// A serialized line, including its CRLF.
+    Line(Vec<u8>),
+    /// A nested batch.
+    Batch(Batch),
+}
+
+impl Batch {
+    /// Open a batch from `source` identified by `reference`, of the type
+    /// `kind` with the given parameters. The reference must not be shared by
+    /// any other batch the client is sent at the same time.
+    #[must_use]
+    pub fn new(reference: &[u8], source: &[u8], kind: &[u8], params: Vec<Vec<u8>>) -> Batch {
+        let mut start_params = vec![[b"+", reference].concat(), kind.to_vec()];
+        start_params.extend(params);
+        Batch {
+            reference: reference.to_vec(),
+            start: outgoing::Message::new(Some(source), b"BATCH", start_params),
+            items: Vec::new(),
+        }
+    }
+
+    /// Add the tag `key` with the unescaped `value` to the line which opens
+    /// this batch.
+    #[must_use]
+    pub fn with_tag(mut self, key: &[u8], value: &[u8]) -> Batch {
+        self.start = self.start.with_tag(key, value);
+        self
+    }
+
+    /// Get the reference which identifies this batch.
+    #[must_use]
+    pub fn reference(&self) -> &[u8] {
+        &self.reference
+    }
+
+    /// Add a serialized `line` to the end of this batch. A line which is
+    /// already tagged as part of another batch is left in that one.
+    pub fn push(&mut self, line: Vec<u8>) {
+        self.items.push(Item::Line(line));
+    }
+
+    /// Nest `batch` at the end of this batch.
+    pub fn push_batch(&mut self, batch: Batch) {
+        self.items.push(Item::Batch(batch));
+    }
+
+    /// Get the lines which send this batch, wrapped in `BATCH` messages if
+    /// `enabled` is true, which it should be only for clients with `batch`.
+    #[must_use]
+    pub fn into_lines(self, enabled: bool) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        self.write(enabled, None, &mut lines);
+        lines
+    }
+
+    /// Write the lines which send this batch to `lines`, tagging those which
+    /// open and close it with the reference of the batch it is nested in, if
+    /// any.
+    fn write(self, enabled: bool, outer: Option<&[u8]>, lines: &mut Vec<Vec<u8>>) {
+        let in_outer = |line: Vec<u8>| match outer {
+            Some(outer) => tags::add_to_line(&line, b"batch", outer),
+            None => line,
+        };
+        if enabled {
+            lines.push(in_outer(self.start.to_line()));
+        }
+        let reference = enabled.then_some(&self.reference[..]);
+        for item in self.items {
+            match item {
+                Item::Line(line) => match reference {
+                    Some(reference) if !tags::line_has(&line, b"batch") => {
+                        lines.push(tags::add_to_line(&line, b"batch", reference));
+                    }
+                    _ => lines.push(line),
+                },
+                Item::Batch(batch) => batch.write(enabled, reference, lines),
+            }
+        }
+        if enabled {
+            let end = outgoing::Message::new(
+                self.start.source(),
+                b"BATCH",
+                vec![[b"-", &self.reference[..]].concat()],
+            );
+            lines.push(in_outer(end.to_line()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Convert `lines` to strings without their CRLF.
+    fn strings(lines: Vec<Vec<u8>>) -> Vec<String> {
+        lines
+            .into_iter()
+            .map(|line| String::from_utf8(line).unwrap().trim_end().to_owned())
+            .collect()
+    }
+
+    #[test]
+    /// Test a batch holding lines and a nested batch.
+    fn nested() {
+        let mut outer =
+            Batch::new(b"1", b"irc.test", b"labeled-response", Vec::new()).with_tag(b"label", b"x");
+        outer.push(b":irc.test NOTICE a :first\r\n".to_vec());
+        let mut inner = Batch::new(b"2", b"irc.test", b"chathistory", vec![b"#chan".to_vec()]);
+        inner.push(b"@time=t :b PRIVMSG #chan hi\r\n".to_vec());
+        outer.push_batch(inner.clone());
+        assert_eq!(outer.reference(), b"1");
+
+        assert_eq!(
+            strings(outer.clone().into_lines(true)),
+            [
+                "@label=x :irc.test BATCH +1 labeled-response",
+                "@batch=1 :irc.test NOTICE a :first",
+                "@batch=1 :irc.test BATCH +2 chathistory #chan",
+                "@batch=2;time=t :b PRIVMSG #chan hi",
+                "@batch=1 :irc.test BATCH -2",
+                ":irc.test BATCH -1",
+            ]
+        );
+        assert_eq!(
+            strings(outer.into_lines(false)),
+            [":irc.test NOTICE a :first", "@time=t :b PRIVMSG #chan hi"]
+        );
+
+        // lines of a batch which was already sent whole stay in it
+        let mut labeled = Batch::new(b"3", b"irc.test", b"labeled-response", Vec::new());
+        for line in inner.into_lines(true) {
+            labeled.push(line);
+        }
+        assert_eq!(
+            strings(labeled.into_lines(true))[1..4],
+            [
+                "@batch=3 :irc.test BATCH +2 chathistory #chan",
+                "@batch=2;time=t :b PRIVMSG #chan hi",
+                "@batch=3 :irc.test BATCH -2",
+            ]
+        );
+    }
+}
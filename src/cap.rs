@@ -0,0 +1,224 @@
+//! A module for the capabilities which clients may negotiate with `CAP`.
+//!
+//! Every capability this server knows is a [`Cap`], and each client keeps
+//! the set of capabilities it has enabled. Output which only some clients
+//! understand is gated on [`Client::has_cap`](crate::client::Client::has_cap).
+
+use crate::sasl;
+
+/// The version of capability negotiation which shows capability values,
+/// splits long lists over several lines, and enables `cap-notify`.
+pub const CAP_302: u16 = 302;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// A capability which clients may enable.
+pub enum Cap {
+    /// `account-notify`: the client is sent `ACCOUNT` when a user it shares
+    /// a channel with logs in or out.
+    AccountNotify,
+    /// `account-tag`: the client is sent the `account` tag, which gives the
+    /// account the sender of each message is logged in to.
+    AccountTag,
+    /// `away-notify`: the client is sent `AWAY` when a user it shares a
+    /// channel with is marked as away or no longer away, or joins a channel
+    /// while away.
+    AwayNotify,
+    /// `batch`: the client is sent related messages grouped together between
+    /// `BATCH` messages.
+    Batch,
+    /// `cap-notify`: the client is told with `CAP NEW` and `CAP DEL` when
+    /// capabilities become available or stop being available.
+    CapNotify,
+    /// `chghost`: the client is sent `CHGHOST` when the username or hostname
+    /// of a user it shares a channel with changes, instead of seeing the user
+    /// quit and rejoin.
+    Chghost,
+    /// `draft/account-registration`: the client may register an account
+    /// with `REGISTER`, before or after it has registered its connection.
+    AccountRegistration,
+    /// `draft/channel-rename`: the client is sent `RENAME` when a channel it
+    /// is in is renamed, instead of seeing itself part the channel and join
+    /// it under its new name.
+    ChannelRename,
+    /// `draft/chathistory`: the client may ask for the messages sent to
+    /// channels and between users before it connected with `CHATHISTORY`.
+    Chathistory,
+    /// `draft/pre-away`: the client may send `AWAY` before it has registered,
+    /// and may send `AWAY *` to be marked as away without giving a message.
+    PreAway,
+    /// `echo-message`: the client is sent back its own `PRIVMSG`, `NOTICE`
+    /// and `TAGMSG` messages once they have been delivered.
+    EchoMessage,
+    /// `extended-join`: the client is sent the account and realname of each
+    /// user who joins a channel in the `JOIN` message.
+    ExtendedJoin,
+    /// `labeled-response`: the client is sent the response to each command
+    /// it gave a `label` tag with that tag, and an `ACK` if there is no
+    /// response at all.
+    LabeledResponse,
+    /// `message-tags`: the client is sent tags, such as `msgid`, which give
+    /// the unique identifier of each message.
+    MessageTags,
+    /// `multi-prefix`: the client is sent the prefix of every status a member
+    /// has in a channel, rather than only the highest, in `NAMES`, `WHO` and
+    /// `WHOIS` replies.
+    MultiPrefix,
+    /// `sasl`: the client may authenticate with `AUTHENTICATE`.
+    Sasl,
+    /// `server-time`: the client is sent the `time` tag, which gives the time
+    /// at which the server received each message.
+    ServerTime,
+    /// `setname`: the client is sent `SETNAME` when the realname of a user it
+    /// shares a channel with, or its own, changes.
+    Setname,
+    /// `userhost-in-names`: the client is sent the full `nick!user@host` of
+    /// each member in `NAMES` replies.
+    UserhostInNames,
+}
+
+impl Cap {
+    /// Every capability, in the order they are listed.
+    pub const ALL: &'static [Cap] = &[
+        Cap::AccountNotify,
+        Cap::AccountTag,
+        Cap::AwayNotify,
+        Cap::Batch,
+        Cap::CapNotify,
+        Cap::Chghost,
+        Cap::AccountRegistration,
+        Cap::ChannelRename,
+        Cap::Chathistory,
+        Cap::PreAway,
+        Cap::EchoMessage,
+        Cap::ExtendedJoin,
+        Cap::LabeledResponse,
+        Cap::MessageTags,
+        Cap::MultiPrefix,
+        Cap::Sasl,
+        Cap::ServerTime,
+        Cap::Setname,
+        Cap::UserhostInNames,
+    ];
+
+    /// Get the name of this capability.
+    #[must_use]
+    pub fn name(self) -> &'static [u8] {
+        match self {
+            Cap::AccountNotify => b"account-notify",
+            Cap::AccountTag => b"account-tag",
+            Cap::AwayNotify => b"away-notify",
+            Cap::Batch => b"batch",
+            Cap::CapNotify => b"cap-notify",
+            Cap::Chghost => b"chghost",
+            Cap::AccountRegistration => b"draft/account-registration",
+            Cap::ChannelRename => b"draft/channel-rename",
+            Cap::Chathistory => b"draft/chathistory",
+            Cap::PreAway => b"draft/pre-away",
+            Cap::EchoMessage => b"echo-message",
+            Cap::ExtendedJoin => b"extended-join",
+            Cap::LabeledResponse => b"labeled-response",
+            Cap::MessageTags => b"message-tags",
+            Cap::MultiPrefix => b"multi-prefix",
+            Cap::Sasl => b"sasl",
+            Cap::ServerTime => b"server-time",
+            Cap::Setname => b"setname",
+            Cap::UserhostInNames => b"userhost-in-names",
+        }
+    }
+
+    /// Get the capability a client must have enabled to be sent the tag
+    /// `key`. Tags without a capability of their own are sent to clients with
+    /// `message-tags`.
+    #[must_use]
+    pub fn for_tag(key: &[u8]) -> Cap {
+        match key {
+            b"account" => Cap::AccountTag,
+            b"batch" => Cap::Batch,
+            b"label" => Cap::LabeledResponse,
+            b"time" => Cap::ServerTime,
+            _ => Cap::MessageTags,
+        }
+    }
+
+    /// Find the capability called `name`.
+    #[must_use]
+    pub fn from_name(name: &[u8]) -> Option<Cap> {
+        Cap::ALL.iter().copied().find(|cap| cap.name() == name)
+    }
+
+    /// Get the value advertised with this capability to clients which
+    /// negotiate version 302, if it has one.
+    #[must_use]
+    pub fn value(self) -> Option<Vec<u8>> {
+        match self {
+            Cap::AccountRegistration => Some(b"before-connect,custom-account-name".to_vec()),
+            Cap::Sasl => Some(sasl::names()),
+            _ => None,
+        }
+    }
+
+    /// Serialize this capability as it is listed by `CAP`, with its value if
+    /// `with_value` is true.
+    #[must_use]
+    pub fn to_bytes(self, with_value: bool) -> Vec<u8> {
+        let mut token = self.name().to_vec();
+        if let Some(value) = self.value().filter(|_| with_value) {
+            token.push(b'=');
+            token.extend(value);
+        }
+        token
+    }
+}
+
+/// Split `tokens` into groups which each take up no more than `budget`
+/// bytes when joined with spaces. A single token longer than `budget` is
+/// still sent, in a group of its own.
+#[must_use]
+pub fn split(tokens: Vec<Vec<u8>>, budget: usize) -> Vec<Vec<u8>> {
+    let mut lines: Vec<Vec<u8>> = Vec::new();
+    for token in tokens {
+        match lines.last_mut() {
+            Some(line) if !line.is_empty() && line.len() + 1 + token.len() <= budget => {
+                line.push(b' ');
+                line.extend(token);
+            }
+            _ => lines.push(token),
+        }
+    }
+    if lines.is_empty() {
+        lines.push(Vec::new());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that capabilities are found by name and shown with their values.
+    fn names_and_values() {
+        for &cap in Cap::ALL {
+            assert_eq!(Cap::from_name(cap.name()), Some(cap));
+        }
+        assert_eq!(Cap::from_name(b"SASL"), None);
+        assert_eq!(Cap::Sasl.to_bytes(false), b"sasl");
+        assert_eq!(
+            Cap::Sasl.to_bytes(true),
+            b"sasl=EXTERNAL,PLAIN,SCRAM-SHA-256"
+        );
+        assert_eq!(Cap::CapNotify.to_bytes(true), b"cap-notify");
+        assert_eq!(Cap::for_tag(b"time"), Cap::ServerTime);
+        assert_eq!(Cap::for_tag(b"msgid"), Cap::MessageTags);
+        assert_eq!(Cap::for_tag(b"+example.com/x"), Cap::MessageTags);
+    }
+
+    #[test]
+    /// Test that long lists are split between lines.
+    fn split_lists() {
+        let tokens = vec![b"aaaa".to_vec(), b"bb".to_vec(), b"cc".to_vec()];
+        assert_eq!(split(tokens.clone(), 7), [&b"aaaa bb"[..], b"cc"]);
+        assert_eq!(split(tokens, 3), [&b"aaaa"[..], b"bb", b"cc"]);
+        assert_eq!(split(Vec::new(), 10), [b""]);
+    }
+}
@@ -0,0 +1,414 @@
+//! A module for the state of the channels on this server.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{
+    client::{Client, ClientId},
+    extban::BanMask,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// The channel modes which hold a list of masks.
+pub enum ListMode {
+    /// `+b`: clients matching a ban may not join or speak in the channel.
+    Ban,
+    /// `+e`: clients matching a ban exception are not affected by bans.
+    Except,
+    /// `+I`: clients matching an invite exception may join the channel even
+    /// when it is invite-only.
+    Invex,
+}
+
+impl ListMode {
+    /// Get the mode letter of this list.
+    #[must_use]
+    pub fn letter(self) -> u8 {
+        match self {
+            ListMode::Ban => b'b',
+            ListMode::Except => b'e',
+            ListMode::Invex => b'I',
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A single entry in a ban, exception or invite exception list.
+pub struct ListEntry {
+    /// The mask exactly as it will be shown to clients.
+    pub(crate) raw: Vec<u8>,
+    /// The parsed form of the mask.
+    pub(crate) mask: BanMask,
+    /// The hostmask of the client who added this entry.
+    pub(crate) setter: Vec<u8>,
+    /// The time this entry was added, in seconds since the Unix epoch.
+    pub(crate) set_at: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// The status of a single member of a channel.
+pub struct Membership {
+    /// Whether the member is a channel operator (`+o`, shown as `@`).
+    pub(crate) op: bool,
+    /// Whether the member is voiced (`+v`, shown as `+`).
+    pub(crate) voice: bool,
+}
+
+impl Membership {
+    /// Get the prefix character of this member's highest status, if it has
+    /// any status at all.
+    #[must_use]
+    pub fn prefix(self) -> Option<u8> {
+        if self.op {
+            Some(b'@')
+        } else if self.voice {
+            Some(b'+')
+        } else {
+            None
+        }
+    }
+
+    /// Get the prefix characters of every status this member has, from the
+    /// highest to the lowest, as shown to clients with `multi-prefix`.
+    #[must_use]
+    pub fn prefixes(self) -> Vec<u8> {
+        [(self.op, b'@'), (self.voice, b'+')]
+            .into_iter()
+            .filter_map(|(set, prefix)| set.then_some(prefix))
+            .collect()
+    }
+
+    /// Determine whether this member may speak when the channel is moderated
+    /// or the member is muted.
+    #[must_use]
+    pub fn can_override_quiet(self) -> bool {
+        self.op || self.voice
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// The settings of a channel which are controlled by channel modes, other
+/// than its mask lists.
+pub struct ChannelModes {
+    /// `+i`: only invited users may join.
+    pub(crate) invite_only: bool,
+    /// `+k`: the key users must give in order to join.
+    pub(crate) key: Option<Vec<u8>>,
+    /// `+l`: the maximum number of members.
+    pub(crate) limit: Option<usize>,
+    /// `+m`: only voiced users and operators may speak.
+    pub(crate) moderated: bool,
+    /// `+n`: users outside the channel may not send messages to it.
+    pub(crate) no_external: bool,
+    /// `+p`: the channel is hidden from users outside it.
+    pub(crate) private: bool,
+    /// `+s`: the channel is hidden from users outside it, and its existence is
+    /// not revealed by `NAMES`.
+    pub(crate) secret: bool,
+    /// `+t`: only channel operators may change the topic.
+    pub(crate) topic_lock: bool,
+}
+
+impl ChannelModes {
+    /// Get the mode string of these modes, such as `+klnt`, followed by the
+    /// arguments of the modes in it.
+    ///
+    /// The key is only included when `show_key` is set, and is otherwise
+    /// replaced with `*`.
+    #[must_use]
+    pub fn to_params(&self, show_key: bool) -> Vec<Vec<u8>> {
+        let mut letters = vec![b'+'];
+        let mut params = Vec::new();
+        if self.invite_only {
+            letters.push(b'i');
+        }
+        if let Some(key) = &self.key {
+            letters.push(b'k');
+            params.push(if show_key { key.clone() } else { b"*".to_vec() });
+        }
+        if let Some(limit) = self.limit {
+            letters.push(b'l');
+            params.push(limit.to_string().into_bytes());
+        }
+        for (set, letter) in [
+            (self.moderated, b'm'),
+            (self.no_external, b'n'),
+            (self.private, b'p'),
+            (self.secret, b's'),
+            (self.topic_lock, b't'),
+        ] {
+            if set {
+                letters.push(letter);
+            }
+        }
+
+        params.insert(0, letters);
+        params
+    }
+
+    /// Determine whether the channel is hidden from users who are not in it.
+    #[must_use]
+    pub fn hidden(&self) -> bool {
+        self.secret || self.private
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The topic of a channel, along with who set it and when.
+pub struct Topic {
+    /// The text of the topic.
+    pub(crate) text: Vec<u8>,
+    /// The hostmask of the client who set the topic.
+    pub(crate) setter: Vec<u8>,
+    /// The time the topic was set, in seconds since the Unix epoch.
+    pub(crate) set_at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The state of a single channel.
+pub struct Channel {
+    /// The name of the channel, in the case it was created with.
+    pub(crate) name: Vec<u8>,
+    /// The time the channel was created, in seconds since the Unix epoch.
+    pub(crate) created_at: u64,
+    /// The modes set on the channel.
+    pub(crate) modes: ChannelModes,
+    /// The topic of the channel, if one is set.
+    pub(crate) topic: Option<Topic>,
+    /// The members of the channel and their status.
+    pub(crate) members: BTreeMap<ClientId, Membership>,
+    /// The clients who have been invited to the channel but not yet joined
+    /// it.
+    pub(crate) invites: HashSet<ClientId>,
+    /// The ban list (`+b`).
+    pub(crate) bans: Vec<ListEntry>,
+    /// The ban exception list (`+e`).
+    pub(crate) excepts: Vec<ListEntry>,
+    /// The invite exception list (`+I`).
+    pub(crate) invexes: Vec<ListEntry>,
+}
+
+impl Channel {
+    /// Construct a new, empty channel named `name`, which was created at
+    /// `created_at` seconds since the Unix epoch.
+    #[must_use]
+    pub fn new(name: Vec<u8>, created_at: u64) -> Channel {
+        Channel {
+            name,
+            created_at,
+            modes: ChannelModes::default(),
+            topic: None,
+            members: BTreeMap::new(),
+            invites: HashSet::new(),
+            bans: Vec::new(),
+            excepts: Vec::new(),
+            invexes: Vec::new(),
+        }
+    }
+
+    /// Get the name of this channel.
+    #[must_use]
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Get the entries of one of this channel's mask lists.
+    #[must_use]
+    pub fn list(&self, mode: ListMode) -> &[ListEntry] {
+        match mode {
+            ListMode::Ban => &self.bans,
+            ListMode::Except => &self.excepts,
+            ListMode::Invex => &self.invexes,
+        }
+    }
+
+    fn list_mut(&mut self, mode: ListMode) -> &mut Vec<ListEntry> {
+        match mode {
+            ListMode::Ban => &mut self.bans,
+            ListMode::Except => &mut self.excepts,
+            ListMode::Invex => &mut self.invexes,
+        }
+    }
+
+    /// Add `raw` to one of this channel's mask lists.
+    ///
+    /// Returns the mask as it was stored (which may have been normalized), or
+    /// `None` if the mask was invalid or already present.
+    /// Mutes are only meaningful in the ban and exception lists, so they are
+    /// rejected from the invite exception list.
+    pub fn add_list_entry(
+        &mut self,
+        mode: ListMode,
+        raw: &[u8],
+        setter: Vec<u8>,
+        set_at: u64,
+    ) -> Option<Vec<u8>> {
+        let mask = BanMask::parse(raw)?;
+        if mode == ListMode::Invex && mask.is_mute() {
+            return None;
+        }
+        let raw = match &mask {
+            BanMask::Host(normalized) => normalized.clone(),
+            _ => raw.to_vec(),
+        };
+
+        let list = self.list_mut(mode);
+        if list.iter().any(|entry| entry.mask == mask) {
+            return None;
+        }
+        list.push(ListEntry {
+            raw: raw.clone(),
+            mask,
+            setter,
+            set_at,
+        });
+        Some(raw)
+    }
+
+    /// Remove `raw` from one of this channel's mask lists.
+    ///
+    /// Returns the mask as it was stored, or `None` if no matching entry was
+    /// present.
+    pub fn remove_list_entry(&mut self, mode: ListMode, raw: &[u8]) -> Option<Vec<u8>> {
+        let mask = BanMask::parse(raw)?;
+        let list = self.list_mut(mode);
+        let index = list.iter().position(|entry| entry.mask == mask)?;
+        Some(list.remove(index).raw)
+    }
+
+    /// Determine whether `client` is banned from joining this channel.
+    ///
+    /// A client is banned if it matches an entry of the ban list which is not
+    /// a mute, and does not match any non-mute entry of the exception list.
+    #[must_use]
+    pub fn is_banned(&self, client: &Client) -> bool {
+        let matching = |entry: &ListEntry| !entry.mask.is_mute() && entry.mask.matches(client);
+        self.bans.iter().any(matching) && !self.excepts.iter().any(matching)
+    }
+
+    /// Determine whether `client` is prevented from speaking in this channel
+    /// by a ban or a mute.
+    ///
+    /// Exceptions for mutes only lift mutes, while plain exceptions lift both.
+    #[must_use]
+    pub fn is_quieted(&self, client: &Client) -> bool {
+        let muted = self
+            .bans
+            .iter()
+            .any(|entry| entry.mask.is_mute() && entry.mask.matches(client))
+            && !self.excepts.iter().any(|entry| entry.mask.matches(client));
+        muted || self.is_banned(client)
+    }
+
+    /// Determine whether `client` matches this channel's invite exception
+    /// list.
+    #[must_use]
+    pub fn is_invex(&self, client: &Client) -> bool {
+        self.invexes.iter().any(|entry| entry.mask.matches(client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Construct a client named `nick!user@host` which is logged in to
+    /// `account`.
+    fn client(nick: &[u8], account: Option<&[u8]>) -> Client {
+        let mut client = Client::new(0, b"host".to_vec());
+        client.nick = Some(nick.to_vec());
+        client.user = Some(b"user".to_vec());
+        client.account = account.map(<[u8]>::to_vec);
+        client
+    }
+
+    #[test]
+    /// Test that the key is only shown when requested, and that modes are
+    /// listed in a stable order.
+    fn mode_params() {
+        let modes = ChannelModes {
+            key: Some(b"secret".to_vec()),
+            limit: Some(10),
+            no_external: true,
+            topic_lock: true,
+            ..ChannelModes::default()
+        };
+        assert_eq!(
+            modes.to_params(true),
+            vec![b"+klnt".to_vec(), b"secret".to_vec(), b"10".to_vec()]
+        );
+        assert_eq!(modes.to_params(false)[1], b"*");
+        assert_eq!(
+            ChannelModes::default().to_params(false),
+            vec![b"+".to_vec()]
+        );
+    }
+
+    #[test]
+    /// Test the prefixes shown for each status.
+    fn prefixes() {
+        let both = Membership {
+            op: true,
+            voice: true,
+        };
+        assert_eq!(both.prefix(), Some(b'@'));
+        assert_eq!(both.prefixes(), b"@+");
+        assert_eq!(Membership::default().prefixes(), b"");
+    }
+
+    #[test]
+    /// Test that list entries are normalized and deduplicated.
+    fn add_and_remove_entries() {
+        let mut chan = Channel::new(b"#test".to_vec(), 0);
+        assert_eq!(
+            chan.add_list_entry(ListMode::Ban, b"nick", b"op".to_vec(), 0),
+            Some(b"nick!*@*".to_vec())
+        );
+        assert_eq!(
+            chan.add_list_entry(ListMode::Ban, b"nick!*@*", b"op".to_vec(), 0),
+            None
+        );
+        assert_eq!(
+            chan.add_list_entry(ListMode::Ban, b"$q:bogus", b"op".to_vec(), 0),
+            None
+        );
+        assert_eq!(
+            chan.add_list_entry(ListMode::Invex, b"$m:nick", b"op".to_vec(), 0),
+            None
+        );
+        assert_eq!(
+            chan.remove_list_entry(ListMode::Ban, b"nick"),
+            Some(b"nick!*@*".to_vec())
+        );
+        assert!(chan.list(ListMode::Ban).is_empty());
+    }
+
+    #[test]
+    /// Test that an exception overrides a matching ban.
+    fn exceptions_override_bans() {
+        let mut chan = Channel::new(b"#test".to_vec(), 0);
+        chan.add_list_entry(ListMode::Ban, b"*!*@host", Vec::new(), 0);
+        chan.add_list_entry(ListMode::Except, b"$a:friend", Vec::new(), 0);
+
+        assert!(chan.is_banned(&client(b"stranger", None)));
+        assert!(chan.is_quieted(&client(b"stranger", None)));
+        assert!(!chan.is_banned(&client(b"pal", Some(b"friend"))));
+        assert!(!chan.is_quieted(&client(b"pal", Some(b"friend"))));
+    }
+
+    #[test]
+    /// Test that a mute prevents speaking but not joining.
+    fn mutes_allow_join() {
+        let mut chan = Channel::new(b"#test".to_vec(), 0);
+        chan.add_list_entry(ListMode::Ban, b"$m:loud", Vec::new(), 0);
+
+        let loud = client(b"loud", None);
+        assert!(!chan.is_banned(&loud));
+        assert!(chan.is_quieted(&loud));
+        assert!(!chan.is_quieted(&client(b"quiet", None)));
+
+        chan.add_list_entry(ListMode::Except, b"$m:$a", Vec::new(), 0);
+        assert!(chan.is_quieted(&loud));
+        assert!(!chan.is_quieted(&client(b"loud", Some(b"acct"))));
+    }
+}
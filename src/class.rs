@@ -0,0 +1,75 @@
+//! A module for connection classes, which group connections by the host they
+//! connect from and set the limits which apply to them.
+
+use crate::mask;
+
+/// The most bytes which may be waiting to be written to a connection, unless
+/// configured otherwise.
+pub const DEFAULT_SENDQ: usize = 1 << 20;
+
+/// The most bytes which may have been received from a connection without yet
+/// being handled, unless configured otherwise.
+pub const DEFAULT_RECVQ: usize = 16 << 10;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A class of connections, and the limits placed on them.
+pub struct Class {
+    /// The name of the class, as shown in `STATS`.
+    pub name: Vec<u8>,
+    /// Masks of the hosts whose connections belong to this class.
+    pub hosts: Vec<Vec<u8>>,
+    /// The most bytes which may be waiting to be written to a connection in
+    /// this class before it is disconnected.
+    pub sendq: usize,
+    /// The most bytes which may have been received from a connection in this
+    /// class, but not yet handled, before it is disconnected.
+    pub recvq: usize,
+}
+
+impl Default for Class {
+    /// The class which accepts connections from every host.
+    fn default() -> Class {
+        Class {
+            name: b"default".to_vec(),
+            hosts: vec![b"*".to_vec()],
+            sendq: DEFAULT_SENDQ,
+            recvq: DEFAULT_RECVQ,
+        }
+    }
+}
+
+impl Class {
+    /// Determine whether connections from `host` belong to this class.
+    #[must_use]
+    pub fn admits(&self, host: &[u8]) -> bool {
+        self.hosts
+            .iter()
+            .any(|pattern| mask::matches(pattern, host))
+    }
+}
+
+/// Find the first of `classes` which admits connections from `host`.
+#[must_use]
+pub fn find<'a>(classes: &'a [Class], host: &[u8]) -> Option<&'a Class> {
+    classes.iter().find(|class| class.admits(host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that connections are put in the first class which admits them.
+    fn find_class() {
+        let local = Class {
+            name: b"local".to_vec(),
+            hosts: vec![b"127.*".to_vec(), b"*.example.com".to_vec()],
+            ..Class::default()
+        };
+        let classes = [local, Class::default()];
+        assert_eq!(find(&classes, b"127.0.0.1").unwrap().name, b"local");
+        assert_eq!(find(&classes, b"irc.EXAMPLE.com").unwrap().name, b"local");
+        assert_eq!(find(&classes, b"10.0.0.1").unwrap().name, b"default");
+        assert_eq!(find(&classes[..1], b"10.0.0.1"), None);
+    }
+}
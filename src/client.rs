@@ -0,0 +1,422 @@
+//! A module for the state the server keeps about each connected client.
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+
+use tokio::sync::Notify;
+
+use crate::{cap::Cap, class::Class, flood::Bucket, incoming, time, IrcError};
+
+/// A unique identifier for a connection to this server.
+/// Identifiers are never reused over the lifetime of the server process.
+pub type ClientId = u64;
+
+#[derive(Clone, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+/// The state of a single client connection.
+///
+/// As with messages, names are stored as raw bytes, since the IRC protocol
+/// makes no guarantees about their encoding.
+pub struct Client {
+    /// The unique identifier of this client's connection.
+    pub(crate) id: ClientId,
+    /// The client's current nickname, if one has been chosen.
+    pub(crate) nick: Option<Vec<u8>>,
+    /// The username given by the client in its `USER` message.
+    pub(crate) user: Option<Vec<u8>>,
+    /// The hostname the client is connecting from, as shown to other users.
+    pub(crate) host: Vec<u8>,
+    /// The hostname the client connected from, which stays the same when
+    /// `host` is replaced by a vhost.
+    pub(crate) real_host: Vec<u8>,
+    /// The "real name" given by the client in its `USER` message.
+    pub(crate) realname: Option<Vec<u8>>,
+    /// The name of the account this client is logged in to, if any.
+    pub(crate) account: Option<Vec<u8>>,
+    /// The lowercase hexadecimal SHA-256 fingerprint of the TLS client
+    /// certificate presented by this client, if any.
+    pub(crate) certfp: Option<Vec<u8>>,
+    /// Whether this client is connected over TLS, which is shown as user
+    /// mode `+Z`.
+    pub(crate) secure: bool,
+    /// The casefolded names of every channel this client is a member of.
+    pub(crate) channels: HashSet<Vec<u8>>,
+    /// The capabilities this client has enabled.
+    pub(crate) caps: HashSet<Cap>,
+    /// The highest version of capability negotiation the client has said it
+    /// supports, or 0 if it has not negotiated capabilities.
+    pub(crate) cap_version: u16,
+    /// Whether registration is being held until the client ends capability
+    /// negotiation with `CAP END`.
+    pub(crate) negotiating: bool,
+    /// Whether this client has completed registration by sending both `NICK`
+    /// and `USER`.
+    pub(crate) registered: bool,
+    /// Whether this client has set user mode `+i`, hiding it from the member
+    /// lists of channels which the requester is not in.
+    pub(crate) invisible: bool,
+    /// Whether this client is an IRC operator.
+    pub(crate) oper: bool,
+    /// The away message this client set, if it is marked as away.
+    pub(crate) away: Option<Vec<u8>>,
+    /// The normalized masks of the users whose messages this client does not
+    /// want to receive.
+    pub(crate) silenced: Vec<Vec<u8>>,
+    /// The nicknames this client is monitoring, by their casefolded form,
+    /// as the client gave them.
+    pub(crate) monitored: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// The queue of lines waiting to be written to this client's connection.
+    pub(crate) outbox: Outbox,
+    /// The time this client connected, in seconds since the Unix epoch.
+    pub(crate) signon: u64,
+    /// When a line was last received from this client.
+    pub(crate) last_activity: Instant,
+    /// When this client last sent a `PRIVMSG` or `NOTICE`, which is used to
+    /// report how long it has been idle.
+    pub(crate) last_message: Instant,
+    /// When the server sent a `PING` which this client has not yet answered,
+    /// if there is one.
+    pub(crate) ping_sent: Option<Instant>,
+    /// The token bucket limiting how quickly this client may send commands.
+    pub(crate) flood: Bucket,
+    /// The connection class this client belongs to.
+    pub(crate) class: Class,
+    /// Lines received from this client which are waiting to be handled, in
+    /// the order they were received.
+    pub(crate) inbox: VecDeque<Received>,
+    /// The total length in bytes of the lines in `inbox`.
+    pub(crate) recvq: usize,
+    /// The number of lines which have been received from this client.
+    pub(crate) lines_received: u64,
+    /// The number of bytes which have been received from this client.
+    pub(crate) bytes_received: u64,
+}
+
+#[derive(Clone, Debug)]
+/// A line received from a client which is waiting to be handled.
+pub(crate) struct Received {
+    /// When the line may be handled without exceeding the client's flood
+    /// limits.
+    pub(crate) due: Instant,
+    /// The length of the line in bytes.
+    pub(crate) len: usize,
+    /// The parsed line, or the error to report if it could not be parsed.
+    pub(crate) message: Result<incoming::Message, IrcError>,
+}
+
+impl Client {
+    /// Construct a new client with no nickname or username which connected
+    /// from `host`.
+    #[must_use]
+    pub fn new(id: ClientId, host: Vec<u8>) -> Client {
+        let now = Instant::now();
+        Client {
+            id,
+            nick: None,
+            user: None,
+            real_host: host.clone(),
+            host,
+            realname: None,
+            account: None,
+            certfp: None,
+            secure: false,
+            channels: HashSet::new(),
+            caps: HashSet::new(),
+            cap_version: 0,
+            negotiating: false,
+            registered: false,
+            invisible: false,
+            oper: false,
+            away: None,
+            silenced: Vec::new(),
+            monitored: BTreeMap::new(),
+            outbox: Outbox::default(),
+            signon: time::unix_time(),
+            last_activity: now,
+            last_message: now,
+            ping_sent: None,
+            flood: Bucket::new(now),
+            class: Class::default(),
+            inbox: VecDeque::new(),
+            recvq: 0,
+            lines_received: 0,
+            bytes_received: 0,
+        }
+    }
+
+    /// Record that a line was received from this client, which also answers
+    /// any outstanding `PING`.
+    pub(crate) fn touch(&mut self) {
+        self.last_activity = Instant::now();
+        self.ping_sent = None;
+    }
+
+    /// Get the identifier of this client's connection.
+    #[must_use]
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    /// Get the connection class this client belongs to.
+    #[must_use]
+    pub fn class(&self) -> &Class {
+        &self.class
+    }
+
+    /// Determine whether this client has enabled the capability `cap`.
+    #[must_use]
+    pub fn has_cap(&self, cap: Cap) -> bool {
+        self.caps.contains(&cap)
+    }
+
+    /// Get the nickname of this client, or `*` if it has not chosen one yet.
+    #[must_use]
+    pub fn nick_or_star(&self) -> &[u8] {
+        self.nick.as_deref().unwrap_or(b"*")
+    }
+
+    /// Get the full `nick!user@host` mask of this client.
+    /// Missing components are filled in with `*`.
+    #[must_use]
+    pub fn hostmask(&self) -> Vec<u8> {
+        let mut mask = self.nick_or_star().to_vec();
+        mask.push(b'!');
+        mask.extend_from_slice(self.user.as_deref().unwrap_or(b"*"));
+        mask.push(b'@');
+        mask.extend_from_slice(&self.host);
+        mask
+    }
+
+    /// Get the `user@host` of this client, as matched against operator
+    /// hosts and server bans. A missing username is left empty.
+    #[must_use]
+    pub fn userhost(&self) -> Vec<u8> {
+        let mut userhost = self.user.clone().unwrap_or_default();
+        userhost.push(b'@');
+        userhost.extend_from_slice(&self.host);
+        userhost
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// A handle to the queue of lines waiting to be written to a client.
+///
+/// The server pushes lines into the outbox while handling commands, and the
+/// task which owns the client's connection takes them out and writes them.
+/// Cloning an outbox gives another handle to the same queue.
+pub struct Outbox {
+    /// The queued lines and whether the outbox has been closed.
+    state: Arc<Mutex<OutboxState>>,
+    /// Used to wake the writing task when lines are queued or the outbox is
+    /// closed.
+    notify: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+/// The shared state behind an `Outbox`.
+struct OutboxState {
+    /// Serialized lines, including their CRLF, in the order they were queued.
+    lines: VecDeque<Vec<u8>>,
+    /// The total length in bytes of `lines`.
+    queued: usize,
+    /// The most bytes which may be queued at once, or `None` if there is no
+    /// limit.
+    limit: Option<usize>,
+    /// Whether a line has been discarded because queueing it would have
+    /// exceeded `limit`.
+    overflowed: bool,
+    /// Whether the connection should be closed once the queued lines are
+    /// written.
+    closed: bool,
+    /// While the lines queued in response to a command are being held back,
+    /// the number of lines at the front of `lines` which may still be
+    /// written.
+    held: Option<usize>,
+    /// The number of lines which have ever been queued.
+    lines_sent: u64,
+    /// The number of bytes which have ever been queued.
+    bytes_sent: u64,
+}
+
+impl Outbox {
+    /// Construct an empty outbox which may hold at most `limit` bytes.
+    #[must_use]
+    pub fn with_limit(limit: usize) -> Outbox {
+        let outbox = Outbox::default();
+        outbox.state().limit = Some(limit);
+        outbox
+    }
+
+    /// Change the most bytes which may be queued at once. Lines which are
+    /// already queued are kept, even if they exceed the new limit.
+    pub fn set_limit(&self, limit: usize) {
+        self.state().limit = Some(limit);
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, OutboxState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queue `line` to be written. Lines pushed after the outbox is closed
+    /// are discarded.
+    ///
+    /// If queueing `line` would exceed the outbox's limit, the line is
+    /// discarded instead, and the outbox is marked as overflowed; every line
+    /// pushed after that is also discarded.
+    pub fn push(&self, line: Vec<u8>) {
+        let mut state = self.state();
+        if state.closed || state.overflowed {
+            return;
+        }
+        if state
+            .limit
+            .is_some_and(|limit| state.queued + line.len() > limit)
+        {
+            state.overflowed = true;
+            return;
+        }
+        self.enqueue(&mut state, line);
+    }
+
+    /// Queue `line` to be written regardless of the outbox's limit, unless
+    /// the outbox is closed or has already overflowed.
+    ///
+    /// This is for replies which the client has been promised will never
+    /// disconnect it, such as those to `LIST`. The line still counts towards
+    /// the limit for lines pushed after it.
+    pub fn push_unlimited(&self, line: Vec<u8>) {
+        let mut state = self.state();
+        if !state.closed && !state.overflowed {
+            self.enqueue(&mut state, line);
+        }
+    }
+
+    /// Add `line` to the end of the queue and wake the writing task.
+    fn enqueue(&self, state: &mut OutboxState, line: Vec<u8>) {
+        state.queued += line.len();
+        state.lines_sent += 1;
+        state.bytes_sent += line.len() as u64;
+        state.lines.push_back(line);
+        self.notify.notify_one();
+    }
+
+    /// Hold back every line queued from now on, until `release` is called.
+    pub fn hold(&self) {
+        let mut state = self.state();
+        let len = state.lines.len();
+        state.held.get_or_insert(len);
+    }
+
+    /// Stop holding back lines, and queue what `edit` makes of the lines which
+    /// were held back in their place.
+    ///
+    /// Nothing is queued if the outbox was closed while lines were held,
+    /// since closing it releases them as they are.
+    pub fn release(&self, edit: impl FnOnce(Vec<Vec<u8>>) -> Vec<Vec<u8>>) {
+        let mut state = self.state();
+        let Some(start) = state.held.take() else {
+            return;
+        };
+        let held: Vec<Vec<u8>> = state.lines.drain(start..).collect();
+        let len: usize = held.iter().map(Vec::len).sum();
+        state.queued -= len;
+        state.lines_sent -= held.len() as u64;
+        state.bytes_sent -= len as u64;
+        for line in edit(held) {
+            self.enqueue(&mut state, line);
+        }
+    }
+
+    /// Queue `line` to be written regardless of the outbox's limit, and then
+    /// close the outbox.
+    pub fn close_with(&self, line: Vec<u8>) {
+        let mut state = self.state();
+        if !state.closed {
+            state.held = None;
+            state.queued += line.len();
+            state.lines.push_back(line);
+            state.closed = true;
+            self.notify.notify_one();
+        }
+    }
+
+    /// Mark this outbox as closed. Lines which were already queued will still
+    /// be returned by `take`.
+    pub fn close(&self) {
+        let mut state = self.state();
+        state.closed = true;
+        state.held = None;
+        self.notify.notify_one();
+    }
+
+    /// Determine whether this outbox has been closed.
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+
+    /// Determine whether a line has been discarded because this outbox was
+    /// full.
+    #[must_use]
+    pub fn is_overflowed(&self) -> bool {
+        self.state().overflowed
+    }
+
+    /// Get the total length in bytes of the queued lines.
+    #[must_use]
+    pub fn queued(&self) -> usize {
+        self.state().queued
+    }
+
+    /// Get the number of lines and bytes which have ever been queued.
+    #[must_use]
+    pub fn sent(&self) -> (u64, u64) {
+        let state = self.state();
+        (state.lines_sent, state.bytes_sent)
+    }
+
+    /// Remove and return every queued line which is not being held back.
+    #[must_use]
+    pub fn take(&self) -> Vec<Vec<u8>> {
+        let mut state = self.state();
+        let end = state.held.unwrap_or(state.lines.len());
+        let lines: Vec<Vec<u8>> = state.lines.drain(..end).collect();
+        state.queued -= lines.iter().map(Vec::len).sum::<usize>();
+        if let Some(held) = &mut state.held {
+            *held = 0;
+        }
+        lines
+    }
+
+    /// Remove and return the first queued line, if there is one which is not
+    /// being held back.
+    #[must_use]
+    pub fn pop(&self) -> Option<Vec<u8>> {
+        let mut state = self.state();
+        if state.held == Some(0) {
+            return None;
+        }
+        let line = state.lines.pop_front()?;
+        state.queued -= line.len();
+        if let Some(held) = &mut state.held {
+            *held -= 1;
+        }
+        Some(line)
+    }
+
+    /// Wait until this outbox has queued lines or has been closed.
+    pub async fn wait(&self) {
+        loop {
+            {
+                let state = self.state();
+                if state.closed || state.held.map_or(!state.lines.is_empty(), |held| held > 0) {
+                    return;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+}
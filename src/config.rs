@@ -0,0 +1,1491 @@
+//! A module for loading and validating the server's configuration file.
+//!
+//! The configuration is written in TOML. Every key is checked strictly:
+//! unknown keys, values of the wrong type and invalid values are all errors,
+//! and each error names the line of the file it was found on.
+//!
+//! ```toml
+//! [server]
+//! name = "irc.example.com"
+//! network = "ExampleNet"
+//! motd = "motd.txt"
+//!
+//! [[listen]]
+//! address = "0.0.0.0:6667"
+//!
+//! [[listen]]
+//! address = "0.0.0.0:6697"
+//! tls_cert = "fullchain.pem"
+//! tls_key = "privkey.pem"
+//!
+//! [limits]
+//! nicklen = 30
+//!
+//! [[class]]
+//! name = "users"
+//! hosts = ["*"]
+//! sendq = 1048576
+//! recvq = 16384
+//!
+//! [[oper]]
+//! name = "admin"
+//! password = "hunter2"
+//! hosts = ["*@127.0.0.1"]
+//! vhost = "staff.example.com"
+//!
+//! [[account]]
+//! name = "alice"
+//! password = "correct horse"
+//!
+//! [[account]]
+//! name = "bob"
+//! scram = "SCRAM-SHA-256$4096:W22ZaJ0SNY7soEsUEjb6gQ==$WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU="
+//! certfp = ["50d858e0985ecc7f60418aaf0cc5ab587f42c2570a884095a9e8ccacd0f6545c"]
+//!
+//! [registration]
+//! file = "accounts.toml"
+//! ```
+//!
+//! Accounts registered with `REGISTER` are appended to the registration file
+//! as `[[account]]` sections, and loaded from it along with the rest of the
+//! configuration.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use toml::{
+    de::{DeTable, DeValue},
+    Spanned,
+};
+
+use crate::{class::Class, flood, mask, scram::Credentials, server, tls};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The complete configuration of the server.
+pub struct Config {
+    /// The name of this server, which must be a valid hostname.
+    pub name: Vec<u8>,
+    /// The name of the network this server is part of.
+    pub network: Vec<u8>,
+    /// The path of the message of the day, if there is one.
+    pub motd_path: Option<PathBuf>,
+    /// The lines of the message of the day, which are read when the
+    /// configuration is loaded.
+    pub motd: Option<Vec<Vec<u8>>>,
+    /// The addresses to listen for connections on.
+    pub listeners: Vec<Listener>,
+    /// The limits on the lengths and numbers of things.
+    pub limits: Limits,
+    /// How long a connection may be silent before it is sent a `PING`.
+    pub ping_interval: Duration,
+    /// How long a connection has to answer a `PING` before it is
+    /// disconnected.
+    pub ping_timeout: Duration,
+    /// The limits on how quickly clients may send commands.
+    pub flood: flood::Limits,
+    /// The connection classes, in the order they are matched.
+    pub classes: Vec<Class>,
+    /// The accounts which may become IRC operators.
+    pub opers: Vec<Oper>,
+    /// The accounts clients may log in to with SASL, including those in the
+    /// registration file.
+    pub accounts: Vec<Account>,
+    /// The file accounts registered with `REGISTER` are appended to, or
+    /// `None` if clients may not register accounts.
+    pub registration_file: Option<PathBuf>,
+    /// The `user@host`s which may not connect to the server.
+    pub bans: Vec<Ban>,
+    /// The other servers this server may link to.
+    pub links: Vec<Link>,
+    /// How messages are kept for `CHATHISTORY`, or `None` if they are not
+    /// kept at all.
+    pub history: Option<History>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// An address to listen for connections on.
+pub struct Listener {
+    /// The address, in the form `host:port`.
+    pub address: String,
+    /// How connections are secured, if this listener accepts TLS.
+    pub tls: Option<Tls>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The settings of a TLS listener.
+pub struct Tls {
+    /// The PEM file holding the certificate chain, starting with the
+    /// server's own certificate.
+    pub cert: PathBuf,
+    /// The PEM file holding the certificate's private key.
+    pub key: PathBuf,
+    /// Whether clients are asked for certificates, so that they can be
+    /// identified by their fingerprints.
+    pub client_certs: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The limits on the lengths and numbers of things, which are enforced by the
+/// server and advertised to clients.
+pub struct Limits {
+    /// The maximum length of a nickname.
+    pub nicklen: usize,
+    /// The maximum length of a channel name, including its prefix.
+    pub channellen: usize,
+    /// The maximum length of a channel topic. Longer topics are truncated.
+    pub topiclen: usize,
+    /// The maximum number of channels a client may be in at once.
+    pub chanlimit: usize,
+    /// The maximum length of an away message. Longer messages are truncated.
+    pub awaylen: usize,
+    /// The maximum length of a hostname, including the server's own name.
+    pub hostlen: usize,
+    /// The maximum length of a `KICK` reason. Longer reasons are truncated.
+    pub kicklen: usize,
+    /// The maximum length of a channel key. Longer keys are refused.
+    pub keylen: usize,
+    /// The maximum length of a username. Longer usernames are truncated.
+    pub userlen: usize,
+    /// The maximum length of a realname. Longer realnames are truncated when
+    /// given with `USER`, and refused when given with `SETNAME`.
+    pub namelen: usize,
+    /// The maximum number of entries in a channel's ban, exception and
+    /// invite exception lists, taken together.
+    pub maxlist: usize,
+    /// The maximum number of targets of a `PRIVMSG`, `NOTICE` or `KICK`.
+    pub maxtargets: usize,
+    /// The maximum number of channel modes with arguments which take effect
+    /// from a single `MODE` command.
+    pub modes: usize,
+    /// The maximum number of entries in a client's silence list.
+    pub silence: usize,
+    /// The maximum number of nicknames a client may monitor at once.
+    pub monitor: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            nicklen: 30,
+            channellen: 50,
+            topiclen: 390,
+            chanlimit: 20,
+            awaylen: 200,
+            hostlen: 64,
+            kicklen: 255,
+            keylen: 23,
+            userlen: 18,
+            namelen: 150,
+            maxlist: 100,
+            maxtargets: 4,
+            modes: 4,
+            silence: 15,
+            monitor: 100,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// An account which may become an IRC operator.
+pub struct Oper {
+    /// The name given in the `OPER` command.
+    pub name: Vec<u8>,
+    /// The password given in the `OPER` command.
+    pub password: Vec<u8>,
+    /// Masks of the `user@host`s which may use this account.
+    pub hosts: Vec<Vec<u8>>,
+    /// The hostname given to clients which log in to this account, if they
+    /// are given one.
+    pub vhost: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A ban on clients connecting from matching `user@host`s.
+pub struct Ban {
+    /// The mask of the `user@host`s which are banned.
+    pub mask: Vec<u8>,
+    /// The reason banned clients are given when they are disconnected.
+    pub reason: Vec<u8>,
+}
+
+impl Ban {
+    /// Determine whether this ban applies to a client with the given
+    /// `user@host`.
+    #[must_use]
+    pub fn matches(&self, userhost: &[u8]) -> bool {
+        mask::matches(&self.mask, userhost)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// An account which clients may log in to with SASL.
+pub struct Account {
+    /// The name of the account.
+    pub name: Vec<u8>,
+    /// The keys derived from the account's password, if it may be logged in
+    /// to with one.
+    pub credentials: Option<Credentials>,
+    /// The lowercase hexadecimal SHA-256 fingerprints of the TLS client
+    /// certificates which may log in to the account.
+    pub certfps: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Another server which this server may link to.
+pub struct Link {
+    /// The name of the other server.
+    pub name: Vec<u8>,
+    /// The address of the other server, in the form `host:port`.
+    pub address: String,
+    /// The password this server sends when linking.
+    pub send_password: Vec<u8>,
+    /// The password the other server must send when linking.
+    pub receive_password: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// How messages sent to channels and between users are kept, so that they
+/// can be played back with `CHATHISTORY`.
+pub struct History {
+    /// The most messages kept for each channel or conversation.
+    pub limit: usize,
+    /// The most messages kept for particular channels, by casefolded name,
+    /// which take the place of `limit`.
+    pub channel_limits: HashMap<Vec<u8>, usize>,
+    /// The most messages a single `CHATHISTORY` command may play back.
+    pub query_limit: usize,
+    /// The file messages are appended to so that they are kept across
+    /// restarts, if there is one.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for History {
+    /// Keep 1000 messages for each channel or conversation in memory, and
+    /// play back at most 100 at a time.
+    fn default() -> History {
+        History {
+            limit: 1000,
+            channel_limits: HashMap::new(),
+            query_limit: 100,
+            file: None,
+        }
+    }
+}
+
+impl History {
+    /// Get the most messages kept for the channel or conversation whose
+    /// history is stored under `key`.
+    #[must_use]
+    pub fn limit_for(&self, key: &[u8]) -> usize {
+        self.channel_limits.get(key).copied().unwrap_or(self.limit)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// An error in a configuration file.
+pub struct ConfigError {
+    /// The line the error was found on, counting from 1, if it belongs to a
+    /// particular line.
+    pub line: Option<usize>,
+    /// A description of the error.
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for Config {
+    /// A configuration for a server named `localhost` which listens on
+    /// `localhost:8080`.
+    fn default() -> Config {
+        Config {
+            name: b"localhost".to_vec(),
+            network: b"Strigoi".to_vec(),
+            motd_path: None,
+            motd: None,
+            listeners: vec![Listener {
+                address: "localhost:8080".to_owned(),
+                tls: None,
+            }],
+            limits: Limits::default(),
+            ping_interval: server::DEFAULT_PING_INTERVAL,
+            ping_timeout: server::DEFAULT_PING_TIMEOUT,
+            flood: flood::Limits::default(),
+            classes: vec![Class::default()],
+            opers: Vec::new(),
+            accounts: Vec::new(),
+            registration_file: None,
+            bans: Vec::new(),
+            links: Vec::new(),
+            history: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load and validate the configuration file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read, or is not a valid
+    /// configuration.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|err| ConfigError {
+            line: None,
+            message: format!("cannot read {}: {err}", path.display()),
+        })?;
+        Config::parse(&text, path.parent().unwrap_or(Path::new("")))
+    }
+
+    /// Parse and validate the configuration in `text`. Relative paths in it
+    /// are resolved against `base`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `text` is not a valid configuration.
+    pub fn parse(text: &str, base: &Path) -> Result<Config, ConfigError> {
+        let file = File { text };
+        let document = DeTable::parse(text).map_err(|err| ConfigError {
+            line: err.span().map(|span| file.line(&span)),
+            message: err.message().to_owned(),
+        })?;
+        let mut root = Table::new(&file, "the file", document.get_ref(), document.span());
+        let mut config = Config::default();
+
+        let mut section = root.required_table("server")?;
+        let (name, name_span) = section.required_string("name")?;
+        if !is_valid_hostname(name) {
+            return Err(file.error(
+                &name_span,
+                "`name` must be a valid hostname, such as irc.example.com",
+            ));
+        }
+        config.name = name.as_bytes().to_vec();
+        let (network, span) = section.required_string("network")?;
+        if network.is_empty() || network.contains(' ') {
+            return Err(file.error(&span, "`network` must be a single word"));
+        }
+        config.network = network.as_bytes().to_vec();
+        if let Some((motd, span)) = section.string("motd")? {
+            let path = base.join(motd);
+            let text = std::fs::read(&path).map_err(|err| {
+                file.error(&span, &format!("cannot read {}: {err}", path.display()))
+            })?;
+            config.motd = Some(split_lines(&text));
+            config.motd_path = Some(path);
+        }
+        if let Some((interval, _)) = section.duration("ping_interval")? {
+            config.ping_interval = interval;
+        }
+        if let Some((timeout, _)) = section.duration("ping_timeout")? {
+            config.ping_timeout = timeout;
+        }
+        section.finish()?;
+
+        config.listeners = parse_listeners(&file, root.required_tables("listen")?, base)?;
+
+        if let Some(mut table) = root.table("limits")? {
+            let limits = &mut config.limits;
+            for (key, limit, min) in [
+                ("nicklen", &mut limits.nicklen, 1),
+                ("channellen", &mut limits.channellen, 2),
+                ("topiclen", &mut limits.topiclen, 1),
+                ("chanlimit", &mut limits.chanlimit, 1),
+                ("awaylen", &mut limits.awaylen, 1),
+                // long enough for the text of any IP address
+                ("hostlen", &mut limits.hostlen, 45),
+                ("kicklen", &mut limits.kicklen, 1),
+                ("keylen", &mut limits.keylen, 1),
+                ("userlen", &mut limits.userlen, 1),
+                ("namelen", &mut limits.namelen, 1),
+                ("maxlist", &mut limits.maxlist, 1),
+                ("maxtargets", &mut limits.maxtargets, 1),
+                ("modes", &mut limits.modes, 1),
+                ("silence", &mut limits.silence, 1),
+                ("monitor", &mut limits.monitor, 1),
+            ] {
+                if let Some(value) = table.size(key, min)? {
+                    *limit = value;
+                }
+            }
+            table.finish()?;
+        }
+        if config.name.len() > config.limits.hostlen {
+            return Err(file.error(&name_span, "`name` is longer than `hostlen`"));
+        }
+
+        if let Some(table) = root.table("flood")? {
+            config.flood = parse_flood(table)?;
+        }
+
+        if let Some(tables) = root.tables("class")? {
+            config.classes = parse_classes(&file, tables)?;
+        }
+        if let Some(tables) = root.tables("oper")? {
+            config.opers = parse_opers(&file, tables, config.limits.hostlen)?;
+        }
+        if let Some(tables) = root.tables("account")? {
+            config.accounts = parse_accounts(&file, tables)?;
+        }
+        if let Some(table) = root.table("registration")? {
+            let (path, registered) = parse_registration(table, base, &config.accounts)?;
+            config.accounts.extend(registered);
+            config.registration_file = Some(path);
+        }
+        if let Some(tables) = root.tables("ban")? {
+            config.bans = parse_bans(&file, tables)?;
+        }
+        if let Some(tables) = root.tables("link")? {
+            config.links = parse_links(&file, tables, &config.name)?;
+        }
+        if let Some(table) = root.table("history")? {
+            config.history = Some(parse_history(&file, table, base)?);
+        }
+        root.finish()?;
+        Ok(config)
+    }
+}
+
+/// Parse the `[flood]` section.
+fn parse_flood(mut table: Table) -> Result<flood::Limits, ConfigError> {
+    let mut limits = flood::Limits::default();
+    if let Some(burst) = table.size("burst", 1)? {
+        limits.burst = table.to_u32(burst, "burst")?;
+    }
+    if let Some((refill, _)) = table.duration("refill")? {
+        limits.refill = refill;
+    }
+    if let Some(excess) = table.size("excess", 0)? {
+        limits.excess = table.to_u32(excess, "excess")?;
+    }
+    if let Some(cost) = table.size("default_cost", 0)? {
+        limits.default_cost = table.to_u32(cost, "default_cost")?;
+    }
+    if let Some((exempt, _)) = table.boolean("exempt_opers")? {
+        limits.exempt_opers = exempt;
+    }
+    if let Some(accounts) = table.strings("exempt_accounts")? {
+        limits.exempt_accounts = accounts
+            .into_iter()
+            .map(|(account, _)| mask::casefold(account.as_bytes()))
+            .collect();
+    }
+    if let Some(mut costs) = table.table("costs")? {
+        for verb in costs.keys() {
+            let cost = costs.size(&verb, 0)?.unwrap_or_default();
+            let cost = costs.to_u32(cost, &verb)?;
+            limits
+                .costs
+                .insert(verb.to_ascii_uppercase().into_bytes(), cost);
+        }
+        costs.finish()?;
+    }
+    table.finish()?;
+    Ok(limits)
+}
+
+/// Parse the `[history]` section, whose file is resolved against `base`.
+fn parse_history(file: &File, mut table: Table, base: &Path) -> Result<History, ConfigError> {
+    let mut history = History::default();
+    if let Some(limit) = table.size("limit", 0)? {
+        history.limit = limit;
+    }
+    if let Some(limit) = table.size("query_limit", 1)? {
+        history.query_limit = limit;
+    }
+    if let Some((path, _)) = table.string("file")? {
+        history.file = Some(base.join(path));
+    }
+    if let Some(mut channels) = table.table("channels")? {
+        for name in channels.keys() {
+            let limit = channels.size(&name, 0)?.unwrap_or_default();
+            if !name.starts_with('#') || name.contains([' ', ',']) {
+                let span = channels
+                    .entries
+                    .get(name.as_str())
+                    .map_or(channels.span.clone(), Spanned::span);
+                return Err(file.error(&span, &format!("`{name}` is not a channel name")));
+            }
+            history
+                .channel_limits
+                .insert(mask::casefold(name.as_bytes()), limit);
+        }
+        channels.finish()?;
+    }
+    table.finish()?;
+    Ok(history)
+}
+
+/// Parse the `[[listen]]` sections, whose certificate and key files are
+/// resolved against `base`.
+fn parse_listeners(
+    file: &File,
+    tables: Vec<Table>,
+    base: &Path,
+) -> Result<Vec<Listener>, ConfigError> {
+    let mut listeners = Vec::new();
+    for mut table in tables {
+        let (address, span) = table.required_string("address")?;
+        check_address(file, address, &span)?;
+        let tls = match (table.string("tls_cert")?, table.string("tls_key")?) {
+            (Some((cert, span)), Some((key, _))) => {
+                let tls = Tls {
+                    cert: base.join(cert),
+                    key: base.join(key),
+                    client_certs: table
+                        .boolean("client_certs")?
+                        .is_none_or(|(value, _)| value),
+                };
+                tls::load_key(&tls).map_err(|err| file.error(&span, &err))?;
+                Some(tls)
+            }
+            (None, None) => None,
+            (Some((_, span)), None) | (None, Some((_, span))) => {
+                return Err(file.error(&span, "`tls_cert` and `tls_key` must be given together"));
+            }
+        };
+        listeners.push(Listener {
+            address: address.to_owned(),
+            tls,
+        });
+        table.finish()?;
+    }
+    Ok(listeners)
+}
+
+/// Parse the `[[class]]` sections.
+fn parse_classes(file: &File, tables: Vec<Table>) -> Result<Vec<Class>, ConfigError> {
+    let mut classes = Vec::new();
+    let mut names = HashSet::new();
+    for mut table in tables {
+        let (name, span) = table.required_string("name")?;
+        if name.is_empty() || name.contains(' ') {
+            return Err(file.error(&span, "`name` must be a single word"));
+        }
+        if !names.insert(name.to_owned()) {
+            return Err(file.error(&span, &format!("there is already a class named {name}")));
+        }
+        let hosts = table.required_masks("hosts")?;
+        let mut class = Class {
+            name: name.as_bytes().to_vec(),
+            hosts,
+            ..Class::default()
+        };
+        if let Some(sendq) = table.size("sendq", 512)? {
+            class.sendq = sendq;
+        }
+        // a connection must at least be able to send a single line
+        if let Some(recvq) = table.size("recvq", 512)? {
+            class.recvq = recvq;
+        }
+        table.finish()?;
+        classes.push(class);
+    }
+    Ok(classes)
+}
+
+/// Parse the `[[oper]]` sections, whose vhosts may be no longer than
+/// `hostlen`.
+fn parse_opers(file: &File, tables: Vec<Table>, hostlen: usize) -> Result<Vec<Oper>, ConfigError> {
+    let mut opers = Vec::new();
+    let mut names = HashSet::new();
+    for mut table in tables {
+        let (name, span) = table.required_string("name")?;
+        if name.is_empty() || name.contains(' ') {
+            return Err(file.error(&span, "`name` must be a single word"));
+        }
+        if !names.insert(name.to_owned()) {
+            return Err(file.error(&span, &format!("there is already an oper named {name}")));
+        }
+        let (password, span) = table.required_string("password")?;
+        if password.is_empty() {
+            return Err(file.error(&span, "`password` must not be empty"));
+        }
+        let vhost = match table.string("vhost")? {
+            Some((vhost, span)) if !is_valid_hostname(vhost) => {
+                return Err(file.error(&span, "`vhost` must be a valid hostname"));
+            }
+            Some((vhost, span)) if vhost.len() > hostlen => {
+                return Err(file.error(&span, "`vhost` is longer than `hostlen`"));
+            }
+            vhost => vhost.map(|(vhost, _)| vhost.as_bytes().to_vec()),
+        };
+        opers.push(Oper {
+            name: name.as_bytes().to_vec(),
+            password: password.as_bytes().to_vec(),
+            hosts: table.required_masks("hosts")?,
+            vhost,
+        });
+        table.finish()?;
+    }
+    Ok(opers)
+}
+
+/// Parse the `[[account]]` sections.
+fn parse_accounts(file: &File, tables: Vec<Table>) -> Result<Vec<Account>, ConfigError> {
+    let mut accounts = Vec::new();
+    let mut names = HashSet::new();
+    let mut certfps = HashMap::new();
+    for mut table in tables {
+        let (name, span) = table.required_string("name")?;
+        if name.is_empty() || name.contains(' ') || name.starts_with(':') {
+            return Err(file.error(&span, "`name` must be a single word"));
+        }
+        if !names.insert(mask::casefold(name.as_bytes())) {
+            return Err(file.error(&span, &format!("there is already an account named {name}")));
+        }
+        // passwords are only kept as the keys derived from them
+        let credentials = match (table.string("password")?, table.string("scram")?) {
+            (Some(("", span)), _) => {
+                return Err(file.error(&span, "`password` must not be empty"));
+            }
+            (Some((password, _)), None) => Some(Credentials::derive(password.as_bytes())),
+            (None, Some((scram, span))) => Some(Credentials::parse(scram).ok_or_else(|| {
+                file.error(&span, "`scram` must be in the form `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`")
+            })?),
+            (None, None) => None,
+            (Some(_), Some((_, span))) => {
+                return Err(file.error(&span, "`password` and `scram` cannot both be given"));
+            }
+        };
+        let mut account = Account {
+            name: name.as_bytes().to_vec(),
+            credentials,
+            certfps: Vec::new(),
+        };
+        for (certfp, span) in table.strings("certfp")?.unwrap_or_default() {
+            // fingerprints are often written in uppercase with colons
+            let certfp: Vec<u8> = certfp
+                .bytes()
+                .filter(|&b| b != b':')
+                .map(|b| b.to_ascii_lowercase())
+                .collect();
+            if certfp.len() != 64 || !certfp.iter().all(u8::is_ascii_hexdigit) {
+                return Err(file.error(&span, "`certfp` must be SHA-256 fingerprints"));
+            }
+            if let Some(other) = certfps.insert(certfp.clone(), name) {
+                let message = format!("the fingerprint is already used by the account {other}");
+                return Err(file.error(&span, &message));
+            }
+            account.certfps.push(certfp);
+        }
+        if account.credentials.is_none() && account.certfps.is_empty() {
+            let message = "the account needs a `password`, a `scram` or a `certfp`";
+            return Err(file.error(&span, message));
+        }
+        table.finish()?;
+        accounts.push(account);
+    }
+    Ok(accounts)
+}
+
+/// Parse the `[registration]` section, whose file is resolved against
+/// `base`, and load the accounts registered in that file, none of which may
+/// share a name with one of `accounts`.
+fn parse_registration(
+    mut table: Table,
+    base: &Path,
+    accounts: &[Account],
+) -> Result<(PathBuf, Vec<Account>), ConfigError> {
+    let (path, _) = table.required_string("file")?;
+    let path = base.join(path);
+    table.finish()?;
+    let registered = load_registered(&path)?;
+    if let Some(account) = registered.iter().find(|account| {
+        accounts
+            .iter()
+            .any(|other| mask::eq_ignore_case(&other.name, &account.name))
+    }) {
+        return Err(ConfigError {
+            line: None,
+            message: format!(
+                "{}: there is already an account named {}",
+                path.display(),
+                String::from_utf8_lossy(&account.name)
+            ),
+        });
+    }
+    Ok((path, registered))
+}
+
+/// Load the accounts in the registration file at `path`, which has none if
+/// it does not exist yet.
+fn load_registered(path: &Path) -> Result<Vec<Account>, ConfigError> {
+    let in_file = |err: ConfigError| ConfigError {
+        line: None,
+        message: format!("{}: {err}", path.display()),
+    };
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(ConfigError {
+                line: None,
+                message: format!("cannot read {}: {err}", path.display()),
+            })
+        }
+    };
+    let file = File { text: &text };
+    let document = DeTable::parse(&text).map_err(|err| {
+        in_file(ConfigError {
+            line: err.span().map(|span| file.line(&span)),
+            message: err.message().to_owned(),
+        })
+    })?;
+    let mut root = Table::new(&file, "the file", document.get_ref(), document.span());
+    let accounts = match root.tables("account").map_err(in_file)? {
+        Some(tables) => parse_accounts(&file, tables).map_err(in_file)?,
+        None => Vec::new(),
+    };
+    root.finish().map_err(in_file)?;
+    Ok(accounts)
+}
+
+/// Append the account `name`, which is logged in to with `credentials`, to
+/// the registration file at `path`, creating the file if it does not exist.
+/// The name must be a valid nickname, so that it needs no escaping.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be written to.
+pub fn append_registered(path: &Path, name: &[u8], credentials: &Credentials) -> io::Result<()> {
+    let section = format!(
+        "[[account]]\nname = '{}'\nscram = '{credentials}'\n\n",
+        String::from_utf8_lossy(name)
+    );
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(section.as_bytes())?;
+    file.sync_all()
+}
+
+/// Parse the `[[ban]]` sections.
+fn parse_bans(file: &File, tables: Vec<Table>) -> Result<Vec<Ban>, ConfigError> {
+    let mut bans = Vec::new();
+    for mut table in tables {
+        let (mask, span) = table.required_string("mask")?;
+        if mask.contains(' ') || !mask.contains('@') || mask.contains('!') {
+            return Err(file.error(&span, "`mask` must have the form user@host"));
+        }
+        let reason = match table.string("reason")? {
+            Some(("", span)) => return Err(file.error(&span, "`reason` must not be empty")),
+            Some((reason, _)) => reason.as_bytes().to_vec(),
+            None => b"Banned".to_vec(),
+        };
+        bans.push(Ban {
+            mask: mask.as_bytes().to_vec(),
+            reason,
+        });
+        table.finish()?;
+    }
+    Ok(bans)
+}
+
+/// Parse the `[[link]]` sections of a server named `own_name`.
+fn parse_links(file: &File, tables: Vec<Table>, own_name: &[u8]) -> Result<Vec<Link>, ConfigError> {
+    let mut links = Vec::new();
+    let mut names = HashSet::new();
+    for mut table in tables {
+        let (name, span) = table.required_string("name")?;
+        if !is_valid_hostname(name) {
+            return Err(file.error(&span, "`name` must be a valid hostname"));
+        }
+        if mask::eq_ignore_case(name.as_bytes(), own_name) {
+            return Err(file.error(&span, "a server cannot link to itself"));
+        }
+        if !names.insert(mask::casefold(name.as_bytes())) {
+            return Err(file.error(&span, &format!("there is already a link to {name}")));
+        }
+        let (address, span) = table.required_string("address")?;
+        check_address(file, address, &span)?;
+        let mut passwords = Vec::new();
+        for key in ["send_password", "receive_password"] {
+            let (password, span) = table.required_string(key)?;
+            if password.is_empty() || password.contains(' ') {
+                return Err(file.error(&span, &format!("`{key}` must be a single word")));
+            }
+            passwords.push(password.as_bytes().to_vec());
+        }
+        let receive_password = passwords.pop().unwrap_or_default();
+        let send_password = passwords.pop().unwrap_or_default();
+        links.push(Link {
+            name: name.as_bytes().to_vec(),
+            address: address.to_owned(),
+            send_password,
+            receive_password,
+        });
+        table.finish()?;
+    }
+    Ok(links)
+}
+
+/// Check that `address`, found at `span`, has the form `host:port`.
+fn check_address(file: &File, address: &str, span: &Range<usize>) -> Result<(), ConfigError> {
+    let valid = address.rsplit_once(':').is_some_and(|(host, port)| {
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        !host.is_empty() && !host.contains(' ') && port.parse::<u16>().is_ok()
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(file.error(
+            span,
+            "`address` must have the form host:port, such as 0.0.0.0:6667",
+        ))
+    }
+}
+
+/// Split the contents of a text file into lines, without their line endings.
+fn split_lines(text: &[u8]) -> Vec<Vec<u8>> {
+    let text = text.strip_suffix(b"\n").unwrap_or(text);
+    text.split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line).to_vec())
+        .collect()
+}
+
+/// Determine whether `host` is a valid hostname for a server or client.
+///
+/// Besides the usual rules for DNS names, a hostname must contain at least one
+/// dot, and internationalized names must be given in their ASCII (punycode)
+/// form.
+#[must_use]
+pub fn is_valid_hostname(host: &str) -> bool {
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    };
+    host.len() <= 253 && host.contains('.') && host.split('.').all(valid_label)
+}
+
+/// The text of a configuration file, used to turn byte offsets into line
+/// numbers.
+struct File<'t> {
+    /// The full text of the file.
+    text: &'t str,
+}
+
+impl File<'_> {
+    /// Get the line number, counting from 1, of the start of `span`.
+    fn line(&self, span: &Range<usize>) -> usize {
+        let start = span.start.min(self.text.len());
+        self.text.as_bytes()[..start].split(|&b| b == b'\n').count()
+    }
+
+    /// Construct an error about the text at `span`.
+    fn error(&self, span: &Range<usize>, message: &str) -> ConfigError {
+        ConfigError {
+            line: Some(self.line(span)),
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// A table from the configuration file. Keys are checked off as they are
+/// read, so that any keys which are left over can be reported as unknown.
+struct Table<'a, 'i> {
+    /// The file the table is in.
+    file: &'a File<'a>,
+    /// A description of the table for error messages, such as `[server]`.
+    name: String,
+    /// The keys and values of the table.
+    entries: &'a DeTable<'i>,
+    /// Where the table is in the file.
+    span: Range<usize>,
+    /// The keys which have been read.
+    read: HashSet<String>,
+}
+
+impl<'a, 'i> Table<'a, 'i> {
+    fn new(file: &'a File<'a>, name: &str, table: &'a DeTable<'i>, span: Range<usize>) -> Self {
+        Table {
+            file,
+            name: name.to_owned(),
+            entries: table,
+            span,
+            read: HashSet::new(),
+        }
+    }
+
+    /// Get the names of every key in the table.
+    fn keys(&self) -> Vec<String> {
+        self.entries
+            .keys()
+            .map(|key| key.get_ref().to_string())
+            .collect()
+    }
+
+    /// Get the value of `key`, if it is present, and mark it as read.
+    fn get(&mut self, key: &str) -> Option<&'a Spanned<DeValue<'i>>> {
+        self.read.insert(key.to_owned());
+        self.entries.get(key)
+    }
+
+    /// Construct an error saying that `key` should have been `expected`.
+    fn wrong_type(&self, key: &str, value: &Spanned<DeValue>, expected: &str) -> ConfigError {
+        self.file
+            .error(&value.span(), &format!("`{key}` must be {expected}"))
+    }
+
+    /// Construct an error saying that `key` is missing.
+    fn missing(&self, key: &str) -> ConfigError {
+        self.file.error(
+            &self.span,
+            &format!("{} is missing the required key `{key}`", self.name),
+        )
+    }
+
+    /// Get the string value of `key`, if it is present.
+    fn string(&mut self, key: &str) -> Result<Option<(&'a str, Range<usize>)>, ConfigError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => match value.get_ref() {
+                DeValue::String(string) => Ok(Some((string.as_ref(), value.span()))),
+                _ => Err(self.wrong_type(key, value, "a string")),
+            },
+        }
+    }
+
+    /// Get the string value of `key`, which must be present.
+    fn required_string(&mut self, key: &str) -> Result<(&'a str, Range<usize>), ConfigError> {
+        self.string(key)?.ok_or_else(|| self.missing(key))
+    }
+
+    /// Get the array of strings which is the value of `key`, if it is
+    /// present.
+    #[allow(clippy::type_complexity)]
+    fn strings(&mut self, key: &str) -> Result<Option<Vec<(&'a str, Range<usize>)>>, ConfigError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        let DeValue::Array(array) = value.get_ref() else {
+            return Err(self.wrong_type(key, value, "an array of strings"));
+        };
+        array
+            .iter()
+            .map(|item| match item.get_ref() {
+                DeValue::String(string) => Ok((string.as_ref(), item.span())),
+                _ => Err(self.wrong_type(key, item, "an array of strings")),
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    /// Get the non-empty array of masks which is the value of `key`, which
+    /// must be present.
+    fn required_masks(&mut self, key: &str) -> Result<Vec<Vec<u8>>, ConfigError> {
+        let Some(masks) = self.strings(key)? else {
+            return Err(self.missing(key));
+        };
+        if masks.is_empty() {
+            return Err(self.file.error(
+                &self
+                    .entries
+                    .get(key)
+                    .map_or(self.span.clone(), Spanned::span),
+                &format!("`{key}` must contain at least one mask"),
+            ));
+        }
+        masks
+            .into_iter()
+            .map(|(mask, span)| {
+                if mask.is_empty() || mask.contains(' ') {
+                    Err(self.file.error(&span, "masks must be a single word"))
+                } else {
+                    Ok(mask.as_bytes().to_vec())
+                }
+            })
+            .collect()
+    }
+
+    /// Get the boolean value of `key`, if it is present.
+    fn boolean(&mut self, key: &str) -> Result<Option<(bool, Range<usize>)>, ConfigError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => match value.get_ref() {
+                DeValue::Boolean(boolean) => Ok(Some((*boolean, value.span()))),
+                _ => Err(self.wrong_type(key, value, "true or false")),
+            },
+        }
+    }
+
+    /// Get the value of `key`, which must be an integer no less than `min`,
+    /// if it is present.
+    fn size(&mut self, key: &str, min: usize) -> Result<Option<usize>, ConfigError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        let expected = format!("an integer no less than {min}");
+        match value.get_ref() {
+            DeValue::Integer(integer) => usize::from_str_radix(integer.as_str(), integer.radix())
+                .ok()
+                .filter(|&size| size >= min)
+                .map(Some)
+                .ok_or_else(|| self.wrong_type(key, value, &expected)),
+            _ => Err(self.wrong_type(key, value, &expected)),
+        }
+    }
+
+    /// Convert `size`, the value of `key`, into a `u32`.
+    fn to_u32(&self, size: usize, key: &str) -> Result<u32, ConfigError> {
+        u32::try_from(size).map_err(|_| {
+            let span = self
+                .entries
+                .get(key)
+                .map_or(self.span.clone(), Spanned::span);
+            self.file.error(&span, &format!("`{key}` is too large"))
+        })
+    }
+
+    /// Get the value of `key`, which must be a positive number of seconds, if
+    /// it is present.
+    fn duration(&mut self, key: &str) -> Result<Option<(Duration, Range<usize>)>, ConfigError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        let seconds = match value.get_ref() {
+            DeValue::Integer(integer) => u64::from_str_radix(integer.as_str(), integer.radix())
+                .ok()
+                .map(Duration::from_secs),
+            DeValue::Float(float) => float
+                .as_str()
+                .parse::<f64>()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()),
+            _ => None,
+        };
+        match seconds {
+            Some(duration) if !duration.is_zero() => Ok(Some((duration, value.span()))),
+            _ => Err(self.wrong_type(key, value, "a positive number of seconds")),
+        }
+    }
+
+    /// Get the table which is the value of `key`, if it is present.
+    fn table(&mut self, key: &str) -> Result<Option<Table<'a, 'i>>, ConfigError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => match value.get_ref() {
+                DeValue::Table(table) => Ok(Some(Table::new(
+                    self.file,
+                    &format!("[{key}]"),
+                    table,
+                    value.span(),
+                ))),
+                _ => Err(self.wrong_type(key, value, "a table")),
+            },
+        }
+    }
+
+    /// Get the table which is the value of `key`, which must be present.
+    fn required_table(&mut self, key: &str) -> Result<Table<'a, 'i>, ConfigError> {
+        let table = self.table(key)?;
+        table.ok_or_else(|| self.missing(&format!("[{key}]")))
+    }
+
+    /// Get the array of tables (written `[[key]]`) which is the value of
+    /// `key`, if it is present.
+    fn tables(&mut self, key: &str) -> Result<Option<Vec<Table<'a, 'i>>>, ConfigError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        let DeValue::Array(array) = value.get_ref() else {
+            return Err(self.wrong_type(key, value, &format!("written as [[{key}]]")));
+        };
+        array
+            .iter()
+            .map(|item| match item.get_ref() {
+                DeValue::Table(table) => Ok(Table::new(
+                    self.file,
+                    &format!("[[{key}]]"),
+                    table,
+                    item.span(),
+                )),
+                _ => Err(self.wrong_type(key, item, &format!("written as [[{key}]]"))),
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    /// Get the array of tables which is the value of `key`, which must be
+    /// present and not empty.
+    fn required_tables(&mut self, key: &str) -> Result<Vec<Table<'a, 'i>>, ConfigError> {
+        match self.tables(key)? {
+            Some(tables) if !tables.is_empty() => Ok(tables),
+            _ => Err(self.missing(&format!("[[{key}]]"))),
+        }
+    }
+
+    /// Check that every key in the table has been read.
+    fn finish(self) -> Result<(), ConfigError> {
+        let mut unknown: HashMap<_, _> = self
+            .entries
+            .iter()
+            .filter(|(key, _)| !self.read.contains(key.get_ref().as_ref()))
+            .map(|(key, _)| (key.span().start, key))
+            .collect();
+        // report the first unknown key in the file
+        let Some(first) = unknown.keys().min().copied() else {
+            return Ok(());
+        };
+        let key = unknown.remove(&first).unwrap();
+        Err(self.file.error(
+            &key.span(),
+            &format!("{} has an unknown key `{}`", self.name, key.get_ref()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small but complete configuration.
+    const EXAMPLE: &str = r#"
+[server]
+name = "irc.example.com"
+network = "ExampleNet"
+ping_interval = 90
+
+[[listen]]
+address = "0.0.0.0:6667"
+
+[[listen]]
+address = "[::1]:6697"
+
+[limits]
+nicklen = 16
+chanlimit = 5
+
+[flood]
+burst = 5
+refill = 0.5
+exempt_accounts = ["HelpBot"]
+costs = { privmsg = 2 }
+
+[[class]]
+name = "local"
+hosts = ["127.*"]
+sendq = 4096
+
+[[oper]]
+name = "admin"
+password = "hunter2"
+hosts = ["*@127.0.0.1"]
+
+[[link]]
+name = "hub.example.com"
+address = "hub.example.com:7000"
+send_password = "out"
+receive_password = "in"
+"#;
+
+    /// Parse `text` and return the error it causes.
+    fn error(text: &str) -> ConfigError {
+        Config::parse(text, Path::new("")).unwrap_err()
+    }
+
+    #[test]
+    /// Test parsing a valid configuration.
+    fn parse_example() {
+        let config = Config::parse(EXAMPLE, Path::new("")).unwrap();
+        assert_eq!(config.name, b"irc.example.com");
+        assert_eq!(config.network, b"ExampleNet");
+        assert_eq!(config.ping_interval, Duration::from_secs(90));
+        assert_eq!(config.ping_timeout, server::DEFAULT_PING_TIMEOUT);
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.limits.nicklen, 16);
+        assert_eq!(config.limits.chanlimit, 5);
+        assert_eq!(config.limits.topiclen, Limits::default().topiclen);
+        assert_eq!(config.flood.burst, 5);
+        assert_eq!(config.flood.refill, Duration::from_millis(500));
+        assert!(config.flood.exempt_accounts.contains(&b"helpbot"[..]));
+        assert_eq!(config.flood.cost(b"PRIVMSG"), 2);
+        assert_eq!(config.flood.cost(b"JOIN"), 2);
+        assert_eq!(config.classes[0].sendq, 4096);
+        assert_eq!(config.classes[0].recvq, crate::class::DEFAULT_RECVQ);
+        assert_eq!(config.opers[0].hosts, [b"*@127.0.0.1".to_vec()]);
+        assert_eq!(config.links[0].receive_password, b"in");
+    }
+
+    #[test]
+    /// Test that errors name the line they were found on.
+    fn error_lines() {
+        let unknown = EXAMPLE.replace("nicklen = 16", "nicklen = 16\nnicklength = 3");
+        assert_eq!(
+            error(&unknown).to_string(),
+            "line 15: [limits] has an unknown key `nicklength`"
+        );
+        let wrong_type = EXAMPLE.replace("sendq = 4096", "sendq = \"lots\"");
+        assert_eq!(
+            error(&wrong_type).to_string(),
+            "line 26: `sendq` must be an integer no less than 512"
+        );
+        let bad_name = EXAMPLE.replace("irc.example.com", "irc");
+        assert_eq!(error(&bad_name).line, Some(3));
+        let long_name = EXAMPLE.replace("irc.example.com", &format!("{}.com", "x".repeat(61)));
+        assert_eq!(
+            error(&long_name).to_string(),
+            "line 3: `name` is longer than `hostlen`"
+        );
+        let bad_address = EXAMPLE.replace("0.0.0.0:6667", "0.0.0.0");
+        assert_eq!(error(&bad_address).line, Some(8));
+        let syntax = EXAMPLE.replace("[limits]", "[limits");
+        assert_eq!(error(&syntax).line, Some(13));
+        let duplicate =
+            format!("{EXAMPLE}\n[[oper]]\nname = \"admin\"\npassword = \"x\"\nhosts = [\"*\"]\n");
+        assert_eq!(
+            error(&duplicate).to_string(),
+            "line 40: there is already an oper named admin"
+        );
+    }
+
+    #[test]
+    /// Test that required sections and keys are enforced.
+    fn required() {
+        assert_eq!(
+            error("").to_string(),
+            "line 1: the file is missing the required key `[server]`"
+        );
+        let no_listen = "[server]\nname = \"irc.example.com\"\nnetwork = \"Net\"\n";
+        assert_eq!(
+            error(no_listen).message,
+            "the file is missing the required key `[[listen]]`"
+        );
+        let no_hosts = EXAMPLE.replace("hosts = [\"127.*\"]\n", "");
+        assert_eq!(
+            error(&no_hosts).message,
+            "[[class]] is missing the required key `hosts`"
+        );
+    }
+
+    #[test]
+    /// Test that operator vhosts must be valid hostnames no longer than
+    /// `hostlen`.
+    fn vhosts() {
+        let with_vhost = |vhost: &str| {
+            let hosts = "hosts = [\"*@127.0.0.1\"]";
+            EXAMPLE.replace(hosts, &format!("{hosts}\nvhost = \"{vhost}\""))
+        };
+        let config = Config::parse(&with_vhost("staff.example.com"), Path::new("")).unwrap();
+        assert_eq!(
+            config.opers[0].vhost.as_deref(),
+            Some(&b"staff.example.com"[..])
+        );
+        assert_eq!(
+            error(&with_vhost("staff")).message,
+            "`vhost` must be a valid hostname"
+        );
+        let long = format!("{}.example.com", "x".repeat(60));
+        assert_eq!(
+            error(&with_vhost(&long)).message,
+            "`vhost` is longer than `hostlen`"
+        );
+    }
+
+    #[test]
+    /// Test that the message of the day is read relative to the file.
+    fn motd() {
+        let dir = std::env::temp_dir().join(format!("strigoi-motd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("motd.txt"), "Hello\r\nWorld\n").unwrap();
+        let text = EXAMPLE.replace(
+            "network = \"ExampleNet\"",
+            "network = \"ExampleNet\"\nmotd = \"motd.txt\"",
+        );
+        let config = Config::parse(&text, &dir).unwrap();
+        assert_eq!(config.motd.unwrap(), [b"Hello".to_vec(), b"World".to_vec()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(error(&text).line, Some(5));
+    }
+
+    #[test]
+    /// Test that TLS listeners need both a certificate and a key which can be
+    /// read.
+    fn tls_listeners() {
+        let text = EXAMPLE.replacen(
+            "[[listen]]\n",
+            "[[listen]]\ntls_cert = \"missing.pem\"\n",
+            1,
+        );
+        assert_eq!(
+            error(&text).message,
+            "`tls_cert` and `tls_key` must be given together"
+        );
+        let text = text.replace(
+            "tls_cert = \"missing.pem\"\n",
+            "tls_cert = \"missing.pem\"\ntls_key = \"missing.key\"\n",
+        );
+        assert!(error(&text).message.starts_with("cannot read missing.pem"));
+    }
+
+    #[test]
+    /// Test the history settings, and that limits are only given to
+    /// channels.
+    fn history() {
+        assert_eq!(Config::parse(EXAMPLE, Path::new("")).unwrap().history, None);
+        let text = format!(
+            "{EXAMPLE}\n[history]\nlimit = 50\nfile = \"history.log\"\n\
+             [history.channels]\n\"#Busy\" = 500\n\"#quiet\" = 0\n"
+        );
+        let history = Config::parse(&text, Path::new("/srv"))
+            .unwrap()
+            .history
+            .unwrap();
+        assert_eq!(history.limit_for(b"#busy"), 500);
+        assert_eq!(history.limit_for(b"#quiet"), 0);
+        assert_eq!(history.limit_for(b"#other"), 50);
+        assert_eq!(history.query_limit, 100);
+        assert_eq!(history.file, Some(PathBuf::from("/srv/history.log")));
+
+        let text = text.replace("\"#quiet\"", "quiet");
+        assert_eq!(error(&text).message, "`quiet` is not a channel name");
+    }
+
+    #[test]
+    /// Test that bans are `user@host` masks, with a default reason.
+    fn bans() {
+        let text = format!(
+            "{EXAMPLE}\n[[ban]]\nmask = \"*@*.spam.example\"\nreason = \"Spam\"\n\
+             [[ban]]\nmask = \"evil@*\"\n"
+        );
+        let bans = Config::parse(&text, Path::new("")).unwrap().bans;
+        assert_eq!(bans[0].reason, b"Spam");
+        assert!(bans[0].matches(b"user@irc.SPAM.example"));
+        assert!(!bans[0].matches(b"user@example.com"));
+        assert_eq!(bans[1].reason, b"Banned");
+
+        let nick = text.replace("evil@*", "nick!evil@*");
+        assert_eq!(error(&nick).message, "`mask` must have the form user@host");
+        let host = text.replace("evil@*", "evil");
+        assert_eq!(error(&host).message, "`mask` must have the form user@host");
+    }
+
+    #[test]
+    /// Test that accounts need a password or a fingerprint, and that
+    /// fingerprints are normalized and unique.
+    fn accounts() {
+        let fingerprint = "AB:".repeat(31) + "AB";
+        let text =
+            format!("{EXAMPLE}\n[[account]]\nname = \"alice\"\ncertfp = [\"{fingerprint}\"]\n");
+        let config = Config::parse(&text, Path::new("")).unwrap();
+        assert_eq!(config.accounts[0].certfps, ["ab".repeat(32).into_bytes()]);
+        assert_eq!(config.accounts[0].credentials, None);
+
+        let duplicate =
+            format!("{text}\n[[account]]\nname = \"bob\"\ncertfp = [\"{fingerprint}\"]\n");
+        assert_eq!(
+            error(&duplicate).message,
+            "the fingerprint is already used by the account alice"
+        );
+        let invalid = text.replace(&fingerprint, "abcd");
+        assert_eq!(
+            error(&invalid).message,
+            "`certfp` must be SHA-256 fingerprints"
+        );
+        let neither = format!("{EXAMPLE}\n[[account]]\nname = \"alice\"\n");
+        assert_eq!(
+            error(&neither).message,
+            "the account needs a `password`, a `scram` or a `certfp`"
+        );
+        let scram = "SCRAM-SHA-256$4096:c2FsdA==$WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:\
+                     wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU=";
+        let text = format!("{EXAMPLE}\n[[account]]\nname = \"alice\"\nscram = \"{scram}\"\n");
+        let credentials = Config::parse(&text, Path::new("")).unwrap().accounts[0]
+            .credentials
+            .clone()
+            .unwrap();
+        assert_eq!(credentials.salt, b"salt");
+        let both = text.replace("scram =", "password = \"x\"\nscram =");
+        assert_eq!(
+            error(&both).message,
+            "`password` and `scram` cannot both be given"
+        );
+        assert!(error(&text.replace("4096:", "4096"))
+            .message
+            .starts_with("`scram` must be in the form"));
+        let text = format!("{EXAMPLE}\n[[account]]\nname = \"alice\"\npassword = \"pencil\"\n");
+        let config = Config::parse(&text, Path::new("")).unwrap();
+        assert!(config.accounts[0]
+            .credentials
+            .as_ref()
+            .unwrap()
+            .verify(b"pencil"));
+    }
+
+    #[test]
+    /// Test that registered accounts are loaded from the registration file,
+    /// and may not share a name with an account in the configuration.
+    fn registration() {
+        let dir = std::env::temp_dir().join(format!("strigoi-registration-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = format!("{EXAMPLE}\n[registration]\nfile = \"accounts.toml\"\n");
+        let config = Config::parse(&text, &dir).unwrap();
+        assert_eq!(config.registration_file, Some(dir.join("accounts.toml")));
+        assert!(config.accounts.is_empty());
+
+        let path = dir.join("accounts.toml");
+        append_registered(&path, b"Alice", &Credentials::derive(b"pencil")).unwrap();
+        append_registered(&path, b"b[o]b", &Credentials::derive(b"sword")).unwrap();
+        let config = Config::parse(&text, &dir).unwrap();
+        assert_eq!(config.accounts.len(), 2);
+        assert_eq!(config.accounts[1].name, b"b[o]b");
+        assert!(config.accounts[0]
+            .credentials
+            .as_ref()
+            .unwrap()
+            .verify(b"pencil"));
+
+        let taken = format!("{text}\n[[account]]\nname = \"alice\"\npassword = \"x\"\n");
+        assert_eq!(
+            Config::parse(&taken, &dir).unwrap_err().message,
+            format!(
+                "{}: there is already an account named Alice",
+                path.display()
+            )
+        );
+        std::fs::write(&path, "[[account]]\nname = 'carol'\n").unwrap();
+        assert_eq!(
+            Config::parse(&text, &dir).unwrap_err().message,
+            format!(
+                "{}: line 2: the account needs a `password`, a `scram` or a `certfp`",
+                path.display()
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    /// Test hostname validation.
+    fn hostnames() {
+        assert!(is_valid_hostname("irc.example.com"));
+        assert!(is_valid_hostname("324.net.uk"));
+        assert!(is_valid_hostname("xn--bcher-kva.ch"));
+        assert!(!is_valid_hostname("irc.Bücher.ch"));
+        assert!(!is_valid_hostname("-lol.net.uk"));
+        assert!(!is_valid_hostname("_irc._sctp.lol.net.uk"));
+        assert!(!is_valid_hostname("irc"));
+        assert!(!is_valid_hostname("a..b"));
+        assert!(!is_valid_hostname(""));
+    }
+}
@@ -0,0 +1,216 @@
+//! A module for the masks which may be placed in a channel's ban (`+b`),
+//! ban exception (`+e`) and invite exception (`+I`) lists.
+//!
+//! Besides plain `nick!user@host` masks, these lists accept extended bans of
+//! the form `$<letter>[:<argument>]`, which match on properties of a client
+//! other than its hostmask.
+//! An extended ban may be negated by writing `$~<letter>`.
+
+use crate::{client::Client, mask};
+
+/// The byte which begins every extended ban, as advertised in the `EXTBAN`
+/// `ISUPPORT` token.
+pub const PREFIX: u8 = b'$';
+
+/// The byte which negates an extended ban when it follows the prefix.
+const NEGATION: u8 = b'~';
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A parsed entry of a channel's ban, exception or invite exception list.
+pub enum BanMask {
+    /// A plain `nick!user@host` mask, stored in normalized form.
+    Host(Vec<u8>),
+    /// `$a[:<account>]`: matches clients logged in to an account whose name
+    /// matches the mask, or any logged-in client when no mask is given.
+    Account(Option<Vec<u8>>),
+    /// `$r:<realname>`: matches clients whose real name matches the mask.
+    Realname(Vec<u8>),
+    /// `$j:<channel>`: matches clients who are members of the named channel.
+    Channel(Vec<u8>),
+    /// `$x:<nick!user@host#realname>`: matches against a client's hostmask and
+    /// real name at the same time.
+    Full(Vec<u8>),
+    /// `$z:<fingerprint>`: matches clients whose TLS client certificate has
+    /// the given fingerprint, or any client with a certificate when no
+    /// fingerprint is given.
+    CertFp(Option<Vec<u8>>),
+    /// `$m:<mask>`: a mute (or "quiet"). Matching clients may join the channel
+    /// but may not speak in it.
+    Mute(Box<BanMask>),
+    /// `$~<letter>...`: matches exactly the clients which the inner mask does
+    /// not.
+    Not(Box<BanMask>),
+}
+
+impl BanMask {
+    /// The letters of every supported extended ban type, in the order they are
+    /// advertised.
+    pub const LETTERS: &'static [u8] = b"ajmrxz";
+
+    /// Parse a mask as given in a `MODE` command.
+    ///
+    /// Plain hostmasks are normalized (so that `nick` becomes `nick!*@*`).
+    /// Returns `None` if `raw` is an extended ban which is malformed or of an
+    /// unknown type.
+    #[must_use]
+    pub fn parse(raw: &[u8]) -> Option<BanMask> {
+        let Some(rest) = raw.strip_prefix(&[PREFIX]) else {
+            return (!raw.is_empty()).then(|| BanMask::Host(mask::normalize(raw)));
+        };
+
+        let (negated, rest) = match rest.strip_prefix(&[NEGATION]) {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (&letter, rest) = rest.split_first()?;
+        let arg = match rest {
+            [] => None,
+            [b':', arg @ ..] if !arg.is_empty() => Some(arg),
+            _ => return None,
+        };
+
+        let parsed = match (letter, arg) {
+            (b'a', arg) => BanMask::Account(arg.map(<[u8]>::to_vec)),
+            (b'r', Some(arg)) => BanMask::Realname(arg.to_vec()),
+            (b'j', Some(arg)) => BanMask::Channel(mask::casefold(arg)),
+            (b'x', Some(arg)) => BanMask::Full(arg.to_vec()),
+            (b'z', arg) => BanMask::CertFp(arg.map(<[u8]>::to_ascii_lowercase)),
+            // a mute may not be negated, and may not contain another mute
+            (b'm', Some(arg)) if !negated => match BanMask::parse(arg)? {
+                BanMask::Mute(_) => return None,
+                inner => BanMask::Mute(Box::new(inner)),
+            },
+            _ => return None,
+        };
+
+        Some(if negated {
+            BanMask::Not(Box::new(parsed))
+        } else {
+            parsed
+        })
+    }
+
+    /// Determine whether this mask is a mute, which restricts speaking rather
+    /// than joining.
+    #[must_use]
+    pub fn is_mute(&self) -> bool {
+        matches!(self, BanMask::Mute(_))
+    }
+
+    /// Determine whether this mask matches `client`.
+    ///
+    /// A mute matches the same clients as the mask it contains.
+    #[must_use]
+    pub fn matches(&self, client: &Client) -> bool {
+        let realname = client.realname.as_deref().unwrap_or_default();
+        match self {
+            BanMask::Host(pattern) => mask::matches(pattern, &client.hostmask()),
+            BanMask::Account(None) => client.account.is_some(),
+            BanMask::Account(Some(pattern)) => client
+                .account
+                .as_ref()
+                .is_some_and(|account| mask::matches(pattern, account)),
+            BanMask::Realname(pattern) => mask::matches(pattern, realname),
+            BanMask::Channel(name) => client.channels.contains(name),
+            BanMask::Full(pattern) => {
+                let mut full = client.hostmask();
+                full.push(b'#');
+                full.extend_from_slice(realname);
+                mask::matches(pattern, &full)
+            }
+            BanMask::CertFp(None) => client.certfp.is_some(),
+            BanMask::CertFp(Some(fingerprint)) => client.certfp.as_ref() == Some(fingerprint),
+            BanMask::Mute(inner) => inner.matches(client),
+            BanMask::Not(inner) => !inner.matches(client),
+        }
+    }
+}
+
+/// Get the value of the `EXTBAN` `ISUPPORT` token, which advertises the
+/// extended ban prefix and every supported type letter.
+#[must_use]
+pub fn isupport_value() -> Vec<u8> {
+    let mut value = vec![PREFIX, b','];
+    value.extend_from_slice(BanMask::LETTERS);
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Construct a logged-out client named `nick!user@host` with the real
+    /// name `Real Name`.
+    fn client() -> Client {
+        let mut client = Client::new(0, b"host.example.com".to_vec());
+        client.nick = Some(b"nick".to_vec());
+        client.user = Some(b"user".to_vec());
+        client.realname = Some(b"Real Name".to_vec());
+        client
+    }
+
+    #[test]
+    /// Test that malformed or unknown extended bans are rejected.
+    fn parse_rejects_malformed() {
+        assert_eq!(BanMask::parse(b"$q:foo"), None);
+        assert_eq!(BanMask::parse(b"$r"), None);
+        assert_eq!(BanMask::parse(b"$r:"), None);
+        assert_eq!(BanMask::parse(b"$~m:foo"), None);
+        assert_eq!(BanMask::parse(b"$m:$m:foo"), None);
+        assert_eq!(BanMask::parse(b""), None);
+    }
+
+    #[test]
+    /// Test that an account extban matches only logged-in clients.
+    fn account_extban() {
+        let mut client = client();
+        let any = BanMask::parse(b"$a").unwrap();
+        let named = BanMask::parse(b"$a:Spam*").unwrap();
+        let none = BanMask::parse(b"$~a").unwrap();
+        assert!(!any.matches(&client));
+        assert!(none.matches(&client));
+
+        client.account = Some(b"spammer".to_vec());
+        assert!(any.matches(&client));
+        assert!(named.matches(&client));
+        assert!(!none.matches(&client));
+    }
+
+    #[test]
+    /// Test the realname, channel, full-match and fingerprint extbans.
+    fn other_extbans() {
+        let mut client = client();
+        client.channels.insert(b"#spam{}".to_vec());
+        client.certfp = Some(b"abcdef".to_vec());
+
+        assert!(BanMask::parse(b"$r:*name").unwrap().matches(&client));
+        assert!(BanMask::parse(b"$j:#SPAM[]").unwrap().matches(&client));
+        assert!(!BanMask::parse(b"$j:#other").unwrap().matches(&client));
+        assert!(BanMask::parse(b"$x:nick!*@*.example.com#real*")
+            .unwrap()
+            .matches(&client));
+        assert!(!BanMask::parse(b"$x:nick!*@*#other")
+            .unwrap()
+            .matches(&client));
+        assert!(BanMask::parse(b"$z:ABCDEF").unwrap().matches(&client));
+        assert!(!BanMask::parse(b"$z:012345").unwrap().matches(&client));
+    }
+
+    #[test]
+    /// Test that a mute wraps and matches like its inner mask.
+    fn mute_extban() {
+        let mute = BanMask::parse(b"$m:nick").unwrap();
+        assert!(mute.is_mute());
+        assert_eq!(
+            mute,
+            BanMask::Mute(Box::new(BanMask::Host(b"nick!*@*".to_vec())))
+        );
+        assert!(mute.matches(&client()));
+    }
+
+    #[test]
+    /// Test the advertised `EXTBAN` value.
+    fn isupport() {
+        assert_eq!(isupport_value(), b"$,ajmrxz");
+    }
+}
@@ -0,0 +1,191 @@
+//! A module for limiting how quickly each client may send commands.
+//!
+//! Every client has a token bucket which holds up to [`Limits::burst`]
+//! tokens and regains one token every [`Limits::refill`]. Each command a
+//! client sends takes its cost in tokens from the bucket. Once the bucket is
+//! empty, further commands are delayed until enough tokens have been regained
+//! (sometimes called "fakelag"), and a client which keeps sending regardless
+//! is disconnected once it owes more than [`Limits::excess`] tokens.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use crate::{client::Client, mask};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The configurable limits on how quickly clients may send commands.
+pub struct Limits {
+    /// The number of tokens in a full bucket, which is how many commands of
+    /// cost 1 a client may send at once before it is slowed down.
+    pub burst: u32,
+    /// How long it takes for a client to regain a single token.
+    pub refill: Duration,
+    /// How many tokens a client may owe before it is disconnected.
+    pub excess: u32,
+    /// The cost of commands which have no entry in `costs`.
+    pub default_cost: u32,
+    /// The cost of each command, by uppercase verb.
+    pub costs: HashMap<Vec<u8>, u32>,
+    /// Whether IRC operators are exempt from the limits.
+    pub exempt_opers: bool,
+    /// The casefolded names of the accounts (typically those of registered
+    /// bots) whose clients are exempt from the limits.
+    pub exempt_accounts: HashSet<Vec<u8>>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        let costs = [
+            (&b"JOIN"[..], 2),
+            (b"LIST", 3),
+            (b"NAMES", 2),
+            (b"NICK", 2),
+            (b"PONG", 0),
+            (b"WHOIS", 2),
+        ];
+        Limits {
+            burst: 10,
+            refill: Duration::from_secs(1),
+            excess: 20,
+            default_cost: 1,
+            costs: costs
+                .into_iter()
+                .map(|(verb, cost)| (verb.to_vec(), cost))
+                .collect(),
+            exempt_opers: true,
+            exempt_accounts: HashSet::new(),
+        }
+    }
+}
+
+impl Limits {
+    /// Get the cost of the command `verb`.
+    #[must_use]
+    pub fn cost(&self, verb: &[u8]) -> u32 {
+        self.costs
+            .get(&verb.to_ascii_uppercase())
+            .copied()
+            .unwrap_or(self.default_cost)
+    }
+
+    /// Determine whether `client` is exempt from these limits.
+    #[must_use]
+    pub fn is_exempt(&self, client: &Client) -> bool {
+        (self.exempt_opers && client.oper)
+            || client
+                .account
+                .as_ref()
+                .is_some_and(|account| self.exempt_accounts.contains(&mask::casefold(account)))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// What should happen to a command after its cost has been charged.
+pub enum Verdict {
+    /// The command may be handled immediately.
+    Allow,
+    /// The command should be handled once the given time has passed.
+    Delay(Duration),
+    /// The client has sent far too much, and should be disconnected.
+    Excess,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// A single client's token bucket.
+pub struct Bucket {
+    /// The time at which the bucket will be full again if nothing more is
+    /// taken from it. This is in the past when the bucket is already full.
+    full_at: Instant,
+}
+
+impl Bucket {
+    /// Construct a bucket which is full at `now`.
+    #[must_use]
+    pub fn new(now: Instant) -> Bucket {
+        Bucket { full_at: now }
+    }
+
+    /// Take `cost` tokens from the bucket at the time `now`, and decide what
+    /// should happen to the command which cost them.
+    pub fn charge(&mut self, limits: &Limits, cost: u32, now: Instant) -> Verdict {
+        self.full_at = self.full_at.max(now) + limits.refill * cost;
+        let backlog = self.full_at - now;
+        match backlog.checked_sub(limits.refill * limits.burst) {
+            None | Some(Duration::ZERO) => Verdict::Allow,
+            Some(owed) if owed > limits.refill * limits.excess => Verdict::Excess,
+            Some(owed) => Verdict::Delay(owed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that a burst is allowed, then delayed, then disconnected.
+    fn burst_then_delay() {
+        let limits = Limits {
+            burst: 3,
+            excess: 2,
+            ..Limits::default()
+        };
+        let now = Instant::now();
+        let mut bucket = Bucket::new(now);
+        for _ in 0..3 {
+            assert_eq!(bucket.charge(&limits, 1, now), Verdict::Allow);
+        }
+        assert_eq!(
+            bucket.charge(&limits, 1, now),
+            Verdict::Delay(Duration::from_secs(1))
+        );
+        assert_eq!(
+            bucket.charge(&limits, 1, now),
+            Verdict::Delay(Duration::from_secs(2))
+        );
+        assert_eq!(bucket.charge(&limits, 1, now), Verdict::Excess);
+    }
+
+    #[test]
+    /// Test that tokens are regained over time, up to the size of the bucket.
+    fn refill() {
+        let limits = Limits {
+            burst: 2,
+            ..Limits::default()
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::new(start);
+        assert_eq!(bucket.charge(&limits, 2, start), Verdict::Allow);
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.charge(&limits, 1, later), Verdict::Allow);
+        assert_eq!(
+            bucket.charge(&limits, 1, later),
+            Verdict::Delay(Duration::from_secs(1))
+        );
+
+        // a long silence does not let the bucket overfill
+        let much_later = start + Duration::from_secs(100);
+        assert_eq!(bucket.charge(&limits, 2, much_later), Verdict::Allow);
+        assert_ne!(bucket.charge(&limits, 1, much_later), Verdict::Allow);
+    }
+
+    #[test]
+    /// Test command costs and exemptions.
+    fn costs_and_exemptions() {
+        let mut limits = Limits::default();
+        assert_eq!(limits.cost(b"join"), 2);
+        assert_eq!(limits.cost(b"PRIVMSG"), 1);
+
+        let mut client = Client::new(0, b"host".to_vec());
+        assert!(!limits.is_exempt(&client));
+        client.oper = true;
+        assert!(limits.is_exempt(&client));
+
+        client.oper = false;
+        client.account = Some(b"HelpBot".to_vec());
+        limits.exempt_accounts.insert(b"helpbot".to_vec());
+        assert!(limits.is_exempt(&client));
+    }
+}
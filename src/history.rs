@@ -0,0 +1,625 @@
+//! A module for keeping the messages sent to channels and between users, so
+//! that clients can play them back with `CHATHISTORY`.
+//!
+//! Messages are kept under a key: the casefolded name of a channel, or for a
+//! private conversation, the casefolded names of the accounts of both users,
+//! given by [`conversation`]. Each key keeps at most the number of messages
+//! configured for it, and the oldest are dropped first.
+//!
+//! If a file is configured, every message is also appended to it, as the
+//! line it was relayed with tagged with its key. The file is read back when
+//! it is opened, and rewritten with only the messages which are still kept.
+//! Renames of channels are appended to it too, so that their messages are
+//! found under their new names when it is read back.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, IntoInnerError, Write},
+    ops::Range,
+    path::PathBuf,
+};
+
+use crate::{
+    config,
+    incoming::{self, MessageKind},
+    mask, outgoing, tags, time,
+};
+
+/// The tag which holds the key of each message in the history file.
+const KEY_TAG: &[u8] = b"key";
+
+/// Get the key of the private conversation between the accounts `a` and `b`,
+/// which is the same whichever of them is given first.
+#[must_use]
+pub fn conversation(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let (a, b) = (mask::casefold(a), mask::casefold(b));
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    [&first[..], b" ", &second].concat()
+}
+
+/// Get the account on the other side of the private conversation `key`, if
+/// it is one which `account` takes part in.
+#[must_use]
+pub fn other_party<'a>(key: &'a [u8], account: &[u8]) -> Option<&'a [u8]> {
+    let space = key.iter().position(|&b| b == b' ')?;
+    let account = mask::casefold(account);
+    if key[..space] == account[..] {
+        Some(&key[space + 1..])
+    } else if key[space + 1..] == account[..] {
+        Some(&key[..space])
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A message which has been kept.
+pub struct Entry {
+    /// The message as it was relayed, with its `time` and `msgid` tags.
+    message: outgoing::Message,
+    /// When the message was sent, in milliseconds since the Unix epoch.
+    time: u64,
+    /// The unique identifier of the message.
+    msgid: Vec<u8>,
+}
+
+impl Entry {
+    /// Construct an entry for `message`, or return `None` if it does not
+    /// have both a valid `time` tag and a `msgid` tag.
+    #[must_use]
+    pub fn new(message: outgoing::Message) -> Option<Entry> {
+        let tag = |key: &[u8]| {
+            message
+                .tags()
+                .iter()
+                .find(|(tag, _)| tag == key)
+                .map(|(_, value)| value.clone())
+        };
+        let time = time::parse_iso8601(&tag(b"time")?).map(time::unix_millis)?;
+        let msgid = tag(b"msgid")?;
+        Some(Entry {
+            message,
+            time,
+            msgid,
+        })
+    }
+
+    /// Get the message as it was relayed.
+    #[must_use]
+    pub fn message(&self) -> &outgoing::Message {
+        &self.message
+    }
+
+    /// Get when the message was sent, in milliseconds since the Unix epoch.
+    #[must_use]
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Get the unique identifier of the message.
+    #[must_use]
+    pub fn msgid(&self) -> &[u8] {
+        &self.msgid
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A point in a conversation named by a `CHATHISTORY` command.
+pub enum Reference {
+    /// The message with this identifier.
+    Msgid(Vec<u8>),
+    /// This time, in milliseconds since the Unix epoch.
+    Time(u64),
+}
+
+impl Reference {
+    /// Parse a reference written as `msgid=<msgid>` or
+    /// `timestamp=<ISO 8601 time>`.
+    #[must_use]
+    pub fn parse(text: &[u8]) -> Option<Reference> {
+        if let Some(msgid) = text.strip_prefix(b"msgid=") {
+            return (!msgid.is_empty()).then(|| Reference::Msgid(msgid.to_vec()));
+        }
+        let time = time::parse_iso8601(text.strip_prefix(b"timestamp=")?)?;
+        Some(Reference::Time(time::unix_millis(time)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Which messages of a conversation a `CHATHISTORY` command asks for.
+pub enum Query {
+    /// The latest messages, or the latest of those after the reference.
+    Latest(Option<Reference>),
+    /// The latest messages before the reference.
+    Before(Reference),
+    /// The earliest messages after the reference.
+    After(Reference),
+    /// The messages on either side of the reference.
+    Around(Reference),
+    /// The messages between the two references, starting from the first.
+    Between(Reference, Reference),
+}
+
+#[derive(Debug, Default)]
+/// Every message which has been kept, and the file they are written to.
+pub struct History {
+    /// How many messages are kept.
+    settings: config::History,
+    /// The messages kept under each key, from the oldest to the newest.
+    conversations: HashMap<Vec<u8>, VecDeque<Entry>>,
+    /// The file new messages are appended to, if there is one.
+    file: Option<File>,
+}
+
+impl History {
+    /// Construct a history with no messages, which is only kept in memory.
+    #[must_use]
+    pub fn new(settings: config::History) -> History {
+        History {
+            settings,
+            ..History::default()
+        }
+    }
+
+    /// Construct a history, reading back the messages in the file named by
+    /// `settings` and appending new messages to it, if one is named. The
+    /// file is created if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or rewritten. Lines in it
+    /// which cannot be understood are skipped.
+    pub fn open(settings: config::History) -> io::Result<History> {
+        let mut history = History::new(settings);
+        let Some(path) = history.settings.file.clone() else {
+            return Ok(history);
+        };
+        match fs::read(&path) {
+            Ok(bytes) => {
+                for line in bytes.split(|&b| b == b'\n') {
+                    history.load(line.strip_suffix(b"\r").unwrap_or(line));
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+
+        // rewrite the file with only the messages which are still kept, and
+        // replace the old one only once that has succeeded
+        let mut temp = OsString::from(path.as_os_str());
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        for (key, entries) in &history.conversations {
+            for entry in entries {
+                writer.write_all(&line(key, entry))?;
+            }
+        }
+        writer
+            .into_inner()
+            .map_err(IntoInnerError::into_error)?
+            .sync_all()?;
+        fs::rename(&temp, &path)?;
+        history.file = Some(OpenOptions::new().append(true).open(&path)?);
+        Ok(history)
+    }
+
+    /// Keep the message read back from `line` of the history file, if it can
+    /// be understood.
+    fn load(&mut self, line: &[u8]) {
+        let Ok(parsed) = incoming::Message::parse_bytes(line) else {
+            return;
+        };
+        if let MessageKind::Rename { channel, new, .. } = parsed.kind() {
+            self.move_channel(channel, new);
+            return;
+        }
+        let Some((_, key)) = parsed.tags().iter().find(|(tag, _)| tag == KEY_TAG) else {
+            return;
+        };
+        let (command, targets, text) = match parsed.kind() {
+            MessageKind::Privmsg { targets, text } => (&b"PRIVMSG"[..], targets, text),
+            MessageKind::Notice { targets, text } => (&b"NOTICE"[..], targets, text),
+            _ => return,
+        };
+        let [target] = &targets[..] else {
+            return;
+        };
+        let mut message =
+            outgoing::Message::new(parsed.source(), command, vec![target.clone(), text.clone()]);
+        for (tag, value) in parsed.tags().iter().filter(|(tag, _)| tag != KEY_TAG) {
+            message = message.with_tag(tag, value);
+        }
+        if let Some(entry) = Entry::new(message) {
+            self.insert(key, entry);
+        }
+    }
+
+    /// Use new `settings`, dropping any messages which are no longer kept.
+    /// The file is only changed by a restart, so the old one stays in the
+    /// settings.
+    pub fn configure(&mut self, settings: config::History) {
+        let file = self.settings.file.take();
+        self.settings = config::History { file, ..settings };
+        let keys: Vec<Vec<u8>> = self.conversations.keys().cloned().collect();
+        for key in keys {
+            self.trim(&key);
+        }
+    }
+
+    /// Get how many messages are kept.
+    #[must_use]
+    pub fn settings(&self) -> &config::History {
+        &self.settings
+    }
+
+    /// Keep `message` under `key`, and append it to the file if there is
+    /// one. Messages without a `time` and `msgid` tag are not kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message cannot be written to the file, in
+    /// which case nothing more is written to it, but messages are still kept
+    /// in memory.
+    pub fn record(&mut self, key: &[u8], message: &outgoing::Message) -> io::Result<()> {
+        if self.settings.limit_for(key) == 0 {
+            return Ok(());
+        }
+        let Some(entry) = Entry::new(message.clone()) else {
+            return Ok(());
+        };
+        let written = match &mut self.file {
+            Some(file) => file.write_all(&line(key, &entry)),
+            None => Ok(()),
+        };
+        if written.is_err() {
+            self.file = None;
+        }
+        self.insert(key, entry);
+        written
+    }
+
+    /// Keep the messages of the channel `old` under its new name `new`, and
+    /// append the rename to the file if there is one. The messages are
+    /// changed to be addressed to `new`, and any kept for an earlier channel
+    /// named `new` are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the rename cannot be written to the file, in
+    /// which case nothing more is written to it, but the messages are still
+    /// moved in memory.
+    pub fn rename(&mut self, old: &[u8], new: &[u8]) -> io::Result<()> {
+        let rename = outgoing::Message::new(None, b"RENAME", vec![old.to_vec(), new.to_vec()]);
+        let written = match &mut self.file {
+            Some(file) => file.write_all(&rename.to_line()),
+            None => Ok(()),
+        };
+        if written.is_err() {
+            self.file = None;
+        }
+        self.move_channel(old, new);
+        written
+    }
+
+    /// Move the messages kept for the channel `old` under its new name `new`.
+    fn move_channel(&mut self, old: &[u8], new: &[u8]) {
+        let key = mask::casefold(new);
+        let entries = self.conversations.remove(&mask::casefold(old));
+        self.conversations.remove(&key);
+        let Some(entries) = entries else {
+            return;
+        };
+        let entries = entries
+            .into_iter()
+            .map(|entry| {
+                let old_message = &entry.message;
+                let mut params = old_message.params().to_vec();
+                if let Some(target) = params.first_mut() {
+                    *target = new.to_vec();
+                }
+                let mut message =
+                    outgoing::Message::new(old_message.source(), old_message.command(), params);
+                for (tag, value) in old_message.tags() {
+                    message = message.with_tag(tag, value);
+                }
+                Entry { message, ..entry }
+            })
+            .collect();
+        self.conversations.insert(key.clone(), entries);
+        self.trim(&key);
+    }
+
+    /// Add `entry` to the end of the messages kept under `key`.
+    fn insert(&mut self, key: &[u8], entry: Entry) {
+        self.conversations
+            .entry(key.to_vec())
+            .or_default()
+            .push_back(entry);
+        self.trim(key);
+    }
+
+    /// Drop the oldest messages kept under `key` until no more are kept than
+    /// its limit.
+    fn trim(&mut self, key: &[u8]) {
+        let limit = self.settings.limit_for(key);
+        let Some(entries) = self.conversations.get_mut(key) else {
+            return;
+        };
+        let excess = entries.len().saturating_sub(limit);
+        entries.drain(..excess);
+        if entries.is_empty() {
+            self.conversations.remove(key);
+        }
+    }
+
+    /// Get at most `limit` of the messages kept under `key` which `query`
+    /// asks for, from the oldest to the newest. A reference to a message
+    /// which is not kept matches nothing.
+    #[must_use]
+    pub fn query(&self, key: &[u8], query: &Query, limit: usize) -> Vec<&Entry> {
+        let Some(entries) = self.conversations.get(key) else {
+            return Vec::new();
+        };
+        let Some((range, latest)) = select(entries, query, limit) else {
+            return Vec::new();
+        };
+        let range = range.start..range.end.max(range.start);
+        let range = if latest {
+            range.end.saturating_sub(limit).max(range.start)..range.end
+        } else {
+            range.start..range.end.min(range.start + limit)
+        };
+        entries.range(range).collect()
+    }
+
+    /// Get the keys accepted by `wanted` which have messages sent between
+    /// the times `from` and `to`, in either order, with the latest of those
+    /// messages. At most `limit` are returned, starting from the one with the
+    /// earliest such message.
+    #[must_use]
+    pub fn targets(
+        &self,
+        wanted: impl Fn(&[u8]) -> bool,
+        from: u64,
+        to: u64,
+        limit: usize,
+    ) -> Vec<(&[u8], &Entry)> {
+        let (start, end) = (from.min(to), from.max(to));
+        let mut targets: Vec<(&[u8], &Entry)> = self
+            .conversations
+            .iter()
+            .filter(|(key, _)| wanted(key))
+            .filter_map(|(key, entries)| {
+                let latest = entries
+                    .iter()
+                    .rev()
+                    .find(|entry| (start..=end).contains(&entry.time))?;
+                Some((&key[..], latest))
+            })
+            .collect();
+        targets.sort_by_key(|(key, entry)| (entry.time, *key));
+        targets.truncate(limit);
+        targets
+    }
+}
+
+/// Find the range of `entries` which `query` asks for, and whether the
+/// latest or the earliest of them should be given if there are more than
+/// `limit`.
+fn select(entries: &VecDeque<Entry>, query: &Query, limit: usize) -> Option<(Range<usize>, bool)> {
+    // the first entry which is not before `reference`
+    let start_of = |reference: &Reference| match reference {
+        Reference::Msgid(msgid) => entries.iter().position(|entry| entry.msgid == *msgid),
+        Reference::Time(time) => Some(entries.partition_point(|entry| entry.time < *time)),
+    };
+    // the first entry which is after `reference`
+    let end_of = |reference: &Reference| match reference {
+        Reference::Msgid(_) => start_of(reference).map(|start| start + 1),
+        Reference::Time(time) => Some(entries.partition_point(|entry| entry.time <= *time)),
+    };
+    let len = entries.len();
+    Some(match query {
+        Query::Latest(None) => (0..len, true),
+        Query::Latest(Some(reference)) => (end_of(reference)?..len, true),
+        Query::Before(reference) => (0..start_of(reference)?, true),
+        Query::After(reference) => (end_of(reference)?..len, false),
+        Query::Around(reference) => {
+            let start = start_of(reference)?.saturating_sub(limit / 2);
+            (start..len, false)
+        }
+        Query::Between(first, second) => {
+            if start_of(first)? <= start_of(second)? {
+                (end_of(first)?..start_of(second)?, false)
+            } else {
+                (end_of(second)?..start_of(first)?, true)
+            }
+        }
+    })
+}
+
+/// Serialize `entry` as a line of the history file, tagged with `key`.
+fn line(key: &[u8], entry: &Entry) -> Vec<u8> {
+    tags::add_to_line(&entry.message.to_line(), KEY_TAG, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Construct a message from `nick` to `#chan` sent `time` milliseconds
+    /// after the epoch, whose identifier is `msgid`.
+    fn message(time: u64, msgid: &str) -> outgoing::Message {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_millis(time);
+        outgoing::Message::new(
+            Some(b"nick!user@host"),
+            b"PRIVMSG",
+            vec![b"#chan".to_vec(), format!("message {msgid}").into_bytes()],
+        )
+        .with_tag(b"time", time::iso8601(time).as_bytes())
+        .with_tag(b"msgid", msgid.as_bytes())
+    }
+
+    /// Get the identifiers of `entries`.
+    fn msgids(entries: &[&Entry]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|entry| String::from_utf8(entry.msgid().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    /// Test every kind of query, with both kinds of reference.
+    fn queries() {
+        let mut history = History::new(config::History::default());
+        for i in 1..=9 {
+            history
+                .record(b"#chan", &message(i * 1000, &format!("m{i}")))
+                .unwrap();
+        }
+        let query = |query: Query, limit| msgids(&history.query(b"#chan", &query, limit));
+        let msgid = |msgid: &str| Reference::Msgid(msgid.as_bytes().to_vec());
+        let time = Reference::Time;
+
+        assert_eq!(query(Query::Latest(None), 2), ["m8", "m9"]);
+        assert_eq!(query(Query::Latest(Some(msgid("m7"))), 5), ["m8", "m9"]);
+        assert_eq!(query(Query::Before(msgid("m4")), 2), ["m2", "m3"]);
+        assert_eq!(query(Query::Before(time(4000)), 9), ["m1", "m2", "m3"]);
+        assert_eq!(query(Query::After(time(7000)), 9), ["m8", "m9"]);
+        assert_eq!(query(Query::After(time(6500)), 1), ["m7"]);
+        assert_eq!(query(Query::Around(msgid("m5")), 3), ["m4", "m5", "m6"]);
+        assert_eq!(query(Query::Around(msgid("m1")), 3), ["m1", "m2", "m3"]);
+        assert_eq!(
+            query(Query::Between(msgid("m2"), time(6000)), 9),
+            ["m3", "m4", "m5"]
+        );
+        assert_eq!(
+            query(Query::Between(msgid("m2"), time(6000)), 2),
+            ["m3", "m4"]
+        );
+        assert_eq!(
+            query(Query::Between(time(6000), msgid("m2")), 2),
+            ["m4", "m5"]
+        );
+        assert_eq!(query(Query::Between(msgid("m2"), msgid("m2")), 9), [""; 0]);
+        assert_eq!(query(Query::Before(msgid("gone")), 9), [""; 0]);
+        assert!(history.query(b"#other", &Query::Latest(None), 9).is_empty());
+
+        assert_eq!(
+            Reference::parse(b"timestamp=1970-01-01T00:00:01.500Z"),
+            Some(time(1500))
+        );
+        assert_eq!(Reference::parse(b"msgid=m1"), Some(msgid("m1")));
+        assert_eq!(Reference::parse(b"msgid="), None);
+        assert_eq!(Reference::parse(b"1970-01-01T00:00:01Z"), None);
+    }
+
+    #[test]
+    /// Test that only the configured number of messages are kept, and that
+    /// targets are found by the time of their latest message.
+    fn limits_and_targets() {
+        let mut settings = config::History {
+            limit: 3,
+            ..config::History::default()
+        };
+        settings.channel_limits.insert(b"#quiet".to_vec(), 0);
+        let mut history = History::new(settings.clone());
+        for i in 1..=5 {
+            history
+                .record(b"#chan", &message(i * 1000, &format!("c{i}")))
+                .unwrap();
+            history
+                .record(b"#quiet", &message(i * 1000, &format!("q{i}")))
+                .unwrap();
+        }
+        history
+            .record(&conversation(b"Bob", b"alice"), &message(2500, "p1"))
+            .unwrap();
+        let latest = |history: &History| msgids(&history.query(b"#chan", &Query::Latest(None), 9));
+        assert_eq!(latest(&history), ["c3", "c4", "c5"]);
+        assert!(history.query(b"#quiet", &Query::Latest(None), 9).is_empty());
+
+        let targets = history.targets(|_| true, 5000, 0, 9);
+        let targets: Vec<_> = targets
+            .iter()
+            .map(|(key, entry)| (*key, entry.msgid()))
+            .collect();
+        assert_eq!(
+            targets,
+            [(&b"alice bob"[..], &b"p1"[..]), (b"#chan", b"c5")]
+        );
+        assert_eq!(history.targets(|key| key != b"#chan", 0, 2000, 9), []);
+        assert_eq!(other_party(b"alice bob", b"Alice"), Some(&b"bob"[..]));
+        assert_eq!(other_party(b"alice bob", b"carol"), None);
+        assert_eq!(other_party(b"#chan", b"alice"), None);
+
+        settings.limit = 1;
+        history.configure(settings);
+        assert_eq!(latest(&history), ["c5"]);
+    }
+
+    #[test]
+    /// Test that messages are kept across restarts, and that the file is
+    /// compacted when it is opened.
+    fn file() {
+        let dir = std::env::temp_dir().join(format!("strigoi-history-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.log");
+        let settings = config::History {
+            limit: 2,
+            file: Some(path.clone()),
+            ..config::History::default()
+        };
+
+        let mut history = History::open(settings.clone()).unwrap();
+        for i in 1..=3 {
+            let message = message(i * 1000, &format!("m{i}")).with_tag(b"+x", b"a b;c");
+            history.record(b"#chan", &message).unwrap();
+        }
+        drop(history);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"not a message\r\n@key=#chan :x PRIVMSG #chan hi\r\n")
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap().split(|&b| b == b'\n').count(), 6);
+
+        let history = History::open(settings).unwrap();
+        let entries = history.query(b"#chan", &Query::Latest(None), 9);
+        assert_eq!(msgids(&entries), ["m2", "m3"]);
+        assert_eq!(
+            entries[1].message(),
+            &message(3000, "m3").with_tag(b"+x", b"a b;c")
+        );
+        let text = String::from_utf8(fs::read(&path).unwrap()).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.starts_with("@key=#chan;time=1970-01-01T00:00:02.000Z;msgid=m2;+x=a\\sb\\:c "));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    /// Test that the messages of a renamed channel are found under its new
+    /// name, including once the file has been read back.
+    fn renames() {
+        let dir = std::env::temp_dir().join(format!("strigoi-renames-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let settings = config::History {
+            file: Some(dir.join("history.log")),
+            ..config::History::default()
+        };
+
+        let mut history = History::open(settings.clone()).unwrap();
+        history.record(b"#new", &message(1000, "stale")).unwrap();
+        history.record(b"#chan", &message(2000, "m1")).unwrap();
+        history.rename(b"#chan", b"#New").unwrap();
+        history.record(b"#new", &message(3000, "m2")).unwrap();
+        drop(history);
+
+        let history = History::open(settings).unwrap();
+        assert!(history.query(b"#chan", &Query::Latest(None), 9).is_empty());
+        let entries = history.query(b"#new", &Query::Latest(None), 9);
+        assert_eq!(msgids(&entries), ["m1", "m2"]);
+        assert_eq!(entries[0].message().params()[0], b"#New");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
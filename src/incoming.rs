@@ -1,14 +1,19 @@
 //! A module for defining the incoming messages and other associated parsers of
 //! an IRC server.
 
-use std::{collections::HashMap, io::Read, iter::Peekable};
+use std::io::Read;
 
-use crate::IrcError;
+use crate::{
+    tags::{self, Tags},
+    IrcError,
+};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 /// A parsed incoming message, containing the heterogenous fields as well as the
 /// common homogenous fields of a message.
 pub struct Message {
+    /// The tags given with this message, with their values unescaped.
+    tags: Tags,
     /// The server which was the original source of this message. The source
     /// will always be `None` for messages received directly from a client.
     source: Option<Vec<u8>>,
@@ -30,6 +35,212 @@ pub enum MessageKind {
     /// Notify the server of a user's nickname. The only field is the bytes of
     /// the user's nickname.
     Nick(Vec<u8>),
+    /// Notify the server of a connecting user's username and real name.
+    User {
+        /// The username, which forms the middle part of the user's hostmask.
+        user: Vec<u8>,
+        /// The user's "real name", which may contain spaces.
+        realname: Vec<u8>,
+    },
+    /// Join one or more channels.
+    Join {
+        /// The names of the channels to join.
+        channels: Vec<Vec<u8>>,
+        /// The keys to use for each channel, in the same order as `channels`.
+        /// There may be fewer keys than channels.
+        keys: Vec<Vec<u8>>,
+    },
+    /// Leave one or more channels.
+    Part {
+        /// The names of the channels to leave.
+        channels: Vec<Vec<u8>>,
+        /// The reason given for leaving, if any.
+        reason: Option<Vec<u8>>,
+    },
+    /// Send a message to one or more channels or users.
+    Privmsg {
+        /// The channels or nicknames to send the message to.
+        targets: Vec<Vec<u8>>,
+        /// The text of the message.
+        text: Vec<u8>,
+    },
+    /// Send a notice to one or more channels or users.
+    /// Unlike `Privmsg`, no automatic replies or errors may be sent in
+    /// response to a notice.
+    Notice {
+        /// The channels or nicknames to send the notice to.
+        targets: Vec<Vec<u8>>,
+        /// The text of the notice.
+        text: Vec<u8>,
+    },
+    /// Send a message with tags but no text to one or more channels or
+    /// users, giving the channels or nicknames to send it to.
+    Tagmsg(Vec<Vec<u8>>),
+    /// Query or change the modes of a channel or user.
+    Mode {
+        /// The channel or nickname whose modes are being queried or changed.
+        target: Vec<u8>,
+        /// The mode string (such as `+kl-m`), followed by the arguments of the
+        /// modes in it.
+        /// This is empty if the modes are only being queried.
+        changes: Vec<Vec<u8>>,
+    },
+    /// List the members of some channels. The only field is the names of the
+    /// channels, which is empty if no channels were named.
+    Names(Vec<Vec<u8>>),
+    /// List the channels on the server. The only field is the names of the
+    /// channels to list, which is empty if every channel should be listed.
+    List(Vec<Vec<u8>>),
+    /// List the users in a channel or matching a mask.
+    Who {
+        /// The name of the channel, or the mask to match against each user's
+        /// nickname, username, hostname, server and real name.
+        mask: Vec<u8>,
+        /// Letters which narrow down the users listed, such as `o` for only
+        /// IRC operators. This is empty if none were given.
+        options: Vec<u8>,
+        /// The fields to send for each user, if the client asked for them
+        /// with `%` after the options.
+        whox: Option<Whox>,
+    },
+    /// Query or change the list of nicknames the client is told about when
+    /// they come online or go offline.
+    Monitor(MonitorCommand),
+    /// Query information about a user. The only field is the nickname of the
+    /// user.
+    Whois(Vec<u8>),
+    /// Become an IRC operator.
+    Oper {
+        /// The name of the operator account.
+        name: Vec<u8>,
+        /// The password of the operator account.
+        password: Vec<u8>,
+    },
+    /// Negotiate the optional protocol extensions the client will use.
+    Cap(CapCommand),
+    /// Authenticate with SASL. The only field is the name of the mechanism
+    /// to use, a chunk of a base64-encoded response, `+` for an empty chunk,
+    /// or `*` to abort.
+    Authenticate(Vec<u8>),
+    /// Request the server's message of the day.
+    Motd,
+    /// Reload the server's configuration.
+    Rehash,
+    /// Query statistics about the server. The only field is the query, which
+    /// is usually a single letter.
+    Stats(Vec<u8>),
+    /// Invite a user to a channel.
+    Invite {
+        /// The nickname of the user being invited.
+        nick: Vec<u8>,
+        /// The channel the user is being invited to.
+        channel: Vec<u8>,
+    },
+    /// Remove one or more users from a channel.
+    Kick {
+        /// The name of the channel.
+        channel: Vec<u8>,
+        /// The nicknames of the users to remove.
+        nicks: Vec<Vec<u8>>,
+        /// The reason given for removing them, if any.
+        reason: Option<Vec<u8>>,
+    },
+    /// Register an account.
+    Register {
+        /// The name of the account, or `*` to name it after the client's
+        /// nickname.
+        account: Vec<u8>,
+        /// The email address to verify the account with, or `*`. This server
+        /// does not verify accounts, so it is ignored.
+        email: Vec<u8>,
+        /// The password of the account.
+        password: Vec<u8>,
+    },
+    /// Rename a channel.
+    Rename {
+        /// The current name of the channel.
+        channel: Vec<u8>,
+        /// The name to give the channel.
+        new: Vec<u8>,
+        /// The reason given for renaming it, if any.
+        reason: Option<Vec<u8>>,
+    },
+    /// Query or change the client's silence list. The only field is the mask
+    /// to add (prefixed with `+` or nothing) or remove (prefixed with `-`),
+    /// or `None` to list the masks.
+    Silence(Option<Vec<u8>>),
+    /// Query the messages kept in the history of a channel or private
+    /// conversation, or which conversations have any. The only field is the
+    /// subcommand followed by its parameters, which may be missing, since
+    /// the server reports that with a standard reply.
+    Chathistory(Vec<Vec<u8>>),
+    /// Mark the client as away, or no longer away. The only field is the away
+    /// message, or `None` if none was given or it was empty.
+    Away(Option<Vec<u8>>),
+    /// Change the client's realname. The only field is the new realname,
+    /// which is empty if none was given, since the server reports that with
+    /// a standard reply.
+    Setname(Vec<u8>),
+    /// Query or change the topic of a channel.
+    Topic {
+        /// The name of the channel.
+        channel: Vec<u8>,
+        /// The new topic, or `None` if the topic is only being queried.
+        /// An empty topic clears the channel's topic.
+        topic: Option<Vec<u8>>,
+    },
+    /// Check that the connection is still alive. The only field is a token
+    /// which must be echoed back in the `PONG` reply.
+    Ping(Vec<u8>),
+    /// Reply to a `PING` sent by the server. The only field is the token of
+    /// the `PING`.
+    Pong(Vec<u8>),
+    /// Disconnect from the server. The only field is the quit message, if one
+    /// was given.
+    Quit(Option<Vec<u8>>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The subcommands of `CAP`.
+pub enum CapCommand {
+    /// List the capabilities the server supports. The only field is the
+    /// version of negotiation the client supports, if it gave one.
+    Ls(Option<u16>),
+    /// List the capabilities the client has enabled.
+    List,
+    /// Enable the capabilities in the space-separated list, or disable those
+    /// prefixed with `-`. Either all of them change or none do.
+    Req(Vec<u8>),
+    /// End negotiation, completing registration if it was held.
+    End,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The fields asked for by an extended `WHO` query, given as
+/// `%fields,token`.
+pub struct Whox {
+    /// The letters of the fields to send, in any order.
+    pub fields: Vec<u8>,
+    /// The number sent back in the `t` field, or `0` if the client did not
+    /// give a number of up to three digits.
+    pub token: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The subcommands of `MONITOR`.
+pub enum MonitorCommand {
+    /// Add nicknames to the monitor list.
+    Add(Vec<Vec<u8>>),
+    /// Remove nicknames from the monitor list.
+    Remove(Vec<Vec<u8>>),
+    /// Remove every nickname from the monitor list.
+    Clear,
+    /// List the nicknames on the monitor list.
+    List,
+    /// Tell the client which nicknames on its monitor list are online.
+    Status,
+    /// A subcommand this server does not know, which is ignored.
+    Unknown(Vec<u8>),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -59,132 +270,430 @@ impl From<&std::io::Error> for ParseError {
     }
 }
 
+impl From<IrcError> for ParseError {
+    fn from(err: IrcError) -> Self {
+        ParseError::Irc(err)
+    }
+}
+
 impl Message {
     /// Read an incoming line of a message from a source, and attempt to create
     /// an `InMessage` from it.
     ///
     /// Will consume all bytes from `source` until it encounters a CRLF
     /// (carriage-return, line-feed) pair of bytes.
+    /// For leniency with simple clients, a lone line-feed is also accepted as
+    /// the end of a line.
     ///
     /// # Errors
     ///
     /// This function will return an error in one of 3 cases:
     /// * The message received is incorrectly formatted, or otherwise cannot be
-    ///     parsed.
-    ///     In this case, the return value will be of variant
-    ///     `Err(ParseError::Irc)`.
+    ///   parsed.
+    ///   In this case, the return value will be of variant
+    ///   `Err(ParseError::Irc)`.
     /// * There was an I/O error while trying to read the incoming input.
-    ///     In this case, the return value will be of variant
-    ///     `Err(ParseError::Io)`.
+    ///   In this case, the return value will be of variant
+    ///   `Err(ParseError::Io)`.
     /// * The connection was closed, or the end of the reader was reached, while
-    ///     parsing the line.
-    ///     In this case, the return value will be of variant
-    ///     `Err(ParseError::End)`.
-    ///
-    /// # Panics
-    /// fuk u clippy
+    ///   parsing the line.
+    ///   In this case, the return value will be of variant
+    ///   `Err(ParseError::End)`.
     pub fn parse_line(source: &mut dyn Read) -> Result<Message, ParseError> {
-        let mut bytes = source.bytes().peekable();
+        let mut line = Vec::new();
+        for byte in source.bytes() {
+            match byte? {
+                b'\n' => {
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+                    return Message::parse_bytes(&line);
+                }
+                byte => line.push(byte),
+            }
+        }
+
+        Err(ParseError::End)
+    }
+
+    /// Parse a single message from `line`, which must not contain the
+    /// terminating CRLF.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `Err(ParseError::Irc)` if the message is a
+    /// well-formed line which cannot be turned into a command, and
+    /// `Err(ParseError::Malformed)` if the line itself is not well-formed.
+    pub fn parse_bytes(line: &[u8]) -> Result<Message, ParseError> {
+        // Characters which may never appear in a line.
+        const FORBIDDEN: &[u8] = b"\r\n\x00";
+        if line.iter().any(|b| FORBIDDEN.contains(b)) {
+            return Err(ParseError::Malformed);
+        }
+        let mut rest = line;
 
         // check for tags
-        let tags: HashMap<Vec<u8>, Vec<u8>> = match bytes.peek().ok_or(ParseError::End)? {
-            Ok(b'@') => todo!(),
-            _ => HashMap::new(),
+        let tags = match rest.strip_prefix(b"@") {
+            Some(after) => {
+                let (section, after) = split_word(after);
+                rest = trim_spaces(after);
+                tags::parse(section)
+            }
+            None => Vec::new(),
         };
         // check for source identifier
-        let source: Option<Vec<u8>> = match bytes.peek().ok_or(ParseError::End)? {
-            Ok(b':') => todo!(),
-            _ => None,
+        let source = match rest.strip_prefix(b":") {
+            Some(after) => {
+                let (source, after) = split_word(after);
+                rest = after;
+                Some(source.to_vec())
+            }
+            None => None,
         };
 
         // Extract a command.
-        // here, we assume there are no leading spaces.
-        let verb = {
-            let mut buf = Vec::new();
-            let mut byte = bytes.next().ok_or(ParseError::End)??;
-            while byte.is_ascii_alphanumeric() {
-                buf.push(byte);
-                byte = bytes.next().ok_or(ParseError::End)??;
-            }
-            consume_spaces(&mut bytes)?;
-            buf
-        };
+        let (verb, mut rest) = split_word(trim_spaces(rest));
+        if verb.is_empty() || !verb.iter().all(u8::is_ascii_alphanumeric) {
+            return Err(ParseError::Malformed);
+        }
 
-        // verb is now a sequence of alphanumeric bytes.
-
-        // lastly, extract the parameters. `params` will have
-        let params: Vec<Vec<u8>> = {
-            let mut params = Vec::new();
-            loop {
-                let next_byte = bytes.peek().ok_or(ParseError::End)?.as_ref()?;
-                if *next_byte == b'\r' {
-                    // end of message
-                    bytes.next().unwrap().unwrap();
-                    if bytes.next().ok_or(ParseError::End)?? != b'\n' {
-                        return Err(ParseError::Malformed);
-                    }
-                    break;
-                }
-                params.push(Message::parse_param(&mut bytes)?);
-                consume_spaces(&mut bytes)?;
+        // lastly, extract the parameters.
+        let mut params = Vec::new();
+        loop {
+            rest = trim_spaces(rest);
+            if let Some(trailing) = rest.strip_prefix(b":") {
+                params.push(trailing.to_vec());
+                break;
             }
-            params
-        };
+            if rest.is_empty() {
+                break;
+            }
+            let (param, after) = split_word(rest);
+            params.push(param.to_vec());
+            rest = after;
+        }
 
         Ok(Message {
+            tags,
             source,
-            kind: match std::convert::AsRef::<[u8]>::as_ref(&verb.to_ascii_uppercase()) {
-                b"NICK" => match params.len() {
-                    0 => Err(ParseError::Irc(IrcError::NeedMoreParams(verb)))?,
-                    1 => MessageKind::Nick(params[0].clone()),
-                    _ => Err(ParseError::Irc(IrcError::ErroneousNickname(
-                        params[0].clone(),
-                    )))?,
-                },
-                _ => Err(ParseError::Irc(IrcError::UnknownCommand(verb)))?,
-            },
+            kind: MessageKind::parse(&verb.to_ascii_uppercase(), params)?,
         })
     }
 
-    /// Parse a single parameter string. I
-    fn parse_param(
-        bytes: &mut Peekable<std::io::Bytes<&mut dyn Read>>,
-    ) -> Result<Vec<u8>, ParseError> {
-        // Characters which cannot be part of the non-trailing parameter.
-        const ESCAPES: &[u8] = b" \r\n\x00";
-        let mut param = Vec::new();
-        match bytes.peek().ok_or(ParseError::End)? {
-            Ok(b':') => {
-                bytes.next().unwrap().unwrap();
-                // read up until we reach the end
-                while !b"\r\n\x00".contains(bytes.peek().ok_or(ParseError::End)?.as_ref()?) {
-                    param.push(bytes.next().unwrap().unwrap());
+    /// Get the tags given with this message.
+    #[must_use]
+    pub fn tags(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.tags
+    }
+
+    /// Get the source of this message, if one was given.
+    #[must_use]
+    pub fn source(&self) -> Option<&[u8]> {
+        self.source.as_deref()
+    }
+
+    /// Get the kind and parameters of this message.
+    #[must_use]
+    pub fn kind(&self) -> &MessageKind {
+        &self.kind
+    }
+
+    /// Take the kind and parameters of this message, discarding the rest.
+    #[must_use]
+    pub fn into_kind(self) -> MessageKind {
+        self.kind
+    }
+}
+
+impl MessageKind {
+    /// Get the uppercase verb of the command this kind of message is sent
+    /// with.
+    #[must_use]
+    pub fn verb(&self) -> &'static [u8] {
+        match self {
+            MessageKind::Nick(_) => b"NICK",
+            MessageKind::User { .. } => b"USER",
+            MessageKind::Join { .. } => b"JOIN",
+            MessageKind::Part { .. } => b"PART",
+            MessageKind::Privmsg { .. } => b"PRIVMSG",
+            MessageKind::Notice { .. } => b"NOTICE",
+            MessageKind::Tagmsg(_) => b"TAGMSG",
+            MessageKind::Mode { .. } => b"MODE",
+            MessageKind::Names(_) => b"NAMES",
+            MessageKind::List(_) => b"LIST",
+            MessageKind::Monitor(_) => b"MONITOR",
+            MessageKind::Who { .. } => b"WHO",
+            MessageKind::Whois(_) => b"WHOIS",
+            MessageKind::Oper { .. } => b"OPER",
+            MessageKind::Cap(_) => b"CAP",
+            MessageKind::Authenticate(_) => b"AUTHENTICATE",
+            MessageKind::Motd => b"MOTD",
+            MessageKind::Rehash => b"REHASH",
+            MessageKind::Stats(_) => b"STATS",
+            MessageKind::Invite { .. } => b"INVITE",
+            MessageKind::Kick { .. } => b"KICK",
+            MessageKind::Register { .. } => b"REGISTER",
+            MessageKind::Rename { .. } => b"RENAME",
+            MessageKind::Silence(_) => b"SILENCE",
+            MessageKind::Chathistory(_) => b"CHATHISTORY",
+            MessageKind::Away(_) => b"AWAY",
+            MessageKind::Setname(_) => b"SETNAME",
+            MessageKind::Topic { .. } => b"TOPIC",
+            MessageKind::Ping(_) => b"PING",
+            MessageKind::Pong(_) => b"PONG",
+            MessageKind::Quit(_) => b"QUIT",
+        }
+    }
+
+    /// Create a message kind from its uppercase `verb` and its parameters.
+    #[allow(clippy::too_many_lines)]
+    fn parse(verb: &[u8], mut params: Vec<Vec<u8>>) -> Result<MessageKind, ParseError> {
+        let need_more = || ParseError::Irc(IrcError::NeedMoreParams(verb.to_vec()));
+        // Take the parameter at `index`, which is assumed to be present.
+        let take = |params: &mut Vec<Vec<u8>>, index: usize| std::mem::take(&mut params[index]);
+
+        Ok(match verb {
+            b"NICK" => match params.len() {
+                0 => Err(IrcError::NoNicknameGiven)?,
+                1 => MessageKind::Nick(take(&mut params, 0)),
+                _ => Err(IrcError::ErroneousNickname(take(&mut params, 0)))?,
+            },
+            b"USER" => {
+                if params.len() < 4 || params[0].is_empty() {
+                    return Err(need_more());
+                }
+                MessageKind::User {
+                    user: take(&mut params, 0),
+                    realname: take(&mut params, 3),
                 }
             }
-            Ok(byte) => {
-                if ESCAPES.contains(byte) {
-                    return Ok(param);
+            b"JOIN" => match params.len() {
+                0 => return Err(need_more()),
+                len => MessageKind::Join {
+                    channels: split_list(&params[0]),
+                    keys: if len > 1 {
+                        split_list(&params[1])
+                    } else {
+                        Vec::new()
+                    },
+                },
+            },
+            b"PART" => match params.len() {
+                0 => return Err(need_more()),
+                len => MessageKind::Part {
+                    channels: split_list(&params[0]),
+                    reason: (len > 1).then(|| take(&mut params, 1)),
+                },
+            },
+            b"PRIVMSG" | b"NOTICE" => {
+                let (targets, text) = match params.len() {
+                    0 => Err(IrcError::NoRecipient(verb.to_vec()))?,
+                    1 => Err(IrcError::NoTextToSend)?,
+                    _ => (split_list(&params[0]), take(&mut params, 1)),
+                };
+                if verb == b"PRIVMSG" {
+                    MessageKind::Privmsg { targets, text }
+                } else {
+                    MessageKind::Notice { targets, text }
                 }
-
-                while !ESCAPES.contains(bytes.peek().ok_or(ParseError::End)?.as_ref()?) {
-                    param.push(bytes.next().unwrap().unwrap());
+            }
+            b"TAGMSG" => match params.first() {
+                Some(targets) => MessageKind::Tagmsg(split_list(targets)),
+                None => Err(IrcError::NoRecipient(verb.to_vec()))?,
+            },
+            b"MODE" => {
+                if params.is_empty() {
+                    return Err(need_more());
+                }
+                let changes = params.split_off(1);
+                MessageKind::Mode {
+                    target: take(&mut params, 0),
+                    changes,
                 }
             }
-            Err(e) => return Err(e.into()),
-        }
-
-        Ok(param)
+            b"NAMES" => {
+                MessageKind::Names(params.first().map(|p| split_list(p)).unwrap_or_default())
+            }
+            b"LIST" => MessageKind::List(params.first().map(|p| split_list(p)).unwrap_or_default()),
+            b"MONITOR" => {
+                let mut params = params.into_iter();
+                let Some(subcommand) = params.next() else {
+                    return Err(need_more());
+                };
+                let mut targets = || -> Result<Vec<Vec<u8>>, ParseError> {
+                    let list = params.next().ok_or_else(need_more)?;
+                    Ok(list
+                        .split(|&b| b == b',')
+                        .filter(|target| !target.is_empty())
+                        .map(<[u8]>::to_vec)
+                        .collect())
+                };
+                MessageKind::Monitor(match &subcommand.to_ascii_uppercase()[..] {
+                    b"+" => MonitorCommand::Add(targets()?),
+                    b"-" => MonitorCommand::Remove(targets()?),
+                    b"C" => MonitorCommand::Clear,
+                    b"L" => MonitorCommand::List,
+                    b"S" => MonitorCommand::Status,
+                    _ => MonitorCommand::Unknown(subcommand),
+                })
+            }
+            b"WHO" => {
+                let mut params = params.into_iter();
+                let mask = params.next().unwrap_or_else(|| b"*".to_vec());
+                let mut options = params.next().unwrap_or_default();
+                let whox = options.iter().position(|&b| b == b'%').map(|percent| {
+                    let query = options.split_off(percent);
+                    let mut query = query[1..].splitn(2, |&b| b == b',');
+                    let fields = query.next().unwrap_or_default().to_vec();
+                    let token = match query.next() {
+                        Some(token)
+                            if (1..=3).contains(&token.len())
+                                && token.iter().all(u8::is_ascii_digit) =>
+                        {
+                            token.to_vec()
+                        }
+                        _ => b"0".to_vec(),
+                    };
+                    Whox { fields, token }
+                });
+                MessageKind::Who {
+                    mask,
+                    options,
+                    whox,
+                }
+            }
+            b"WHOIS" => match params.pop() {
+                // the optional first parameter names a server, which we ignore
+                Some(nick) => MessageKind::Whois(nick),
+                None => Err(IrcError::NoNicknameGiven)?,
+            },
+            b"OPER" => {
+                if params.len() < 2 {
+                    return Err(need_more());
+                }
+                MessageKind::Oper {
+                    name: take(&mut params, 0),
+                    password: take(&mut params, 1),
+                }
+            }
+            b"CAP" => {
+                let mut params = params.into_iter();
+                let Some(subcommand) = params.next() else {
+                    return Err(need_more());
+                };
+                MessageKind::Cap(match &subcommand.to_ascii_uppercase()[..] {
+                    b"LS" => CapCommand::Ls(
+                        params
+                            .next()
+                            .and_then(|version| std::str::from_utf8(&version).ok()?.parse().ok()),
+                    ),
+                    b"LIST" => CapCommand::List,
+                    b"REQ" => CapCommand::Req(params.next().ok_or_else(need_more)?),
+                    b"END" => CapCommand::End,
+                    _ => return Err(IrcError::InvalidCapCmd(subcommand).into()),
+                })
+            }
+            b"AUTHENTICATE" => match params.into_iter().next() {
+                Some(param) if !param.is_empty() => MessageKind::Authenticate(param),
+                _ => return Err(need_more()),
+            },
+            // the optional parameter names a server, which we ignore
+            b"MOTD" => MessageKind::Motd,
+            b"REHASH" => MessageKind::Rehash,
+            b"STATS" => match params.into_iter().next() {
+                // the optional second parameter names a server, which we ignore
+                Some(query) => MessageKind::Stats(query),
+                None => return Err(need_more()),
+            },
+            b"INVITE" => {
+                if params.len() < 2 {
+                    return Err(need_more());
+                }
+                MessageKind::Invite {
+                    nick: take(&mut params, 0),
+                    channel: take(&mut params, 1),
+                }
+            }
+            b"KICK" => {
+                if params.len() < 2 {
+                    return Err(need_more());
+                }
+                MessageKind::Kick {
+                    reason: (params.len() > 2).then(|| take(&mut params, 2)),
+                    nicks: split_list(&params[1]),
+                    channel: take(&mut params, 0),
+                }
+            }
+            b"REGISTER" => {
+                if params.len() < 3 {
+                    return Err(need_more());
+                }
+                MessageKind::Register {
+                    password: take(&mut params, 2),
+                    email: take(&mut params, 1),
+                    account: take(&mut params, 0),
+                }
+            }
+            b"RENAME" => {
+                if params.len() < 2 {
+                    return Err(need_more());
+                }
+                MessageKind::Rename {
+                    reason: (params.len() > 2).then(|| take(&mut params, 2)),
+                    new: take(&mut params, 1),
+                    channel: take(&mut params, 0),
+                }
+            }
+            b"SILENCE" => MessageKind::Silence(params.into_iter().next()),
+            b"SETNAME" => MessageKind::Setname(params.into_iter().next().unwrap_or_default()),
+            b"AWAY" => MessageKind::Away(params.into_iter().next().filter(|text| !text.is_empty())),
+            b"CHATHISTORY" => MessageKind::Chathistory(params),
+            b"TOPIC" => match params.len() {
+                0 => return Err(need_more()),
+                len => MessageKind::Topic {
+                    topic: (len > 1).then(|| take(&mut params, 1)),
+                    channel: take(&mut params, 0),
+                },
+            },
+            b"PING" | b"PONG" => {
+                let Some(token) = params.into_iter().next() else {
+                    return Err(need_more());
+                };
+                if verb == b"PING" {
+                    MessageKind::Ping(token)
+                } else {
+                    MessageKind::Pong(token)
+                }
+            }
+            b"QUIT" => MessageKind::Quit(params.into_iter().next()),
+            _ => Err(IrcError::UnknownCommand(verb.to_vec()))?,
+        })
     }
 }
 
-/// Helper function to consume all spaces in an iterator.
-/// Will not consume any bytes which are not the ASCII space byte (b' ').
-fn consume_spaces(iter: &mut Peekable<std::io::Bytes<&mut dyn Read>>) -> Result<(), ParseError> {
-    while let Ok(b' ') = iter.peek().ok_or(ParseError::End)? {
-        iter.next().unwrap()?;
-    }
+/// Helper function to split off the first space-delimited word of `bytes`.
+/// Returns the word and everything after it, including the delimiting space.
+fn split_word(bytes: &[u8]) -> (&[u8], &[u8]) {
+    let end = bytes.iter().position(|&b| b == b' ').unwrap_or(bytes.len());
+    bytes.split_at(end)
+}
+
+/// Helper function to remove all leading spaces from `bytes`.
+/// Will not remove any bytes which are not the ASCII space byte (b' ').
+fn trim_spaces(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|&b| b != b' ').unwrap_or(bytes.len());
+    &bytes[start..]
+}
 
-    Ok(())
+/// Helper function to split a comma-separated parameter, such as a list of
+/// channels, into its elements.
+/// Empty elements are discarded.
+fn split_list(param: &[u8]) -> Vec<Vec<u8>> {
+    param
+        .split(|&b| b == b',')
+        .filter(|item| !item.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect()
 }
 
 #[cfg(test)]
@@ -206,6 +715,7 @@ mod tests {
         parse_helper(
             b"NICK johnny5\r\n",
             &Ok(Message {
+                tags: Vec::new(),
                 source: None,
                 kind: MessageKind::Nick("johnny5".bytes().collect()),
             }),
@@ -219,9 +729,114 @@ mod tests {
         parse_helper(
             b"NICK :Reginald P: Floorbuster\r\n",
             &Ok(Message {
+                tags: Vec::new(),
                 source: None,
                 kind: MessageKind::Nick("Reginald P: Floorbuster".bytes().collect()),
             }),
-        )
+        );
+    }
+
+    #[test]
+    /// Test that a command with no parameters is parsed, and that a lone
+    /// line-feed ends a line.
+    fn parse_no_params() {
+        parse_helper(
+            b"QUIT\n",
+            &Ok(Message {
+                tags: Vec::new(),
+                source: None,
+                kind: MessageKind::Quit(None),
+            }),
+        );
+    }
+
+    #[test]
+    /// Test that comma-separated lists are split and that a source is kept.
+    fn parse_join_list() {
+        parse_helper(
+            b":nick JOIN #a,#b,,#c key\r\n",
+            &Ok(Message {
+                tags: Vec::new(),
+                source: Some(b"nick".to_vec()),
+                kind: MessageKind::Join {
+                    channels: vec![b"#a".to_vec(), b"#b".to_vec(), b"#c".to_vec()],
+                    keys: vec![b"key".to_vec()],
+                },
+            }),
+        );
+    }
+
+    #[test]
+    /// Test that tags are parsed and unescaped before the source.
+    fn parse_tags() {
+        parse_helper(
+            b"@+draft/reply=a1;label=x\\sy :nick NICK  bob\r\n",
+            &Ok(Message {
+                tags: vec![
+                    (b"+draft/reply".to_vec(), b"a1".to_vec()),
+                    (b"label".to_vec(), b"x y".to_vec()),
+                ],
+                source: Some(b"nick".to_vec()),
+                kind: MessageKind::Nick(b"bob".to_vec()),
+            }),
+        );
+    }
+
+    #[test]
+    /// Test that mode changes keep their arguments in order, regardless of
+    /// trailing spaces.
+    fn parse_mode() {
+        parse_helper(
+            b"MODE #chan +kl-i key 10  \r\n",
+            &Ok(Message {
+                tags: Vec::new(),
+                source: None,
+                kind: MessageKind::Mode {
+                    target: b"#chan".to_vec(),
+                    changes: vec![b"+kl-i".to_vec(), b"key".to_vec(), b"10".to_vec()],
+                },
+            }),
+        );
+    }
+
+    #[test]
+    /// Test that a topic query is distinguished from clearing the topic.
+    fn parse_topic() {
+        parse_helper(
+            b"TOPIC #chan\r\n",
+            &Ok(Message {
+                tags: Vec::new(),
+                source: None,
+                kind: MessageKind::Topic {
+                    channel: b"#chan".to_vec(),
+                    topic: None,
+                },
+            }),
+        );
+        parse_helper(
+            b"TOPIC #chan :\r\n",
+            &Ok(Message {
+                tags: Vec::new(),
+                source: None,
+                kind: MessageKind::Topic {
+                    channel: b"#chan".to_vec(),
+                    topic: Some(Vec::new()),
+                },
+            }),
+        );
+    }
+
+    #[test]
+    /// Test that commands missing parameters give the correct errors.
+    fn parse_missing_params() {
+        parse_helper(
+            b"JOIN\r\n",
+            &Err(ParseError::Irc(IrcError::NeedMoreParams(b"JOIN".to_vec()))),
+        );
+        parse_helper(
+            b"PRIVMSG #chan\r\n",
+            &Err(ParseError::Irc(IrcError::NoTextToSend)),
+        );
+        parse_helper(b"PRIVMSG #chan", &Err(ParseError::End));
     }
 }
@@ -0,0 +1,317 @@
+//! A module for building the `RPL_ISUPPORT` (005) tokens which advertise the
+//! server's features and limits to clients.
+//!
+//! Each token is a name, optionally followed by `=` and a value. A client
+//! may be sent any number of tokens, so they are split over as many 005
+//! replies as are needed to keep each one within the length of a line.
+
+/// The most tokens sent in a single 005 reply, which keeps the whole line
+/// within the 15 parameters a message may have.
+pub const MAX_TOKENS_PER_LINE: usize = 13;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A single `ISUPPORT` token.
+pub enum Token {
+    /// The maximum length of an away message.
+    AwayLen(usize),
+    /// The case mapping used to compare nicknames and channel names, which
+    /// for this server is always `rfc1459`.
+    CaseMapping,
+    /// The maximum number of channels a client may be in at once, for each of
+    /// the given channel types.
+    ChanLimit {
+        /// The prefixes of the channel types the limit applies to.
+        types: Vec<u8>,
+        /// The maximum number of channels.
+        limit: usize,
+    },
+    /// The channel modes this server supports, grouped by how they take
+    /// arguments.
+    ChanModes {
+        /// Modes which add or remove an entry from a list (type A).
+        lists: Vec<u8>,
+        /// Modes which always take an argument (type B).
+        always: Vec<u8>,
+        /// Modes which take an argument only when set (type C).
+        when_set: Vec<u8>,
+        /// Modes which never take an argument (type D).
+        flags: Vec<u8>,
+    },
+    /// The maximum length of a channel name, including its prefix.
+    ChannelLen(usize),
+    /// The prefixes which begin channel names.
+    ChanTypes(Vec<u8>),
+    /// The most messages a single `CHATHISTORY` command may ask for.
+    ChatHistory(usize),
+    /// The letters of the search extensions supported by `LIST`.
+    EList(Vec<u8>),
+    /// The letter of the ban exception channel mode.
+    Excepts(u8),
+    /// The prefix and type letters of extended bans.
+    ExtBan(Vec<u8>),
+    /// The maximum length of a hostname.
+    HostLen(usize),
+    /// The letter of the invite exception channel mode.
+    Invex(u8),
+    /// The maximum length of a channel key.
+    KeyLen(usize),
+    /// The maximum length of a `KICK` reason.
+    KickLen(usize),
+    /// The maximum number of entries in each group of list modes.
+    MaxList(Vec<(Vec<u8>, usize)>),
+    /// The maximum number of targets of a `PRIVMSG` or `NOTICE`.
+    MaxTargets(usize),
+    /// The maximum number of channel modes with arguments in a single `MODE`
+    /// command.
+    Modes(usize),
+    /// The maximum number of nicknames a client may monitor.
+    Monitor(usize),
+    /// The kinds of reference to a message which `CHATHISTORY` accepts.
+    MsgRefTypes(Vec<u8>),
+    /// The maximum length of a realname.
+    NameLen(usize),
+    /// The name of the network.
+    Network(Vec<u8>),
+    /// The maximum length of a nickname.
+    NickLen(usize),
+    /// The channel membership modes, and the prefixes shown for them, from
+    /// the highest status to the lowest.
+    Prefix {
+        /// The letters of the modes.
+        modes: Vec<u8>,
+        /// The prefixes shown for each mode.
+        prefixes: Vec<u8>,
+    },
+    /// `LIST` never sends so much that the client is disconnected.
+    SafeList,
+    /// The maximum number of entries in a client's silence list.
+    Silence(usize),
+    /// The membership prefixes which may be put before a channel name to
+    /// message only the members with that status or higher.
+    StatusMsg(Vec<u8>),
+    /// The maximum number of targets of each command, or `None` for no limit.
+    TargMax(Vec<(Vec<u8>, Option<usize>)>),
+    /// The maximum length of a channel topic.
+    TopicLen(usize),
+    /// The maximum length of a username.
+    UserLen(usize),
+    /// `WHO` accepts a `%` followed by the fields to send for each user.
+    Whox,
+}
+
+impl Token {
+    /// Get the name of this token.
+    #[must_use]
+    pub fn name(&self) -> &'static [u8] {
+        match self {
+            Token::AwayLen(_) => b"AWAYLEN",
+            Token::CaseMapping => b"CASEMAPPING",
+            Token::ChanLimit { .. } => b"CHANLIMIT",
+            Token::ChanModes { .. } => b"CHANMODES",
+            Token::ChannelLen(_) => b"CHANNELLEN",
+            Token::ChanTypes(_) => b"CHANTYPES",
+            Token::ChatHistory(_) => b"CHATHISTORY",
+            Token::EList(_) => b"ELIST",
+            Token::Excepts(_) => b"EXCEPTS",
+            Token::ExtBan(_) => b"EXTBAN",
+            Token::HostLen(_) => b"HOSTLEN",
+            Token::Invex(_) => b"INVEX",
+            Token::KeyLen(_) => b"KEYLEN",
+            Token::KickLen(_) => b"KICKLEN",
+            Token::MaxList(_) => b"MAXLIST",
+            Token::MaxTargets(_) => b"MAXTARGETS",
+            Token::Modes(_) => b"MODES",
+            Token::Monitor(_) => b"MONITOR",
+            Token::MsgRefTypes(_) => b"MSGREFTYPES",
+            Token::NameLen(_) => b"NAMELEN",
+            Token::Network(_) => b"NETWORK",
+            Token::NickLen(_) => b"NICKLEN",
+            Token::Prefix { .. } => b"PREFIX",
+            Token::SafeList => b"SAFELIST",
+            Token::Silence(_) => b"SILENCE",
+            Token::StatusMsg(_) => b"STATUSMSG",
+            Token::TargMax(_) => b"TARGMAX",
+            Token::TopicLen(_) => b"TOPICLEN",
+            Token::UserLen(_) => b"USERLEN",
+            Token::Whox => b"WHOX",
+        }
+    }
+
+    /// Get the value of this token, if it has one.
+    #[must_use]
+    pub fn value(&self) -> Option<Vec<u8>> {
+        let number = |n: &usize| n.to_string().into_bytes();
+        let value = match self {
+            Token::CaseMapping => b"rfc1459".to_vec(),
+            Token::SafeList | Token::Whox => return None,
+            Token::AwayLen(n)
+            | Token::ChannelLen(n)
+            | Token::ChatHistory(n)
+            | Token::HostLen(n)
+            | Token::KeyLen(n)
+            | Token::KickLen(n)
+            | Token::MaxTargets(n)
+            | Token::Modes(n)
+            | Token::Monitor(n)
+            | Token::NameLen(n)
+            | Token::NickLen(n)
+            | Token::Silence(n)
+            | Token::TopicLen(n)
+            | Token::UserLen(n) => number(n),
+            Token::ChanLimit { types, limit } => [&types[..], b":", &number(limit)].concat(),
+            Token::ChanModes {
+                lists,
+                always,
+                when_set,
+                flags,
+            } => [&lists[..], always, when_set, flags].join(&b','),
+            Token::ChanTypes(value)
+            | Token::EList(value)
+            | Token::ExtBan(value)
+            | Token::MsgRefTypes(value)
+            | Token::Network(value)
+            | Token::StatusMsg(value) => value.clone(),
+            Token::Excepts(letter) | Token::Invex(letter) => vec![*letter],
+            Token::MaxList(limits) => limits
+                .iter()
+                .map(|(modes, limit)| [&modes[..], b":", &number(limit)].concat())
+                .collect::<Vec<_>>()
+                .join(&b','),
+            Token::Prefix { modes, prefixes } => [b"(", &modes[..], b")", prefixes].concat(),
+            Token::TargMax(limits) => limits
+                .iter()
+                .map(|(command, limit)| {
+                    let limit = limit.as_ref().map(number).unwrap_or_default();
+                    [&command[..], b":", &limit].concat()
+                })
+                .collect::<Vec<_>>()
+                .join(&b','),
+        };
+        Some(value)
+    }
+
+    /// Serialize this token as it is sent to clients, such as `NICKLEN=30`.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut token = self.name().to_vec();
+        if let Some(value) = self.value() {
+            token.push(b'=');
+            token.extend(value);
+        }
+        token
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// A builder for the full set of `ISUPPORT` tokens sent to a client.
+pub struct ISupport {
+    /// The tokens, in the order they will be sent.
+    tokens: Vec<Token>,
+}
+
+impl ISupport {
+    /// Construct a builder with no tokens.
+    #[must_use]
+    pub fn new() -> ISupport {
+        ISupport::default()
+    }
+
+    /// Add `token` to the tokens which will be sent.
+    #[must_use]
+    pub fn token(mut self, token: Token) -> ISupport {
+        self.tokens.push(token);
+        self
+    }
+
+    /// Add `token` to the tokens which will be sent, if there is one.
+    #[must_use]
+    pub fn maybe_token(self, token: Option<Token>) -> ISupport {
+        match token {
+            Some(token) => self.token(token),
+            None => self,
+        }
+    }
+
+    /// Get the tokens which will be sent.
+    #[must_use]
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// Split the serialized tokens into groups, one for each 005 reply, so
+    /// that no group has more than [`MAX_TOKENS_PER_LINE`] tokens, and the
+    /// tokens of each group take up no more than `budget` bytes when joined
+    /// with spaces.
+    ///
+    /// A single token longer than `budget` is still sent, in a group of its
+    /// own.
+    #[must_use]
+    pub fn lines(&self, budget: usize) -> Vec<Vec<Vec<u8>>> {
+        let mut lines: Vec<Vec<Vec<u8>>> = Vec::new();
+        let mut used = 0;
+        for token in &self.tokens {
+            let token = token.to_bytes();
+            match lines.last_mut() {
+                Some(line)
+                    if line.len() < MAX_TOKENS_PER_LINE && used + 1 + token.len() <= budget =>
+                {
+                    used += 1 + token.len();
+                    line.push(token);
+                }
+                _ => {
+                    used = token.len();
+                    lines.push(vec![token]);
+                }
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test how each kind of token is serialized.
+    fn serialize() {
+        let chanmodes = Token::ChanModes {
+            lists: b"Ibe".to_vec(),
+            always: b"k".to_vec(),
+            when_set: b"l".to_vec(),
+            flags: b"imnpst".to_vec(),
+        };
+        assert_eq!(chanmodes.to_bytes(), b"CHANMODES=Ibe,k,l,imnpst");
+        let prefix = Token::Prefix {
+            modes: b"ov".to_vec(),
+            prefixes: b"@+".to_vec(),
+        };
+        assert_eq!(prefix.to_bytes(), b"PREFIX=(ov)@+");
+        let targmax = Token::TargMax(vec![(b"JOIN".to_vec(), None), (b"KICK".to_vec(), Some(4))]);
+        assert_eq!(targmax.to_bytes(), b"TARGMAX=JOIN:,KICK:4");
+        let maxlist = Token::MaxList(vec![(b"beI".to_vec(), 100)]);
+        assert_eq!(maxlist.to_bytes(), b"MAXLIST=beI:100");
+        assert_eq!(Token::SafeList.to_bytes(), b"SAFELIST");
+        assert_eq!(Token::CaseMapping.to_bytes(), b"CASEMAPPING=rfc1459");
+        assert_eq!(Token::Excepts(b'e').to_bytes(), b"EXCEPTS=e");
+    }
+
+    #[test]
+    /// Test that tokens are split by count and by length.
+    fn split_lines() {
+        let isupport = (1..=20).fold(ISupport::new(), |isupport, n| {
+            isupport.token(Token::NickLen(n))
+        });
+        let lines = isupport.lines(usize::MAX);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), MAX_TOKENS_PER_LINE);
+        assert_eq!(lines[1].len(), 7);
+
+        // `NICKLEN=1 NICKLEN=2` is 19 bytes
+        let lines = isupport.lines(19);
+        assert_eq!(lines[0], [b"NICKLEN=1".to_vec(), b"NICKLEN=2".to_vec()]);
+        assert!(lines.iter().flatten().count() == 20);
+        let long = ISupport::new().token(Token::Network(vec![b'x'; 50]));
+        assert_eq!(long.lines(10).len(), 1);
+    }
+}
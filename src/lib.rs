@@ -1,7 +1,28 @@
 #![warn(clippy::pedantic)]
 #![warn(clippy::cargo)]
+// ring and tokio depend on different versions of windows-sys, which only
+// the authors of those crates can align
+#![allow(clippy::multiple_crate_versions)]
 
+pub mod batch;
+pub mod cap;
+pub mod channel;
+pub mod class;
+pub mod client;
+pub mod config;
+pub mod extban;
+pub mod flood;
+pub mod history;
 pub mod incoming;
+pub mod isupport;
+pub mod mask;
+pub mod outgoing;
+pub mod sasl;
+pub mod scram;
+pub mod server;
+pub mod tags;
+pub mod time;
+pub mod tls;
 
 /// A simple type alias for a result whose error case is an IRC error.
 pub type IrcResult<T> = Result<T, IrcError>;
@@ -15,11 +36,59 @@ pub type IrcResult<T> = Result<T, IrcError>;
 /// specification.
 pub enum IrcError {
     /* TODO fill out the rest of the error numbers here */
+    /// A message was sent to a nickname which is not in use.
+    /// The only field of this error is the nickname which was not found.
+    ///
+    /// This error is sometimes referred to by its number, 401.
+    NoSuchNick(Vec<u8>),
+    /// A client referred to a channel which does not exist, or whose name is
+    /// not a valid channel name.
+    /// The only field of this error is the name of the channel.
+    ///
+    /// This error is sometimes referred to by its number, 403.
+    NoSuchChannel(Vec<u8>),
+    /// A client attempted to send a message to a channel which it is not
+    /// allowed to speak in, such as a moderated channel or one which it is
+    /// muted in.
+    /// The only field of this error is the name of the channel.
+    ///
+    /// This error is sometimes referred to by its number, 404.
+    CannotSendToChan(Vec<u8>),
+    /// A client attempted to join a channel while already in as many channels
+    /// as it is allowed to be.
+    /// The only field of this error is the name of the channel.
+    ///
+    /// This error is sometimes referred to by its number, 405.
+    TooManyChannels(Vec<u8>),
+    /// A `PRIVMSG` or `NOTICE` was sent with no recipient.
+    /// The only field of this error is the command which was sent.
+    ///
+    /// This error is sometimes referred to by its number, 411.
+    NoRecipient(Vec<u8>),
+    /// A command was sent to more targets than this server allows at once,
+    /// so it was not carried out for any of them.
+    /// The only field of this error is the first target which was too many.
+    ///
+    /// This error is sometimes referred to by its number, 407.
+    TooManyTargets(Vec<u8>),
+    /// A client sent a `CAP` subcommand which this server does not know.
+    /// The only field of this error is the subcommand.
+    ///
+    /// This error is sometimes referred to by its number, 410.
+    InvalidCapCmd(Vec<u8>),
+    /// A `PRIVMSG` or `NOTICE` was sent with no text.
+    ///
+    /// This error is sometimes referred to by its number, 412.
+    NoTextToSend,
     /// A message was requested for a command which this server does not know
     /// how to process.
     ///
     /// This error is sometimes referred to by its number, 421.
     UnknownCommand(Vec<u8>),
+    /// The server has no message of the day to send.
+    ///
+    /// This error is sometimes referred to by its number, 422.
+    NoMotd,
     /// The client did not give a nickname when sending a `NICK` message to the
     /// server.
     ///
@@ -47,6 +116,28 @@ pub enum IrcError {
     ///
     /// This error is sometimes referred to by its number, 436.
     NicknameCollision(Vec<u8>),
+    /// A client attempted to change the modes of a member of a channel, but
+    /// that user is not a member of the channel.
+    /// The fields of this error are the nickname and the channel name.
+    ///
+    /// This error is sometimes referred to by its number, 441.
+    UserNotInChannel(Vec<u8>, Vec<u8>),
+    /// A client attempted to act on a channel which it is not a member of.
+    /// The only field of this error is the name of the channel.
+    ///
+    /// This error is sometimes referred to by its number, 442.
+    NotOnChannel(Vec<u8>),
+    /// A client attempted to invite a user to a channel which that user is
+    /// already a member of.
+    /// The fields of this error are the nickname and the channel name.
+    ///
+    /// This error is sometimes referred to by its number, 443.
+    UserOnChannel(Vec<u8>, Vec<u8>),
+    /// A client sent a command which requires it to have completed
+    /// registration before doing so.
+    ///
+    /// This error is sometimes referred to by its number, 451.
+    NotRegistered,
     /// A message was sent to this server, but too few parameters were supplied
     /// for the message to be properly parsed.
     /// The only field of this variant is the command which did not receive
@@ -54,4 +145,223 @@ pub enum IrcError {
     ///
     /// This error is sometimes referred to by its number, 461.
     NeedMoreParams(Vec<u8>),
+    /// A client attempted to send `USER` after it had already registered.
+    ///
+    /// This error is sometimes referred to by its number, 462.
+    AlreadyRegistered,
+    /// A client gave the wrong password, such as in an `OPER` command.
+    ///
+    /// This error is sometimes referred to by its number, 464.
+    PasswdMismatch,
+    /// A client matched one of the server's bans, and is being disconnected.
+    ///
+    /// This error is sometimes referred to by its number, 465.
+    YoureBannedCreep,
+    /// A client attempted to join a channel whose user limit (`+l`) has been
+    /// reached.
+    /// The only field of this error is the name of the channel.
+    ///
+    /// This error is sometimes referred to by its number, 471.
+    ChannelIsFull(Vec<u8>),
+    /// A client attempted to add an entry to a channel's ban, exception or
+    /// invite exception list when the lists are already full.
+    /// The fields of this error are the channel name and the mode letter.
+    ///
+    /// This error is sometimes referred to by its number, 478.
+    BanListFull(Vec<u8>, u8),
+    /// A client attempted to set a channel mode which this server does not
+    /// know.
+    /// The only field of this error is the mode letter.
+    ///
+    /// This error is sometimes referred to by its number, 472.
+    UnknownMode(u8),
+    /// A client attempted to join an invite-only (`+i`) channel without being
+    /// invited.
+    /// The only field of this error is the name of the channel.
+    ///
+    /// This error is sometimes referred to by its number, 473.
+    InviteOnlyChan(Vec<u8>),
+    /// A client attempted to join a channel which it is banned from.
+    /// The only field of this error is the name of the channel.
+    ///
+    /// This error is sometimes referred to by its number, 474.
+    BannedFromChan(Vec<u8>),
+    /// A client attempted to join a channel with a key (`+k`) without giving
+    /// the correct key.
+    /// The only field of this error is the name of the channel.
+    ///
+    /// This error is sometimes referred to by its number, 475.
+    BadChannelKey(Vec<u8>),
+    /// A client attempted a command which only IRC operators may use.
+    ///
+    /// This error is sometimes referred to by its number, 481.
+    NoPrivileges,
+    /// A client attempted an action on a channel which requires it to be a
+    /// channel operator.
+    /// The only field of this error is the name of the channel.
+    ///
+    /// This error is sometimes referred to by its number, 482.
+    ChanOPrivsNeeded(Vec<u8>),
+    /// A client attempted to become an IRC operator, but either there is no
+    /// operator account with the name it gave, or it is not connecting from
+    /// a host which may use that account.
+    ///
+    /// This error is sometimes referred to by its number, 491.
+    NoOperHost,
+    /// A client attempted to add a mask to its silence list when the list is
+    /// already full.
+    /// The only field of this error is the mask.
+    ///
+    /// This error is sometimes referred to by its number, 511.
+    SileListFull(Vec<u8>),
+    /// A client attempted to set a user mode which this server does not know.
+    ///
+    /// This error is sometimes referred to by its number, 501.
+    UModeUnknownFlag,
+    /// A client attempted to query or change the user modes of another user.
+    ///
+    /// This error is sometimes referred to by its number, 502.
+    UsersDontMatch,
+    /// A client attempted to add nicknames to its monitor list which would
+    /// make it longer than the limit. The fields are the limit and a
+    /// comma-separated list of the nicknames which were not added.
+    ///
+    /// This error is sometimes referred to by its number, 734.
+    MonListFull(usize, Vec<u8>),
+    /// SASL authentication failed, because the credentials were wrong or the
+    /// mechanism was not supported.
+    ///
+    /// This error is sometimes referred to by its number, 904.
+    SaslFail,
+    /// A client sent an `AUTHENTICATE` parameter or response which was too
+    /// long.
+    ///
+    /// This error is sometimes referred to by its number, 905.
+    SaslTooLong,
+    /// A client aborted SASL authentication, or did not finish it before
+    /// completing registration.
+    ///
+    /// This error is sometimes referred to by its number, 906.
+    SaslAborted,
+    /// A client which is already logged in attempted to authenticate again.
+    ///
+    /// This error is sometimes referred to by its number, 907.
+    SaslAlready,
+}
+
+impl IrcError {
+    /// Get the numeric code of this error, as sent to clients.
+    #[must_use]
+    pub fn code(&self) -> u16 {
+        match self {
+            IrcError::NoSuchNick(_) => 401,
+            IrcError::NoSuchChannel(_) => 403,
+            IrcError::CannotSendToChan(_) => 404,
+            IrcError::TooManyChannels(_) => 405,
+            IrcError::TooManyTargets(_) => 407,
+            IrcError::InvalidCapCmd(_) => 410,
+            IrcError::NoRecipient(_) => 411,
+            IrcError::NoTextToSend => 412,
+            IrcError::UnknownCommand(_) => 421,
+            IrcError::NoMotd => 422,
+            IrcError::NoNicknameGiven => 431,
+            IrcError::ErroneousNickname(_) => 432,
+            IrcError::NicknameInUse(_) => 433,
+            IrcError::NicknameCollision(_) => 436,
+            IrcError::UserNotInChannel(..) => 441,
+            IrcError::NotOnChannel(_) => 442,
+            IrcError::UserOnChannel(..) => 443,
+            IrcError::NotRegistered => 451,
+            IrcError::NeedMoreParams(_) => 461,
+            IrcError::AlreadyRegistered => 462,
+            IrcError::PasswdMismatch => 464,
+            IrcError::YoureBannedCreep => 465,
+            IrcError::ChannelIsFull(_) => 471,
+            IrcError::UnknownMode(_) => 472,
+            IrcError::InviteOnlyChan(_) => 473,
+            IrcError::BanListFull(..) => 478,
+            IrcError::BannedFromChan(_) => 474,
+            IrcError::BadChannelKey(_) => 475,
+            IrcError::NoPrivileges => 481,
+            IrcError::ChanOPrivsNeeded(_) => 482,
+            IrcError::NoOperHost => 491,
+            IrcError::UModeUnknownFlag => 501,
+            IrcError::SileListFull(_) => 511,
+            IrcError::UsersDontMatch => 502,
+            IrcError::MonListFull(..) => 734,
+            IrcError::SaslFail => 904,
+            IrcError::SaslTooLong => 905,
+            IrcError::SaslAborted => 906,
+            IrcError::SaslAlready => 907,
+        }
+    }
+
+    /// Get the parameters of this error as sent to clients, excluding the
+    /// nickname of the client the error is being sent to.
+    /// The last parameter is always a human-readable description of the error.
+    #[must_use]
+    pub fn params(&self) -> Vec<Vec<u8>> {
+        let (mut params, text): (Vec<&[u8]>, &[u8]) = match self {
+            IrcError::NoSuchNick(nick) => (vec![nick], b"No such nick/channel"),
+            IrcError::NoSuchChannel(chan) => (vec![chan], b"No such channel"),
+            IrcError::CannotSendToChan(chan) => (vec![chan], b"Cannot send to channel"),
+            IrcError::TooManyChannels(chan) => (vec![chan], b"You have joined too many channels"),
+            IrcError::TooManyTargets(target) => (vec![target], b"Too many targets"),
+            IrcError::InvalidCapCmd(cmd) => (vec![cmd], b"Invalid CAP command"),
+            IrcError::NoRecipient(cmd) => {
+                let mut text = b"No recipient given (".to_vec();
+                text.extend_from_slice(cmd);
+                text.push(b')');
+                return vec![text];
+            }
+            IrcError::NoTextToSend => (vec![], b"No text to send"),
+            IrcError::UnknownCommand(cmd) => (vec![cmd], b"Unknown command"),
+            IrcError::NoMotd => (vec![], b"MOTD File is missing"),
+            IrcError::NoNicknameGiven => (vec![], b"No nickname given"),
+            IrcError::ErroneousNickname(nick) => (vec![nick], b"Erroneous nickname"),
+            IrcError::NicknameInUse(nick) => (vec![nick], b"Nickname is already in use"),
+            IrcError::NicknameCollision(nick) => (vec![nick], b"Nickname collision KILL"),
+            IrcError::UserNotInChannel(nick, chan) => {
+                (vec![nick, chan], b"They aren't on that channel")
+            }
+            IrcError::NotOnChannel(chan) => (vec![chan], b"You're not on that channel"),
+            IrcError::UserOnChannel(nick, chan) => (vec![nick, chan], b"is already on channel"),
+            IrcError::NotRegistered => (vec![], b"You have not registered"),
+            IrcError::NeedMoreParams(cmd) => (vec![cmd], b"Not enough parameters"),
+            IrcError::AlreadyRegistered => (vec![], b"You may not reregister"),
+            IrcError::PasswdMismatch => (vec![], b"Password incorrect"),
+            IrcError::YoureBannedCreep => (vec![], b"You are banned from this server"),
+            IrcError::ChannelIsFull(chan) => (vec![chan], b"Cannot join channel (+l)"),
+            IrcError::UnknownMode(letter) => (
+                vec![std::slice::from_ref(letter)],
+                b"is unknown mode char to me",
+            ),
+            IrcError::InviteOnlyChan(chan) => (vec![chan], b"Cannot join channel (+i)"),
+            IrcError::BannedFromChan(chan) => (vec![chan], b"Cannot join channel (+b)"),
+            IrcError::BadChannelKey(chan) => (vec![chan], b"Cannot join channel (+k)"),
+            IrcError::BanListFull(chan, letter) => (
+                vec![chan, std::slice::from_ref(letter)],
+                b"Channel list is full",
+            ),
+            IrcError::NoPrivileges => (vec![], b"Permission Denied- You're not an IRC operator"),
+            IrcError::ChanOPrivsNeeded(chan) => (vec![chan], b"You're not channel operator"),
+            IrcError::NoOperHost => (vec![], b"No O-lines for your host"),
+            IrcError::SileListFull(mask) => (vec![mask], b"Your silence list is full"),
+            IrcError::UModeUnknownFlag => (vec![], b"Unknown MODE flag"),
+            IrcError::UsersDontMatch => (vec![], b"Cant change mode for other users"),
+            IrcError::MonListFull(limit, targets) => {
+                return vec![
+                    limit.to_string().into_bytes(),
+                    targets.clone(),
+                    b"Monitor list is full".to_vec(),
+                ];
+            }
+            IrcError::SaslFail => (vec![], b"SASL authentication failed"),
+            IrcError::SaslTooLong => (vec![], b"SASL message too long"),
+            IrcError::SaslAborted => (vec![], b"SASL authentication aborted"),
+            IrcError::SaslAlready => (vec![], b"You have already authenticated using SASL"),
+        };
+        params.push(text);
+        params.into_iter().map(<[u8]>::to_vec).collect()
+    }
 }
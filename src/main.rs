@@ -1,31 +1,396 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use strigoi::{
+    client::{ClientId, Outbox},
+    config::Config,
+    history::History,
+    incoming::{Message, ParseError},
+    scram::Credentials,
+    server::Server,
+    tls::{self, CertResolver},
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::TcpListener,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+    task::JoinSet,
 };
+use tokio_rustls::TlsAcceptor;
+
+/// The configuration file read when no other is given on the command line.
+const DEFAULT_CONFIG: &str = "strigoi.toml";
+
+/// How to run the server, as given on the command line.
+const USAGE: &str = "usage: strigoi [--check-config] [CONFIG]\n       strigoi --hash-password";
+
+/// How often connections are checked for keepalive timeouts and overflowing
+/// send queues.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait after accepting a connection fails before trying again,
+/// so that running out of file descriptors does not turn into a busy loop.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long a client connecting to a TLS listener has to finish the
+/// handshake before it is disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long connections are given to write out everything queued for them
+/// when the server shuts down.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
 
 #[tokio::main]
-async fn main() {
-    // simple echo server
-    let listener = TcpListener::bind("localhost:8080")
-        .await
-        .expect("bind failed");
-    let (mut socket, _addr) = listener.accept().await.expect("accept failed");
-
-    let (reader, mut writer) = socket.split();
+async fn main() -> ExitCode {
+    let mut check_only = false;
+    let mut path = None;
+    for arg in std::env::args_os().skip(1) {
+        if arg == "--hash-password" && path.is_none() && !check_only {
+            return hash_password();
+        } else if arg == "--check-config" {
+            check_only = true;
+        } else if path.is_none() && !arg.to_string_lossy().starts_with('-') {
+            path = Some(PathBuf::from(arg));
+        } else {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    }
+    let path = path.unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG));
+
+    let config = match Config::load(&path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("strigoi: {}: {err}", path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    if check_only {
+        println!("{}: configuration is valid", path.display());
+        return ExitCode::SUCCESS;
+    }
+
+    let mut server = Server::from_config(&config);
+    server.set_config_path(path);
+    // history without a file is only kept in memory, which the server sets
+    // up by itself
+    if let Some(settings) = config.history.as_ref() {
+        if let Some(file) = &settings.file {
+            match History::open(settings.clone()) {
+                Ok(history) => server.set_history(history),
+                Err(err) => {
+                    eprintln!(
+                        "strigoi: cannot use the history file {}: {err}",
+                        file.display()
+                    );
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+
+    // every listener hands its connections to the main loop
+    let (accepted_tx, mut accepted) = mpsc::channel(16);
+    let mut listeners = JoinSet::new();
+    for listen in &config.listeners {
+        let acceptor = match &listen.tls {
+            Some(settings) => {
+                let acceptor = tls::load_key(settings).and_then(|key| {
+                    let resolver = Arc::new(CertResolver::new(key));
+                    let config = tls::server_config(settings, Arc::clone(&resolver))
+                        .map_err(|err| err.to_string())?;
+                    server.add_cert_resolver(listen.address.clone(), resolver);
+                    Ok(TlsAcceptor::from(Arc::new(config)))
+                });
+                match acceptor {
+                    Ok(acceptor) => Some(acceptor),
+                    Err(err) => {
+                        eprintln!("strigoi: cannot use TLS on {}: {err}", listen.address);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            None => None,
+        };
+        let listener = match TcpListener::bind(&listen.address).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                eprintln!("strigoi: cannot listen on {}: {err}", listen.address);
+                return ExitCode::FAILURE;
+            }
+        };
+        listeners.spawn(accept(listener, acceptor, accepted_tx.clone()));
+    }
+    drop(accepted_tx);
+    let signals = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::hangup()),
+    );
+    let (mut terminate, mut interrupt, mut hangup) = match signals {
+        (Ok(terminate), Ok(interrupt), Ok(hangup)) => (terminate, interrupt, hangup),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => {
+            eprintln!("strigoi: cannot handle signals: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let server = Arc::new(Mutex::new(server));
+
+    let checker = tokio::spawn(check_connections(Arc::clone(&server)));
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            Some((socket, addr, acceptor)) = accepted.recv() => {
+                connections.spawn(connect(Arc::clone(&server), socket, addr, acceptor));
+            }
+            // forget about connections which have finished
+            Some(_) = connections.join_next() => (),
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+            _ = hangup.recv() => rehash(&server),
+        }
+    }
+
+    // stop accepting connections, and give the existing ones a chance to
+    // write out their final lines
+    listeners.shutdown().await;
+    checker.abort();
+    lock(&server).shutdown(b"Server shutting down");
+    let finished = tokio::time::timeout(SHUTDOWN_DEADLINE, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if finished.is_err() {
+        eprintln!(
+            "strigoi: closing {} connections which did not finish in time",
+            connections.len()
+        );
+        connections.shutdown().await;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Read a password from the first line of standard input, and print the
+/// SCRAM credentials derived from it, to be used as the `scram` of an
+/// account.
+fn hash_password() -> ExitCode {
+    let mut password = String::new();
+    if let Err(err) = std::io::stdin().read_line(&mut password) {
+        eprintln!("strigoi: cannot read the password: {err}");
+        return ExitCode::FAILURE;
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("strigoi: the password must not be empty");
+        return ExitCode::FAILURE;
+    }
+    println!("{}", Credentials::derive(password.as_bytes()));
+    ExitCode::SUCCESS
+}
+
+/// Lock the server.
+///
+/// A panic while handling one client's command should not take down every
+/// other connection with it, so poisoning is ignored.
+fn lock(server: &Mutex<Server>) -> MutexGuard<'_, Server> {
+    server.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A connection accepted by a listener, with the acceptor for its TLS
+/// handshake if the listener accepts TLS.
+type Accepted = (TcpStream, SocketAddr, Option<TlsAcceptor>);
+
+/// Accept connections on `listener` and send them to the main loop, until it
+/// stops receiving them.
+async fn accept(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    accepted: mpsc::Sender<Accepted>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                if accepted
+                    .send((socket, addr, acceptor.clone()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(err) => {
+                eprintln!("strigoi: cannot accept a connection: {err}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Reload the server's configuration, as requested by `SIGHUP`.
+fn rehash(server: &Mutex<Server>) {
+    match lock(server).rehash(None) {
+        Ok(ignored) => {
+            eprintln!("strigoi: configuration reloaded");
+            for warning in ignored {
+                eprintln!("strigoi: {warning}");
+            }
+        }
+        Err(err) => eprintln!("strigoi: cannot reload the configuration: {err}"),
+    }
+}
+
+/// Periodically ping silent connections, and drop those which have timed out
+/// or whose send queue has overflowed.
+async fn check_connections(server: Arc<Mutex<Server>>) {
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let mut server = lock(&server);
+        server.check_keepalive(Instant::now());
+        server.check_sendq();
+    }
+}
+
+/// Add a new client for `socket`, first completing the TLS handshake if the
+/// connection was accepted with `acceptor`, and then serve it.
+///
+/// Connections which do not complete the handshake in time are closed
+/// without ever becoming clients.
+async fn connect(
+    server: Arc<Mutex<Server>>,
+    socket: TcpStream,
+    addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+) {
+    let host = addr.ip().to_string().into_bytes();
+    let Some(acceptor) = acceptor else {
+        let (id, outbox) = lock(&server).connect(host);
+        serve(server, socket, id, outbox).await;
+        return;
+    };
+    let Ok(Ok(stream)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await
+    else {
+        return;
+    };
+    let certfp = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(tls::fingerprint);
+    let (id, outbox) = lock(&server).connect_tls(host, certfp);
+    serve(server, stream, id, outbox).await;
+}
+
+/// Serve a single client connection until either side closes it.
+async fn serve<S>(server: Arc<Mutex<Server>>, stream: S, id: ClientId, outbox: Outbox)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let recvq = lock(&server)
+        .client(id)
+        .map_or(0, |client| client.class().recvq);
+    let (reader, writer) = tokio::io::split(stream);
+
+    let mut writing = std::pin::pin!(write_lines(outbox, writer));
+    tokio::select! {
+        () = read_lines(&server, id, reader, recvq) => {
+            lock(&server)
+                .disconnect(id, b"Connection closed");
+            writing.await;
+        }
+        // the server closed the connection, and everything has been written
+        () = &mut writing => {}
+    }
+}
+
+/// Read lines from a client and hand them to the server, until the client
+/// closes the connection or sends something which is not a line.
+///
+/// Lines which the server has queued because of the client's flood limits
+/// are handled once they are due, while more lines continue to be read.
+/// A client which sends a single line longer than its receive queue limit is disconnected.
+async fn read_lines(
+    server: &Mutex<Server>,
+    id: ClientId,
+    reader: impl AsyncRead + Unpin,
+    recvq: usize,
+) {
     let mut reader = BufReader::new(reader);
-    let mut line = String::with_capacity(1024);
+    let mut line = Vec::with_capacity(512);
+    // when the next line queued by the server will be due
+    let mut due: Option<Instant> = None;
 
     loop {
-        line.clear();
-        let bytes_read = reader.read_line(&mut line).await.expect("read failed");
+        // read no further than one byte past the limit
+        let room = (recvq + 1).saturating_sub(line.len()) as u64;
+        let mut limited = (&mut reader).take(room);
+        tokio::select! {
+            () = tokio::time::sleep_until(due.unwrap_or_else(Instant::now).into()),
+                if due.is_some() =>
+            {
+                due = lock(server)
+                    .process(id, Instant::now());
+                continue;
+            }
+            read = limited.read_until(b'\n', &mut line) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(_) => (),
+            },
+        }
 
-        if bytes_read == 0 {
-            break;
+        if !line.ends_with(b"\n") {
+            if line.len() > recvq {
+                lock(server).disconnect(id, b"Max RecvQ exceeded");
+                return;
+            }
+            // the connection was closed partway through a line
+            continue;
+        }
+        let len = line.len();
+        let trimmed = line
+            .strip_suffix(b"\n")
+            .map(|rest| rest.strip_suffix(b"\r").unwrap_or(rest))
+            .unwrap_or(&line);
+        if !trimmed.is_empty() {
+            let parsed = match Message::parse_bytes(trimmed) {
+                Ok(message) => Ok(message),
+                Err(ParseError::Irc(err)) => Err(err),
+                Err(_) => break,
+            };
+            due = lock(server).receive(id, parsed, len, Instant::now());
         }
+        line.clear();
+    }
+
+    // the client has stopped sending, but what it already sent still counts
+    while let Some(next) = due {
+        tokio::time::sleep_until(next.into()).await;
+        due = lock(server).process(id, Instant::now());
+    }
+}
 
-        writer
-            .write_all(line.as_bytes())
-            .await
-            .expect("write failed");
+/// Write lines queued for a client until its outbox is closed and empty.
+async fn write_lines(outbox: Outbox, mut writer: impl AsyncWrite + Unpin) {
+    loop {
+        outbox.wait().await;
+        let closed = outbox.is_closed();
+        // lines stay in the outbox until they are written, so that they count
+        // towards the client's send queue
+        while let Some(line) = outbox.pop() {
+            if writer.write_all(&line).await.is_err() {
+                return;
+            }
+        }
+        if closed {
+            let _ = writer.shutdown().await;
+            return;
+        }
     }
 }
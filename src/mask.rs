@@ -0,0 +1,154 @@
+//! Hostmask matching and case mapping for nicknames, channel names and masks.
+//!
+//! This server uses the `rfc1459` case mapping, in which the characters
+//! `[]\~` are considered the uppercase equivalents of `{}|^`.
+
+/// Fold a single byte to its lowercase equivalent under the `rfc1459` case
+/// mapping.
+#[must_use]
+pub fn fold_byte(byte: u8) -> u8 {
+    match byte {
+        b'A'..=b'Z' => byte.to_ascii_lowercase(),
+        b'[' => b'{',
+        b']' => b'}',
+        b'\\' => b'|',
+        b'~' => b'^',
+        _ => byte,
+    }
+}
+
+/// Fold a string of bytes to lowercase under the `rfc1459` case mapping.
+///
+/// Two names refer to the same nickname or channel if and only if their
+/// casefolded forms are equal.
+#[must_use]
+pub fn casefold(name: &[u8]) -> Vec<u8> {
+    name.iter().copied().map(fold_byte).collect()
+}
+
+/// Determine whether two names are equal under the `rfc1459` case mapping.
+#[must_use]
+pub fn eq_ignore_case(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(&x, &y)| fold_byte(x) == fold_byte(y))
+}
+
+/// Determine whether `subject` matches the wildcard mask `mask`.
+///
+/// In a mask, `*` matches any sequence of bytes (including an empty one) and
+/// `?` matches exactly one byte.
+/// Every other byte, including `[` and `]`, matches itself.
+/// Comparison is case-insensitive under the `rfc1459` case mapping.
+#[must_use]
+pub fn matches(mask: &[u8], subject: &[u8]) -> bool {
+    let (mut m, mut s) = (0, 0);
+    // The position in the mask just after the most recent `*`, and the
+    // position in the subject that star is currently assumed to extend to.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < subject.len() {
+        match mask.get(m) {
+            Some(b'*') => {
+                m += 1;
+                backtrack = Some((m, s));
+            }
+            Some(&b) if b == b'?' || fold_byte(b) == fold_byte(subject[s]) => {
+                m += 1;
+                s += 1;
+            }
+            _ => match backtrack {
+                // let the last star swallow one more byte and try again
+                Some((star_m, star_s)) => {
+                    m = star_m;
+                    s = star_s + 1;
+                    backtrack = Some((star_m, star_s + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    mask[m..].iter().all(|&b| b == b'*')
+}
+
+/// Split a `nick!user@host` source into its nickname, username and hostname.
+///
+/// Any component which is missing from `source` is returned as `None`.
+#[must_use]
+pub fn split_userhost(source: &[u8]) -> (&[u8], Option<&[u8]>, Option<&[u8]>) {
+    let (rest, host) = match source.iter().position(|&b| b == b'@') {
+        Some(at) => (&source[..at], Some(&source[at + 1..])),
+        None => (source, None),
+    };
+    match rest.iter().position(|&b| b == b'!') {
+        Some(bang) => (&rest[..bang], Some(&rest[bang + 1..]), host),
+        None => (rest, None, host),
+    }
+}
+
+/// Expand a partial hostmask, such as `nick` or `user@host`, to a complete
+/// `nick!user@host` mask by filling any missing component with `*`.
+#[must_use]
+pub fn normalize(mask: &[u8]) -> Vec<u8> {
+    let (nick, user, host) = match split_userhost(mask) {
+        // a lone `host.name` is more likely meant as a host than a nickname
+        (bare, None, None) if bare.contains(&b'.') || bare.contains(&b':') => {
+            (&b"*"[..], None, Some(bare))
+        }
+        // in a mask, `user@host` names a username rather than a nickname
+        (user, None, Some(host)) => (&b"*"[..], Some(user), Some(host)),
+        parts => parts,
+    };
+    let or_star = |part: &[u8]| -> Vec<u8> {
+        if part.is_empty() {
+            b"*".to_vec()
+        } else {
+            part.to_vec()
+        }
+    };
+
+    let mut full = or_star(nick);
+    full.push(b'!');
+    full.extend(or_star(user.unwrap_or_default()));
+    full.push(b'@');
+    full.extend(or_star(host.unwrap_or_default()));
+    full
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that the `rfc1459` special characters fold together.
+    fn casefold_special_characters() {
+        assert_eq!(casefold(b"Nick[A]\\~"), b"nick{a}|^");
+        assert!(eq_ignore_case(b"FOO[]", b"foo{}"));
+        assert!(!eq_ignore_case(b"foo", b"fooo"));
+    }
+
+    #[test]
+    /// Test that stars can match across the separators of a hostmask.
+    fn star_spans_separators() {
+        assert!(matches(b"*!*@*.example.com", b"nick!user@host.example.com"));
+        assert!(matches(b"*", b""));
+        assert!(!matches(b"*!*@*.example.com", b"nick!user@example.org"));
+    }
+
+    #[test]
+    /// Test that a star followed by literal bytes backtracks correctly.
+    fn star_backtracks() {
+        assert!(matches(b"*ab*ab", b"xabyabab"));
+        assert!(!matches(b"*ab*ab", b"xabyaba"));
+        assert!(matches(b"a?c*", b"ABCdef"));
+    }
+
+    #[test]
+    /// Test that partial masks are filled out with wildcards.
+    fn normalize_partial_masks() {
+        assert_eq!(normalize(b"nick"), b"nick!*@*");
+        assert_eq!(normalize(b"user@host"), b"*!user@host");
+        assert_eq!(normalize(b"host.example.com"), b"*!*@host.example.com");
+        assert_eq!(normalize(b"nick!user"), b"nick!user@*");
+        assert_eq!(normalize(b"a!b@c"), b"a!b@c");
+    }
+}
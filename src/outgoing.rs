@@ -0,0 +1,1036 @@
+//! A module for defining the messages sent from this server to its clients.
+
+use crate::{channel::ListMode, tags};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A message ready to be sent to a client.
+pub struct Message {
+    /// The tags of this message, which are only sent to clients that have
+    /// enabled the capability each one belongs to.
+    tags: tags::Tags,
+    /// The source of the message, which is either the name of this server or
+    /// the `nick!user@host` mask of the user who caused it.
+    source: Option<Vec<u8>>,
+    /// The command or three-digit numeric of this message.
+    command: Vec<u8>,
+    /// The parameters of this message.
+    /// Only the last parameter may contain spaces or begin with a colon.
+    params: Vec<Vec<u8>>,
+}
+
+impl Message {
+    /// Construct a new message from `source` with the given command and
+    /// parameters.
+    #[must_use]
+    pub fn new(source: Option<&[u8]>, command: &[u8], params: Vec<Vec<u8>>) -> Message {
+        Message {
+            tags: Vec::new(),
+            source: source.map(<[u8]>::to_vec),
+            command: command.to_vec(),
+            params,
+        }
+    }
+
+    /// Add the tag `key` with the unescaped `value` to this message.
+    #[must_use]
+    pub fn with_tag(mut self, key: &[u8], value: &[u8]) -> Message {
+        self.tags.push((key.to_vec(), value.to_vec()));
+        self
+    }
+
+    /// Get the tags of this message.
+    #[must_use]
+    pub fn tags(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.tags
+    }
+
+    /// Get the source of this message, if it has one.
+    #[must_use]
+    pub fn source(&self) -> Option<&[u8]> {
+        self.source.as_deref()
+    }
+
+    /// Get the command or numeric of this message.
+    #[must_use]
+    pub fn command(&self) -> &[u8] {
+        &self.command
+    }
+
+    /// Get the parameters of this message.
+    #[must_use]
+    pub fn params(&self) -> &[Vec<u8>] {
+        &self.params
+    }
+
+    /// Serialize this message to the bytes that should be sent over the wire,
+    /// including the terminating CRLF.
+    ///
+    /// The last parameter is prefixed with a colon when it is empty, contains a
+    /// space, or begins with a colon, so that it is read back as a single
+    /// parameter.
+    #[must_use]
+    pub fn to_line(&self) -> Vec<u8> {
+        self.to_line_with(|_| true)
+    }
+
+    /// Serialize this message like [`Message::to_line`], but with only the
+    /// tags whose keys `keep` accepts.
+    #[must_use]
+    pub fn to_line_with(&self, keep: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+        let mut line = tags::serialize(self.tags.iter().filter(|(key, _)| keep(key)));
+        line.reserve(512);
+        if let Some(source) = &self.source {
+            line.push(b':');
+            line.extend_from_slice(source);
+            line.push(b' ');
+        }
+        line.extend_from_slice(&self.command);
+
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                line.push(b' ');
+                line.extend_from_slice(param);
+            }
+            line.push(b' ');
+            if last.is_empty() || last.contains(&b' ') || last[0] == b':' {
+                line.push(b':');
+            }
+            line.extend_from_slice(last);
+        }
+
+        line.extend_from_slice(b"\r\n");
+        line
+    }
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The numeric replies which this server sends in response to successful
+/// commands.
+///
+/// Errors are described separately by `IrcError`.
+pub enum Reply {
+    /// The first message sent after registration, welcoming the client to the
+    /// network.
+    ///
+    /// This reply is sometimes referred to by its number, 001.
+    Welcome {
+        /// The name of the network.
+        network: Vec<u8>,
+        /// The full hostmask of the client.
+        mask: Vec<u8>,
+    },
+    /// Names the server the client is connected to and its software version.
+    ///
+    /// This reply is sometimes referred to by its number, 002.
+    YourHost {
+        /// The name of the server.
+        server: Vec<u8>,
+        /// The software version of the server.
+        version: Vec<u8>,
+    },
+    /// Describes when the server was started. The only field is a
+    /// human-readable date.
+    ///
+    /// This reply is sometimes referred to by its number, 003.
+    Created(Vec<u8>),
+    /// Describes the server and the user and channel modes it supports.
+    ///
+    /// This reply is sometimes referred to by its number, 004.
+    MyInfo {
+        /// The name of the server.
+        server: Vec<u8>,
+        /// The software version of the server.
+        version: Vec<u8>,
+        /// The letters of every supported user mode.
+        user_modes: Vec<u8>,
+        /// The letters of every supported channel mode.
+        channel_modes: Vec<u8>,
+    },
+    /// Advertises some of the features and limits of the server. The only
+    /// field is the tokens to advertise.
+    ///
+    /// This reply is sometimes referred to by its number, 005.
+    ISupport(Vec<Vec<u8>>),
+    /// Part of a `STATS l` reply describing a single connection and its
+    /// queues.
+    ///
+    /// Besides the standard fields, this server reports the size of the
+    /// connection's receive queue after the time it has been open.
+    ///
+    /// This reply is sometimes referred to by its number, 211.
+    StatsLinkInfo {
+        /// The connection, in the form `nick[user@host]`.
+        link: Vec<u8>,
+        /// The number of bytes waiting to be written to the connection.
+        sendq: usize,
+        /// The number of lines which have been sent to the connection.
+        sent_lines: u64,
+        /// The number of kibibytes which have been sent to the connection.
+        sent_kbytes: u64,
+        /// The number of lines which have been received from the connection.
+        received_lines: u64,
+        /// The number of kibibytes which have been received from the
+        /// connection.
+        received_kbytes: u64,
+        /// The number of seconds the connection has been open.
+        open: u64,
+        /// The number of bytes received from the connection which have not
+        /// yet been handled.
+        recvq: usize,
+    },
+    /// Part of a `STATS y` reply describing a connection class.
+    ///
+    /// This reply is sometimes referred to by its number, 218.
+    StatsYLine {
+        /// The name of the class.
+        class: Vec<u8>,
+        /// How many seconds a connection may be silent before it is pinged.
+        ping_frequency: u64,
+        /// The send queue limit of the class, in bytes.
+        sendq: usize,
+        /// The receive queue limit of the class, in bytes.
+        recvq: usize,
+    },
+    /// The end of a `STATS` reply. The only field is the query letter.
+    ///
+    /// This reply is sometimes referred to by its number, 219.
+    EndOfStats(Vec<u8>),
+    /// The current user modes of the client, such as `+i`.
+    ///
+    /// This reply is sometimes referred to by its number, 221.
+    UModeIs(Vec<u8>),
+    /// Part of a reply to `SILENCE`, listing a single mask from the client's
+    /// silence list.
+    ///
+    /// This reply is sometimes referred to by its number, 271.
+    SileList(Vec<u8>),
+    /// Marks the end of a reply to `SILENCE`.
+    ///
+    /// This reply is sometimes referred to by its number, 272.
+    EndOfSileList,
+    /// Part of a `WHOIS` reply giving the fingerprint of the TLS client
+    /// certificate a user presented.
+    ///
+    /// This reply is sometimes referred to by its number, 276.
+    WhoisCertFp {
+        /// The nickname of the user.
+        nick: Vec<u8>,
+        /// The fingerprint of the user's certificate.
+        fingerprint: Vec<u8>,
+    },
+    /// Tells a client that a user it messaged or queried is away.
+    ///
+    /// This reply is sometimes referred to by its number, 301.
+    Away {
+        /// The nickname of the user.
+        nick: Vec<u8>,
+        /// The user's away message.
+        message: Vec<u8>,
+    },
+    /// Tells a client that it is no longer marked as away.
+    ///
+    /// This reply is sometimes referred to by its number, 305.
+    UnAway,
+    /// Tells a client that it has been marked as away.
+    ///
+    /// This reply is sometimes referred to by its number, 306.
+    NowAway,
+    /// The first line of a `WHOIS` reply, describing a user's hostmask and
+    /// real name.
+    ///
+    /// This reply is sometimes referred to by its number, 311.
+    WhoisUser {
+        /// The nickname of the user.
+        nick: Vec<u8>,
+        /// The username of the user.
+        user: Vec<u8>,
+        /// The hostname of the user.
+        host: Vec<u8>,
+        /// The real name of the user.
+        realname: Vec<u8>,
+    },
+    /// Part of a `WHOIS` reply naming the server a user is connected to.
+    ///
+    /// This reply is sometimes referred to by its number, 312.
+    WhoisServer {
+        /// The nickname of the user.
+        nick: Vec<u8>,
+        /// The name of the server.
+        server: Vec<u8>,
+        /// A description of the server.
+        info: Vec<u8>,
+    },
+    /// Part of a `WHOIS` reply describing how long a user has been idle.
+    ///
+    /// This reply is sometimes referred to by its number, 317.
+    WhoisIdle {
+        /// The nickname of the user.
+        nick: Vec<u8>,
+        /// The number of seconds since the user last sent a message.
+        idle: u64,
+        /// The time the user connected, in seconds since the Unix epoch.
+        signon: u64,
+    },
+    /// The end of a `WHO` reply. The only field is the mask which was
+    /// queried.
+    ///
+    /// This reply is sometimes referred to by its number, 315.
+    EndOfWho(Vec<u8>),
+    /// The end of a `WHOIS` reply. The only field is the nickname which was
+    /// queried.
+    ///
+    /// This reply is sometimes referred to by its number, 318.
+    EndOfWhois(Vec<u8>),
+    /// Part of a `WHOIS` reply listing the channels a user is in.
+    ///
+    /// This reply is sometimes referred to by its number, 319.
+    WhoisChannels {
+        /// The nickname of the user.
+        nick: Vec<u8>,
+        /// The space-separated names of the channels, each prefixed with the
+        /// user's highest status in that channel.
+        channels: Vec<u8>,
+    },
+    /// The start of a `LIST` reply.
+    ///
+    /// This reply is sometimes referred to by its number, 321.
+    ListStart,
+    /// A single channel in a `LIST` reply.
+    ///
+    /// This reply is sometimes referred to by its number, 322.
+    List {
+        /// The name of the channel.
+        channel: Vec<u8>,
+        /// The number of users visible in the channel.
+        visible: usize,
+        /// The channel's topic.
+        topic: Vec<u8>,
+    },
+    /// The end of a `LIST` reply.
+    ///
+    /// This reply is sometimes referred to by its number, 323.
+    ListEnd,
+    /// The modes of a channel.
+    ///
+    /// This reply is sometimes referred to by its number, 324.
+    ChannelModeIs {
+        /// The name of the channel.
+        channel: Vec<u8>,
+        /// The mode string, such as `+ntk`, followed by the arguments of the
+        /// modes in it.
+        modes: Vec<Vec<u8>>,
+    },
+    /// The time a channel was created.
+    ///
+    /// This reply is sometimes referred to by its number, 329.
+    CreationTime {
+        /// The name of the channel.
+        channel: Vec<u8>,
+        /// The time the channel was created, in seconds since the Unix epoch.
+        time: u64,
+    },
+    /// Sent in reply to a topic query for a channel which has no topic. The
+    /// only field is the name of the channel.
+    ///
+    /// This reply is sometimes referred to by its number, 331.
+    NoTopic(Vec<u8>),
+    /// The topic of a channel.
+    ///
+    /// This reply is sometimes referred to by its number, 332.
+    Topic {
+        /// The name of the channel.
+        channel: Vec<u8>,
+        /// The text of the topic.
+        topic: Vec<u8>,
+    },
+    /// Part of an extended `WHO` reply describing a single user. The only
+    /// field is the values of the fields the client asked for, in the order
+    /// `tcuihsnfdlaor`.
+    ///
+    /// This reply is sometimes referred to by its number, 354.
+    WhoxReply(Vec<Vec<u8>>),
+    /// Part of a `WHO` reply describing a single user.
+    ///
+    /// This reply is sometimes referred to by its number, 352.
+    WhoReply {
+        /// The channel the user was listed for, or `*`.
+        channel: Vec<u8>,
+        /// The username of the user.
+        user: Vec<u8>,
+        /// The hostname of the user.
+        host: Vec<u8>,
+        /// The name of the server the user is connected to.
+        server: Vec<u8>,
+        /// The nickname of the user.
+        nick: Vec<u8>,
+        /// `H` if the user is here or `G` if it is away, followed by `*` if it
+        /// is an IRC operator and its status prefixes in the channel.
+        flags: Vec<u8>,
+        /// The real name of the user.
+        realname: Vec<u8>,
+    },
+    /// Who set the topic of a channel, and when.
+    ///
+    /// This reply is sometimes referred to by its number, 333.
+    TopicWhoTime {
+        /// The name of the channel.
+        channel: Vec<u8>,
+        /// The nickname or hostmask of whoever set the topic.
+        setter: Vec<u8>,
+        /// The time the topic was set, in seconds since the Unix epoch.
+        time: u64,
+    },
+    /// Confirms that a user was invited to a channel.
+    ///
+    /// This reply is sometimes referred to by its number, 341.
+    Inviting {
+        /// The nickname of the invited user.
+        nick: Vec<u8>,
+        /// The name of the channel.
+        channel: Vec<u8>,
+    },
+    /// A single entry of a channel's ban, exception or invite exception list.
+    ///
+    /// This reply is sometimes referred to by its number: 367 for bans, 348
+    /// for exceptions or 346 for invite exceptions.
+    MaskList {
+        /// The list this entry belongs to.
+        mode: ListMode,
+        /// The name of the channel.
+        channel: Vec<u8>,
+        /// The mask of the entry.
+        mask: Vec<u8>,
+        /// The hostmask of whoever added the entry.
+        setter: Vec<u8>,
+        /// The time the entry was added, in seconds since the Unix epoch.
+        time: u64,
+    },
+    /// The end of a channel's ban, exception or invite exception list.
+    ///
+    /// This reply is sometimes referred to by its number: 368 for bans, 349
+    /// for exceptions or 347 for invite exceptions.
+    EndOfMaskList {
+        /// The list which was sent.
+        mode: ListMode,
+        /// The name of the channel.
+        channel: Vec<u8>,
+    },
+    /// Part of the list of members of a channel.
+    ///
+    /// This reply is sometimes referred to by its number, 353.
+    NamReply {
+        /// `=` for public channels, `*` for private channels and `@` for secret
+        /// channels.
+        symbol: u8,
+        /// The name of the channel.
+        channel: Vec<u8>,
+        /// The space-separated nicknames of the members, each prefixed with
+        /// their status in the channel.
+        names: Vec<u8>,
+    },
+    /// The end of the list of members of a channel. The only field is the
+    /// name of the channel.
+    ///
+    /// This reply is sometimes referred to by its number, 366.
+    EndOfNames(Vec<u8>),
+    /// The first line of the message of the day. The only field is the name
+    /// of the server.
+    ///
+    /// This reply is sometimes referred to by its number, 375.
+    MotdStart(Vec<u8>),
+    /// A single line of the message of the day.
+    ///
+    /// This reply is sometimes referred to by its number, 372.
+    Motd(Vec<u8>),
+    /// The end of the message of the day.
+    ///
+    /// This reply is sometimes referred to by its number, 376.
+    EndOfMotd,
+    /// Confirms that the client is now an IRC operator.
+    ///
+    /// This reply is sometimes referred to by its number, 381.
+    YoureOper,
+    /// Confirms that the server is reloading its configuration. The only
+    /// field is the name of the configuration file.
+    ///
+    /// This reply is sometimes referred to by its number, 382.
+    Rehashing(Vec<u8>),
+    /// Part of a `WHOIS` reply saying that a user is connected over TLS. The
+    /// only field is the nickname of the user.
+    ///
+    /// This reply is sometimes referred to by its number, 671.
+    WhoisSecure(Vec<u8>),
+    /// Tells a client the hostname it is now shown with. The only field is
+    /// the hostname.
+    ///
+    /// This reply is sometimes referred to by its number, 396.
+    HostHidden(Vec<u8>),
+    /// Tells a client that users it monitors are online. The only field is a
+    /// comma-separated list of their hostmasks.
+    ///
+    /// This reply is sometimes referred to by its number, 730.
+    MonOnline(Vec<u8>),
+    /// Tells a client that users it monitors are offline. The only field is
+    /// a comma-separated list of their nicknames.
+    ///
+    /// This reply is sometimes referred to by its number, 731.
+    MonOffline(Vec<u8>),
+    /// Part of a reply to `MONITOR L`. The only field is a comma-separated
+    /// list of nicknames the client monitors.
+    ///
+    /// This reply is sometimes referred to by its number, 732.
+    MonList(Vec<u8>),
+    /// Marks the end of a reply to `MONITOR L`.
+    ///
+    /// This reply is sometimes referred to by its number, 733.
+    EndOfMonList,
+    /// Tells a client that it has logged in to an account.
+    ///
+    /// This reply is sometimes referred to by its number, 900.
+    LoggedIn {
+        /// The `nick!user@host` mask of the client.
+        mask: Vec<u8>,
+        /// The name of the account.
+        account: Vec<u8>,
+    },
+    /// Tells a client that it has logged out of its account. The only field
+    /// is the `nick!user@host` mask of the client.
+    ///
+    /// This reply is sometimes referred to by its number, 901.
+    LoggedOut(Vec<u8>),
+    /// Tells a client that SASL authentication succeeded.
+    ///
+    /// This reply is sometimes referred to by its number, 903.
+    SaslSuccess,
+    /// Lists the SASL mechanisms this server supports. The only field is the
+    /// names of the mechanisms, separated by commas.
+    ///
+    /// This reply is sometimes referred to by its number, 908.
+    SaslMechs(Vec<u8>),
+}
+
+impl Reply {
+    /// Get the numeric code of this reply, as sent to clients.
+    #[must_use]
+    pub fn code(&self) -> u16 {
+        match self {
+            Reply::Welcome { .. } => 1,
+            Reply::YourHost { .. } => 2,
+            Reply::Created(_) => 3,
+            Reply::MyInfo { .. } => 4,
+            Reply::ISupport(_) => 5,
+            Reply::StatsLinkInfo { .. } => 211,
+            Reply::StatsYLine { .. } => 218,
+            Reply::EndOfStats(_) => 219,
+            Reply::UModeIs(_) => 221,
+            Reply::SileList(_) => 271,
+            Reply::EndOfSileList => 272,
+            Reply::WhoisCertFp { .. } => 276,
+            Reply::Away { .. } => 301,
+            Reply::UnAway => 305,
+            Reply::NowAway => 306,
+            Reply::WhoisUser { .. } => 311,
+            Reply::WhoisServer { .. } => 312,
+            Reply::WhoisIdle { .. } => 317,
+            Reply::EndOfWho(_) => 315,
+            Reply::EndOfWhois(_) => 318,
+            Reply::WhoisChannels { .. } => 319,
+            Reply::ListStart => 321,
+            Reply::List { .. } => 322,
+            Reply::ListEnd => 323,
+            Reply::ChannelModeIs { .. } => 324,
+            Reply::CreationTime { .. } => 329,
+            Reply::NoTopic(_) => 331,
+            Reply::Topic { .. } => 332,
+            Reply::TopicWhoTime { .. } => 333,
+            Reply::Inviting { .. } => 341,
+            Reply::MaskList { mode, .. } => match mode {
+                ListMode::Invex => 346,
+                ListMode::Except => 348,
+                ListMode::Ban => 367,
+            },
+            Reply::EndOfMaskList { mode, .. } => match mode {
+                ListMode::Invex => 347,
+                ListMode::Except => 349,
+                ListMode::Ban => 368,
+            },
+            Reply::WhoReply { .. } => 352,
+            Reply::NamReply { .. } => 353,
+            Reply::WhoxReply(_) => 354,
+            Reply::EndOfNames(_) => 366,
+            Reply::Motd(_) => 372,
+            Reply::MotdStart(_) => 375,
+            Reply::EndOfMotd => 376,
+            Reply::YoureOper => 381,
+            Reply::Rehashing(_) => 382,
+            Reply::HostHidden(_) => 396,
+            Reply::WhoisSecure(_) => 671,
+            Reply::MonOnline(_) => 730,
+            Reply::MonOffline(_) => 731,
+            Reply::MonList(_) => 732,
+            Reply::EndOfMonList => 733,
+            Reply::LoggedIn { .. } => 900,
+            Reply::LoggedOut(_) => 901,
+            Reply::SaslSuccess => 903,
+            Reply::SaslMechs(_) => 908,
+        }
+    }
+
+    /// Get the parameters of this reply as sent to clients, excluding the
+    /// nickname of the client the reply is being sent to.
+    #[must_use]
+    #[allow(clippy::too_many_lines)]
+    pub fn params(&self) -> Vec<Vec<u8>> {
+        let concat = |parts: &[&[u8]]| parts.concat();
+        match self {
+            Reply::Welcome { network, mask } => vec![concat(&[
+                b"Welcome to the ",
+                network,
+                b" Internet Relay Chat Network ",
+                mask,
+            ])],
+            Reply::YourHost { server, version } => vec![concat(&[
+                b"Your host is ",
+                server,
+                b", running version ",
+                version,
+            ])],
+            Reply::Created(date) => vec![concat(&[b"This server was created ", date])],
+            Reply::MyInfo {
+                server,
+                version,
+                user_modes,
+                channel_modes,
+            } => vec![
+                server.clone(),
+                version.clone(),
+                user_modes.clone(),
+                channel_modes.clone(),
+            ],
+            Reply::ISupport(tokens) => {
+                let mut params = tokens.clone();
+                params.push(b"are supported by this server".to_vec());
+                params
+            }
+            Reply::StatsLinkInfo {
+                link,
+                sendq,
+                sent_lines,
+                sent_kbytes,
+                received_lines,
+                received_kbytes,
+                open,
+                recvq,
+            } => {
+                let mut params = vec![link.clone()];
+                params.extend(
+                    [
+                        sendq.to_string(),
+                        sent_lines.to_string(),
+                        sent_kbytes.to_string(),
+                        received_lines.to_string(),
+                        received_kbytes.to_string(),
+                        open.to_string(),
+                        recvq.to_string(),
+                    ]
+                    .map(String::into_bytes),
+                );
+                params
+            }
+            Reply::StatsYLine {
+                class,
+                ping_frequency,
+                sendq,
+                recvq,
+            } => vec![
+                b"Y".to_vec(),
+                class.clone(),
+                ping_frequency.to_string().into_bytes(),
+                // connect frequency, which only applies to server links
+                b"0".to_vec(),
+                sendq.to_string().into_bytes(),
+                recvq.to_string().into_bytes(),
+            ],
+            Reply::EndOfStats(query) => vec![query.clone(), b"End of /STATS report".to_vec()],
+            Reply::UModeIs(modes) => vec![modes.clone()],
+            Reply::SileList(mask) => vec![mask.clone()],
+            Reply::EndOfSileList => vec![b"End of Silence List".to_vec()],
+            Reply::WhoisCertFp { nick, fingerprint } => vec![
+                nick.clone(),
+                concat(&[b"has client certificate fingerprint ", fingerprint]),
+            ],
+            Reply::Away { nick, message } => vec![nick.clone(), message.clone()],
+            Reply::UnAway => vec![b"You are no longer marked as being away".to_vec()],
+            Reply::NowAway => vec![b"You have been marked as being away".to_vec()],
+            Reply::WhoisUser {
+                nick,
+                user,
+                host,
+                realname,
+            } => vec![
+                nick.clone(),
+                user.clone(),
+                host.clone(),
+                b"*".to_vec(),
+                realname.clone(),
+            ],
+            Reply::WhoisServer { nick, server, info } => {
+                vec![nick.clone(), server.clone(), info.clone()]
+            }
+            Reply::WhoisIdle { nick, idle, signon } => vec![
+                nick.clone(),
+                idle.to_string().into_bytes(),
+                signon.to_string().into_bytes(),
+                b"seconds idle, signon time".to_vec(),
+            ],
+            Reply::EndOfWho(mask) => vec![mask.clone(), b"End of /WHO list".to_vec()],
+            Reply::EndOfWhois(nick) => vec![nick.clone(), b"End of /WHOIS list".to_vec()],
+            Reply::WhoisChannels { nick, channels } => vec![nick.clone(), channels.clone()],
+            Reply::ListStart => vec![b"Channel".to_vec(), b"Users  Name".to_vec()],
+            Reply::List {
+                channel,
+                visible,
+                topic,
+            } => vec![
+                channel.clone(),
+                visible.to_string().into_bytes(),
+                topic.clone(),
+            ],
+            Reply::ListEnd => vec![b"End of /LIST".to_vec()],
+            Reply::ChannelModeIs { channel, modes } => {
+                let mut params = vec![channel.clone()];
+                params.extend(modes.iter().cloned());
+                params
+            }
+            Reply::CreationTime { channel, time } => {
+                vec![channel.clone(), time.to_string().into_bytes()]
+            }
+            Reply::NoTopic(channel) => vec![channel.clone(), b"No topic is set".to_vec()],
+            Reply::Topic { channel, topic } => vec![channel.clone(), topic.clone()],
+            Reply::TopicWhoTime {
+                channel,
+                setter,
+                time,
+            } => vec![
+                channel.clone(),
+                setter.clone(),
+                time.to_string().into_bytes(),
+            ],
+            Reply::Inviting { nick, channel } => vec![nick.clone(), channel.clone()],
+            Reply::MaskList {
+                mode: _,
+                channel,
+                mask,
+                setter,
+                time,
+            } => vec![
+                channel.clone(),
+                mask.clone(),
+                setter.clone(),
+                time.to_string().into_bytes(),
+            ],
+            Reply::EndOfMaskList { mode, channel } => {
+                let text: &[u8] = match mode {
+                    ListMode::Ban => b"End of channel ban list",
+                    ListMode::Except => b"End of channel exception list",
+                    ListMode::Invex => b"End of channel invite list",
+                };
+                vec![channel.clone(), text.to_vec()]
+            }
+            Reply::WhoReply {
+                channel,
+                user,
+                host,
+                server,
+                nick,
+                flags,
+                realname,
+            } => vec![
+                channel.clone(),
+                user.clone(),
+                host.clone(),
+                server.clone(),
+                nick.clone(),
+                flags.clone(),
+                // servers are never more than zero hops away
+                concat(&[b"0 ", realname]),
+            ],
+            Reply::WhoxReply(fields) => fields.clone(),
+            Reply::NamReply {
+                symbol,
+                channel,
+                names,
+            } => vec![vec![*symbol], channel.clone(), names.clone()],
+            Reply::EndOfNames(channel) => vec![channel.clone(), b"End of /NAMES list".to_vec()],
+            Reply::Motd(line) => vec![[b"- ", &line[..]].concat()],
+            Reply::MotdStart(server) => vec![concat(&[b"- ", server, b" Message of the day - "])],
+            Reply::EndOfMotd => vec![b"End of /MOTD command.".to_vec()],
+            Reply::YoureOper => vec![b"You are now an IRC operator".to_vec()],
+            Reply::Rehashing(file) => vec![file.clone(), b"Rehashing".to_vec()],
+            Reply::WhoisSecure(nick) => {
+                vec![nick.clone(), b"is using a secure connection".to_vec()]
+            }
+            Reply::HostHidden(host) => vec![host.clone(), b"is now your displayed host".to_vec()],
+            Reply::MonOnline(targets) | Reply::MonOffline(targets) | Reply::MonList(targets) => {
+                vec![targets.clone()]
+            }
+            Reply::EndOfMonList => vec![b"End of MONITOR list".to_vec()],
+            Reply::LoggedIn { mask, account } => vec![
+                mask.clone(),
+                account.clone(),
+                concat(&[b"You are now logged in as ", account]),
+            ],
+            Reply::LoggedOut(mask) => vec![mask.clone(), b"You are now logged out".to_vec()],
+            Reply::SaslSuccess => vec![b"SASL authentication successful".to_vec()],
+            Reply::SaslMechs(mechanisms) => vec![
+                mechanisms.clone(),
+                b"are available SASL mechanisms".to_vec(),
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How serious a standard reply is, which decides the command it is sent as.
+pub enum Severity {
+    /// `FAIL`: the command failed.
+    Fail,
+    /// `WARN`: the command did not fail, but something about it may need the
+    /// client's attention.
+    Warn,
+}
+
+impl Severity {
+    /// Get the command standard replies of this severity are sent as.
+    #[must_use]
+    pub fn command(self) -> &'static [u8] {
+        match self {
+            Severity::Fail => b"FAIL",
+            Severity::Warn => b"WARN",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The machine-readable codes of the standard replies this server sends.
+pub enum StandardCode {
+    /// The account given to `REGISTER` already exists.
+    AccountExists,
+    /// The command needs the client to be logged in to an account.
+    AccountRequired,
+    /// The client is already logged in to an account, so it may not
+    /// register another.
+    AlreadyAuthenticated,
+    /// The account given to `REGISTER` is not a valid nickname.
+    BadAccountName,
+    /// The channel given to `RENAME` may not be given the new name.
+    CannotRename,
+    /// A TLS certificate could not be reloaded, so the old one is still
+    /// served.
+    CertNotReloaded,
+    /// The new name given to `RENAME` is taken by another channel.
+    ChannelNameInUse,
+    /// A parameter of the command was not valid.
+    InvalidParams,
+    /// The realname given to `SETNAME` was empty or too long.
+    InvalidRealname,
+    /// The client may not use the target of the command.
+    InvalidTarget,
+    /// The command was not given enough parameters.
+    NeedMoreParams,
+    /// `REGISTER` was asked to name the account after the client's
+    /// nickname before it had one.
+    NeedNick,
+    /// A setting was changed which only takes effect when the server is
+    /// restarted.
+    RestartRequired,
+    /// The command cannot be carried out right now, but may succeed later.
+    TemporarilyUnavailable,
+    /// The command, or one of its subcommands, is not known.
+    UnknownCommand,
+    /// The password given to `REGISTER` is too weak.
+    WeakPassword,
+}
+
+impl StandardCode {
+    /// Get this code as it is sent to clients.
+    #[must_use]
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            StandardCode::AccountExists => b"ACCOUNT_EXISTS",
+            StandardCode::AccountRequired => b"ACCOUNT_REQUIRED",
+            StandardCode::AlreadyAuthenticated => b"ALREADY_AUTHENTICATED",
+            StandardCode::BadAccountName => b"BAD_ACCOUNT_NAME",
+            StandardCode::CannotRename => b"CANNOT_RENAME",
+            StandardCode::CertNotReloaded => b"CERT_NOT_RELOADED",
+            StandardCode::ChannelNameInUse => b"CHANNEL_NAME_IN_USE",
+            StandardCode::InvalidParams => b"INVALID_PARAMS",
+            StandardCode::InvalidRealname => b"INVALID_REALNAME",
+            StandardCode::InvalidTarget => b"INVALID_TARGET",
+            StandardCode::NeedMoreParams => b"NEED_MORE_PARAMS",
+            StandardCode::NeedNick => b"NEED_NICK",
+            StandardCode::RestartRequired => b"RESTART_REQUIRED",
+            StandardCode::TemporarilyUnavailable => b"TEMPORARILY_UNAVAILABLE",
+            StandardCode::UnknownCommand => b"UNKNOWN_COMMAND",
+            StandardCode::WeakPassword => b"WEAK_PASSWORD",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A standard reply (`FAIL` or `WARN`), which reports on a command with a
+/// machine-readable code instead of a numeric.
+///
+/// A reply is started with [`fail`](StandardReply::fail) or
+/// [`warn`](StandardReply::warn), and any context parameters are added to it
+/// with
+/// [`with_context`](StandardReply::with_context).
+pub struct StandardReply {
+    /// How serious this reply is.
+    severity: Severity,
+    /// The command this reply is about, or `*` if it is about none.
+    command: Vec<u8>,
+    /// The machine-readable code of this reply.
+    code: StandardCode,
+    /// The parameters between the code and the description, which say what
+    /// the reply applies to.
+    context: Vec<Vec<u8>>,
+    /// The human-readable description of this reply.
+    description: Vec<u8>,
+}
+
+impl StandardReply {
+    /// Start a reply of the given severity about `command`.
+    fn new(
+        severity: Severity,
+        command: &[u8],
+        code: StandardCode,
+        description: &[u8],
+    ) -> StandardReply {
+        StandardReply {
+            severity,
+            command: command.to_vec(),
+            code,
+            context: Vec::new(),
+            description: description.to_vec(),
+        }
+    }
+
+    /// Start a `FAIL` reply saying that `command` failed.
+    #[must_use]
+    pub fn fail(command: &[u8], code: StandardCode, description: &[u8]) -> StandardReply {
+        StandardReply::new(Severity::Fail, command, code, description)
+    }
+
+    /// Start a `WARN` reply about `command`.
+    #[must_use]
+    pub fn warn(command: &[u8], code: StandardCode, description: &[u8]) -> StandardReply {
+        StandardReply::new(Severity::Warn, command, code, description)
+    }
+
+    /// Add `param` to the end of the context of this reply.
+    #[must_use]
+    pub fn with_context(mut self, param: &[u8]) -> StandardReply {
+        self.context.push(param.to_vec());
+        self
+    }
+
+    /// Get the severity of this reply.
+    #[must_use]
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    /// Get the message which sends this reply from `source`.
+    #[must_use]
+    pub fn to_message(&self, source: &[u8]) -> Message {
+        let mut params = vec![self.command.clone(), self.code.as_bytes().to_vec()];
+        params.extend(self.context.iter().cloned());
+        params.push(self.description.clone());
+        Message::new(Some(source), self.severity.command(), params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that only a last parameter which needs it is prefixed with a
+    /// colon.
+    fn trailing_param_colon() {
+        let plain = Message::new(
+            Some(b"src"),
+            b"MODE",
+            vec![b"#chan".to_vec(), b"+n".to_vec()],
+        );
+        assert_eq!(plain.to_line(), b":src MODE #chan +n\r\n");
+
+        let spaced = Message::new(
+            None,
+            b"PRIVMSG",
+            vec![b"#chan".to_vec(), b"hi there".to_vec()],
+        );
+        assert_eq!(spaced.to_line(), b"PRIVMSG #chan :hi there\r\n");
+
+        let empty = Message::new(None, b"AWAY", vec![Vec::new()]);
+        assert_eq!(empty.to_line(), b"AWAY :\r\n");
+
+        let colon = Message::new(None, b"PRIVMSG", vec![b"x".to_vec(), b":)".to_vec()]);
+        assert_eq!(colon.to_line(), b"PRIVMSG x ::)\r\n");
+    }
+
+    #[test]
+    /// Test that tags are sent before the source, and can be left out.
+    fn tagged_line() {
+        let message = Message::new(Some(b"src"), b"PING", vec![b"x".to_vec()])
+            .with_tag(b"time", b"2011-10-19T16:40:51.620Z")
+            .with_tag(b"msgid", b"a b");
+        assert_eq!(
+            message.to_line(),
+            b"@time=2011-10-19T16:40:51.620Z;msgid=a\\sb :src PING x\r\n"
+        );
+        assert_eq!(
+            message.to_line_with(|key| key == b"msgid"),
+            b"@msgid=a\\sb :src PING x\r\n"
+        );
+        assert_eq!(message.to_line_with(|_| false), b":src PING x\r\n");
+    }
+
+    #[test]
+    /// Test building standard replies of each severity.
+    fn standard_replies() {
+        let fail = StandardReply::fail(
+            b"CHATHISTORY",
+            StandardCode::InvalidTarget,
+            b"Messages could not be retrieved",
+        )
+        .with_context(b"LATEST")
+        .with_context(b"#chan");
+        assert_eq!(fail.severity(), Severity::Fail);
+        assert_eq!(
+            fail.to_message(b"irc.test").to_line(),
+            b":irc.test FAIL CHATHISTORY INVALID_TARGET LATEST #chan :Messages could not be retrieved\r\n"
+        );
+
+        let warn = StandardReply::warn(b"REHASH", StandardCode::RestartRequired, b"Later");
+        assert_eq!(warn.severity(), Severity::Warn);
+        assert_eq!(
+            warn.to_message(b"s").to_line(),
+            b":s WARN REHASH RESTART_REQUIRED Later\r\n"
+        );
+    }
+
+    #[test]
+    /// Test that list replies use the numeric matching their list.
+    fn mask_list_codes() {
+        let reply = |mode| Reply::EndOfMaskList {
+            mode,
+            channel: b"#c".to_vec(),
+        };
+        assert_eq!(reply(ListMode::Ban).code(), 368);
+        assert_eq!(reply(ListMode::Except).code(), 349);
+        assert_eq!(reply(ListMode::Invex).code(), 347);
+    }
+}
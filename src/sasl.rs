@@ -0,0 +1,326 @@
+//! A module for authenticating clients with SASL, through the `AUTHENTICATE`
+//! command.
+//!
+//! A client names a mechanism, and then it and the server exchange responses
+//! and challenges until the mechanism decides which account the client has
+//! proven it owns, if any. Each response and challenge is base64-encoded and
+//! split into chunks of [`CHUNK_LEN`] bytes, since it may be longer than fits
+//! in a line. A chunk shorter than that ends the message, so a message whose
+//! encoding is a multiple of [`CHUNK_LEN`] bytes long is ended with `+`,
+//! which is also how an empty message is sent.
+//!
+//! Mechanisms are added by implementing [`Mechanism`] and listing it in
+//! [`MECHANISMS`].
+
+use std::fmt;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{config::Account, mask, scram::ScramSha256};
+
+/// The length of every chunk of a message except the last.
+pub const CHUNK_LEN: usize = 400;
+
+/// The longest encoded response a client may send, after its chunks have been
+/// joined together.
+pub const MAX_RESPONSE_LEN: usize = 8192;
+
+/// A function which starts an exchange using a mechanism.
+pub type Start = fn() -> Box<dyn Mechanism>;
+
+/// The mechanisms this server supports, by name, in the order they are
+/// advertised, with the function which starts an exchange using each one.
+pub const MECHANISMS: &[(&[u8], Start)] = &[
+    (b"EXTERNAL", || Box::new(External)),
+    (b"PLAIN", || Box::new(Plain)),
+    (b"SCRAM-SHA-256", || Box::new(ScramSha256::new())),
+];
+
+/// What a mechanism knows about the server and the client authenticating.
+#[derive(Clone, Copy, Debug)]
+pub struct Context<'a> {
+    /// Every account on the server.
+    pub accounts: &'a [Account],
+    /// The fingerprint of the TLS client certificate presented by the client,
+    /// if any.
+    pub certfp: Option<&'a [u8]>,
+}
+
+impl Context<'_> {
+    /// Find the account named `name`, ignoring case.
+    #[must_use]
+    pub fn account(&self, name: &[u8]) -> Option<&Account> {
+        let name = mask::casefold(name);
+        self.accounts
+            .iter()
+            .find(|account| mask::casefold(&account.name) == name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// What a mechanism does after receiving a response from a client.
+pub enum Step {
+    /// Send the client a challenge and wait for another response.
+    Challenge(Vec<u8>),
+    /// The client has proven that it owns the named account.
+    Success(Vec<u8>),
+    /// The client has failed to authenticate.
+    Failure,
+}
+
+/// A SASL mechanism, which holds the state of a single exchange with one
+/// client.
+///
+/// Every mechanism begins with the server sending an empty challenge, so
+/// the first response is the first message the client sends.
+pub trait Mechanism: fmt::Debug + Send {
+    /// Handle the next `response` from the client.
+    fn step(&mut self, context: &Context<'_>, response: &[u8]) -> Step;
+}
+
+/// Start an exchange using the mechanism called `name`, ignoring case, or
+/// return `None` if this server does not support it.
+#[must_use]
+pub fn start(name: &[u8]) -> Option<Box<dyn Mechanism>> {
+    MECHANISMS
+        .iter()
+        .find(|(mechanism, _)| mechanism.eq_ignore_ascii_case(name))
+        .map(|(_, start)| start())
+}
+
+/// Get the names of every supported mechanism, separated by commas.
+#[must_use]
+pub fn names() -> Vec<u8> {
+    MECHANISMS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(&b',')
+}
+
+/// Encode `message` and split it into the parameters of the `AUTHENTICATE`
+/// messages which send it.
+#[must_use]
+pub fn encode(message: &[u8]) -> Vec<Vec<u8>> {
+    let encoded = STANDARD.encode(message).into_bytes();
+    let mut chunks: Vec<Vec<u8>> = encoded.chunks(CHUNK_LEN).map(<[u8]>::to_vec).collect();
+    if encoded.len().is_multiple_of(CHUNK_LEN) {
+        chunks.push(b"+".to_vec());
+    }
+    chunks
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Why a response could not be received.
+pub enum ResponseError {
+    /// A chunk, or the whole response, was too long.
+    TooLong,
+    /// The response was not valid base64.
+    Invalid,
+}
+
+#[derive(Debug)]
+/// A client's exchange with a mechanism, which is in progress until the
+/// mechanism succeeds or fails.
+pub struct Session {
+    /// The mechanism the client chose.
+    mechanism: Box<dyn Mechanism>,
+    /// The encoded chunks of the response being received, joined together.
+    buffer: Vec<u8>,
+}
+
+impl Session {
+    /// Begin an exchange using `mechanism`.
+    #[must_use]
+    pub fn new(mechanism: Box<dyn Mechanism>) -> Session {
+        Session {
+            mechanism,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Receive a single chunk of a response from the client.
+    ///
+    /// Returns the decoded response once its last chunk has been received,
+    /// or `None` if more chunks are expected.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the chunk or the response is too long, or the
+    /// response is not valid base64.
+    pub fn receive(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>, ResponseError> {
+        if chunk.len() > CHUNK_LEN || self.buffer.len() + chunk.len() > MAX_RESPONSE_LEN {
+            return Err(ResponseError::TooLong);
+        }
+        if chunk != b"+" {
+            self.buffer.extend_from_slice(chunk);
+        }
+        if chunk.len() == CHUNK_LEN {
+            return Ok(None);
+        }
+        let encoded = std::mem::take(&mut self.buffer);
+        STANDARD
+            .decode(encoded)
+            .map(Some)
+            .map_err(|_| ResponseError::Invalid)
+    }
+
+    /// Hand a complete `response` to the mechanism.
+    pub fn step(&mut self, context: &Context<'_>, response: &[u8]) -> Step {
+        self.mechanism.step(context, response)
+    }
+}
+
+#[derive(Debug)]
+/// The `PLAIN` mechanism (RFC 4616), where the client sends the name and
+/// password of an account in a single response.
+struct Plain;
+
+impl Mechanism for Plain {
+    fn step(&mut self, context: &Context<'_>, response: &[u8]) -> Step {
+        // the response is `authzid NUL authcid NUL password`, where the
+        // authorization identity is the account to act as, and the
+        // authentication identity is the account the password is for
+        let mut fields = response.split(|&b| b == 0);
+        let (Some(act_as), Some(name), Some(password), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Step::Failure;
+        };
+        // logging in to one account as another is not supported
+        if !act_as.is_empty() && mask::casefold(act_as) != mask::casefold(name) {
+            return Step::Failure;
+        }
+        match context.account(name) {
+            Some(Account {
+                name,
+                credentials: Some(credentials),
+                ..
+            }) if credentials.verify(password) => Step::Success(name.clone()),
+            _ => Step::Failure,
+        }
+    }
+}
+
+#[derive(Debug)]
+/// The `EXTERNAL` mechanism (RFC 4422), where the client is identified by
+/// the TLS client certificate it presented when it connected.
+struct External;
+
+impl Mechanism for External {
+    fn step(&mut self, context: &Context<'_>, response: &[u8]) -> Step {
+        let Some(certfp) = context.certfp else {
+            return Step::Failure;
+        };
+        let Some(account) = context
+            .accounts
+            .iter()
+            .find(|account| account.certfps.iter().any(|fp| fp == certfp))
+        else {
+            return Step::Failure;
+        };
+        // the response is the account the client wants, if it names one
+        if response.is_empty() || mask::casefold(response) == mask::casefold(&account.name) {
+            Step::Success(account.name.clone())
+        } else {
+            Step::Failure
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scram::Credentials;
+
+    /// The accounts used by the tests.
+    fn accounts() -> Vec<Account> {
+        vec![
+            Account {
+                name: b"Alice".to_vec(),
+                credentials: Some(Credentials::derive(b"secret")),
+                certfps: vec![b"aa".to_vec()],
+            },
+            Account {
+                name: b"bob".to_vec(),
+                credentials: None,
+                certfps: vec![b"bb".to_vec()],
+            },
+        ]
+    }
+
+    #[test]
+    /// Test logging in with `PLAIN`.
+    fn plain() {
+        let accounts = accounts();
+        let context = Context {
+            accounts: &accounts,
+            certfp: None,
+        };
+        let step = |response: &[u8]| start(b"plain").unwrap().step(&context, response);
+        assert_eq!(step(b"\0alice\0secret"), Step::Success(b"Alice".to_vec()));
+        assert_eq!(
+            step(b"ALICE\0alice\0secret"),
+            Step::Success(b"Alice".to_vec())
+        );
+        assert_eq!(step(b"\0alice\0wrong"), Step::Failure);
+        assert_eq!(step(b"bob\0alice\0secret"), Step::Failure);
+        assert_eq!(step(b"\0alice\0secret\0"), Step::Failure);
+        // accounts without passwords can only be logged in to with a certificate
+        assert_eq!(step(b"\0bob\0"), Step::Failure);
+    }
+
+    #[test]
+    /// Test logging in with `EXTERNAL`.
+    fn external() {
+        let accounts = accounts();
+        let mut context = Context {
+            accounts: &accounts,
+            certfp: Some(b"bb"),
+        };
+        let mut external = start(b"EXTERNAL").unwrap();
+        assert_eq!(external.step(&context, b""), Step::Success(b"bob".to_vec()));
+        assert_eq!(
+            external.step(&context, b"Bob"),
+            Step::Success(b"bob".to_vec())
+        );
+        assert_eq!(external.step(&context, b"alice"), Step::Failure);
+        context.certfp = Some(b"cc");
+        assert_eq!(external.step(&context, b""), Step::Failure);
+        context.certfp = None;
+        assert_eq!(external.step(&context, b""), Step::Failure);
+        assert!(start(b"CRAM-MD5").is_none());
+        assert_eq!(names(), b"EXTERNAL,PLAIN,SCRAM-SHA-256");
+    }
+
+    #[test]
+    /// Test that long messages are split into chunks, and joined back
+    /// together.
+    fn chunks() {
+        assert_eq!(encode(b""), [b"+".to_vec()]);
+        // 300 bytes encode to exactly one full chunk
+        let chunks = encode(&[b'x'; 300]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), CHUNK_LEN);
+        assert_eq!(chunks[1], b"+");
+        assert_eq!(encode(&[b'x'; 301]).len(), 2);
+
+        let message = vec![b'y'; 700];
+        let mut session = Session::new(start(b"PLAIN").unwrap());
+        let mut received = None;
+        for chunk in encode(&message) {
+            assert!(received.is_none());
+            received = session.receive(&chunk).unwrap();
+        }
+        assert_eq!(received, Some(message));
+        assert_eq!(session.receive(b"+"), Ok(Some(Vec::new())));
+
+        assert_eq!(session.receive(&[b'A'; 401]), Err(ResponseError::TooLong));
+        assert_eq!(session.receive(b"not base64!"), Err(ResponseError::Invalid));
+        let mut session = Session::new(start(b"PLAIN").unwrap());
+        let too_long = (0..=MAX_RESPONSE_LEN / CHUNK_LEN)
+            .map(|_| session.receive(&[b'A'; CHUNK_LEN]))
+            .last();
+        assert_eq!(too_long, Some(Err(ResponseError::TooLong)));
+    }
+}
@@ -0,0 +1,538 @@
+//! A module for the SCRAM-SHA-256 SASL mechanism (RFC 7677), which lets a
+//! client prove that it knows an account's password without ever sending
+//! it, and lets the server store only salted, iterated keys derived from the
+//! password.
+//!
+//! The exchange takes three round trips over `AUTHENTICATE`:
+//!
+//! 1. The client sends its client-first message, naming the account and
+//!    giving a nonce.
+//! 2. The server replies with its server-first message, giving the full
+//!    nonce and the salt and iteration count of the account's credentials.
+//! 3. The client sends its client-final message, holding a proof derived
+//!    from the password.
+//! 4. The server checks the proof, and replies with its server-final
+//!    message, holding a signature which proves to the client that the
+//!    server knew the credentials too.
+//! 5. The client sends an empty response, and is logged in.
+//!
+//! Passwords are used exactly as given, without being normalized. An
+//! account which does not exist, or has no password, is given a made-up
+//! salt, so that the exchange only fails at step 4 and does not reveal
+//! which accounts exist (RFC 5802, section 5.1).
+
+use std::{fmt, num::NonZeroU32, sync::OnceLock};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    digest, hmac, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{
+    config::Account,
+    mask,
+    sasl::{Context, Mechanism, Step},
+    server::constant_time_eq,
+};
+
+/// The number of iterations used when deriving new credentials, which is the
+/// minimum RFC 7677 recommends.
+pub const ITERATIONS: NonZeroU32 = NonZeroU32::new(4096).unwrap();
+
+/// The length of the salt of new credentials, in bytes.
+const SALT_LEN: usize = 16;
+
+/// The number of random bytes in the server's part of the nonce.
+const NONCE_LEN: usize = 18;
+
+/// The length of a SHA-256 digest, in bytes.
+const KEY_LEN: usize = 32;
+
+/// The name of the mechanism, which also begins the text form of
+/// [`Credentials`].
+pub const NAME: &str = "SCRAM-SHA-256";
+
+/// Fill a buffer of `len` bytes from the system's secure random number
+/// generator.
+///
+/// # Panics
+///
+/// Panics if the random number generator fails, which it only does if the
+/// operating system cannot provide randomness at all.
+pub(crate) fn random(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the system random number generator failed");
+    bytes
+}
+
+/// The secret the made-up salts of accounts which do not exist are derived
+/// from, which is chosen once each time the server starts.
+static FAKE_SALT_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Compute the HMAC-SHA-256 of `message` with `key`.
+fn hmac(key: &[u8], message: &[u8]) -> [u8; KEY_LEN] {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), message);
+    let mut out = [0; KEY_LEN];
+    out.copy_from_slice(tag.as_ref());
+    out
+}
+
+/// Compute the SHA-256 digest of `message`.
+fn sha256(message: &[u8]) -> [u8; KEY_LEN] {
+    let mut out = [0; KEY_LEN];
+    out.copy_from_slice(digest::digest(&digest::SHA256, message).as_ref());
+    out
+}
+
+#[derive(Clone, PartialEq, Eq)]
+/// The keys stored for an account, from which its password cannot be
+/// recovered.
+///
+/// They are written as `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`,
+/// with the salt and keys in base64, which is the format of RFC 5803.
+pub struct Credentials {
+    /// The salt the password was hashed with.
+    pub salt: Vec<u8>,
+    /// How many iterations of PBKDF2 the password was hashed with.
+    pub iterations: NonZeroU32,
+    /// The digest of the key a client derives its proof from.
+    pub stored_key: [u8; KEY_LEN],
+    /// The key the server signs its final message with.
+    pub server_key: [u8; KEY_LEN],
+}
+
+impl Credentials {
+    /// Derive new credentials from `password`, with a random salt and the
+    /// default number of iterations.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the system cannot provide randomness for the salt.
+    #[must_use]
+    pub fn derive(password: &[u8]) -> Credentials {
+        Credentials::with_salt(password, random(SALT_LEN), ITERATIONS)
+    }
+
+    /// Derive credentials from `password` with the given `salt` and number of
+    /// `iterations`.
+    #[must_use]
+    pub fn with_salt(password: &[u8], salt: Vec<u8>, iterations: NonZeroU32) -> Credentials {
+        let mut salted = [0; KEY_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password,
+            &mut salted,
+        );
+        Credentials {
+            salt,
+            iterations,
+            stored_key: sha256(&hmac(&salted, b"Client Key")),
+            server_key: hmac(&salted, b"Server Key"),
+        }
+    }
+
+    /// Check whether `password` is the one these credentials were derived
+    /// from.
+    #[must_use]
+    pub fn verify(&self, password: &[u8]) -> bool {
+        let derived = Credentials::with_salt(password, self.salt.clone(), self.iterations);
+        constant_time_eq(&derived.stored_key, &self.stored_key)
+    }
+
+    /// Parse credentials written in the format of RFC 5803, returning `None`
+    /// if `text` is not in that format.
+    #[must_use]
+    pub fn parse(text: &str) -> Option<Credentials> {
+        let key = |encoded: &str| STANDARD.decode(encoded).ok()?.try_into().ok();
+        let rest = text.strip_prefix(NAME)?.strip_prefix('$')?;
+        let (parameters, keys) = rest.split_once('$')?;
+        let (iterations, salt) = parameters.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+        Some(Credentials {
+            salt: STANDARD.decode(salt).ok().filter(|salt| !salt.is_empty())?,
+            iterations: iterations.parse().ok()?,
+            stored_key: key(stored_key)?,
+            server_key: key(server_key)?,
+        })
+    }
+}
+
+impl fmt::Display for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{NAME}${}:{}${}:{}",
+            self.iterations,
+            STANDARD.encode(&self.salt),
+            STANDARD.encode(self.stored_key),
+            STANDARD.encode(self.server_key)
+        )
+    }
+}
+
+impl fmt::Debug for Credentials {
+    // the keys are as good as the password to anyone who can run PBKDF2
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("iterations", &self.iterations)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Default)]
+/// How far an exchange has got.
+enum State {
+    #[default]
+    /// Waiting for the client-first message.
+    ClientFirst,
+    /// Waiting for the client-final message.
+    ClientFinal {
+        /// The name of the account being logged in to, or `None` if it
+        /// cannot be logged in to with a password, in which case the
+        /// exchange fails whatever the client sends.
+        account: Option<Vec<u8>>,
+        /// The credentials of the account, or made-up ones if there is no
+        /// account.
+        credentials: Credentials,
+        /// The GS2 header which began the client-first message, which the
+        /// client must repeat.
+        gs2_header: Vec<u8>,
+        /// The full nonce, which the client must repeat.
+        nonce: Vec<u8>,
+        /// The client-first message without its header, then a comma, then
+        /// the server-first message, which begin the message both sides
+        /// sign.
+        signed: Vec<u8>,
+    },
+    /// Waiting for the client to accept the server-final message.
+    Verified(Vec<u8>),
+    /// The exchange has failed.
+    Failed,
+}
+
+#[derive(Debug, Default)]
+/// The `SCRAM-SHA-256` mechanism.
+pub struct ScramSha256 {
+    /// How far the exchange has got.
+    state: State,
+    /// The server's part of the nonce, or `None` to generate a random one.
+    server_nonce: Option<Vec<u8>>,
+}
+
+impl ScramSha256 {
+    /// Start an exchange.
+    #[must_use]
+    pub fn new() -> ScramSha256 {
+        ScramSha256::default()
+    }
+
+    /// Handle the client-first message, returning the server-first message.
+    fn client_first(&mut self, context: &Context<'_>, message: &[u8]) -> Option<Vec<u8>> {
+        // the GS2 header is a channel binding flag and an optional
+        // authorization identity; channel binding is not supported
+        let mut header = message.splitn(3, |&b| b == b',');
+        let (flag, act_as, bare) = (header.next()?, header.next()?, header.next()?);
+        if flag != b"n" && flag != b"y" {
+            return None;
+        }
+        let gs2_header = message[..flag.len() + act_as.len() + 2].to_vec();
+
+        let mut fields = bare.split(|&b| b == b',');
+        let name = decode_name(attribute(fields.next()?, b'n')?)?;
+        let client_nonce = attribute(fields.next()?, b'r')?;
+        if client_nonce.is_empty() || !client_nonce.iter().all(|&b| b.is_ascii_graphic()) {
+            return None;
+        }
+        if !act_as.is_empty() {
+            let act_as = decode_name(attribute(act_as, b'a')?)?;
+            if mask::casefold(&act_as) != mask::casefold(&name) {
+                return None;
+            }
+        }
+        let (account, credentials) = match context.account(&name) {
+            Some(Account {
+                name,
+                credentials: Some(credentials),
+                ..
+            }) => (Some(name.clone()), credentials.clone()),
+            _ => (None, fake_credentials(&name)),
+        };
+
+        let server_nonce = self
+            .server_nonce
+            .take()
+            .unwrap_or_else(|| STANDARD.encode(random(NONCE_LEN)).into_bytes());
+        let nonce = [client_nonce, &server_nonce].concat();
+        let server_first = [
+            b"r=",
+            &nonce[..],
+            b",s=",
+            STANDARD.encode(&credentials.salt).as_bytes(),
+            b",i=",
+            credentials.iterations.to_string().as_bytes(),
+        ]
+        .concat();
+        self.state = State::ClientFinal {
+            account,
+            credentials,
+            gs2_header,
+            nonce,
+            signed: [bare, b",", &server_first].concat(),
+        };
+        Some(server_first)
+    }
+}
+
+impl Mechanism for ScramSha256 {
+    fn step(&mut self, context: &Context<'_>, response: &[u8]) -> Step {
+        let step = match std::mem::replace(&mut self.state, State::Failed) {
+            State::ClientFirst => self.client_first(context, response).map(Step::Challenge),
+            State::ClientFinal {
+                account,
+                credentials,
+                gs2_header,
+                nonce,
+                signed,
+            } => client_final(&credentials, &gs2_header, &nonce, &signed, response).and_then(
+                |server_final| {
+                    self.state = State::Verified(account?);
+                    Some(Step::Challenge(server_final))
+                },
+            ),
+            State::Verified(account) if response.is_empty() => Some(Step::Success(account)),
+            State::Verified(_) | State::Failed => None,
+        };
+        step.unwrap_or(Step::Failure)
+    }
+}
+
+/// Make up credentials for the account `name`, which cannot be logged in to
+/// with a password. Its salt is the same each time, as a real account's
+/// would be, and no proof matches its keys.
+fn fake_credentials(name: &[u8]) -> Credentials {
+    let key = FAKE_SALT_KEY.get_or_init(|| random(KEY_LEN));
+    Credentials {
+        salt: hmac(key, &mask::casefold(name))[..SALT_LEN].to_vec(),
+        iterations: ITERATIONS,
+        stored_key: [0; KEY_LEN],
+        server_key: [0; KEY_LEN],
+    }
+}
+
+/// Check the client-final `message` of an exchange with the account whose
+/// credentials are `credentials`, returning the server-final message if the
+/// client's proof is correct.
+fn client_final(
+    credentials: &Credentials,
+    gs2_header: &[u8],
+    nonce: &[u8],
+    signed: &[u8],
+    message: &[u8],
+) -> Option<Vec<u8>> {
+    // the proof is always last, and is not itself signed
+    let split = message.windows(3).rposition(|window| window == b",p=")?;
+    let (without_proof, proof) = (&message[..split], &message[split + 3..]);
+    let mut fields = without_proof.split(|&b| b == b',');
+    if STANDARD.decode(attribute(fields.next()?, b'c')?).ok()? != gs2_header
+        || attribute(fields.next()?, b'r')? != nonce
+    {
+        return None;
+    }
+    let proof: [u8; KEY_LEN] = STANDARD.decode(proof).ok()?.try_into().ok()?;
+
+    let auth_message = [signed, b",", without_proof].concat();
+    let client_signature = hmac(&credentials.stored_key, &auth_message);
+    let client_key: Vec<u8> = proof
+        .iter()
+        .zip(client_signature)
+        .map(|(a, b)| a ^ b)
+        .collect();
+    if !constant_time_eq(&sha256(&client_key), &credentials.stored_key) {
+        return None;
+    }
+    let server_signature = hmac(&credentials.server_key, &auth_message);
+    Some([b"v=", STANDARD.encode(server_signature).as_bytes()].concat())
+}
+
+/// Get the value of `field` if it is the attribute named `name`, such as the
+/// `user` of `n=user`.
+fn attribute(field: &[u8], name: u8) -> Option<&[u8]> {
+    match field {
+        [first, b'=', value @ ..] if *first == name => Some(value),
+        _ => None,
+    }
+}
+
+/// Decode a username, in which `,` and `=` are escaped as `=2C` and `=3D`.
+fn decode_name(name: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(name.len());
+    let mut rest = name;
+    while let Some((&b, after)) = rest.split_first() {
+        if b == b'=' {
+            decoded.push(match after.get(..2)? {
+                b"2C" => b',',
+                b"3D" => b'=',
+                _ => return None,
+            });
+            rest = &after[2..];
+        } else {
+            decoded.push(b);
+            rest = after;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The password of the RFC 7677 example.
+    const PASSWORD: &[u8] = b"pencil";
+    /// The salt of the RFC 7677 example.
+    const SALT: &str = "W22ZaJ0SNY7soEsUEjb6gQ==";
+    /// The client-first message of the RFC 7677 example.
+    const CLIENT_FIRST: &[u8] = b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+    /// The server's part of the nonce in the RFC 7677 example.
+    const SERVER_NONCE: &[u8] = b"%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    /// The server-first message of the RFC 7677 example.
+    const SERVER_FIRST: &[u8] =
+        b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    /// The client-final message of the RFC 7677 example.
+    const CLIENT_FINAL: &[u8] = b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+        p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    /// The server-final message of the RFC 7677 example.
+    const SERVER_FINAL: &[u8] = b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    /// The account of the RFC 7677 example.
+    fn accounts() -> Vec<Account> {
+        let salt = STANDARD.decode(SALT).unwrap();
+        vec![Account {
+            name: b"user".to_vec(),
+            credentials: Some(Credentials::with_salt(PASSWORD, salt, ITERATIONS)),
+            certfps: Vec::new(),
+        }]
+    }
+
+    /// Start an exchange which uses the server nonce of the RFC 7677
+    /// example.
+    fn exchange() -> ScramSha256 {
+        ScramSha256 {
+            server_nonce: Some(SERVER_NONCE.to_vec()),
+            ..ScramSha256::new()
+        }
+    }
+
+    #[test]
+    /// Test the full exchange of RFC 7677.
+    fn rfc7677() {
+        let accounts = accounts();
+        let context = Context {
+            accounts: &accounts,
+            certfp: None,
+        };
+        let mut scram = exchange();
+        assert_eq!(
+            scram.step(&context, CLIENT_FIRST),
+            Step::Challenge(SERVER_FIRST.to_vec())
+        );
+        assert_eq!(
+            scram.step(&context, CLIENT_FINAL),
+            Step::Challenge(SERVER_FINAL.to_vec())
+        );
+        assert_eq!(scram.step(&context, b""), Step::Success(b"user".to_vec()));
+    }
+
+    #[test]
+    /// Test that wrong proofs, nonces and usernames fail the exchange.
+    fn failures() {
+        let accounts = accounts();
+        let context = Context {
+            accounts: &accounts,
+            certfp: None,
+        };
+        let wrong_proof = String::from_utf8(CLIENT_FINAL.to_vec())
+            .unwrap()
+            .replace("p=dHzb", "p=dHzc");
+        let wrong_nonce = String::from_utf8(CLIENT_FINAL.to_vec())
+            .unwrap()
+            .replace("k0,", "k1,");
+        let wrong_binding = String::from_utf8(CLIENT_FINAL.to_vec())
+            .unwrap()
+            .replace("c=biws", "c=eSws");
+        for client_final in [wrong_proof, wrong_nonce, wrong_binding] {
+            let mut scram = exchange();
+            scram.step(&context, CLIENT_FIRST);
+            assert_eq!(scram.step(&context, client_final.as_bytes()), Step::Failure);
+        }
+
+        for client_first in [
+            &b"p=tls-unique,,n=user,r=abc"[..],
+            b"n,a=other,n=user,r=abc",
+            b"n,,r=abc,n=user",
+            b"n,,n=us=2Xer,r=abc",
+        ] {
+            assert_eq!(exchange().step(&context, client_first), Step::Failure);
+        }
+        // an authorization identity naming the same account is fine
+        let mut scram = exchange();
+        let step = scram.step(&context, b"n,a=USER,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        assert!(matches!(step, Step::Challenge(_)));
+    }
+
+    #[test]
+    /// Test that accounts which do not exist, or have no password, are given
+    /// a consistent challenge and only fail at the client-final message.
+    fn unknown_accounts() {
+        let mut accounts = accounts();
+        accounts.push(Account {
+            name: b"certonly".to_vec(),
+            credentials: None,
+            certfps: vec![b"c0ffee".to_vec()],
+        });
+        let context = Context {
+            accounts: &accounts,
+            certfp: None,
+        };
+        let challenge = |name: &str| {
+            let client_first = format!("n,,n={name},r=rOprNGfwEbeRWgbNEkqO");
+            let mut scram = exchange();
+            let Step::Challenge(server_first) = scram.step(&context, client_first.as_bytes())
+            else {
+                panic!("{name} was not challenged");
+            };
+            assert_eq!(scram.step(&context, CLIENT_FINAL), Step::Failure);
+            server_first
+        };
+        for name in ["nobody", "certonly"] {
+            let server_first = challenge(name);
+            assert!(server_first.ends_with(b",i=4096"));
+            assert_ne!(server_first, SERVER_FIRST);
+            assert_eq!(challenge(&name.to_uppercase()), server_first);
+        }
+        assert_ne!(challenge("nobody"), challenge("somebody"));
+    }
+
+    #[test]
+    /// Test deriving, verifying, writing and parsing credentials.
+    fn credentials() {
+        let credentials = Credentials::derive(b"hunter2");
+        assert!(credentials.verify(b"hunter2"));
+        assert!(!credentials.verify(b"hunter3"));
+        assert_eq!(credentials.salt.len(), SALT_LEN);
+        assert_ne!(Credentials::derive(b"hunter2"), credentials);
+
+        let text = credentials.to_string();
+        assert!(text.starts_with("SCRAM-SHA-256$4096:"));
+        assert_eq!(Credentials::parse(&text), Some(credentials));
+        assert_eq!(Credentials::parse("SCRAM-SHA-1$4096:c2FsdA==$a:b"), None);
+        assert_eq!(Credentials::parse("SCRAM-SHA-256$0:c2FsdA==$a:b"), None);
+        assert!(!format!("{:?}", accounts()[0]).contains("stored_key"));
+        assert_eq!(decode_name(b"a=2Cb=3D"), Some(b"a,b=".to_vec()));
+    }
+}
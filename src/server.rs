@@ -0,0 +1,5347 @@
+//! A module for the shared state of the server and the handling of the
+//! commands sent to it by clients.
+//!
+//! The server itself performs no I/O, apart from reading its configuration
+//! file when it is rehashed and appending messages to the history file, if
+//! one is configured. Each connection is given an `Outbox` when it
+//! connects, and any lines the server wants to send to that connection are
+//! queued in it.
+
+use std::{
+    cell::Cell,
+    collections::{BTreeSet, HashMap},
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use crate::{
+    batch::Batch,
+    cap::{self, Cap, CAP_302},
+    channel::{Channel, ListMode, Membership, Topic},
+    class::{self, Class},
+    client::{Client, ClientId, Outbox, Received},
+    config::{self, Config, ConfigError},
+    extban,
+    flood::{self, Verdict},
+    history::{self, History, Query, Reference},
+    incoming::{self, CapCommand, MessageKind, MonitorCommand, Whox},
+    isupport::{ISupport, Token},
+    mask,
+    outgoing::{self, Reply, StandardCode, StandardReply},
+    sasl, scram,
+    tags::{self, Tags},
+    time,
+    tls::{self, CertResolver},
+    IrcError, IrcResult,
+};
+
+/// The software version reported to clients.
+const VERSION: &str = concat!("strigoi-", env!("CARGO_PKG_VERSION"));
+
+/// The letters of every user mode this server supports.
+const USER_MODES: &[u8] = b"ioZ";
+
+/// The prefixes which begin channel names.
+const CHANNEL_TYPES: &[u8] = b"#";
+
+/// The letters of every channel mode this server supports.
+const CHANNEL_MODES: &[u8] = b"Ibeiklmnopstv";
+
+/// How long a connection may be silent before it is sent a `PING`, unless
+/// configured otherwise.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_mins(2);
+
+/// How long a connection has to answer a `PING` before it is disconnected,
+/// unless configured otherwise.
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_mins(1);
+
+/// The away message of clients which sent `AWAY *` to be marked as away
+/// without giving a message of their own.
+const DEFAULT_AWAY: &[u8] = b"Away";
+
+/// The number of random bytes which begin every message identifier given out
+/// by a server, so that identifiers stay unique across restarts.
+const MSGID_PREFIX_LEN: usize = 9;
+
+/// The shared state of the server: every connected client and every channel.
+pub struct Server {
+    /// The name of this server, used as the source of numerics.
+    name: Vec<u8>,
+    /// The name of the network this server is part of.
+    network: Vec<u8>,
+    /// A human-readable description of when this server was started.
+    created: Vec<u8>,
+    /// The identifier which will be given to the next client to connect.
+    next_id: ClientId,
+    /// Every connected client, by identifier.
+    clients: HashMap<ClientId, Client>,
+    /// The identifiers of every client with a nickname, by casefolded
+    /// nickname.
+    nicks: HashMap<Vec<u8>, ClientId>,
+    /// The identifiers of the clients monitoring each casefolded nickname.
+    monitors: HashMap<Vec<u8>, BTreeSet<ClientId>>,
+    /// Every channel, by casefolded name.
+    channels: HashMap<Vec<u8>, Channel>,
+    /// How long a connection may be silent before it is sent a `PING`.
+    ping_interval: Duration,
+    /// How long a connection has to answer a `PING` before it is
+    /// disconnected.
+    ping_timeout: Duration,
+    /// The limits on how quickly clients may send commands.
+    flood: flood::Limits,
+    /// The connection classes, in the order they are matched against new
+    /// connections.
+    classes: Vec<Class>,
+    /// The limits on the lengths and numbers of things.
+    limits: config::Limits,
+    /// The lines of the message of the day, if there is one.
+    motd: Option<Vec<Vec<u8>>>,
+    /// The accounts which may become IRC operators.
+    opers: Vec<config::Oper>,
+    /// The accounts clients may log in to with SASL.
+    accounts: Vec<config::Account>,
+    /// The file accounts registered with `REGISTER` are appended to, if
+    /// clients may register accounts.
+    registration_file: Option<PathBuf>,
+    /// The `user@host`s which may not connect to the server.
+    bans: Vec<config::Ban>,
+    /// The SASL exchanges in progress, by client.
+    sasl: HashMap<ClientId, sasl::Session>,
+    /// The addresses being listened on, which cannot change without a
+    /// restart.
+    listeners: Vec<config::Listener>,
+    /// The file the configuration is reloaded from when the server is
+    /// rehashed, if there is one.
+    config_path: Option<PathBuf>,
+    /// The certificates served by each TLS listener, by address.
+    certs: Vec<(String, Arc<CertResolver>)>,
+    /// The random start of every message identifier given out by this
+    /// server.
+    msgid_prefix: Vec<u8>,
+    /// The number which ends the next message identifier.
+    next_msgid: Cell<u64>,
+    /// The reference of the next batch to be opened.
+    next_batch: Cell<u64>,
+    /// The messages kept for `CHATHISTORY`, if history is enabled.
+    history: Option<History>,
+}
+
+/// A search condition given to `LIST`, which a channel must match to be
+/// listed.
+type Condition<'a> = Box<dyn Fn(&Channel) -> bool + 'a>;
+
+/// A single change requested in a channel `MODE` command.
+struct ModeChange {
+    /// Whether the mode is being set (`+`) or unset (`-`).
+    adding: bool,
+    /// The letter of the mode.
+    letter: u8,
+    /// The argument of the mode, if it takes one.
+    arg: Option<Vec<u8>>,
+}
+
+impl Server {
+    /// Construct a new server with no clients or channels.
+    #[must_use]
+    pub fn new(name: &[u8], network: &[u8]) -> Server {
+        Server {
+            name: name.to_vec(),
+            network: network.to_vec(),
+            created: time::iso8601(SystemTime::now()).into_bytes(),
+            next_id: 0,
+            clients: HashMap::new(),
+            nicks: HashMap::new(),
+            monitors: HashMap::new(),
+            channels: HashMap::new(),
+            ping_interval: DEFAULT_PING_INTERVAL,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            flood: flood::Limits::default(),
+            classes: vec![Class::default()],
+            limits: config::Limits::default(),
+            motd: None,
+            opers: Vec::new(),
+            accounts: Vec::new(),
+            registration_file: None,
+            bans: Vec::new(),
+            sasl: HashMap::new(),
+            listeners: Vec::new(),
+            config_path: None,
+            certs: Vec::new(),
+            msgid_prefix: URL_SAFE_NO_PAD
+                .encode(scram::random(MSGID_PREFIX_LEN))
+                .into_bytes(),
+            next_msgid: Cell::new(0),
+            next_batch: Cell::new(0),
+            history: None,
+        }
+    }
+
+    /// Construct a new server with no clients or channels from `config`.
+    #[must_use]
+    pub fn from_config(config: &Config) -> Server {
+        let mut server = Server::new(&config.name, &config.network);
+        server.configure(config);
+        server.listeners.clone_from(&config.listeners);
+        server
+    }
+
+    /// Set the file the configuration is reloaded from when the server is
+    /// rehashed.
+    pub fn set_config_path(&mut self, path: PathBuf) {
+        self.config_path = Some(path);
+    }
+
+    /// Reload the configuration file, as requested by the client `by` or, if
+    /// it is `None`, by the server's administrator.
+    ///
+    /// The new configuration replaces the old one all at once, and only if it
+    /// is valid. Settings which cannot change while the server is running are
+    /// left as they were. The outcome is sent to every IRC operator as a
+    /// server notice, except that `by` is sent the settings which did not
+    /// take effect as `WARN REHASH` replies.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no configuration file or it is invalid,
+    /// in which case the old configuration is kept. Otherwise, returns a
+    /// description of each setting which was changed but will not take
+    /// effect until the server is restarted.
+    pub fn rehash(&mut self, by: Option<ClientId>) -> Result<Vec<String>, ConfigError> {
+        let path = self.config_path.clone().ok_or_else(|| ConfigError {
+            line: None,
+            message: "the server was not started with a configuration file".to_owned(),
+        })?;
+        let who = by.map_or_else(
+            || b"the server".to_vec(),
+            |id| self.clients[&id].nick_or_star().to_vec(),
+        );
+        let config = match Config::load(&path) {
+            Ok(config) => config,
+            Err(err) => {
+                let notice = format!(
+                    "Rehash by {} failed: {}: {err}",
+                    String::from_utf8_lossy(&who),
+                    path.display()
+                );
+                self.server_notice(notice.as_bytes());
+                return Err(err);
+            }
+        };
+
+        let mut ignored = Vec::new();
+        let mut unreloaded = Vec::new();
+        if config.name != self.name {
+            ignored.push("the server name cannot change until a restart".to_owned());
+        }
+        if config.network != self.network {
+            ignored.push("the network name cannot change until a restart".to_owned());
+        }
+        let find = |listeners: &'_ [config::Listener], address: &str| {
+            listeners
+                .iter()
+                .find(|listener| listener.address == address)
+                .cloned()
+        };
+        for listener in &self.listeners {
+            match find(&config.listeners, &listener.address) {
+                None => ignored.push(format!(
+                    "{} will be listened on until a restart",
+                    listener.address
+                )),
+                Some(new)
+                    if new.tls.as_ref().map(|tls| tls.client_certs)
+                        != listener.tls.as_ref().map(|tls| tls.client_certs) =>
+                {
+                    ignored.push(format!(
+                        "{} will keep its TLS settings until a restart",
+                        listener.address
+                    ));
+                }
+                Some(_) => (),
+            }
+        }
+        for listener in &config.listeners {
+            if find(&self.listeners, &listener.address).is_none() {
+                ignored.push(format!(
+                    "{} will not be listened on until a restart",
+                    listener.address
+                ));
+            }
+        }
+        // history which is turned off stops being kept, but only a restart
+        // opens or changes its file
+        if let Some(settings) = &config.history {
+            let file = self
+                .history
+                .as_ref()
+                .and_then(|history| history.settings().file.as_ref());
+            match (file, &settings.file) {
+                (None, Some(new)) => ignored.push(format!(
+                    "history will be kept only in memory, not in {}, until a restart",
+                    new.display()
+                )),
+                (Some(old), new) if new.as_ref() != Some(old) => ignored.push(format!(
+                    "history will be kept in {} until a restart",
+                    old.display()
+                )),
+                _ => (),
+            }
+        }
+        // certificates are reloaded even if their files have not changed,
+        // since renewals usually replace the files in place
+        for (address, resolver) in &self.certs {
+            let Some(tls) = find(&config.listeners, address).and_then(|listener| listener.tls)
+            else {
+                continue;
+            };
+            match tls::load_key(&tls) {
+                Ok(key) => resolver.set(key),
+                Err(err) => unreloaded.push(format!("{address} keeps its old certificate: {err}")),
+            }
+        }
+        self.configure(&config);
+
+        let notice = format!(
+            "Configuration reloaded by {} from {}",
+            String::from_utf8_lossy(&who),
+            path.display()
+        );
+        self.server_notice(notice.as_bytes());
+        self.rehash_warnings(by, StandardCode::RestartRequired, &ignored);
+        self.rehash_warnings(by, StandardCode::CertNotReloaded, &unreloaded);
+        ignored.append(&mut unreloaded);
+        Ok(ignored)
+    }
+
+    /// Report `warnings` about a rehash to the client `by` as `WARN REHASH`
+    /// replies with `code`, and to every other IRC operator as server
+    /// notices.
+    fn rehash_warnings(&self, by: Option<ClientId>, code: StandardCode, warnings: &[String]) {
+        for warning in warnings {
+            if let Some(id) = by {
+                let reply = StandardReply::warn(b"REHASH", code, warning.as_bytes());
+                self.standard_reply(id, &reply);
+            }
+            self.server_notice_except(format!("Rehash: {warning}").as_bytes(), by);
+        }
+    }
+
+    /// Apply the settings in `config` which can be changed while the server
+    /// is running. The server's name and network cannot be changed.
+    pub fn configure(&mut self, config: &Config) {
+        let available = self.available_caps();
+        self.ping_interval = config.ping_interval;
+        self.ping_timeout = config.ping_timeout;
+        self.flood = config.flood.clone();
+        self.classes.clone_from(&config.classes);
+        // connected clients move to the class they would be put in now
+        for client in self.clients.values_mut() {
+            let class = class::find(&self.classes, &client.real_host)
+                .cloned()
+                .unwrap_or_default();
+            client.outbox.set_limit(class.sendq);
+            client.class = class;
+        }
+        self.limits = config.limits;
+        self.motd.clone_from(&config.motd);
+        self.opers.clone_from(&config.opers);
+        self.accounts.clone_from(&config.accounts);
+        self.registration_file.clone_from(&config.registration_file);
+        // clients logged in to accounts which no longer exist are logged out
+        let orphaned: Vec<ClientId> = self
+            .clients
+            .values()
+            .filter(|client| {
+                client.account.as_ref().is_some_and(|account| {
+                    !self
+                        .accounts
+                        .iter()
+                        .any(|existing| mask::eq_ignore_case(&existing.name, account))
+                })
+            })
+            .map(|client| client.id)
+            .collect();
+        for id in orphaned {
+            self.set_account(id, None);
+        }
+        self.bans.clone_from(&config.bans);
+        let banned: Vec<ClientId> = self
+            .clients
+            .values()
+            .filter(|client| client.registered)
+            .map(|client| client.id)
+            .collect();
+        for id in banned {
+            self.enforce_bans(id);
+        }
+        match (&mut self.history, &config.history) {
+            (Some(history), Some(settings)) => history.configure(settings.clone()),
+            // only a restart opens the file
+            (history, settings) => {
+                *history = settings.clone().map(|settings| {
+                    History::new(config::History {
+                        file: None,
+                        ..settings
+                    })
+                });
+            }
+        }
+        self.notify_caps(&available);
+    }
+
+    /// Keep messages in `history`, such as one opened with its file by
+    /// [`History::open`], instead of the one kept only in memory which
+    /// [`configure`](Server::configure) sets up. History enabled by a rehash
+    /// is only kept in memory until the server is restarted.
+    pub fn set_history(&mut self, history: History) {
+        let available = self.available_caps();
+        self.history = Some(history);
+        self.notify_caps(&available);
+    }
+
+    /// Set how long a connection may be silent before it is sent a `PING`,
+    /// and how long it then has to answer before it is disconnected.
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration) {
+        self.ping_interval = interval;
+        self.ping_timeout = timeout;
+    }
+
+    /// Set the limits on how quickly clients may send commands.
+    pub fn set_flood_limits(&mut self, limits: flood::Limits) {
+        self.flood = limits;
+    }
+
+    /// Set the connection classes, in the order they should be matched
+    /// against new connections. Connections which match none of them are put
+    /// in the default class.
+    pub fn set_classes(&mut self, classes: Vec<Class>) {
+        self.classes = classes;
+    }
+
+    /// Accept a new connection from `host`.
+    ///
+    /// Returns the identifier of the new client and the outbox which lines
+    /// for it will be queued in.
+    pub fn connect(&mut self, host: Vec<u8>) -> (ClientId, Outbox) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let class = class::find(&self.classes, &host)
+            .cloned()
+            .unwrap_or_default();
+        let mut client = Client::new(id, host);
+        client.outbox = Outbox::with_limit(class.sendq);
+        client.class = class;
+        let outbox = client.outbox.clone();
+        self.clients.insert(id, client);
+        (id, outbox)
+    }
+
+    /// Add a new client which connected over TLS from `host`, presenting a
+    /// client certificate with the fingerprint `certfp` if it presented one.
+    pub fn connect_tls(&mut self, host: Vec<u8>, certfp: Option<Vec<u8>>) -> (ClientId, Outbox) {
+        let (id, outbox) = self.connect(host);
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.secure = true;
+            client.certfp = certfp;
+        }
+        (id, outbox)
+    }
+
+    /// Register the certificate served by the TLS listener on `address`, so
+    /// that it is reloaded when the server is rehashed.
+    pub fn add_cert_resolver(&mut self, address: String, resolver: Arc<CertResolver>) {
+        self.certs.push((address, resolver));
+    }
+
+    /// Get the client with identifier `id`, if it is still connected.
+    #[must_use]
+    pub fn client(&self, id: ClientId) -> Option<&Client> {
+        self.clients.get(&id)
+    }
+
+    /// Get the channel named `name`, if it exists.
+    #[must_use]
+    pub fn channel(&self, name: &[u8]) -> Option<&Channel> {
+        self.channels.get(&mask::casefold(name))
+    }
+
+    /// Handle a message sent by the client `id`.
+    ///
+    /// If the client has enabled `labeled-response` and gave the message a
+    /// `label` tag, the lines sent to it in response are held back and then
+    /// labeled with that tag.
+    pub fn handle(&mut self, id: ClientId, message: incoming::Message) {
+        let label = self
+            .clients
+            .get(&id)
+            .filter(|client| client.has_cap(Cap::LabeledResponse))
+            .and_then(|_| message.tags().iter().find(|(key, _)| key == b"label"))
+            .map(|(_, label)| label.clone())
+            .filter(|label| !label.is_empty());
+        let Some(label) = label else {
+            self.dispatch(id, message);
+            return;
+        };
+        let outbox = self.clients[&id].outbox.clone();
+        outbox.hold();
+        self.dispatch(id, message);
+        outbox.release(|lines| self.label_response(id, &label, lines));
+    }
+
+    /// Label the `lines` sent to the client `id` in response to a command it
+    /// gave `label`.
+    ///
+    /// A single line is given the label itself, several are wrapped in a
+    /// `labeled-response` batch if the client has enabled `batch`, and no
+    /// lines at all are replaced by an `ACK`.
+    fn label_response(&self, id: ClientId, label: &[u8], lines: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let batch = self
+            .clients
+            .get(&id)
+            .is_some_and(|client| client.has_cap(Cap::Batch));
+        match &lines[..] {
+            [] => {
+                let ack = outgoing::Message::new(Some(&self.name), b"ACK", Vec::new());
+                vec![ack.with_tag(b"label", label).to_line()]
+            }
+            [line] => vec![tags::add_to_line(line, b"label", label)],
+            _ if batch => {
+                let mut batch = self
+                    .batch(b"labeled-response", Vec::new())
+                    .with_tag(b"label", label);
+                for line in lines {
+                    batch.push(line);
+                }
+                batch.into_lines(true)
+            }
+            // a label may only be given to several lines inside a batch
+            _ => lines,
+        }
+    }
+
+    /// Open a batch from this server of the type `kind`, with the given
+    /// parameters and a reference no other batch shares.
+    #[must_use]
+    pub fn batch(&self, kind: &[u8], params: Vec<Vec<u8>>) -> Batch {
+        let number = self.next_batch.get();
+        self.next_batch.set(number + 1);
+        Batch::new(number.to_string().as_bytes(), &self.name, kind, params)
+    }
+
+    /// Send `batch` to the client `id`, without the `BATCH` messages around
+    /// it if the client has not enabled `batch`.
+    pub fn send_batch(&self, id: ClientId, batch: Batch) {
+        if let Some(client) = self.clients.get(&id) {
+            for line in batch.into_lines(client.has_cap(Cap::Batch)) {
+                client.outbox.push(line);
+            }
+        }
+    }
+
+    /// Carry out a message sent by the client `id`.
+    #[allow(clippy::too_many_lines)]
+    fn dispatch(&mut self, id: ClientId, message: incoming::Message) {
+        // only client-only tags, which start with `+`, are passed on
+        let client_tags: Tags = message
+            .tags()
+            .iter()
+            .filter(|(key, _)| key.starts_with(b"+"))
+            .cloned()
+            .collect();
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        let registered = client.registered;
+        let pre_away = client.has_cap(Cap::PreAway);
+        client.touch();
+        if let MessageKind::Privmsg { .. } | MessageKind::Notice { .. } = message.kind() {
+            client.last_message = client.last_activity;
+        }
+
+        let result = match message.into_kind() {
+            MessageKind::Ping(token) => {
+                let pong = outgoing::Message::new(
+                    Some(&self.name),
+                    b"PONG",
+                    vec![self.name.clone(), token],
+                );
+                self.send(id, &pong);
+                Ok(())
+            }
+            // any line proves the connection is alive, so there is nothing
+            // more to do for a reply to our own `PING`
+            MessageKind::Pong(_) => Ok(()),
+            MessageKind::Nick(nick) => self.nick(id, nick),
+            MessageKind::User { user, realname } => self.user(id, &user, &realname),
+            MessageKind::Cap(command) => {
+                self.cap(id, command);
+                Ok(())
+            }
+            MessageKind::Authenticate(param) => self.authenticate(id, &param),
+            // `draft/account-registration` is advertised with `before-connect`
+            MessageKind::Register {
+                account, password, ..
+            } => self.register(id, &account, &password),
+            MessageKind::Quit(reason) => {
+                let mut text = b"Quit: ".to_vec();
+                text.extend(reason.unwrap_or_default());
+                self.disconnect(id, &text);
+                Ok(())
+            }
+            // `draft/pre-away` lets clients be marked as away while registering
+            MessageKind::Away(_) if !registered && !pre_away => Err(IrcError::NotRegistered),
+            MessageKind::Away(text) => {
+                self.away(id, text);
+                Ok(())
+            }
+            _ if !registered => Err(IrcError::NotRegistered),
+            MessageKind::Join { channels, keys } => {
+                for (i, channel) in channels.iter().enumerate() {
+                    if let Err(err) = self.join(id, channel, keys.get(i)) {
+                        self.error(id, &err);
+                    }
+                }
+                Ok(())
+            }
+            MessageKind::Part { channels, reason } => {
+                for channel in &channels {
+                    if let Err(err) = self.part(id, channel, reason.as_deref()) {
+                        self.error(id, &err);
+                    }
+                }
+                Ok(())
+            }
+            MessageKind::Privmsg { targets, text } => self.check_targets(&targets).map(|()| {
+                for target in &targets {
+                    if let Err(err) =
+                        self.message(id, b"PRIVMSG", target, Some(&text), &client_tags)
+                    {
+                        self.error(id, &err);
+                    }
+                }
+            }),
+            MessageKind::Notice { targets, text } => {
+                // notices must never generate automatic replies
+                if self.check_targets(&targets).is_ok() {
+                    for target in &targets {
+                        let _ = self.message(id, b"NOTICE", target, Some(&text), &client_tags);
+                    }
+                }
+                Ok(())
+            }
+            MessageKind::Tagmsg(targets) => self.check_targets(&targets).map(|()| {
+                for target in &targets {
+                    if let Err(err) = self.message(id, b"TAGMSG", target, None, &client_tags) {
+                        self.error(id, &err);
+                    }
+                }
+            }),
+            MessageKind::Mode { target, changes } => {
+                if is_channel_name(&target) {
+                    self.channel_mode(id, &target, &changes)
+                } else {
+                    self.user_mode(id, &target, &changes)
+                }
+            }
+            MessageKind::Names(channels) => {
+                self.names(id, &channels);
+                Ok(())
+            }
+            MessageKind::List(channels) => {
+                self.list(id, &channels);
+                Ok(())
+            }
+            MessageKind::Monitor(command) => {
+                self.monitor(id, command);
+                Ok(())
+            }
+            MessageKind::Who {
+                mask,
+                options,
+                whox,
+            } => {
+                self.who(id, &mask, &options, whox.as_ref());
+                Ok(())
+            }
+            MessageKind::Whois(nick) => {
+                self.whois(id, &nick);
+                Ok(())
+            }
+            MessageKind::Oper { name, password } => self.oper(id, &name, &password),
+            MessageKind::Motd => self.send_motd(id),
+            MessageKind::Rehash => self.rehash_command(id),
+            MessageKind::Kick {
+                channel,
+                nicks,
+                reason,
+            } => self.check_targets(&nicks).map(|()| {
+                for nick in &nicks {
+                    if let Err(err) = self.kick(id, &channel, nick, reason.as_deref()) {
+                        self.error(id, &err);
+                    }
+                }
+            }),
+            MessageKind::Rename {
+                channel,
+                new,
+                reason,
+            } => self.rename(id, &channel, &new, reason.as_deref()),
+            MessageKind::Silence(mask) => self.silence(id, mask),
+            MessageKind::Stats(query) => {
+                self.stats(id, &query);
+                Ok(())
+            }
+            MessageKind::Invite { nick, channel } => self.invite(id, &nick, &channel),
+            MessageKind::Topic { channel, topic } => self.topic(id, &channel, topic),
+            MessageKind::Setname(realname) => {
+                self.setname(id, realname);
+                Ok(())
+            }
+            MessageKind::Chathistory(params) => self.chathistory(id, &params),
+        };
+
+        if let Err(err) = result {
+            self.error(id, &err);
+        }
+    }
+
+    /// Receive a line of `len` bytes from the client `id` at the time `now`.
+    /// The line has already been parsed, or failed to parse with an error
+    /// which should be reported to the client.
+    ///
+    /// The line is charged against the client's flood limits and queued until
+    /// it is due, and then every queued line which is due is handled. A client
+    /// which exceeds its flood limits or its receive queue is disconnected instead.
+    ///
+    /// Returns when the next queued line will be due, if there is one.
+    pub fn receive(
+        &mut self,
+        id: ClientId,
+        message: Result<incoming::Message, IrcError>,
+        len: usize,
+        now: Instant,
+    ) -> Option<Instant> {
+        let client = self.clients.get_mut(&id)?;
+        client.lines_received += 1;
+        client.bytes_received += len as u64;
+
+        // lines which could not be parsed are charged the default cost
+        let verb = message
+            .as_ref()
+            .map_or(&b""[..], |message| message.kind().verb());
+        let delay = self.throttle(id, verb, now)?;
+
+        let client = self.clients.get_mut(&id)?;
+        client.recvq += len;
+        if client.recvq > client.class.recvq {
+            self.disconnect(id, b"Max RecvQ exceeded");
+            return None;
+        }
+        client.inbox.push_back(Received {
+            due: now + delay,
+            len,
+            message,
+        });
+        self.process(id, now)
+    }
+
+    /// Handle every line queued for the client `id` which is due at the time
+    /// `now`.
+    ///
+    /// Returns when the next queued line will be due, if there is one.
+    pub fn process(&mut self, id: ClientId, now: Instant) -> Option<Instant> {
+        loop {
+            let client = self.clients.get_mut(&id)?;
+            match client.inbox.front() {
+                Some(received) if received.due <= now => (),
+                next => return next.map(|received| received.due),
+            }
+            let received = client.inbox.pop_front()?;
+            client.recvq -= received.len;
+            match received.message {
+                Ok(message) => self.handle(id, message),
+                Err(err) => self.handle_error(id, &err),
+            }
+        }
+    }
+
+    /// Charge the client `id` for sending a command with the given `verb` at
+    /// the time `now`.
+    ///
+    /// Returns how long the command should wait before it is handled, or
+    /// `None` if the client has sent so much that it was disconnected for
+    /// flooding.
+    fn throttle(&mut self, id: ClientId, verb: &[u8], now: Instant) -> Option<Duration> {
+        let client = self.clients.get_mut(&id)?;
+        if self.flood.is_exempt(client) {
+            return Some(Duration::ZERO);
+        }
+        let cost = self.flood.cost(verb);
+        match client.flood.charge(&self.flood, cost, now) {
+            Verdict::Allow => Some(Duration::ZERO),
+            Verdict::Delay(delay) => Some(delay),
+            Verdict::Excess => {
+                self.disconnect(id, b"Excess Flood");
+                None
+            }
+        }
+    }
+
+    /// Report an error that occurred while parsing a message from the client
+    /// `id`.
+    pub fn handle_error(&mut self, id: ClientId, err: &IrcError) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.touch();
+        }
+        self.error(id, err);
+    }
+
+    /// Send a `PING` to every client which has been silent for longer than
+    /// the ping interval, and disconnect every client which has not answered
+    /// a `PING` within the ping timeout.
+    ///
+    /// This should be called regularly, with `now` as the current time.
+    pub fn check_keepalive(&mut self, now: Instant) {
+        let mut timed_out = Vec::new();
+        let ping = outgoing::Message::new(None, b"PING", vec![self.name.clone()]).to_line();
+        for client in self.clients.values_mut() {
+            match client.ping_sent {
+                Some(sent) if now.saturating_duration_since(sent) >= self.ping_timeout => {
+                    timed_out.push(client.id);
+                }
+                None if now.saturating_duration_since(client.last_activity)
+                    >= self.ping_interval =>
+                {
+                    client.outbox.push(ping.clone());
+                    client.ping_sent = Some(now);
+                }
+                _ => (),
+            }
+        }
+
+        for id in timed_out {
+            self.disconnect(id, b"Ping timeout");
+        }
+    }
+
+    /// Disconnect every client for `reason` because the server is shutting
+    /// down, and forget every channel.
+    ///
+    /// Unlike `disconnect`, this does not tell anyone else that the clients
+    /// quit, since everyone is leaving at once.
+    pub fn shutdown(&mut self, reason: &[u8]) {
+        let error = outgoing::Message::new(None, b"ERROR", vec![reason.to_vec()]).to_line();
+        for (_, client) in self.clients.drain() {
+            client.outbox.close_with(error.clone());
+        }
+        self.nicks.clear();
+        self.channels.clear();
+    }
+
+    /// Disconnect every client whose outbox overflowed its send queue limit.
+    ///
+    /// This should be called regularly.
+    pub fn check_sendq(&mut self) {
+        let slow: Vec<_> = self
+            .clients
+            .values()
+            .filter(|client| client.outbox.is_overflowed())
+            .map(|client| client.id)
+            .collect();
+        for id in slow {
+            self.disconnect(id, b"Max SendQ exceeded");
+        }
+    }
+
+    /// Disconnect the client `id`, telling it and every user who shares a
+    /// channel with it that it quit for `reason`.
+    pub fn disconnect(&mut self, id: ClientId, reason: &[u8]) {
+        let peers = self.peers(id);
+        let Some(client) = self.clients.remove(&id) else {
+            return;
+        };
+        self.sasl.remove(&id);
+
+        if let Some(nick) = &client.nick {
+            self.nicks.remove(&mask::casefold(nick));
+        }
+        for name in &client.channels {
+            if let Some(channel) = self.channels.get_mut(name) {
+                channel.members.remove(&id);
+                if channel.members.is_empty() {
+                    self.channels.remove(name);
+                }
+            }
+        }
+        for folded in client.monitored.keys() {
+            self.unmonitor(id, folded);
+        }
+        if client.registered {
+            let quit = self.stamp(
+                &client,
+                outgoing::Message::new(Some(&client.hostmask()), b"QUIT", vec![reason.to_vec()]),
+            );
+            self.send_to_all(peers.into_iter().filter(|&peer| peer != id), &quit);
+            let nick = client.nick_or_star();
+            self.notify_monitors(nick, &Reply::MonOffline(nick.to_vec()));
+        }
+
+        let error = outgoing::Message::new(None, b"ERROR", vec![reason.to_vec()]);
+        client.outbox.close_with(error.to_line());
+    }
+
+    /// Queue `message` to be sent to the client `id`.
+    fn send(&self, id: ClientId, message: &outgoing::Message) {
+        if let Some(client) = self.clients.get(&id) {
+            client.outbox.push(tagged_line(client, message));
+        }
+    }
+
+    /// Queue `message` to be sent to every client in `ids`.
+    fn send_to_all(&self, ids: impl IntoIterator<Item = ClientId>, message: &outgoing::Message) {
+        for id in ids {
+            if let Some(client) = self.clients.get(&id) {
+                client.outbox.push(tagged_line(client, message));
+            }
+        }
+    }
+
+    /// Tag `message`, which relays something the user `from` did, with the
+    /// current time, a new message identifier, and the account `from` is
+    /// logged in to, if any. Each recipient is only sent the tags it has
+    /// enabled the capabilities for.
+    fn stamp(&self, from: &Client, message: outgoing::Message) -> outgoing::Message {
+        let number = self.next_msgid.get();
+        self.next_msgid.set(number + 1);
+        let mut msgid = self.msgid_prefix.clone();
+        msgid.extend(number.to_string().into_bytes());
+        let message = message
+            .with_tag(b"time", time::iso8601(SystemTime::now()).as_bytes())
+            .with_tag(b"msgid", &msgid);
+        match &from.account {
+            Some(account) => message.with_tag(b"account", account),
+            None => message,
+        }
+    }
+
+    /// Send a numeric with the given code and parameters to the client `id`.
+    fn numeric(&self, id: ClientId, code: u16, params: Vec<Vec<u8>>) {
+        if let Some(client) = self.clients.get(&id) {
+            client.outbox.push(self.numeric_line(id, code, params));
+        }
+    }
+
+    /// Serialize a numeric reply to the client `id`, which must exist.
+    fn numeric_line(&self, id: ClientId, code: u16, params: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut all_params = vec![self.clients[&id].nick_or_star().to_vec()];
+        all_params.extend(params);
+        let message = outgoing::Message::new(
+            Some(&self.name),
+            format!("{code:03}").as_bytes(),
+            all_params,
+        );
+        message.to_line()
+    }
+
+    /// Send a notice from the server to every IRC operator.
+    fn server_notice(&self, text: &[u8]) {
+        self.server_notice_except(text, None);
+    }
+
+    /// Send a notice from the server to every IRC operator other than
+    /// `except`.
+    fn server_notice_except(&self, text: &[u8], except: Option<ClientId>) {
+        let opers = self
+            .clients
+            .values()
+            .filter(|client| client.oper && Some(client.id) != except);
+        for client in opers {
+            let params = vec![
+                client.nick_or_star().to_vec(),
+                [b"*** Notice -- ", text].concat(),
+            ];
+            self.send(
+                client.id,
+                &outgoing::Message::new(Some(&self.name), b"NOTICE", params),
+            );
+        }
+    }
+
+    /// Send a `FAIL` or `WARN` message to the client `id`.
+    fn standard_reply(&self, id: ClientId, reply: &StandardReply) {
+        self.send(id, &reply.to_message(&self.name));
+    }
+
+    /// Keep `message` in the history under `key`, if history is enabled.
+    fn record(&mut self, key: &[u8], message: &outgoing::Message) {
+        let Some(history) = &mut self.history else {
+            return;
+        };
+        if let Err(err) = history.record(key, message) {
+            self.history_write_failed(&err);
+        }
+    }
+
+    /// Tell the operators that the history file could not be written to.
+    fn history_write_failed(&self, err: &io::Error) {
+        let text = format!("Cannot write to the history file, so no more will be written: {err}");
+        self.server_notice(text.as_bytes());
+    }
+
+    /// Send a numeric reply to the client `id`.
+    fn reply(&self, id: ClientId, reply: &Reply) {
+        self.numeric(id, reply.code(), reply.params());
+    }
+
+    /// Send a numeric error to the client `id`.
+    fn error(&self, id: ClientId, err: &IrcError) {
+        self.numeric(id, err.code(), err.params());
+    }
+
+    /// Get the identifiers of every client which shares a channel with the
+    /// client `id`, including that client itself.
+    fn peers(&self, id: ClientId) -> BTreeSet<ClientId> {
+        let mut peers = BTreeSet::from([id]);
+        if let Some(client) = self.clients.get(&id) {
+            for name in &client.channels {
+                if let Some(channel) = self.channels.get(name) {
+                    peers.extend(channel.members.keys());
+                }
+            }
+        }
+        peers
+    }
+
+    /// Look up the client using the nickname `nick`.
+    fn find_nick(&self, nick: &[u8]) -> Option<&Client> {
+        let id = self.nicks.get(&mask::casefold(nick))?;
+        self.clients.get(id)
+    }
+
+    /// Get the hostmask of the client `id`.
+    fn hostmask(&self, id: ClientId) -> Vec<u8> {
+        self.clients
+            .get(&id)
+            .map(Client::hostmask)
+            .unwrap_or_default()
+    }
+
+    /// Handle a `NICK` command from the client `id`.
+    fn nick(&mut self, id: ClientId, nick: Vec<u8>) -> IrcResult<()> {
+        if !is_valid_nick(&nick, self.limits.nicklen) {
+            return Err(IrcError::ErroneousNickname(nick));
+        }
+        let folded = mask::casefold(&nick);
+        match self.nicks.get(&folded) {
+            Some(&owner) if owner != id => return Err(IrcError::NicknameInUse(nick)),
+            _ => (),
+        }
+
+        let client = &self.clients[&id];
+        if client.nick.as_ref() == Some(&nick) {
+            return Ok(());
+        }
+        if client.registered {
+            let change = self.stamp(
+                client,
+                outgoing::Message::new(Some(&client.hostmask()), b"NICK", vec![nick.clone()]),
+            );
+            self.send_to_all(self.peers(id), &change);
+        }
+
+        let client = self.clients.get_mut(&id).unwrap();
+        let registered = client.registered;
+        let old = client.nick.replace(nick);
+        if let Some(old) = &old {
+            self.nicks.remove(&mask::casefold(old));
+        }
+        // a change of case alone leaves the user online under the same name
+        match old {
+            Some(old) if registered && mask::casefold(&old) != folded => {
+                self.notify_monitors(&old, &Reply::MonOffline(old.clone()));
+                self.notify_monitors(&folded, &Reply::MonOnline(self.hostmask(id)));
+            }
+            _ => (),
+        }
+        self.nicks.insert(folded, id);
+        self.try_register(id);
+        Ok(())
+    }
+
+    /// Handle a `USER` command from the client `id`.
+    fn user(&mut self, id: ClientId, user: &[u8], realname: &[u8]) -> IrcResult<()> {
+        let client = self.clients.get_mut(&id).unwrap();
+        if client.registered {
+            return Err(IrcError::AlreadyRegistered);
+        }
+        client.user = Some(truncate(user, self.limits.userlen).to_vec());
+        client.realname = Some(truncate(realname, self.limits.namelen).to_vec());
+        self.try_register(id);
+        Ok(())
+    }
+
+    /// Complete the registration of the client `id` if it has sent both
+    /// `NICK` and `USER`, and welcome it to the server.
+    fn try_register(&mut self, id: ClientId) {
+        let client = &self.clients[&id];
+        if client.registered || client.negotiating || client.nick.is_none() || client.user.is_none()
+        {
+            return;
+        }
+        if self.enforce_bans(id) {
+            return;
+        }
+        let client = self.clients.get_mut(&id).unwrap();
+        client.registered = true;
+        let mask = client.hostmask();
+        let nick = client.nick_or_star().to_vec();
+        // the account is whatever the client had logged in to by now
+        if self.sasl.remove(&id).is_some() {
+            self.error(id, &IrcError::SaslAborted);
+        }
+
+        self.reply(
+            id,
+            &Reply::Welcome {
+                network: self.network.clone(),
+                mask,
+            },
+        );
+        self.reply(
+            id,
+            &Reply::YourHost {
+                server: self.name.clone(),
+                version: VERSION.as_bytes().to_vec(),
+            },
+        );
+        self.reply(id, &Reply::Created(self.created.clone()));
+        self.reply(
+            id,
+            &Reply::MyInfo {
+                server: self.name.clone(),
+                version: VERSION.as_bytes().to_vec(),
+                user_modes: USER_MODES.to_vec(),
+                channel_modes: CHANNEL_MODES.to_vec(),
+            },
+        );
+        // leave room for `:server 005 nick ` and ` :are supported by this server`
+        let nick_len = self.clients[&id].nick_or_star().len();
+        let budget = 510usize.saturating_sub(self.name.len() + nick_len + 38);
+        for tokens in self.isupport().lines(budget) {
+            self.reply(id, &Reply::ISupport(tokens));
+        }
+        if let Err(err) = self.send_motd(id) {
+            self.error(id, &err);
+        }
+        self.notify_monitors(&nick, &Reply::MonOnline(self.hostmask(id)));
+    }
+
+    /// Disconnect the client `id` if it matches one of the server's bans,
+    /// telling the IRC operators why.
+    ///
+    /// Returns whether the client was disconnected.
+    fn enforce_bans(&mut self, id: ClientId) -> bool {
+        let client = &self.clients[&id];
+        let userhost = client.userhost();
+        let Some(ban) = self.bans.iter().find(|ban| ban.matches(&userhost)) else {
+            return false;
+        };
+        let reason = ban.reason.clone();
+        let notice = [
+            client.nick_or_star(),
+            b" (",
+            &userhost,
+            b") was disconnected by a server ban: ",
+            &reason,
+        ]
+        .concat();
+        self.error(id, &IrcError::YoureBannedCreep);
+        self.disconnect(id, &reason);
+        self.server_notice(&notice);
+        true
+    }
+
+    /// Get the `ISUPPORT` tokens advertising this server's features and
+    /// limits, which are the same ones it enforces.
+    fn isupport(&self) -> ISupport {
+        let limits = &self.limits;
+        let targets = Some(limits.maxtargets);
+        ISupport::new()
+            .token(Token::AwayLen(limits.awaylen))
+            .token(Token::CaseMapping)
+            .token(Token::ChanLimit {
+                types: CHANNEL_TYPES.to_vec(),
+                limit: limits.chanlimit,
+            })
+            .token(Token::ChanModes {
+                lists: b"Ibe".to_vec(),
+                always: b"k".to_vec(),
+                when_set: b"l".to_vec(),
+                flags: b"imnpst".to_vec(),
+            })
+            .token(Token::ChannelLen(limits.channellen))
+            .token(Token::ChanTypes(CHANNEL_TYPES.to_vec()))
+            .maybe_token(
+                self.history
+                    .as_ref()
+                    .map(|history| Token::ChatHistory(history.settings().query_limit)),
+            )
+            .token(Token::EList(b"MNU".to_vec()))
+            .token(Token::Excepts(b'e'))
+            .token(Token::ExtBan(extban::isupport_value()))
+            .token(Token::HostLen(limits.hostlen))
+            .token(Token::Invex(b'I'))
+            .token(Token::KeyLen(limits.keylen))
+            .token(Token::KickLen(limits.kicklen))
+            .token(Token::MaxList(vec![(b"beI".to_vec(), limits.maxlist)]))
+            .token(Token::MaxTargets(limits.maxtargets))
+            .token(Token::Modes(limits.modes))
+            .token(Token::Monitor(limits.monitor))
+            .maybe_token(
+                self.history
+                    .as_ref()
+                    .map(|_| Token::MsgRefTypes(b"msgid,timestamp".to_vec())),
+            )
+            .token(Token::NameLen(limits.namelen))
+            .token(Token::Network(self.network.clone()))
+            .token(Token::NickLen(limits.nicklen))
+            .token(Token::Prefix {
+                modes: b"ov".to_vec(),
+                prefixes: b"@+".to_vec(),
+            })
+            .token(Token::SafeList)
+            .token(Token::Silence(limits.silence))
+            .token(Token::StatusMsg(b"@+".to_vec()))
+            .token(Token::TargMax(vec![
+                (b"JOIN".to_vec(), None),
+                (b"KICK".to_vec(), targets),
+                (b"LIST".to_vec(), None),
+                (b"NAMES".to_vec(), None),
+                (b"NOTICE".to_vec(), targets),
+                (b"PART".to_vec(), None),
+                (b"PRIVMSG".to_vec(), targets),
+                (b"TAGMSG".to_vec(), targets),
+                (b"WHOIS".to_vec(), Some(1)),
+            ]))
+            .token(Token::TopicLen(limits.topiclen))
+            .token(Token::UserLen(limits.userlen))
+            .token(Token::Whox)
+    }
+
+    /// Check that a command has no more than the allowed number of
+    /// `targets`.
+    fn check_targets(&self, targets: &[Vec<u8>]) -> IrcResult<()> {
+        match targets.get(self.limits.maxtargets) {
+            Some(extra) => Err(IrcError::TooManyTargets(extra.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Send the message of the day to the client `id`.
+    fn send_motd(&self, id: ClientId) -> IrcResult<()> {
+        let motd = self.motd.as_ref().ok_or(IrcError::NoMotd)?;
+        self.reply(id, &Reply::MotdStart(self.name.clone()));
+        for line in motd {
+            self.reply(id, &Reply::Motd(line.clone()));
+        }
+        self.reply(id, &Reply::EndOfMotd);
+        Ok(())
+    }
+
+    /// Handle the client `id` joining the channel `name`, using `key` as the
+    /// channel key if one was given.
+    fn join(&mut self, id: ClientId, name: &[u8], key: Option<&Vec<u8>>) -> IrcResult<()> {
+        if !is_channel_name(name) || name.len() > self.limits.channellen {
+            return Err(IrcError::NoSuchChannel(name.to_vec()));
+        }
+        let folded = mask::casefold(name);
+        let client = &self.clients[&id];
+        if !client.channels.contains(&folded) && client.channels.len() >= self.limits.chanlimit {
+            return Err(IrcError::TooManyChannels(name.to_vec()));
+        }
+
+        let membership = if let Some(channel) = self.channels.get(&folded) {
+            if channel.members.contains_key(&id) {
+                return Ok(());
+            }
+            // an invitation overrides every other restriction
+            if !channel.invites.contains(&id) {
+                check_can_join(channel, client, key)?;
+            }
+            Membership::default()
+        } else {
+            let mut channel = Channel::new(name.to_vec(), time::unix_time());
+            channel.modes.no_external = true;
+            channel.modes.topic_lock = true;
+            self.channels.insert(folded.clone(), channel);
+            Membership {
+                op: true,
+                voice: false,
+            }
+        };
+
+        let channel = self.channels.get_mut(&folded).unwrap();
+        channel.members.insert(id, membership);
+        channel.invites.remove(&id);
+        self.clients
+            .get_mut(&id)
+            .unwrap()
+            .channels
+            .insert(folded.clone());
+
+        let channel = &self.channels[&folded];
+        let members: Vec<ClientId> = channel.members.keys().copied().collect();
+        self.send_join(id, &channel.name, &members);
+        if self.clients[&id].away.is_some() {
+            self.send_away(id, members.into_iter().filter(|&member| member != id));
+        }
+        if channel.topic.is_some() {
+            self.send_topic(id, channel);
+        }
+        self.names_reply(id, channel);
+        Ok(())
+    }
+
+    /// Send the `JOIN` of the client `id` to the channel `name` to each of
+    /// `recipients`, with its account and realname for those which have
+    /// enabled `extended-join`.
+    fn send_join(&self, id: ClientId, name: &[u8], recipients: &[ClientId]) {
+        let client = &self.clients[&id];
+        let mask = client.hostmask();
+        let extended = outgoing::Message::new(
+            Some(&mask),
+            b"JOIN",
+            vec![
+                name.to_vec(),
+                client.account.clone().unwrap_or_else(|| b"*".to_vec()),
+                client.realname.clone().unwrap_or_default(),
+            ],
+        );
+        let extended = self.stamp(client, extended);
+        let mut join = outgoing::Message::new(Some(&mask), b"JOIN", vec![name.to_vec()]);
+        for (key, value) in extended.tags() {
+            join = join.with_tag(key, value);
+        }
+        for &recipient in recipients {
+            if self.clients[&recipient].has_cap(Cap::ExtendedJoin) {
+                self.send(recipient, &extended);
+            } else {
+                self.send(recipient, &join);
+            }
+        }
+    }
+
+    /// Handle the client `id` leaving the channel `name`.
+    fn part(&mut self, id: ClientId, name: &[u8], reason: Option<&[u8]>) -> IrcResult<()> {
+        let folded = mask::casefold(name);
+        let channel = self
+            .channels
+            .get(&folded)
+            .ok_or_else(|| IrcError::NoSuchChannel(name.to_vec()))?;
+        if !channel.members.contains_key(&id) {
+            return Err(IrcError::NotOnChannel(channel.name.clone()));
+        }
+
+        let mut params = vec![channel.name.clone()];
+        params.extend(reason.map(<[u8]>::to_vec));
+        let part = self.stamp(
+            &self.clients[&id],
+            outgoing::Message::new(Some(&self.hostmask(id)), b"PART", params),
+        );
+        self.send_to_all(channel.members.keys().copied(), &part);
+        self.remove_member(id, &folded);
+        Ok(())
+    }
+
+    /// Remove the client `id` from the channel with casefolded name `folded`,
+    /// destroying the channel if it becomes empty.
+    fn remove_member(&mut self, id: ClientId, folded: &[u8]) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.channels.remove(folded);
+        }
+        if let Some(channel) = self.channels.get_mut(folded) {
+            channel.members.remove(&id);
+            if channel.members.is_empty() {
+                self.channels.remove(folded);
+            }
+        }
+    }
+
+    /// Handle a `PRIVMSG`, `NOTICE` or `TAGMSG` (given by `command`) from the
+    /// client `id` to `target`, with the given `text` and client-only `tags`.
+    ///
+    /// A `TAGMSG` has no text, and is only sent to clients which have enabled
+    /// `message-tags`. The sender is sent the message back if it has enabled
+    /// `echo-message`. A `PRIVMSG` or `NOTICE` is kept in the history if it
+    /// was sent to a whole channel, or delivered from one user logged in to
+    /// an account to another.
+    fn message(
+        &mut self,
+        id: ClientId,
+        command: &[u8],
+        target: &[u8],
+        text: Option<&[u8]>,
+        tags: &[(Vec<u8>, Vec<u8>)],
+    ) -> IrcResult<()> {
+        let client = &self.clients[&id];
+        let mut params = vec![target.to_vec()];
+        params.extend(text.map(<[u8]>::to_vec));
+        let mut message = self.stamp(
+            client,
+            outgoing::Message::new(Some(&client.hostmask()), command, params),
+        );
+        for (key, value) in tags {
+            message = message.with_tag(key, value);
+        }
+        let wants = |recipient: &Client| text.is_some() || recipient.has_cap(Cap::MessageTags);
+
+        // `@#chan` and `+#chan` address only the members with that status
+        let (status, name) = match target {
+            [prefix @ (b'@' | b'+'), name @ ..] if is_channel_name(name) => (Some(*prefix), name),
+            _ => (None, target),
+        };
+        // the key the message is kept under in the history, if it is kept
+        let key = if is_channel_name(name) {
+            let channel = self
+                .channel(name)
+                .ok_or_else(|| IrcError::NoSuchNick(target.to_vec()))?;
+            let may_speak = match channel.members.get(&id) {
+                Some(membership) if membership.can_override_quiet() => true,
+                Some(_) => !channel.modes.moderated && !channel.is_quieted(client),
+                None => {
+                    !channel.modes.no_external
+                        && !channel.modes.moderated
+                        && !channel.is_quieted(client)
+                }
+            };
+            if !may_speak {
+                return Err(IrcError::CannotSendToChan(channel.name.clone()));
+            }
+            let recipients = channel
+                .members
+                .iter()
+                .filter(|&(&member, &membership)| {
+                    member != id
+                        && match status {
+                            Some(b'@') => membership.op,
+                            Some(_) => membership.can_override_quiet(),
+                            None => true,
+                        }
+                        && !is_silenced(&self.clients[&member], client)
+                        && wants(&self.clients[&member])
+                })
+                .map(|(&member, _)| member);
+            self.send_to_all(recipients, &message);
+            status.is_none().then(|| mask::casefold(name))
+        } else {
+            let recipient = self
+                .find_nick(target)
+                .ok_or_else(|| IrcError::NoSuchNick(target.to_vec()))?;
+            if is_silenced(recipient, client) || !wants(recipient) {
+                None
+            } else {
+                self.send(recipient.id, &message);
+                if let (b"PRIVMSG", Some(away)) = (command, &recipient.away) {
+                    self.reply(
+                        id,
+                        &Reply::Away {
+                            nick: recipient.nick_or_star().to_vec(),
+                            message: away.clone(),
+                        },
+                    );
+                }
+                // private messages are only kept between accounts, so that
+                // taking someone's nickname does not give away their history
+                match (&client.account, &recipient.account) {
+                    (Some(sender), Some(recipient)) => {
+                        Some(history::conversation(sender, recipient))
+                    }
+                    _ => None,
+                }
+            }
+        };
+        if client.has_cap(Cap::EchoMessage) {
+            self.send(id, &message);
+        }
+        if let (Some(key), Some(_)) = (key, text) {
+            self.record(&key, &message);
+        }
+        Ok(())
+    }
+
+    /// Handle a `MODE` command from the client `id` targeting the channel
+    /// `name`.
+    fn channel_mode(&mut self, id: ClientId, name: &[u8], changes: &[Vec<u8>]) -> IrcResult<()> {
+        let folded = mask::casefold(name);
+        let channel = self
+            .channels
+            .get(&folded)
+            .ok_or_else(|| IrcError::NoSuchChannel(name.to_vec()))?;
+
+        let is_member = channel.members.contains_key(&id);
+        // outsiders see nothing of a hidden channel
+        let hidden = channel.modes.hidden() && !is_member;
+
+        let Some((modestring, args)) = changes.split_first() else {
+            if hidden {
+                return Err(IrcError::NoSuchChannel(name.to_vec()));
+            }
+            self.reply(
+                id,
+                &Reply::ChannelModeIs {
+                    channel: channel.name.clone(),
+                    modes: channel.modes.to_params(is_member),
+                },
+            );
+            self.reply(
+                id,
+                &Reply::CreationTime {
+                    channel: channel.name.clone(),
+                    time: channel.created_at,
+                },
+            );
+            return Ok(());
+        };
+
+        let is_op = channel.members.get(&id).is_some_and(|m| m.op);
+        let setter = self.hostmask(id);
+        let mut args = args.iter();
+        let mut adding = true;
+        let mut applied = Vec::new();
+        // the number of changes with arguments, which is limited by `modes`
+        let mut with_args = 0;
+
+        for &letter in modestring {
+            let arg = match letter {
+                b'+' | b'-' => {
+                    adding = letter == b'+';
+                    continue;
+                }
+                b'b' | b'e' | b'I' | b'k' | b'o' | b'v' => args.next(),
+                b'l' if adding => args.next(),
+                _ => None,
+            };
+            let list_mode = match letter {
+                b'b' => Some(ListMode::Ban),
+                b'e' => Some(ListMode::Except),
+                b'I' => Some(ListMode::Invex),
+                _ => None,
+            };
+
+            // viewing a list requires no privileges, unless the channel is
+            // hidden
+            if let (Some(mode), None) = (list_mode, arg) {
+                if hidden {
+                    self.error(
+                        id,
+                        &IrcError::NotOnChannel(self.channels[&folded].name.clone()),
+                    );
+                } else {
+                    self.send_mask_list(id, &folded, mode);
+                }
+                continue;
+            }
+            if !CHANNEL_MODES.contains(&letter) {
+                self.error(id, &IrcError::UnknownMode(letter));
+                continue;
+            }
+            if !is_op {
+                return Err(IrcError::ChanOPrivsNeeded(
+                    self.channels[&folded].name.clone(),
+                ));
+            }
+
+            if arg.is_some() {
+                if with_args == self.limits.modes {
+                    continue;
+                }
+                with_args += 1;
+            }
+
+            let change = ModeChange {
+                adding,
+                letter,
+                arg: arg.cloned(),
+            };
+            if let Some(change) = self.apply_channel_mode(id, &folded, change, &setter) {
+                applied.push(change);
+            }
+        }
+
+        if !applied.is_empty() {
+            let channel = &self.channels[&folded];
+            let mut params = vec![channel.name.clone()];
+            params.extend(format_mode_changes(&applied));
+            let message = self.stamp(
+                &self.clients[&id],
+                outgoing::Message::new(Some(&setter), b"MODE", params),
+            );
+            self.send_to_all(channel.members.keys().copied(), &message);
+        }
+        Ok(())
+    }
+
+    /// Apply a single mode change to the channel with casefolded name
+    /// `folded`, made by the client `id` whose hostmask is `setter`.
+    ///
+    /// Returns the change as it should be announced to the channel, or `None`
+    /// if it had no effect.
+    fn apply_channel_mode(
+        &mut self,
+        id: ClientId,
+        folded: &[u8],
+        change: ModeChange,
+        setter: &[u8],
+    ) -> Option<ModeChange> {
+        let ModeChange {
+            adding,
+            letter,
+            arg,
+        } = change;
+
+        if let b'o' | b'v' = letter {
+            return self.apply_member_mode(id, folded, adding, letter, arg?);
+        }
+        if adding && matches!(letter, b'b' | b'e' | b'I') {
+            let channel = &self.channels[folded];
+            let entries: usize = [ListMode::Ban, ListMode::Except, ListMode::Invex]
+                .into_iter()
+                .map(|mode| channel.list(mode).len())
+                .sum();
+            if entries >= self.limits.maxlist {
+                self.error(id, &IrcError::BanListFull(channel.name.clone(), letter));
+                return None;
+            }
+        }
+
+        let keylen = self.limits.keylen;
+        let channel = self.channels.get_mut(folded).unwrap();
+        let modes = &mut channel.modes;
+        let flag = match letter {
+            b'b' | b'e' | b'I' => {
+                let mode = match letter {
+                    b'b' => ListMode::Ban,
+                    b'e' => ListMode::Except,
+                    _ => ListMode::Invex,
+                };
+                let raw = arg?;
+                let stored = if adding {
+                    channel.add_list_entry(mode, &raw, setter.to_vec(), time::unix_time())
+                } else {
+                    channel.remove_list_entry(mode, &raw)
+                };
+                return stored.map(|mask| ModeChange {
+                    adding,
+                    letter,
+                    arg: Some(mask),
+                });
+            }
+            b'k' => {
+                if adding {
+                    let key = arg.filter(|key| is_valid_key(key, keylen))?;
+                    modes.key = Some(key.clone());
+                    return Some(ModeChange {
+                        adding,
+                        letter,
+                        arg: Some(key),
+                    });
+                }
+                modes.key.take()?;
+                return Some(ModeChange {
+                    adding,
+                    letter,
+                    arg: Some(b"*".to_vec()),
+                });
+            }
+            b'l' => {
+                if adding {
+                    let limit = arg
+                        .and_then(|arg| std::str::from_utf8(&arg).ok()?.parse::<usize>().ok())
+                        .filter(|&limit| limit > 0)?;
+                    if modes.limit == Some(limit) {
+                        return None;
+                    }
+                    modes.limit = Some(limit);
+                    return Some(ModeChange {
+                        adding,
+                        letter,
+                        arg: Some(limit.to_string().into_bytes()),
+                    });
+                }
+                modes.limit.take()?;
+                return Some(ModeChange {
+                    adding,
+                    letter,
+                    arg: None,
+                });
+            }
+            b'i' => &mut modes.invite_only,
+            b'm' => &mut modes.moderated,
+            b'n' => &mut modes.no_external,
+            b'p' => &mut modes.private,
+            b's' => &mut modes.secret,
+            b't' => &mut modes.topic_lock,
+            _ => return None,
+        };
+
+        if *flag == adding {
+            return None;
+        }
+        *flag = adding;
+        Some(ModeChange {
+            adding,
+            letter,
+            arg: None,
+        })
+    }
+
+    /// Give or take the channel status given by `letter` (`o` or `v`) to or
+    /// from the member `nick` of the channel with casefolded name `folded`, on
+    /// behalf of the client `id`.
+    ///
+    /// Returns the change as it should be announced to the channel, or `None`
+    /// if it had no effect.
+    fn apply_member_mode(
+        &mut self,
+        id: ClientId,
+        folded: &[u8],
+        adding: bool,
+        letter: u8,
+        nick: Vec<u8>,
+    ) -> Option<ModeChange> {
+        let Some(target) = self.find_nick(&nick).map(|c| c.id) else {
+            self.error(id, &IrcError::NoSuchNick(nick));
+            return None;
+        };
+        let channel = self.channels.get_mut(folded).unwrap();
+        let Some(membership) = channel.members.get_mut(&target) else {
+            let channel = channel.name.clone();
+            self.error(id, &IrcError::UserNotInChannel(nick, channel));
+            return None;
+        };
+        let status = if letter == b'o' {
+            &mut membership.op
+        } else {
+            &mut membership.voice
+        };
+        if *status == adding {
+            return None;
+        }
+        *status = adding;
+        let nick = self.clients[&target].nick_or_star().to_vec();
+        Some(ModeChange {
+            adding,
+            letter,
+            arg: Some(nick),
+        })
+    }
+
+    /// Send the entries of one of the mask lists of the channel with
+    /// casefolded name `folded` to the client `id`.
+    fn send_mask_list(&self, id: ClientId, folded: &[u8], mode: ListMode) {
+        let channel = &self.channels[folded];
+        for entry in channel.list(mode) {
+            self.reply(
+                id,
+                &Reply::MaskList {
+                    mode,
+                    channel: channel.name.clone(),
+                    mask: entry.raw.clone(),
+                    setter: entry.setter.clone(),
+                    time: entry.set_at,
+                },
+            );
+        }
+        self.reply(
+            id,
+            &Reply::EndOfMaskList {
+                mode,
+                channel: channel.name.clone(),
+            },
+        );
+    }
+
+    /// Handle a `MODE` command from the client `id` targeting the user
+    /// `nick`.
+    fn user_mode(&mut self, id: ClientId, nick: &[u8], changes: &[Vec<u8>]) -> IrcResult<()> {
+        let target = self
+            .find_nick(nick)
+            .ok_or_else(|| IrcError::NoSuchNick(nick.to_vec()))?;
+        if target.id != id {
+            return Err(IrcError::UsersDontMatch);
+        }
+
+        let Some(modestring) = changes.first() else {
+            let mut modes = b"+".to_vec();
+            if target.invisible {
+                modes.push(b'i');
+            }
+            if target.oper {
+                modes.push(b'o');
+            }
+            if target.secure {
+                modes.push(b'Z');
+            }
+            self.reply(id, &Reply::UModeIs(modes));
+            return Ok(());
+        };
+
+        let client = self.clients.get_mut(&id).unwrap();
+        let mut adding = true;
+        let mut applied = Vec::new();
+        let mut unknown = false;
+        for &letter in modestring {
+            match letter {
+                b'+' | b'-' => adding = letter == b'+',
+                b'i' if client.invisible != adding => {
+                    client.invisible = adding;
+                    applied.push(ModeChange {
+                        adding,
+                        letter,
+                        arg: None,
+                    });
+                }
+                b'o' if client.oper && !adding => {
+                    client.oper = false;
+                    applied.push(ModeChange {
+                        adding,
+                        letter,
+                        arg: None,
+                    });
+                }
+                // operator status can only be gained with `OPER`, and whether
+                // the connection is secure cannot be changed at all
+                b'i' | b'o' | b'Z' => (),
+                _ => unknown = true,
+            }
+        }
+
+        if !applied.is_empty() {
+            let mut params = vec![client.nick_or_star().to_vec()];
+            params.extend(format_mode_changes(&applied));
+            let message = outgoing::Message::new(Some(&client.hostmask()), b"MODE", params);
+            let message = self.stamp(&self.clients[&id], message);
+            self.send(id, &message);
+        }
+        if unknown {
+            return Err(IrcError::UModeUnknownFlag);
+        }
+        Ok(())
+    }
+
+    /// Handle a `NAMES` command from the client `id`.
+    fn names(&self, id: ClientId, names: &[Vec<u8>]) {
+        if names.is_empty() {
+            self.reply(id, &Reply::EndOfNames(b"*".to_vec()));
+        }
+        for name in names {
+            match self.channel(name) {
+                Some(channel) if !channel.modes.hidden() || channel.members.contains_key(&id) => {
+                    self.names_reply(id, channel);
+                }
+                _ => self.reply(id, &Reply::EndOfNames(name.clone())),
+            }
+        }
+    }
+
+    /// Send the member list of `channel` to the client `id`, split over as
+    /// many `353` replies as it takes to keep each one within 512 bytes.
+    ///
+    /// Invisible users are only listed if the client `id` is also a member of
+    /// the channel. Members are listed by their full `nick!user@host` if the
+    /// client has enabled `userhost-in-names`.
+    fn names_reply(&self, id: ClientId, channel: &Channel) {
+        let requester = &self.clients[&id];
+        let is_member = channel.members.contains_key(&id);
+        // the length of `:server 353 nick = #channel :` and the CRLF
+        let overhead = 13 + self.name.len() + requester.nick_or_star().len() + channel.name.len();
+        let budget = 512usize.saturating_sub(overhead);
+        let mut entries = Vec::new();
+        for (member, membership) in &channel.members {
+            let client = &self.clients[member];
+            if !is_member && client.invisible {
+                continue;
+            }
+            let mut entry = prefixes_for(requester, *membership);
+            if requester.has_cap(Cap::UserhostInNames) {
+                entry.extend(client.hostmask());
+            } else {
+                entry.extend_from_slice(client.nick_or_star());
+            }
+            entries.push(entry);
+        }
+
+        let symbol = if channel.modes.secret {
+            b'@'
+        } else if channel.modes.private {
+            b'*'
+        } else {
+            b'='
+        };
+        for names in pack(entries, b' ', budget) {
+            self.reply(
+                id,
+                &Reply::NamReply {
+                    symbol,
+                    channel: channel.name.clone(),
+                    names,
+                },
+            );
+        }
+        self.reply(id, &Reply::EndOfNames(channel.name.clone()));
+    }
+
+    /// Handle a `LIST` command from the client `id`.
+    ///
+    /// Secret and private channels are only listed for their members.
+    ///
+    /// Besides channel names, the parameters may be search conditions
+    /// (advertised as `ELIST=MNU`): `>n` and `<n` match channels with more or
+    /// fewer than `n` users, a wildcard mask matches channel names, and a
+    /// mask prefixed with `!` excludes them. Every condition must match.
+    ///
+    /// The replies are queued regardless of the client's send queue limit
+    /// (advertised as `SAFELIST`).
+    fn list(&self, id: ClientId, params: &[Vec<u8>]) {
+        let mut names = Vec::new();
+        let mut conditions: Vec<Condition> = Vec::new();
+        for param in params {
+            let count = || std::str::from_utf8(&param[1..]).ok()?.parse::<usize>().ok();
+            match param.first() {
+                Some(b'>') => {
+                    if let Some(min) = count() {
+                        conditions.push(Box::new(move |channel| channel.members.len() > min));
+                    }
+                }
+                Some(b'<') => {
+                    if let Some(max) = count() {
+                        conditions.push(Box::new(move |channel| channel.members.len() < max));
+                    }
+                }
+                Some(b'!') => {
+                    conditions.push(Box::new(|channel| {
+                        !mask::matches(&param[1..], &channel.name)
+                    }));
+                }
+                _ if param.contains(&b'*') || param.contains(&b'?') => {
+                    conditions.push(Box::new(|channel| mask::matches(param, &channel.name)));
+                }
+                _ => names.push(param),
+            }
+        }
+
+        let mut channels: Vec<&Channel> = if names.is_empty() {
+            self.channels.values().collect()
+        } else {
+            names.iter().filter_map(|name| self.channel(name)).collect()
+        };
+        channels.retain(|channel| conditions.iter().all(|condition| condition(channel)));
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let outbox = &self.clients[&id].outbox;
+        let reply = |reply: &Reply| {
+            outbox.push_unlimited(self.numeric_line(id, reply.code(), reply.params()));
+        };
+        reply(&Reply::ListStart);
+        for channel in channels {
+            if channel.modes.hidden() && !channel.members.contains_key(&id) {
+                continue;
+            }
+            reply(&Reply::List {
+                channel: channel.name.clone(),
+                visible: channel.members.len(),
+                topic: channel
+                    .topic
+                    .as_ref()
+                    .map(|topic| topic.text.clone())
+                    .unwrap_or_default(),
+            });
+        }
+        reply(&Reply::ListEnd);
+    }
+
+    /// Handle a `WHO` command from the client `id`.
+    ///
+    /// If `mask` is a channel name, its members are listed, unless it is
+    /// secret or private and the client is not a member. Otherwise every
+    /// user whose nickname, username, hostname, server or real name matches
+    /// `mask` is listed, with `0` matching everyone. In both cases invisible
+    /// users are only listed if they share a channel with the client. If
+    /// `options` contains `o`, only IRC operators are listed.
+    ///
+    /// If the client asked for particular fields with `whox`, they are sent
+    /// in `354` replies instead of `352`.
+    fn who(&self, id: ClientId, mask: &[u8], options: &[u8], whox: Option<&Whox>) {
+        let requester = &self.clients[&id];
+        let mut entries: Vec<(&[u8], &Client, Option<Membership>)> = Vec::new();
+        if is_channel_name(mask) {
+            if let Some(channel) = self.channel(mask) {
+                let is_member = channel.members.contains_key(&id);
+                if !channel.modes.hidden() || is_member {
+                    for (member, membership) in &channel.members {
+                        let client = &self.clients[member];
+                        if is_member || !client.invisible {
+                            entries.push((&channel.name, client, Some(*membership)));
+                        }
+                    }
+                }
+            }
+        } else {
+            let mask: &[u8] = if mask == b"0" { b"*" } else { mask };
+            let mut clients: Vec<&Client> = self
+                .clients
+                .values()
+                .filter(|client| client.registered)
+                .filter(|client| {
+                    !client.invisible
+                        || client.id == id
+                        || client
+                            .channels
+                            .iter()
+                            .any(|name| requester.channels.contains(name))
+                })
+                .filter(|client| {
+                    [
+                        client.nick_or_star(),
+                        client.user.as_deref().unwrap_or_default(),
+                        &client.host,
+                        &self.name,
+                        client.realname.as_deref().unwrap_or_default(),
+                    ]
+                    .iter()
+                    .any(|field| mask::matches(mask, field))
+                })
+                .collect();
+            clients.sort_by_key(|client| client.id);
+            entries.extend(clients.into_iter().map(|client| (&b"*"[..], client, None)));
+        }
+
+        for (channel, client, membership) in entries {
+            if options.contains(&b'o') && !client.oper {
+                continue;
+            }
+            let mut flags = vec![if client.away.is_some() { b'G' } else { b'H' }];
+            if client.oper {
+                flags.push(b'*');
+            }
+            if let Some(membership) = membership {
+                flags.extend(prefixes_for(requester, membership));
+            }
+            if let Some(whox) = whox {
+                let mut fields = Vec::new();
+                for field in b"tcuihsnfdlaor" {
+                    if !whox.fields.contains(field) {
+                        continue;
+                    }
+                    fields.push(match field {
+                        b't' => whox.token.clone(),
+                        b'c' => channel.to_vec(),
+                        b'u' => client.user.clone().unwrap_or_default(),
+                        // addresses are never shown apart from hostnames
+                        b'i' => b"255.255.255.255".to_vec(),
+                        b'h' => client.host.clone(),
+                        b's' => self.name.clone(),
+                        b'n' => client.nick_or_star().to_vec(),
+                        b'f' => flags.clone(),
+                        b'd' => b"0".to_vec(),
+                        b'l' => client
+                            .last_message
+                            .elapsed()
+                            .as_secs()
+                            .to_string()
+                            .into_bytes(),
+                        b'a' => client.account.clone().unwrap_or_else(|| b"0".to_vec()),
+                        // channels have no numeric op levels
+                        b'o' => b"n/a".to_vec(),
+                        _ => client.realname.clone().unwrap_or_default(),
+                    });
+                }
+                self.reply(id, &Reply::WhoxReply(fields));
+                continue;
+            }
+            self.reply(
+                id,
+                &Reply::WhoReply {
+                    channel: channel.to_vec(),
+                    user: client.user.clone().unwrap_or_default(),
+                    host: client.host.clone(),
+                    server: self.name.clone(),
+                    nick: client.nick_or_star().to_vec(),
+                    flags,
+                    realname: client.realname.clone().unwrap_or_default(),
+                },
+            );
+        }
+        self.reply(id, &Reply::EndOfWho(mask.to_vec()));
+    }
+
+    /// Handle a `WHOIS` command from the client `id` about `nick`.
+    ///
+    /// Secret and private channels are only shown to clients which are also
+    /// members of them.
+    fn whois(&self, id: ClientId, nick: &[u8]) {
+        let Some(target) = self.find_nick(nick) else {
+            self.error(id, &IrcError::NoSuchNick(nick.to_vec()));
+            self.reply(id, &Reply::EndOfWhois(nick.to_vec()));
+            return;
+        };
+        let nick = target.nick_or_star().to_vec();
+
+        self.reply(
+            id,
+            &Reply::WhoisUser {
+                nick: nick.clone(),
+                user: target.user.clone().unwrap_or_default(),
+                host: target.host.clone(),
+                realname: target.realname.clone().unwrap_or_default(),
+            },
+        );
+
+        let mut channels = Vec::new();
+        let mut names: Vec<_> = target.channels.iter().collect();
+        names.sort();
+        for name in names {
+            let channel = &self.channels[name];
+            if channel.modes.hidden() && !channel.members.contains_key(&id) {
+                continue;
+            }
+            if !channels.is_empty() {
+                channels.push(b' ');
+            }
+            channels.extend(prefixes_for(
+                &self.clients[&id],
+                channel.members[&target.id],
+            ));
+            channels.extend_from_slice(&channel.name);
+        }
+        if !channels.is_empty() {
+            self.reply(
+                id,
+                &Reply::WhoisChannels {
+                    nick: nick.clone(),
+                    channels,
+                },
+            );
+        }
+
+        self.reply(
+            id,
+            &Reply::WhoisServer {
+                nick: nick.clone(),
+                server: self.name.clone(),
+                info: self.network.clone(),
+            },
+        );
+        if let Some(away) = &target.away {
+            self.reply(
+                id,
+                &Reply::Away {
+                    nick: nick.clone(),
+                    message: away.clone(),
+                },
+            );
+        }
+        if target.secure {
+            self.reply(id, &Reply::WhoisSecure(nick.clone()));
+        }
+        // fingerprints identify users, so only they and operators see them
+        if let Some(fingerprint) = &target.certfp {
+            if target.id == id || self.clients[&id].oper {
+                self.reply(
+                    id,
+                    &Reply::WhoisCertFp {
+                        nick: nick.clone(),
+                        fingerprint: fingerprint.clone(),
+                    },
+                );
+            }
+        }
+        self.reply(
+            id,
+            &Reply::WhoisIdle {
+                nick: nick.clone(),
+                idle: target.last_message.elapsed().as_secs(),
+                signon: target.signon,
+            },
+        );
+        self.reply(id, &Reply::EndOfWhois(nick));
+    }
+
+    /// Handle a `STATS` command from the client `id`.
+    ///
+    /// `STATS l` describes the connection of the client (or of every client,
+    /// for operators) and its queues, and `STATS y` describes the connection
+    /// classes. Other queries are answered with an empty report.
+    fn stats(&self, id: ClientId, query: &[u8]) {
+        match query {
+            b"l" | b"L" => {
+                let oper = self.clients[&id].oper;
+                let mut shown: Vec<_> = self
+                    .clients
+                    .values()
+                    .filter(|client| oper || client.id == id)
+                    .collect();
+                shown.sort_by_key(|client| client.id);
+                let now = time::unix_time();
+                for client in shown {
+                    let mut link = client.nick_or_star().to_vec();
+                    link.push(b'[');
+                    link.extend_from_slice(client.user.as_deref().unwrap_or(b"*"));
+                    link.push(b'@');
+                    link.extend_from_slice(&client.host);
+                    link.push(b']');
+                    let (sent_lines, sent_bytes) = client.outbox.sent();
+                    self.reply(
+                        id,
+                        &Reply::StatsLinkInfo {
+                            link,
+                            sendq: client.outbox.queued(),
+                            sent_lines,
+                            sent_kbytes: sent_bytes / 1024,
+                            received_lines: client.lines_received,
+                            received_kbytes: client.bytes_received / 1024,
+                            open: now.saturating_sub(client.signon),
+                            recvq: client.recvq,
+                        },
+                    );
+                }
+            }
+            b"y" | b"Y" => {
+                for class in &self.classes {
+                    self.reply(
+                        id,
+                        &Reply::StatsYLine {
+                            class: class.name.clone(),
+                            ping_frequency: self.ping_interval.as_secs(),
+                            sendq: class.sendq,
+                            recvq: class.recvq,
+                        },
+                    );
+                }
+            }
+            _ => (),
+        }
+        self.reply(id, &Reply::EndOfStats(query.to_vec()));
+    }
+
+    /// Handle an `OPER` command from the client `id`, which is trying to log
+    /// in to the operator account `name` with `password`.
+    fn oper(&mut self, id: ClientId, name: &[u8], password: &[u8]) -> IrcResult<()> {
+        let client = &self.clients[&id];
+        let userhost = client.userhost();
+        let oper = self
+            .opers
+            .iter()
+            .find(|oper| oper.name == name)
+            .filter(|oper| oper.hosts.iter().any(|host| mask::matches(host, &userhost)))
+            .ok_or(IrcError::NoOperHost)?;
+        if !constant_time_eq(&oper.password, password) {
+            return Err(IrcError::PasswdMismatch);
+        }
+        if client.oper {
+            return Ok(());
+        }
+
+        let vhost = oper.vhost.clone();
+        let client = self.clients.get_mut(&id).unwrap();
+        client.oper = true;
+        let params = vec![client.nick_or_star().to_vec(), b"+o".to_vec()];
+        let message = outgoing::Message::new(Some(&client.hostmask()), b"MODE", params);
+        let message = self.stamp(&self.clients[&id], message);
+        self.reply(id, &Reply::YoureOper);
+        self.send(id, &message);
+        if let Some(vhost) = vhost {
+            let user = self.clients[&id].user.clone().unwrap_or_default();
+            self.change_host(id, user, vhost);
+        }
+        Ok(())
+    }
+
+    /// Handle a `REHASH` command from the client `id`.
+    fn rehash_command(&mut self, id: ClientId) -> IrcResult<()> {
+        if !self.clients[&id].oper {
+            return Err(IrcError::NoPrivileges);
+        }
+        if let Some(path) = &self.config_path {
+            let file = path.display().to_string().into_bytes();
+            self.reply(id, &Reply::Rehashing(file));
+        }
+        // the outcome is reported to every operator, including this one
+        let _ = self.rehash(Some(id));
+        Ok(())
+    }
+
+    /// Get the capabilities which clients may currently enable.
+    fn available_caps(&self) -> Vec<Cap> {
+        Cap::ALL
+            .iter()
+            .copied()
+            .filter(|&cap| match cap {
+                // there is nothing to log in to without accounts
+                Cap::Sasl => !self.accounts.is_empty(),
+                Cap::AccountRegistration => self.registration_file.is_some(),
+                // nothing can be played back unless history is kept
+                Cap::Chathistory => self.history.is_some(),
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Tell every client with `cap-notify` which capabilities have become
+    /// available or stopped being available since only those in `before`
+    /// were. Capabilities which are no longer available are disabled.
+    fn notify_caps(&mut self, before: &[Cap]) {
+        let after = self.available_caps();
+        let added: Vec<Cap> = after
+            .iter()
+            .filter(|cap| !before.contains(cap))
+            .copied()
+            .collect();
+        let removed: Vec<Cap> = before
+            .iter()
+            .filter(|cap| !after.contains(cap))
+            .copied()
+            .collect();
+        let mut ids: Vec<ClientId> = self.clients.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let client = self.clients.get_mut(&id).unwrap();
+            for cap in &removed {
+                client.caps.remove(cap);
+            }
+            if !client.has_cap(Cap::CapNotify) {
+                continue;
+            }
+            if !added.is_empty() {
+                self.send_cap_list(id, b"NEW", &added);
+            }
+            if !removed.is_empty() {
+                self.send_cap_list(id, b"DEL", &removed);
+            }
+        }
+    }
+
+    /// Send the client `id` a `CAP` reply with `subcommand`, followed by
+    /// `params`.
+    fn send_cap(&self, id: ClientId, subcommand: &[u8], params: Vec<Vec<u8>>) {
+        let nick = self.clients[&id].nick_or_star().to_vec();
+        let mut all = vec![nick, subcommand.to_vec()];
+        all.extend(params);
+        self.send(id, &outgoing::Message::new(Some(&self.name), b"CAP", all));
+    }
+
+    /// Send the client `id` a `CAP` reply with `subcommand` listing `caps`,
+    /// split over as many lines as are needed if the client negotiated
+    /// version 302.
+    fn send_cap_list(&self, id: ClientId, subcommand: &[u8], caps: &[Cap]) {
+        let client = &self.clients[&id];
+        // values describe what is available, so `LIST` and `DEL` omit them
+        let with_values = client.cap_version >= CAP_302 && matches!(subcommand, b"LS" | b"NEW");
+        let tokens: Vec<Vec<u8>> = caps.iter().map(|cap| cap.to_bytes(with_values)).collect();
+        if client.cap_version < CAP_302 {
+            self.send_cap(id, subcommand, vec![tokens.join(&b' ')]);
+            return;
+        }
+        // leave room for `:server CAP nick LS * :`
+        let budget = 510usize.saturating_sub(self.name.len() + client.nick_or_star().len() + 16);
+        let lines = cap::split(tokens, budget);
+        // only `LS` and `LIST` mark the lines which are continued
+        let continued = subcommand == b"LS" || subcommand == b"LIST";
+        let last = lines.len() - 1;
+        for (i, line) in lines.into_iter().enumerate() {
+            let mut params = Vec::new();
+            if continued && i < last {
+                params.push(b"*".to_vec());
+            }
+            params.push(line);
+            self.send_cap(id, subcommand, params);
+        }
+    }
+
+    /// Handle a `CAP` command from the client `id`.
+    ///
+    /// Listing or requesting capabilities before registration holds it
+    /// until the client sends `CAP END`.
+    fn cap(&mut self, id: ClientId, command: CapCommand) {
+        let client = self.clients.get_mut(&id).unwrap();
+        if matches!(command, CapCommand::Ls(_) | CapCommand::Req(_)) && !client.registered {
+            client.negotiating = true;
+        }
+        match command {
+            CapCommand::Ls(version) => {
+                client.cap_version = client.cap_version.max(version.unwrap_or(301));
+                if client.cap_version >= CAP_302 {
+                    client.caps.insert(Cap::CapNotify);
+                }
+                self.send_cap_list(id, b"LS", &self.available_caps());
+            }
+            CapCommand::List => {
+                let mut caps: Vec<Cap> = client.caps.iter().copied().collect();
+                caps.sort_unstable();
+                self.send_cap_list(id, b"LIST", &caps);
+            }
+            CapCommand::Req(list) => {
+                let available = self.available_caps();
+                let client = self.clients.get_mut(&id).unwrap();
+                let mut changes = Vec::new();
+                for token in list.split(|&b| b == b' ').filter(|token| !token.is_empty()) {
+                    let (enable, name) = match token.strip_prefix(b"-") {
+                        Some(name) => (false, name),
+                        None => (true, token),
+                    };
+                    // `cap-notify` is always on for clients which negotiate 302
+                    let sticky = client.cap_version >= CAP_302;
+                    match Cap::from_name(name) {
+                        Some(Cap::CapNotify) if !enable && sticky => (),
+                        Some(cap) if available.contains(&cap) => {
+                            changes.push((enable, cap));
+                            continue;
+                        }
+                        _ => (),
+                    }
+                    self.send_cap(id, b"NAK", vec![list]);
+                    return;
+                }
+                for (enable, cap) in changes {
+                    if enable {
+                        client.caps.insert(cap);
+                    } else {
+                        client.caps.remove(&cap);
+                    }
+                }
+                self.send_cap(id, b"ACK", vec![list]);
+            }
+            CapCommand::End => {
+                if client.negotiating {
+                    client.negotiating = false;
+                    self.try_register(id);
+                }
+            }
+        }
+    }
+
+    /// Handle an `AUTHENTICATE` command from the client `id`, which either
+    /// starts a SASL exchange using the mechanism it names, continues the
+    /// exchange in progress, or aborts it if it is `*`.
+    fn authenticate(&mut self, id: ClientId, param: &[u8]) -> IrcResult<()> {
+        if param == b"*" {
+            self.sasl.remove(&id);
+            return Err(IrcError::SaslAborted);
+        }
+        let client = &self.clients[&id];
+        let Some(session) = self.sasl.get_mut(&id) else {
+            if client.account.is_some() {
+                return Err(IrcError::SaslAlready);
+            }
+            let Some(mechanism) = sasl::start(param) else {
+                self.reply(id, &Reply::SaslMechs(sasl::names()));
+                return Err(IrcError::SaslFail);
+            };
+            self.sasl.insert(id, sasl::Session::new(mechanism));
+            self.send(
+                id,
+                &outgoing::Message::new(None, b"AUTHENTICATE", vec![b"+".to_vec()]),
+            );
+            return Ok(());
+        };
+
+        let response = match session.receive(param) {
+            Ok(Some(response)) => response,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                self.sasl.remove(&id);
+                return Err(match err {
+                    sasl::ResponseError::TooLong => IrcError::SaslTooLong,
+                    sasl::ResponseError::Invalid => IrcError::SaslFail,
+                });
+            }
+        };
+        let context = sasl::Context {
+            accounts: &self.accounts,
+            certfp: client.certfp.as_deref(),
+        };
+        match session.step(&context, &response) {
+            sasl::Step::Challenge(challenge) => {
+                for chunk in sasl::encode(&challenge) {
+                    self.send(
+                        id,
+                        &outgoing::Message::new(None, b"AUTHENTICATE", vec![chunk]),
+                    );
+                }
+                Ok(())
+            }
+            sasl::Step::Success(account) => {
+                self.sasl.remove(&id);
+                self.set_account(id, Some(account));
+                self.reply(id, &Reply::SaslSuccess);
+                Ok(())
+            }
+            sasl::Step::Failure => {
+                self.sasl.remove(&id);
+                Err(IrcError::SaslFail)
+            }
+        }
+    }
+
+    /// Handle a `SETNAME` command from the client `id`, changing its realname
+    /// to `realname` unless it is empty or longer than the configured
+    /// `namelen`.
+    /// Everyone who shares a channel with the client and has enabled
+    /// `setname` is told, including the client itself.
+    fn setname(&mut self, id: ClientId, realname: Vec<u8>) {
+        if realname.is_empty() || realname.len() > self.limits.namelen {
+            let reply = StandardReply::fail(
+                b"SETNAME",
+                StandardCode::InvalidRealname,
+                b"Realname is not valid",
+            );
+            self.standard_reply(id, &reply);
+            return;
+        }
+        let client = self.clients.get_mut(&id).unwrap();
+        client.realname = Some(realname.clone());
+        let message = outgoing::Message::new(Some(&client.hostmask()), b"SETNAME", vec![realname]);
+        let message = self.stamp(&self.clients[&id], message);
+        let recipients = self
+            .peers(id)
+            .into_iter()
+            .filter(|peer| self.clients[peer].has_cap(Cap::Setname));
+        self.send_to_all(recipients, &message);
+    }
+
+    /// Change the username and hostname of the client `id` to `user` and
+    /// `host`, such as when it is given a vhost.
+    ///
+    /// Clients it shares a channel with are sent `CHGHOST` if they have
+    /// enabled `chghost`. The rest see it quit and rejoin the channels they
+    /// share with it, and get back its status in each and its away message.
+    fn change_host(&mut self, id: ClientId, user: Vec<u8>, host: Vec<u8>) {
+        let client = self.clients.get_mut(&id).unwrap();
+        if client.user.as_ref() == Some(&user) && client.host == host {
+            return;
+        }
+        let old_mask = client.hostmask();
+        client.user = Some(user.clone());
+        client.host.clone_from(&host);
+        let client = &self.clients[&id];
+        let nick = client.nick_or_star().to_vec();
+        self.reply(id, &Reply::HostHidden(host.clone()));
+
+        let chghost = outgoing::Message::new(Some(&old_mask), b"CHGHOST", vec![user, host]);
+        let chghost = self.stamp(client, chghost);
+        let (notified, fallback): (Vec<ClientId>, Vec<ClientId>) = self
+            .peers(id)
+            .into_iter()
+            .partition(|peer| self.clients[peer].has_cap(Cap::Chghost));
+        self.send_to_all(notified, &chghost);
+        let fallback: Vec<ClientId> = fallback.into_iter().filter(|&peer| peer != id).collect();
+        if fallback.is_empty() {
+            return;
+        }
+
+        let quit =
+            outgoing::Message::new(Some(&old_mask), b"QUIT", vec![b"Changing host".to_vec()]);
+        self.send_to_all(fallback.iter().copied(), &self.stamp(client, quit));
+        let mut names: Vec<&Vec<u8>> = client.channels.iter().collect();
+        names.sort();
+        for name in names {
+            let channel = &self.channels[name];
+            let members: Vec<ClientId> = fallback
+                .iter()
+                .copied()
+                .filter(|member| channel.members.contains_key(member))
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            self.send_join(id, &channel.name, &members);
+            let membership = channel.members[&id];
+            let letters: Vec<u8> = [(membership.op, b'o'), (membership.voice, b'v')]
+                .into_iter()
+                .filter_map(|(set, letter)| set.then_some(letter))
+                .collect();
+            if !letters.is_empty() {
+                let mut params = vec![channel.name.clone(), [b"+", &letters[..]].concat()];
+                params.extend(letters.iter().map(|_| nick.clone()));
+                let mode = outgoing::Message::new(Some(&self.name), b"MODE", params);
+                self.send_to_all(members, &mode);
+            }
+        }
+        if client.away.is_some() {
+            self.send_away(id, fallback);
+        }
+    }
+
+    /// Handle an `AWAY` command from the client `id`, marking it as away with
+    /// the message `text`, or no longer away if there is none. Messages
+    /// longer than the configured `awaylen` are truncated.
+    fn away(&mut self, id: ClientId, text: Option<Vec<u8>>) {
+        let text = text.map(|text| match &text[..] {
+            b"*" => DEFAULT_AWAY.to_vec(),
+            text => truncate(text, self.limits.awaylen).to_vec(),
+        });
+        let client = self.clients.get_mut(&id).unwrap();
+        let changed = client.away != text;
+        client.away.clone_from(&text);
+        let registered = client.registered;
+        if text.is_some() {
+            self.reply(id, &Reply::NowAway);
+        } else {
+            self.reply(id, &Reply::UnAway);
+        }
+        if registered && changed {
+            let others = self.peers(id).into_iter().filter(|&peer| peer != id);
+            self.send_away(id, others);
+        }
+    }
+
+    /// Send `AWAY` with the away message of the client `id`, or without one
+    /// if it is not away, to those of `recipients` which have enabled
+    /// `away-notify`.
+    fn send_away(&self, id: ClientId, recipients: impl IntoIterator<Item = ClientId>) {
+        let client = &self.clients[&id];
+        let params = client.away.iter().cloned().collect();
+        let message = outgoing::Message::new(Some(&client.hostmask()), b"AWAY", params);
+        let message = self.stamp(client, message);
+        let recipients = recipients
+            .into_iter()
+            .filter(|recipient| self.clients[recipient].has_cap(Cap::AwayNotify));
+        self.send_to_all(recipients, &message);
+    }
+
+    /// Handle the client `id` registering the account `name`, or one named
+    /// after its nickname if `name` is `*`, with `password`. The account is
+    /// appended to the registration file, and the client is logged in to it.
+    fn register(&mut self, id: ClientId, name: &[u8], password: &[u8]) -> IrcResult<()> {
+        let Some(path) = self.registration_file.clone() else {
+            return Err(IrcError::UnknownCommand(b"REGISTER".to_vec()));
+        };
+        let fail = |code, name: &[u8], description: &[u8]| {
+            let reply = StandardReply::fail(b"REGISTER", code, description).with_context(name);
+            self.standard_reply(id, &reply);
+            Ok(())
+        };
+        let client = &self.clients[&id];
+        let name = match (name, &client.nick) {
+            (b"*", Some(nick)) => nick.clone(),
+            (b"*", None) => {
+                return fail(StandardCode::NeedNick, name, b"Choose a nickname first");
+            }
+            _ => name.to_vec(),
+        };
+        if client.account.is_some() {
+            return fail(
+                StandardCode::AlreadyAuthenticated,
+                &name,
+                b"You are already logged in to an account",
+            );
+        }
+        if !is_valid_nick(&name, self.limits.nicklen) {
+            return fail(
+                StandardCode::BadAccountName,
+                &name,
+                b"Account names must be valid nicknames",
+            );
+        }
+        if self
+            .accounts
+            .iter()
+            .any(|account| mask::eq_ignore_case(&account.name, &name))
+        {
+            return fail(
+                StandardCode::AccountExists,
+                &name,
+                b"Account already exists",
+            );
+        }
+        if password.is_empty() {
+            return fail(
+                StandardCode::WeakPassword,
+                &name,
+                b"Password must not be empty",
+            );
+        }
+
+        let credentials = scram::Credentials::derive(password);
+        if let Err(err) = config::append_registered(&path, &name, &credentials) {
+            let text = format!("Cannot register accounts in {}: {err}", path.display());
+            self.server_notice(text.as_bytes());
+            return fail(
+                StandardCode::TemporarilyUnavailable,
+                &name,
+                b"Accounts cannot be registered right now",
+            );
+        }
+        let available = self.available_caps();
+        self.accounts.push(config::Account {
+            name: name.clone(),
+            credentials: Some(credentials),
+            certfps: Vec::new(),
+        });
+        self.notify_caps(&available);
+        let success = outgoing::Message::new(
+            Some(&self.name),
+            b"REGISTER",
+            vec![
+                b"SUCCESS".to_vec(),
+                name.clone(),
+                b"Account registered".to_vec(),
+            ],
+        );
+        self.send(id, &success);
+        self.set_account(id, Some(name));
+        Ok(())
+    }
+
+    /// Log the client `id` in to `account`, or out of its account if it is
+    /// `None`. Once the client is registered, the clients it shares a channel
+    /// with which have enabled `account-notify` are sent `ACCOUNT`.
+    fn set_account(&mut self, id: ClientId, account: Option<Vec<u8>>) {
+        let client = self.clients.get_mut(&id).unwrap();
+        client.account.clone_from(&account);
+        let mask = client.hostmask();
+        let registered = client.registered;
+        match &account {
+            Some(account) => self.reply(
+                id,
+                &Reply::LoggedIn {
+                    mask: mask.clone(),
+                    account: account.clone(),
+                },
+            ),
+            None => self.reply(id, &Reply::LoggedOut(mask.clone())),
+        }
+        if !registered {
+            return;
+        }
+
+        let message = outgoing::Message::new(
+            Some(&mask),
+            b"ACCOUNT",
+            vec![account.unwrap_or_else(|| b"*".to_vec())],
+        );
+        let message = self.stamp(&self.clients[&id], message);
+        let recipients = self
+            .peers(id)
+            .into_iter()
+            .filter(|&peer| peer != id && self.clients[&peer].has_cap(Cap::AccountNotify));
+        self.send_to_all(recipients, &message);
+    }
+
+    /// Handle the client `id` kicking `nick` from the channel `name`, giving
+    /// `reason` if it gave one. Reasons longer than the configured `kicklen`
+    /// are truncated.
+    fn kick(
+        &mut self,
+        id: ClientId,
+        name: &[u8],
+        nick: &[u8],
+        reason: Option<&[u8]>,
+    ) -> IrcResult<()> {
+        let folded = mask::casefold(name);
+        let channel = self
+            .channels
+            .get(&folded)
+            .ok_or_else(|| IrcError::NoSuchChannel(name.to_vec()))?;
+        let membership = channel
+            .members
+            .get(&id)
+            .ok_or_else(|| IrcError::NotOnChannel(channel.name.clone()))?;
+        if !membership.op {
+            return Err(IrcError::ChanOPrivsNeeded(channel.name.clone()));
+        }
+        let target = self
+            .find_nick(nick)
+            .filter(|target| channel.members.contains_key(&target.id))
+            .ok_or_else(|| IrcError::UserNotInChannel(nick.to_vec(), channel.name.clone()))?;
+
+        let kicker = &self.clients[&id];
+        let reason = reason.unwrap_or_else(|| kicker.nick_or_star());
+        let params = vec![
+            channel.name.clone(),
+            target.nick_or_star().to_vec(),
+            truncate(reason, self.limits.kicklen).to_vec(),
+        ];
+        let message = self.stamp(
+            kicker,
+            outgoing::Message::new(Some(&kicker.hostmask()), b"KICK", params),
+        );
+        self.send_to_all(channel.members.keys().copied(), &message);
+        self.remove_member(target.id, &folded);
+        Ok(())
+    }
+
+    /// Handle the client `id` renaming the channel `name` to `new`. Members
+    /// which have enabled `draft/channel-rename` are sent `RENAME`, and the
+    /// rest see themselves part the channel and join it under its new name.
+    fn rename(
+        &mut self,
+        id: ClientId,
+        name: &[u8],
+        new: &[u8],
+        reason: Option<&[u8]>,
+    ) -> IrcResult<()> {
+        let folded = mask::casefold(name);
+        let channel = self
+            .channels
+            .get(&folded)
+            .ok_or_else(|| IrcError::NoSuchChannel(name.to_vec()))?;
+        let membership = channel
+            .members
+            .get(&id)
+            .ok_or_else(|| IrcError::NotOnChannel(channel.name.clone()))?;
+        if !membership.op {
+            return Err(IrcError::ChanOPrivsNeeded(channel.name.clone()));
+        }
+        let fail = |code, description: &[u8]| {
+            let reply = StandardReply::fail(b"RENAME", code, description)
+                .with_context(&channel.name)
+                .with_context(new);
+            self.standard_reply(id, &reply);
+            Ok(())
+        };
+        let new_folded = mask::casefold(new);
+        if !is_channel_name(new) || new.len() > self.limits.channellen {
+            return fail(StandardCode::CannotRename, b"Invalid channel name");
+        }
+        if new_folded != folded && self.channels.contains_key(&new_folded) {
+            return fail(StandardCode::ChannelNameInUse, b"Channel already exists");
+        }
+
+        let old = channel.name.clone();
+        let members: Vec<ClientId> = channel.members.keys().copied().collect();
+        let mut channel = self.channels.remove(&folded).unwrap();
+        channel.name = new.to_vec();
+        self.channels.insert(new_folded.clone(), channel);
+        for member in &members {
+            let channels = &mut self.clients.get_mut(member).unwrap().channels;
+            channels.remove(&folded);
+            channels.insert(new_folded.clone());
+        }
+        if let Some(history) = &mut self.history {
+            if let Err(err) = history.rename(&old, new) {
+                self.history_write_failed(&err);
+            }
+        }
+
+        let renamer = &self.clients[&id];
+        let params = vec![
+            old.clone(),
+            new.to_vec(),
+            reason.unwrap_or_default().to_vec(),
+        ];
+        let rename = self.stamp(
+            renamer,
+            outgoing::Message::new(Some(&renamer.hostmask()), b"RENAME", params),
+        );
+        let mut part_reason = [&b"Renamed to "[..], new].concat();
+        if let Some(reason) = reason.filter(|reason| !reason.is_empty()) {
+            part_reason.extend([b": ", reason].concat());
+        }
+        let channel = &self.channels[&new_folded];
+        for &member in &members {
+            if self.clients[&member].has_cap(Cap::ChannelRename) {
+                self.send(member, &rename);
+                continue;
+            }
+            let params = vec![old.clone(), part_reason.clone()];
+            let part = self.stamp(
+                &self.clients[&member],
+                outgoing::Message::new(Some(&self.hostmask(member)), b"PART", params),
+            );
+            self.send(member, &part);
+            self.send_join(member, new, &[member]);
+            if channel.topic.is_some() {
+                self.send_topic(member, channel);
+            }
+            self.names_reply(member, channel);
+        }
+        Ok(())
+    }
+
+    /// Handle a `SILENCE` command from the client `id`, which lists its
+    /// silence list if `mask` is `None`, and otherwise adds or removes `mask`.
+    fn silence(&mut self, id: ClientId, mask: Option<Vec<u8>>) -> IrcResult<()> {
+        let Some(mask) = mask else {
+            for mask in &self.clients[&id].silenced {
+                self.reply(id, &Reply::SileList(mask.clone()));
+            }
+            self.reply(id, &Reply::EndOfSileList);
+            return Ok(());
+        };
+        let (adding, raw) = match mask.split_first() {
+            Some((b'-', rest)) => (false, rest),
+            Some((b'+', rest)) => (true, rest),
+            _ => (true, &mask[..]),
+        };
+        if raw.is_empty() {
+            return Err(IrcError::NeedMoreParams(b"SILENCE".to_vec()));
+        }
+
+        let mut normalized = mask::normalize(raw);
+        let client = self.clients.get_mut(&id).unwrap();
+        let existing = client
+            .silenced
+            .iter()
+            .position(|entry| mask::eq_ignore_case(entry, &normalized));
+        match (adding, existing) {
+            (true, None) => {
+                if client.silenced.len() >= self.limits.silence {
+                    return Err(IrcError::SileListFull(normalized));
+                }
+                client.silenced.push(normalized.clone());
+            }
+            (false, Some(index)) => {
+                normalized = client.silenced.remove(index);
+            }
+            _ => return Ok(()),
+        }
+
+        let change = [if adding { &b"+"[..] } else { b"-" }, &normalized].concat();
+        let message = outgoing::Message::new(Some(&client.hostmask()), b"SILENCE", vec![change]);
+        self.send(id, &message);
+        Ok(())
+    }
+
+    /// Handle a `MONITOR` command from the client `id`.
+    ///
+    /// Nicknames are added to the monitor list until it reaches the `monitor`
+    /// limit, and the rest are refused. The client is then told which of the
+    /// nicknames it asked to add are online.
+    fn monitor(&mut self, id: ClientId, command: MonitorCommand) {
+        match command {
+            MonitorCommand::Add(targets) => {
+                let mut added = Vec::new();
+                let mut refused = Vec::new();
+                for target in targets {
+                    if !is_valid_nick(&target, self.limits.nicklen) {
+                        continue;
+                    }
+                    let folded = mask::casefold(&target);
+                    let client = self.clients.get_mut(&id).unwrap();
+                    if !client.monitored.contains_key(&folded) {
+                        if client.monitored.len() >= self.limits.monitor {
+                            refused.push(target);
+                            continue;
+                        }
+                        client.monitored.insert(folded.clone(), target.clone());
+                        self.monitors.entry(folded).or_default().insert(id);
+                    }
+                    added.push(target);
+                }
+                if !refused.is_empty() {
+                    let err = IrcError::MonListFull(self.limits.monitor, refused.join(&b','));
+                    self.error(id, &err);
+                }
+                self.send_monitor_status(id, added);
+            }
+            MonitorCommand::Remove(targets) => {
+                for target in targets {
+                    let folded = mask::casefold(&target);
+                    let client = self.clients.get_mut(&id).unwrap();
+                    if client.monitored.remove(&folded).is_some() {
+                        self.unmonitor(id, &folded);
+                    }
+                }
+            }
+            MonitorCommand::Clear => {
+                let client = self.clients.get_mut(&id).unwrap();
+                for folded in std::mem::take(&mut client.monitored).into_keys() {
+                    self.unmonitor(id, &folded);
+                }
+            }
+            MonitorCommand::List => {
+                let targets = self.clients[&id].monitored.values().cloned().collect();
+                self.reply_list(id, targets, Reply::MonList);
+                self.reply(id, &Reply::EndOfMonList);
+            }
+            MonitorCommand::Status => {
+                let targets = self.clients[&id].monitored.values().cloned().collect();
+                self.send_monitor_status(id, targets);
+            }
+            MonitorCommand::Unknown(_) => (),
+        }
+    }
+
+    /// Remove the client `id` from the clients monitoring the casefolded
+    /// nickname `folded`.
+    fn unmonitor(&mut self, id: ClientId, folded: &[u8]) {
+        if let Some(watchers) = self.monitors.get_mut(folded) {
+            watchers.remove(&id);
+            if watchers.is_empty() {
+                self.monitors.remove(folded);
+            }
+        }
+    }
+
+    /// Tell the client `id` which of the nicknames `targets` are online.
+    fn send_monitor_status(&self, id: ClientId, targets: Vec<Vec<u8>>) {
+        let mut online = Vec::new();
+        let mut offline = Vec::new();
+        for target in targets {
+            match self.find_nick(&target) {
+                Some(client) if client.registered => online.push(client.hostmask()),
+                _ => offline.push(target),
+            }
+        }
+        self.reply_list(id, online, Reply::MonOnline);
+        self.reply_list(id, offline, Reply::MonOffline);
+    }
+
+    /// Send `reply` to every client monitoring `nick`.
+    fn notify_monitors(&self, nick: &[u8], reply: &Reply) {
+        if let Some(watchers) = self.monitors.get(&mask::casefold(nick)) {
+            for &watcher in watchers {
+                self.reply(watcher, reply);
+            }
+        }
+    }
+
+    /// Send `entries` to the client `id` as comma-separated lists, in as few
+    /// of the replies made by `reply` as fit within 512 bytes each. Nothing
+    /// is sent if there are no entries.
+    fn reply_list(&self, id: ClientId, entries: Vec<Vec<u8>>, reply: fn(Vec<u8>) -> Reply) {
+        if entries.is_empty() {
+            return;
+        }
+        // the length of `:server 000 nick :` and the CRLF
+        let overhead = 10 + self.name.len() + self.clients[&id].nick_or_star().len();
+        for list in pack(entries, b',', 512usize.saturating_sub(overhead)) {
+            self.reply(id, &reply(list));
+        }
+    }
+
+    /// Handle an `INVITE` command from the client `id`, inviting `nick` to the
+    /// channel `name`.
+    fn invite(&mut self, id: ClientId, nick: &[u8], name: &[u8]) -> IrcResult<()> {
+        let target = self
+            .find_nick(nick)
+            .ok_or_else(|| IrcError::NoSuchNick(nick.to_vec()))?;
+        let channel = self
+            .channel(name)
+            .ok_or_else(|| IrcError::NoSuchChannel(name.to_vec()))?;
+
+        let membership = channel
+            .members
+            .get(&id)
+            .ok_or_else(|| IrcError::NotOnChannel(channel.name.clone()))?;
+        if channel.modes.invite_only && !membership.op {
+            return Err(IrcError::ChanOPrivsNeeded(channel.name.clone()));
+        }
+        let nick = target.nick_or_star().to_vec();
+        if channel.members.contains_key(&target.id) {
+            return Err(IrcError::UserOnChannel(nick, channel.name.clone()));
+        }
+
+        let target = target.id;
+        let channel_name = channel.name.clone();
+        self.reply(
+            id,
+            &Reply::Inviting {
+                nick: nick.clone(),
+                channel: channel_name.clone(),
+            },
+        );
+        let message = self.stamp(
+            &self.clients[&id],
+            outgoing::Message::new(
+                Some(&self.hostmask(id)),
+                b"INVITE",
+                vec![nick, channel_name],
+            ),
+        );
+        if !is_silenced(&self.clients[&target], &self.clients[&id]) {
+            self.send(target, &message);
+        }
+        self.channels
+            .get_mut(&mask::casefold(name))
+            .unwrap()
+            .invites
+            .insert(target);
+        Ok(())
+    }
+
+    /// Handle a `TOPIC` command from the client `id` for the channel `name`,
+    /// either querying the topic or changing it to `topic`.
+    ///
+    /// Topics longer than the configured `topiclen` are truncated.
+    fn topic(&mut self, id: ClientId, name: &[u8], topic: Option<Vec<u8>>) -> IrcResult<()> {
+        let folded = mask::casefold(name);
+        let channel = self
+            .channels
+            .get(&folded)
+            .ok_or_else(|| IrcError::NoSuchChannel(name.to_vec()))?;
+        let membership = channel.members.get(&id);
+
+        let Some(text) = topic else {
+            if channel.modes.hidden() && membership.is_none() {
+                return Err(IrcError::NotOnChannel(channel.name.clone()));
+            }
+            self.send_topic(id, channel);
+            return Ok(());
+        };
+
+        let membership = membership.ok_or_else(|| IrcError::NotOnChannel(channel.name.clone()))?;
+        if channel.modes.topic_lock && !membership.op {
+            return Err(IrcError::ChanOPrivsNeeded(channel.name.clone()));
+        }
+
+        let text = truncate(&text, self.limits.topiclen).to_vec();
+        let setter = self.hostmask(id);
+        let channel = self.channels.get_mut(&folded).unwrap();
+        channel.topic = (!text.is_empty()).then(|| Topic {
+            text: text.clone(),
+            setter: setter.clone(),
+            set_at: time::unix_time(),
+        });
+
+        let message =
+            outgoing::Message::new(Some(&setter), b"TOPIC", vec![channel.name.clone(), text]);
+        let message = self.stamp(&self.clients[&id], message);
+        let channel = &self.channels[&folded];
+        self.send_to_all(channel.members.keys().copied(), &message);
+        Ok(())
+    }
+
+    /// Send the topic of `channel` to the client `id`, along with who set it
+    /// and when.
+    fn send_topic(&self, id: ClientId, channel: &Channel) {
+        let Some(topic) = &channel.topic else {
+            self.reply(id, &Reply::NoTopic(channel.name.clone()));
+            return;
+        };
+        self.reply(
+            id,
+            &Reply::Topic {
+                channel: channel.name.clone(),
+                topic: topic.text.clone(),
+            },
+        );
+        self.reply(
+            id,
+            &Reply::TopicWhoTime {
+                channel: channel.name.clone(),
+                setter: topic.setter.clone(),
+                time: topic.set_at,
+            },
+        );
+    }
+
+    /// Handle a `CHATHISTORY` command from the client `id`, whose parameters
+    /// start with the subcommand.
+    ///
+    /// The messages asked for are sent in a `chathistory` batch, and problems
+    /// with the command are reported with `FAIL`.
+    fn chathistory(&self, id: ClientId, params: &[Vec<u8>]) -> IrcResult<()> {
+        let Some(history) = &self.history else {
+            return Err(IrcError::UnknownCommand(b"CHATHISTORY".to_vec()));
+        };
+        let Some(subcommand) = params.first() else {
+            let reply = StandardReply::fail(
+                b"CHATHISTORY",
+                StandardCode::NeedMoreParams,
+                b"Insufficient parameters",
+            );
+            self.standard_reply(id, &reply);
+            return Ok(());
+        };
+        let subcommand = subcommand.to_ascii_uppercase();
+        let params = &params[1..];
+        let fail = |code, context: &[&[u8]], description: &[u8]| {
+            let reply = context.iter().fold(
+                StandardReply::fail(b"CHATHISTORY", code, description).with_context(&subcommand),
+                |reply, param| reply.with_context(param),
+            );
+            self.standard_reply(id, &reply);
+            Ok(())
+        };
+
+        // the limit is always the last parameter
+        let needed = match &subcommand[..] {
+            b"LATEST" | b"BEFORE" | b"AFTER" | b"AROUND" | b"TARGETS" => 3,
+            b"BETWEEN" => 4,
+            _ => return fail(StandardCode::UnknownCommand, &[], b"Unknown command"),
+        };
+        if params.len() < needed {
+            return fail(
+                StandardCode::NeedMoreParams,
+                &[],
+                b"Insufficient parameters",
+            );
+        }
+        let limit = std::str::from_utf8(&params[needed - 1])
+            .ok()
+            .and_then(|limit| limit.parse::<usize>().ok())
+            .filter(|&limit| limit > 0);
+        let Some(limit) = limit.map(|limit| limit.min(history.settings().query_limit)) else {
+            return fail(StandardCode::InvalidParams, &[], b"Invalid limit");
+        };
+
+        let reference = |i: usize| Reference::parse(&params[i]);
+        if subcommand == b"TARGETS" {
+            let (Some(Reference::Time(from)), Some(Reference::Time(to))) =
+                (reference(0), reference(1))
+            else {
+                return fail(StandardCode::InvalidParams, &[], b"Invalid timestamp");
+            };
+            self.chathistory_targets(id, history, from, to, limit);
+            return Ok(());
+        }
+        let query = match &subcommand[..] {
+            b"LATEST" if params[1] == b"*" => Some(Query::Latest(None)),
+            b"LATEST" => reference(1).map(|reference| Query::Latest(Some(reference))),
+            b"BEFORE" => reference(1).map(Query::Before),
+            b"AFTER" => reference(1).map(Query::After),
+            b"AROUND" => reference(1).map(Query::Around),
+            _ => reference(1)
+                .zip(reference(2))
+                .map(|(first, second)| Query::Between(first, second)),
+        };
+        let Some(query) = query else {
+            return fail(
+                StandardCode::InvalidParams,
+                &[],
+                b"Invalid message reference",
+            );
+        };
+        let target = &params[0];
+        let client = &self.clients[&id];
+        let Some(key) = self.history_key(id, target) else {
+            if is_channel_name(target) {
+                return fail(
+                    StandardCode::InvalidTarget,
+                    &[target],
+                    b"Messages could not be retrieved",
+                );
+            }
+            let code = match client.account {
+                Some(_) => StandardCode::InvalidTarget,
+                None => StandardCode::AccountRequired,
+            };
+            return fail(
+                code,
+                &[target],
+                b"Private messages are only kept between users logged in to accounts",
+            );
+        };
+
+        let mut batch = self.batch(b"chathistory", vec![target.clone()]);
+        for entry in history.query(&key, &query, limit) {
+            batch.push(tagged_line(client, entry.message()));
+        }
+        self.send_batch(id, batch);
+        Ok(())
+    }
+
+    /// Get the key the history of `target` is kept under, if the client `id`
+    /// may see it. Only members may see the history of a channel, and the
+    /// history of a private conversation is only kept between accounts.
+    fn history_key(&self, id: ClientId, target: &[u8]) -> Option<Vec<u8>> {
+        let client = &self.clients[&id];
+        if is_channel_name(target) {
+            let folded = mask::casefold(target);
+            return client.channels.contains(&folded).then_some(folded);
+        }
+        let account = client.account.as_ref()?;
+        // a user who is not connected is taken to be named after their account
+        let other = match self.find_nick(target) {
+            Some(other) => other.account.clone()?,
+            None => target.to_vec(),
+        };
+        Some(history::conversation(account, &other))
+    }
+
+    /// Send the client `id` the channels and users it has history with
+    /// between the times `from` and `to`, in a `draft/chathistory-targets`
+    /// batch.
+    fn chathistory_targets(
+        &self,
+        id: ClientId,
+        history: &History,
+        from: u64,
+        to: u64,
+        limit: usize,
+    ) {
+        let client = &self.clients[&id];
+        let account = client.account.as_deref().unwrap_or_default();
+        let targets = history.targets(
+            |key| match history::other_party(key, account) {
+                Some(_) => client.account.is_some(),
+                None => client.channels.contains(key),
+            },
+            from,
+            to,
+            limit,
+        );
+
+        let mut batch = self.batch(b"draft/chathistory-targets", Vec::new());
+        for (key, entry) in targets {
+            let name = match history::other_party(key, account) {
+                // users are shown by the nickname they are using now, if any
+                Some(other) => self
+                    .clients
+                    .values()
+                    .find(|user| {
+                        user.account.as_deref().map(mask::casefold).as_deref() == Some(other)
+                    })
+                    .and_then(|user| user.nick.clone())
+                    .unwrap_or_else(|| other.to_vec()),
+                None => self
+                    .channels
+                    .get(key)
+                    .map_or_else(|| key.to_vec(), |channel| channel.name.clone()),
+            };
+            let time = time::iso8601(UNIX_EPOCH + Duration::from_millis(entry.time()));
+            let params = vec![b"TARGETS".to_vec(), name, time.into_bytes()];
+            batch.push(outgoing::Message::new(Some(&self.name), b"CHATHISTORY", params).to_line());
+        }
+        self.send_batch(id, batch);
+    }
+}
+
+/// Check whether `client` may join `channel` using `key`, given that it has
+/// not been invited.
+fn check_can_join(channel: &Channel, client: &Client, key: Option<&Vec<u8>>) -> IrcResult<()> {
+    let name = || channel.name.clone();
+    if channel.modes.key.is_some() && channel.modes.key.as_ref() != key {
+        return Err(IrcError::BadChannelKey(name()));
+    }
+    if channel
+        .modes
+        .limit
+        .is_some_and(|limit| channel.members.len() >= limit)
+    {
+        return Err(IrcError::ChannelIsFull(name()));
+    }
+    if channel.modes.invite_only && !channel.is_invex(client) {
+        return Err(IrcError::InviteOnlyChan(name()));
+    }
+    if channel.is_banned(client) {
+        return Err(IrcError::BannedFromChan(name()));
+    }
+    Ok(())
+}
+
+/// Format a sequence of applied mode changes as a mode string followed by the
+/// arguments of the modes in it, such as `+kl-i key 10`.
+fn format_mode_changes(changes: &[ModeChange]) -> Vec<Vec<u8>> {
+    let mut modestring = Vec::new();
+    let mut args = Vec::new();
+    let mut current = None;
+    for change in changes {
+        if current != Some(change.adding) {
+            modestring.push(if change.adding { b'+' } else { b'-' });
+            current = Some(change.adding);
+        }
+        modestring.push(change.letter);
+        args.extend(change.arg.clone());
+    }
+
+    let mut params = vec![modestring];
+    params.extend(args);
+    params
+}
+
+/// Determine whether `recipient` has silenced `sender`.
+fn is_silenced(recipient: &Client, sender: &Client) -> bool {
+    let hostmask = sender.hostmask();
+    recipient
+        .silenced
+        .iter()
+        .any(|mask| mask::matches(mask, &hostmask))
+}
+
+/// Join `entries` with `separator` into as few lists as possible which are no
+/// longer than `budget`, unless a single entry is. There is always at least
+/// one list, which is empty if there are no entries.
+fn pack(entries: impl IntoIterator<Item = Vec<u8>>, separator: u8, budget: usize) -> Vec<Vec<u8>> {
+    let mut lists = vec![Vec::new()];
+    for entry in entries {
+        let list = lists.last_mut().expect("there is always a list");
+        if list.is_empty() {
+            list.extend(entry);
+        } else if list.len() + 1 + entry.len() > budget {
+            lists.push(entry);
+        } else {
+            list.push(separator);
+            list.extend(entry);
+        }
+    }
+    lists
+}
+
+/// Get the status prefixes of `membership` as shown to `viewer`: every one of
+/// them if it has enabled `multi-prefix`, or only the highest otherwise.
+fn prefixes_for(viewer: &Client, membership: Membership) -> Vec<u8> {
+    if viewer.has_cap(Cap::MultiPrefix) {
+        membership.prefixes()
+    } else {
+        membership.prefix().into_iter().collect()
+    }
+}
+
+/// Serialize `message` for `client`, with only the tags it has enabled the
+/// capabilities for.
+fn tagged_line(client: &Client, message: &outgoing::Message) -> Vec<u8> {
+    message.to_line_with(|key| client.has_cap(Cap::for_tag(key)))
+}
+
+/// Compare two byte strings in time which depends only on their lengths, so
+/// that comparing passwords does not reveal how much of one was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Determine whether `name` is a valid channel name.
+fn is_channel_name(name: &[u8]) -> bool {
+    name.first() == Some(&b'#') && name.len() > 1 && !name.iter().any(|b| b" ,\x07".contains(b))
+}
+
+/// Determine whether `nick` is a valid nickname.
+///
+/// Nicknames may contain letters, digits and the characters ``[]\`_^{|}-``,
+/// but may not begin with a digit or a hyphen.
+fn is_valid_nick(nick: &[u8], nicklen: usize) -> bool {
+    let valid_byte = |b: &u8| b.is_ascii_alphanumeric() || b"[]\\`_^{|}-".contains(b);
+    match nick.first() {
+        Some(first) if !first.is_ascii_digit() && *first != b'-' => {
+            nick.len() <= nicklen && nick.iter().all(valid_byte)
+        }
+        _ => false,
+    }
+}
+
+/// Truncate `text` to at most `max` bytes.
+///
+/// If `text` is valid UTF-8, it is cut at a character boundary so that the
+/// result is also valid UTF-8.
+fn truncate(text: &[u8], max: usize) -> &[u8] {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    if std::str::from_utf8(text).is_ok() {
+        // back off past any continuation bytes of a multi-byte character
+        while end > 0 && text[end] & 0b1100_0000 == 0b1000_0000 {
+            end -= 1;
+        }
+    }
+    &text[..end]
+}
+
+/// Determine whether `key` is a valid channel key no longer than `keylen`.
+fn is_valid_key(key: &[u8], keylen: usize) -> bool {
+    !key.is_empty() && key.len() <= keylen && !key.iter().any(|b| b" ,:".contains(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `line` and handle it as a message from the client `id`.
+    fn send(server: &mut Server, id: ClientId, line: &str) {
+        let message = incoming::Message::parse_bytes(line.as_bytes()).unwrap();
+        server.handle(id, message);
+    }
+
+    /// Take every line queued in `outbox` as a string, without its CRLF.
+    fn lines(outbox: &Outbox) -> Vec<String> {
+        outbox
+            .take()
+            .into_iter()
+            .map(|line| String::from_utf8(line).unwrap().trim_end().to_owned())
+            .collect()
+    }
+
+    /// Connect and register a client named `nick`, discarding its welcome.
+    fn register(server: &mut Server, nick: &str) -> (ClientId, Outbox) {
+        let (id, outbox) = server.connect(b"host".to_vec());
+        send(server, id, &format!("NICK {nick}"));
+        send(server, id, &format!("USER {nick} 0 * :Real {nick}"));
+        lines(&outbox);
+        (id, outbox)
+    }
+
+    /// Construct a server with an operator `op` in `#chan` and a second,
+    /// unjoined client `user`.
+    fn setup() -> (Server, (ClientId, Outbox), (ClientId, Outbox)) {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        let op = register(&mut server, "op");
+        let user = register(&mut server, "user");
+        send(&mut server, op.0, "JOIN #chan");
+        lines(&op.1);
+        (server, op, user)
+    }
+
+    #[test]
+    /// Test that registration sends the welcome numerics, and that commands
+    /// are rejected before it.
+    fn registration() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        let (id, outbox) = server.connect(b"host".to_vec());
+        send(&mut server, id, "JOIN #chan");
+        assert_eq!(lines(&outbox), [":irc.test 451 * :You have not registered"]);
+
+        send(&mut server, id, "NICK nick");
+        send(&mut server, id, "USER user 0 * :Real Name");
+        let welcome = lines(&outbox);
+        assert_eq!(
+            welcome[0],
+            ":irc.test 001 nick :Welcome to the TestNet Internet Relay Chat Network nick!user@host"
+        );
+        assert_eq!(welcome.len(), 8);
+        for line in &welcome[4..7] {
+            assert!(line.starts_with(":irc.test 005 nick "));
+            assert!(line.ends_with(" :are supported by this server"));
+            assert!(line.len() + 2 <= 512);
+        }
+        assert!(welcome[4].contains(" AWAYLEN=200 CASEMAPPING=rfc1459 CHANLIMIT=#:20 "));
+        assert!(welcome[5].contains(" NICKLEN=30 PREFIX=(ov)@+ SAFELIST SILENCE=15 "));
+        assert!(welcome[6].contains(" WHOX "));
+        assert_eq!(welcome[7], ":irc.test 422 nick :MOTD File is missing");
+    }
+
+    #[test]
+    /// Test that the message of the day is sent on registration and by `MOTD`.
+    fn motd() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        server.motd = Some(vec![b"Hello".to_vec(), b"World".to_vec()]);
+        let (id, outbox) = register(&mut server, "nick");
+        let motd = [
+            ":irc.test 375 nick :- irc.test Message of the day -",
+            ":irc.test 372 nick :- Hello",
+            ":irc.test 372 nick :- World",
+            ":irc.test 376 nick :End of /MOTD command.",
+        ];
+        send(&mut server, id, "MOTD");
+        assert_eq!(lines(&outbox), motd);
+    }
+
+    #[test]
+    /// Test logging in as an operator with `OPER`.
+    fn oper() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        server.opers = vec![config::Oper {
+            name: b"admin".to_vec(),
+            password: b"secret".to_vec(),
+            hosts: vec![b"nick@host".to_vec()],
+            vhost: None,
+        }];
+        let (id, outbox) = register(&mut server, "nick");
+        send(&mut server, id, "OPER admin wrong");
+        assert_eq!(lines(&outbox), [":irc.test 464 nick :Password incorrect"]);
+        send(&mut server, id, "OPER nobody secret");
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test 491 nick :No O-lines for your host"]
+        );
+
+        send(&mut server, id, "OPER admin secret");
+        assert_eq!(
+            lines(&outbox),
+            [
+                ":irc.test 381 nick :You are now an IRC operator",
+                ":nick!nick@host MODE nick +o",
+            ]
+        );
+        send(&mut server, id, "MODE nick");
+        assert_eq!(lines(&outbox), [":irc.test 221 nick +o"]);
+
+        // operator status can be dropped, but not regained with MODE
+        send(&mut server, id, "MODE nick -o");
+        send(&mut server, id, "MODE nick +o");
+        assert_eq!(lines(&outbox), [":nick!nick@host MODE nick -o"]);
+    }
+
+    #[test]
+    /// Test that capability negotiation holds registration until `CAP END`,
+    /// and that requests are accepted or rejected as a whole.
+    fn cap_negotiation() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        server.accounts = vec![config::Account {
+            name: b"alice".to_vec(),
+            credentials: None,
+            certfps: vec![b"c0ffee".to_vec()],
+        }];
+        let (id, outbox) = server.connect(b"host".to_vec());
+        send(&mut server, id, "CAP LS");
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test CAP * LS :account-notify account-tag away-notify batch cap-notify chghost draft/channel-rename draft/pre-away echo-message extended-join labeled-response message-tags multi-prefix sasl server-time setname userhost-in-names"]
+        );
+        send(&mut server, id, "CAP LS 302");
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test CAP * LS :account-notify account-tag away-notify batch cap-notify chghost draft/channel-rename draft/pre-away echo-message extended-join labeled-response message-tags multi-prefix sasl=EXTERNAL,PLAIN,SCRAM-SHA-256 server-time setname userhost-in-names"]
+        );
+        send(&mut server, id, "NICK nick");
+        send(&mut server, id, "USER nick 0 * :Real nick");
+        assert!(lines(&outbox).is_empty());
+
+        send(&mut server, id, "CAP REQ :sasl bogus");
+        assert_eq!(lines(&outbox), [":irc.test CAP nick NAK :sasl bogus"]);
+        send(&mut server, id, "CAP REQ -cap-notify");
+        assert_eq!(lines(&outbox), [":irc.test CAP nick NAK -cap-notify"]);
+        send(&mut server, id, "CAP REQ sasl");
+        assert_eq!(lines(&outbox), [":irc.test CAP nick ACK sasl"]);
+        assert!(server.clients[&id].has_cap(Cap::Sasl));
+        send(&mut server, id, "CAP LIST");
+        assert_eq!(lines(&outbox), [":irc.test CAP nick LIST :cap-notify sasl"]);
+        let Err(incoming::ParseError::Irc(err)) = incoming::Message::parse_bytes(b"CAP FOO") else {
+            panic!("an unknown subcommand was parsed");
+        };
+        server.handle_error(id, &err);
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test 410 nick FOO :Invalid CAP command"]
+        );
+
+        send(&mut server, id, "CAP END");
+        assert!(lines(&outbox)[0].starts_with(":irc.test 001 nick "));
+        send(&mut server, id, "CAP REQ -sasl");
+        send(&mut server, id, "CAP END");
+        assert_eq!(lines(&outbox), [":irc.test CAP nick ACK -sasl"]);
+        assert!(!server.clients[&id].has_cap(Cap::Sasl));
+    }
+
+    #[test]
+    /// Test that clients with `cap-notify` are told when capabilities come
+    /// and go, and that capabilities which go are disabled.
+    fn cap_notify() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        let (id, outbox) = register(&mut server, "nick");
+        let (old, old_out) = register(&mut server, "old");
+        send(&mut server, id, "CAP LS 302");
+        send(&mut server, old, "CAP LS");
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test CAP nick LS :account-notify account-tag away-notify batch cap-notify chghost draft/channel-rename draft/pre-away echo-message extended-join labeled-response message-tags multi-prefix server-time setname userhost-in-names"]
+        );
+        lines(&old_out);
+
+        let mut config = Config::default();
+        config.accounts = vec![config::Account {
+            name: b"alice".to_vec(),
+            credentials: None,
+            certfps: vec![b"c0ffee".to_vec()],
+        }];
+        server.configure(&config);
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test CAP nick NEW sasl=EXTERNAL,PLAIN,SCRAM-SHA-256"]
+        );
+        assert!(lines(&old_out).is_empty());
+        send(&mut server, id, "CAP REQ sasl");
+        lines(&outbox);
+
+        config.accounts.clear();
+        server.configure(&config);
+        assert_eq!(lines(&outbox), [":irc.test CAP nick DEL sasl"]);
+        assert!(!server.clients[&id].has_cap(Cap::Sasl));
+    }
+
+    #[test]
+    /// Test that relayed messages are tagged with the time and a message
+    /// identifier, which each client only sees if it asked for them.
+    fn server_time_and_msgid() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        let (plain, plain_out) = register(&mut server, "plain");
+        send(&mut server, user, "JOIN #chan");
+        send(&mut server, plain, "JOIN #chan");
+        send(&mut server, op, "CAP REQ :message-tags server-time");
+        send(&mut server, user, "CAP REQ server-time");
+        for outbox in [&op_out, &user_out, &plain_out] {
+            lines(outbox);
+        }
+
+        send(&mut server, plain, "PRIVMSG #chan :hi there");
+        let op_line = lines(&op_out).remove(0);
+        let (tags, rest) = op_line.split_once(' ').unwrap();
+        assert_eq!(rest, ":plain!plain@host PRIVMSG #chan :hi there");
+        let tags = crate::tags::parse(&tags.as_bytes()[1..]);
+        let [(time_key, time), (msgid_key, msgid)] = &tags[..] else {
+            panic!("unexpected tags {tags:?}");
+        };
+        assert_eq!(
+            (&time_key[..], &msgid_key[..]),
+            (&b"time"[..], &b"msgid"[..])
+        );
+        let time = String::from_utf8(time.clone()).unwrap();
+        assert!(time.len() == 24 && time.ends_with('Z'), "{time}");
+        assert_eq!(
+            lines(&user_out),
+            [format!(
+                "@time={time} :plain!plain@host PRIVMSG #chan :hi there"
+            )]
+        );
+
+        // every event has an identifier of its own
+        send(&mut server, user, "PART #chan");
+        let part = lines(&op_out).remove(0);
+        assert!(part.ends_with(" :user!user@host PART #chan"));
+        assert!(!part.contains(&*String::from_utf8_lossy(msgid)));
+        send(&mut server, op, "PRIVMSG plain hello");
+        assert_eq!(
+            lines(&plain_out),
+            [
+                ":user!user@host PART #chan",
+                ":op!op@host PRIVMSG plain hello"
+            ]
+        );
+    }
+
+    #[test]
+    /// Test that `echo-message` sends messages back to their sender, and
+    /// that `TAGMSG` and client-only tags only reach clients with
+    /// `message-tags`.
+    fn echo_message_and_tagmsg() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, user, "JOIN #chan");
+        send(&mut server, op, "CAP REQ :echo-message message-tags");
+        lines(&op_out);
+        lines(&user_out);
+
+        send(&mut server, op, "@+draft/react=x;label=y PRIVMSG #chan hi");
+        let echo = lines(&op_out);
+        assert_eq!(echo.len(), 1);
+        assert!(echo[0].ends_with(";+draft/react=x :op!op@host PRIVMSG #chan hi"));
+        assert!(!echo[0].contains("label"));
+        assert_eq!(lines(&user_out), [":op!op@host PRIVMSG #chan hi"]);
+
+        send(&mut server, op, "@+typing=active TAGMSG #chan");
+        assert!(lines(&op_out)[0].ends_with(";+typing=active :op!op@host TAGMSG #chan"));
+        assert!(lines(&user_out).is_empty());
+        send(&mut server, user, "@+typing=active TAGMSG op");
+        assert!(lines(&op_out)[0].ends_with(";+typing=active :user!user@host TAGMSG op"));
+        assert!(lines(&user_out).is_empty());
+        send(&mut server, user, "TAGMSG nobody");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 401 user nobody :No such nick/channel"]
+        );
+    }
+
+    #[test]
+    /// Test that responses to labeled commands are labeled, batched or
+    /// acknowledged.
+    fn labeled_response() {
+        let (mut server, (op, op_out), _) = setup();
+        send(&mut server, op, "CAP REQ labeled-response");
+        lines(&op_out);
+        send(&mut server, op, "@label=a PING x");
+        assert_eq!(lines(&op_out), ["@label=a :irc.test PONG irc.test x"]);
+        send(&mut server, op, "@label=b PRIVMSG user :hi");
+        assert_eq!(lines(&op_out), ["@label=b :irc.test ACK"]);
+        send(&mut server, op, "PING y");
+        assert_eq!(lines(&op_out), [":irc.test PONG irc.test y"]);
+
+        // several lines are only labeled inside a batch
+        send(&mut server, op, "@label=c NAMES #chan");
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":irc.test 353 op = #chan @op",
+                ":irc.test 366 op #chan :End of /NAMES list"
+            ]
+        );
+        send(&mut server, op, "CAP REQ batch");
+        lines(&op_out);
+        send(&mut server, op, "@label=d NAMES #chan");
+        let batch = lines(&op_out);
+        let reference = batch[0]
+            .strip_prefix("@label=d :irc.test BATCH +")
+            .and_then(|rest| rest.strip_suffix(" labeled-response"))
+            .unwrap();
+        assert_eq!(
+            batch[1..],
+            [
+                format!("@batch={reference} :irc.test 353 op = #chan @op"),
+                format!("@batch={reference} :irc.test 366 op #chan :End of /NAMES list"),
+                format!(":irc.test BATCH -{reference}"),
+            ]
+        );
+    }
+
+    #[test]
+    /// Test that batches are only wrapped in `BATCH` messages for clients
+    /// with `batch`, and that nested batches have references of their own.
+    fn batches() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, op, "CAP REQ batch");
+        lines(&op_out);
+        for id in [op, user] {
+            let mut outer = server.batch(b"netjoin", vec![b"a.test".to_vec()]);
+            let mut inner = server.batch(b"example", Vec::new());
+            inner.push(b":irc.test NOTICE * :inner\r\n".to_vec());
+            outer.push(b":irc.test NOTICE * :outer\r\n".to_vec());
+            outer.push_batch(inner);
+            server.send_batch(id, outer);
+        }
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":irc.test BATCH +0 netjoin a.test",
+                "@batch=0 :irc.test NOTICE * :outer",
+                "@batch=0 :irc.test BATCH +1 example",
+                "@batch=1 :irc.test NOTICE * :inner",
+                "@batch=0 :irc.test BATCH -1",
+                ":irc.test BATCH -0",
+            ]
+        );
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test NOTICE * :outer", ":irc.test NOTICE * :inner"]
+        );
+    }
+
+    #[test]
+    /// Test that clients which asked for them are told who is logged in by
+    /// `ACCOUNT`, the `account` tag and extended `JOIN` messages.
+    fn accounts() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        let (plain, plain_out) = register(&mut server, "plain");
+        server.accounts = vec![config::Account {
+            name: b"Alice".to_vec(),
+            credentials: Some(crate::scram::Credentials::derive(b"secret")),
+            certfps: Vec::new(),
+        }];
+        send(
+            &mut server,
+            op,
+            "CAP REQ :account-notify account-tag extended-join",
+        );
+        send(&mut server, plain, "JOIN #chan");
+        lines(&op_out);
+        lines(&plain_out);
+        send(&mut server, user, "JOIN #chan");
+        assert_eq!(lines(&op_out), [":user!user@host JOIN #chan * :Real user"]);
+
+        send(&mut server, user, "AUTHENTICATE PLAIN");
+        let response = sasl::encode(b"\0alice\0secret").remove(0);
+        send(
+            &mut server,
+            user,
+            &format!("AUTHENTICATE {}", String::from_utf8(response).unwrap()),
+        );
+        assert!(lines(&user_out).contains(
+            &":irc.test 900 user user!user@host Alice :You are now logged in as Alice".to_owned()
+        ));
+        assert_eq!(
+            lines(&op_out),
+            ["@account=Alice :user!user@host ACCOUNT Alice"]
+        );
+        send(&mut server, user, "PRIVMSG #chan :hi");
+        assert_eq!(
+            lines(&op_out),
+            ["@account=Alice :user!user@host PRIVMSG #chan hi"]
+        );
+        send(&mut server, user, "PART #chan");
+        send(&mut server, user, "JOIN #chan");
+        assert_eq!(
+            lines(&op_out)[1],
+            "@account=Alice :user!user@host JOIN #chan Alice :Real user"
+        );
+        assert_eq!(
+            lines(&plain_out),
+            [
+                ":user!user@host JOIN #chan",
+                ":user!user@host PRIVMSG #chan hi",
+                ":user!user@host PART #chan",
+                ":user!user@host JOIN #chan",
+            ]
+        );
+
+        lines(&user_out);
+
+        // removing the account logs its users out
+        server.configure(&Config::default());
+        assert_eq!(lines(&op_out), [":user!user@host ACCOUNT *"]);
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 901 user user!user@host :You are now logged out"]
+        );
+        assert!(lines(&plain_out).is_empty());
+        assert!(server.clients[&user].account.is_none());
+    }
+
+    #[test]
+    /// Test registering accounts with `REGISTER`, before and after
+    /// connection registration.
+    fn register_accounts() {
+        let dir = std::env::temp_dir().join(format!("strigoi-register-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("accounts.toml");
+        let (mut server, (op, op_out), _) = setup();
+        send(&mut server, op, "REGISTER op * pencil");
+        assert_eq!(
+            lines(&op_out),
+            [":irc.test 421 op REGISTER :Unknown command"]
+        );
+        server.registration_file = Some(path.clone());
+        assert!(server.available_caps().contains(&Cap::AccountRegistration));
+
+        let (id, outbox) = server.connect(b"host".to_vec());
+        send(&mut server, id, "REGISTER * * pencil");
+        send(&mut server, id, "NICK alice");
+        send(
+            &mut server,
+            id,
+            "REGISTER * alice@example.com :correct horse",
+        );
+        send(&mut server, id, "REGISTER other * pencil");
+        assert_eq!(
+            lines(&outbox),
+            [
+                ":irc.test FAIL REGISTER NEED_NICK * :Choose a nickname first",
+                ":irc.test REGISTER SUCCESS alice :Account registered",
+                ":irc.test 900 alice alice!*@host alice :You are now logged in as alice",
+                ":irc.test FAIL REGISTER ALREADY_AUTHENTICATED other :You are already logged in to an account",
+            ]
+        );
+        assert!(server.available_caps().contains(&Cap::Sasl));
+
+        send(&mut server, op, "REGISTER Alice * pencil");
+        send(&mut server, op, "REGISTER 1op * pencil");
+        send(&mut server, op, "REGISTER op * :");
+        server.registration_file = Some(dir.clone());
+        send(&mut server, op, "REGISTER op * pencil");
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":irc.test FAIL REGISTER ACCOUNT_EXISTS Alice :Account already exists",
+                ":irc.test FAIL REGISTER BAD_ACCOUNT_NAME 1op :Account names must be valid nicknames",
+                ":irc.test FAIL REGISTER WEAK_PASSWORD op :Password must not be empty",
+                ":irc.test FAIL REGISTER TEMPORARILY_UNAVAILABLE op :Accounts cannot be registered right now",
+            ]
+        );
+
+        // registered accounts are kept in the file, so they survive a rehash
+        let config = format!(
+            "[server]\nname = \"irc.test\"\nnetwork = \"TestNet\"\n\n\
+             [[listen]]\naddress = \"localhost:6667\"\n\n\
+             [registration]\nfile = \"{}\"\n",
+            path.display()
+        );
+        let config = Config::parse(&config, &dir).unwrap();
+        assert_eq!(config.accounts.len(), 1);
+        assert!(config.accounts[0]
+            .credentials
+            .as_ref()
+            .unwrap()
+            .verify(b"correct horse"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    /// Test marking users as away, and who is told about it.
+    fn away() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        server.limits.awaylen = 10;
+        send(&mut server, op, "CAP REQ away-notify");
+        send(&mut server, user, "JOIN #chan");
+        lines(&op_out);
+        lines(&user_out);
+
+        send(&mut server, user, "AWAY :gone fishing for a while");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 306 user :You have been marked as being away"]
+        );
+        assert_eq!(lines(&op_out), [":user!user@host AWAY :gone fishi"]);
+        send(&mut server, op, "PRIVMSG user :hi");
+        assert_eq!(lines(&op_out), [":irc.test 301 op user :gone fishi"]);
+        send(&mut server, op, "NOTICE user :hi");
+        assert!(lines(&op_out).is_empty());
+        send(&mut server, op, "WHOIS user");
+        assert!(lines(&op_out).contains(&":irc.test 301 op user :gone fishi".to_owned()));
+
+        // users who join while away are announced as away
+        send(&mut server, user, "PART #chan");
+        send(&mut server, user, "JOIN #chan");
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":user!user@host PART #chan",
+                ":user!user@host JOIN #chan",
+                ":user!user@host AWAY :gone fishi",
+            ]
+        );
+        lines(&user_out);
+        send(&mut server, user, "AWAY");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 305 user :You are no longer marked as being away"]
+        );
+        assert_eq!(lines(&op_out), [":user!user@host AWAY"]);
+
+        // only clients with `draft/pre-away` may be marked as away while
+        // registering
+        let (id, outbox) = server.connect(b"host".to_vec());
+        send(&mut server, id, "AWAY :early");
+        assert_eq!(lines(&outbox), [":irc.test 451 * :You have not registered"]);
+        send(&mut server, id, "CAP REQ draft/pre-away");
+        send(&mut server, id, "AWAY *");
+        assert_eq!(
+            lines(&outbox)[1],
+            ":irc.test 306 * :You have been marked as being away"
+        );
+        assert_eq!(server.clients[&id].away.as_deref(), Some(DEFAULT_AWAY));
+    }
+
+    #[test]
+    /// Test that a change of host is shown with `CHGHOST` to clients which
+    /// asked for it, and as the user quitting and rejoining to the rest.
+    fn chghost() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        server.opers = vec![config::Oper {
+            name: b"admin".to_vec(),
+            password: b"secret".to_vec(),
+            hosts: vec![b"*".to_vec()],
+            vhost: Some(b"staff.test".to_vec()),
+        }];
+        let (plain, plain_out) = register(&mut server, "plain");
+        send(&mut server, op, "CAP REQ chghost");
+        send(&mut server, user, "JOIN #chan");
+        send(&mut server, plain, "JOIN #chan");
+        send(&mut server, plain, "JOIN #other");
+        send(&mut server, op, "MODE #chan +v op");
+        send(&mut server, op, "AWAY :busy");
+        for outbox in [&op_out, &user_out, &plain_out] {
+            lines(outbox);
+        }
+
+        send(&mut server, op, "OPER admin secret");
+        assert_eq!(
+            lines(&op_out)[2..],
+            [
+                ":irc.test 396 op staff.test :is now your displayed host",
+                ":op!op@host CHGHOST op staff.test",
+            ]
+        );
+        assert_eq!(
+            lines(&user_out),
+            [
+                ":op!op@host QUIT :Changing host",
+                ":op!op@staff.test JOIN #chan",
+                ":irc.test MODE #chan +ov op op",
+            ]
+        );
+        assert_eq!(lines(&plain_out).len(), 3);
+        assert_eq!(server.hostmask(op), b"op!op@staff.test");
+    }
+
+    #[test]
+    /// Test changing realnames with `SETNAME`.
+    fn setname() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        server.limits.namelen = 10;
+        send(&mut server, op, "CAP REQ setname");
+        send(&mut server, user, "JOIN #chan");
+        lines(&op_out);
+        lines(&user_out);
+
+        send(&mut server, user, "SETNAME :New Name");
+        assert_eq!(lines(&op_out), [":user!user@host SETNAME :New Name"]);
+        assert!(lines(&user_out).is_empty());
+        send(&mut server, op, "SETNAME :Much Too Long");
+        assert_eq!(
+            lines(&op_out),
+            [":irc.test FAIL SETNAME INVALID_REALNAME :Realname is not valid"]
+        );
+        for empty in ["SETNAME", "SETNAME :"] {
+            send(&mut server, op, empty);
+            assert_eq!(
+                lines(&op_out),
+                [":irc.test FAIL SETNAME INVALID_REALNAME :Realname is not valid"]
+            );
+        }
+        send(&mut server, op, "SETNAME Op");
+        assert_eq!(lines(&op_out), [":op!op@host SETNAME Op"]);
+        send(&mut server, op, "WHOIS user");
+        assert_eq!(
+            lines(&op_out)[0],
+            ":irc.test 311 op user user host * :New Name"
+        );
+
+        // realnames given at registration are truncated instead
+        let (id, _) = register(&mut server, "long");
+        assert_eq!(
+            server.clients[&id].realname.as_deref(),
+            Some(&b"Real long"[..])
+        );
+    }
+
+    #[test]
+    /// Test `multi-prefix` and `userhost-in-names` in `NAMES`, `WHO` and
+    /// `WHOIS` replies, and that long member lists are split.
+    fn names_and_who() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, user, "JOIN #chan");
+        send(&mut server, op, "MODE #chan +v op");
+        send(&mut server, user, "AWAY :gone");
+        lines(&op_out);
+        lines(&user_out);
+
+        send(&mut server, user, "NAMES #chan");
+        assert_eq!(
+            lines(&user_out),
+            [
+                ":irc.test 353 user = #chan :@op user",
+                ":irc.test 366 user #chan :End of /NAMES list",
+            ]
+        );
+        send(&mut server, user, "CAP REQ :multi-prefix userhost-in-names");
+        lines(&user_out);
+        send(&mut server, user, "NAMES #chan");
+        assert_eq!(
+            lines(&user_out)[0],
+            ":irc.test 353 user = #chan :@+op!op@host user!user@host"
+        );
+        send(&mut server, user, "WHOIS op");
+        assert!(lines(&user_out).contains(&":irc.test 319 user op @+#chan".to_owned()));
+
+        send(&mut server, user, "WHO #chan");
+        assert_eq!(
+            lines(&user_out),
+            [
+                ":irc.test 352 user #chan op host irc.test op H@+ :0 Real op",
+                ":irc.test 352 user #chan user host irc.test user G :0 Real user",
+                ":irc.test 315 user #chan :End of /WHO list",
+            ]
+        );
+        send(&mut server, op, "WHO u*");
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":irc.test 352 op * user host irc.test user G :0 Real user",
+                ":irc.test 315 op u* :End of /WHO list",
+            ]
+        );
+
+        // invisible users are hidden from those who share no channel
+        let (other, other_out) = register(&mut server, "other");
+        send(&mut server, user, "MODE user +i");
+        send(&mut server, other, "WHO 0");
+        assert_eq!(
+            lines(&other_out),
+            [
+                ":irc.test 352 other * op host irc.test op H :0 Real op",
+                ":irc.test 352 other * other host irc.test other H :0 Real other",
+                ":irc.test 315 other 0 :End of /WHO list",
+            ]
+        );
+        send(&mut server, other, "WHO #chan o");
+        assert_eq!(
+            lines(&other_out),
+            [":irc.test 315 other #chan :End of /WHO list"]
+        );
+
+        // long member lists are split over several lines of at most 512 bytes
+        for i in 0..40 {
+            let (id, _) = register(&mut server, &format!("member{i:02}"));
+            send(&mut server, id, "JOIN #chan");
+        }
+        lines(&user_out);
+        send(&mut server, user, "NAMES #chan");
+        let names = lines(&user_out);
+        assert!(names.len() >= 3);
+        assert!(names.iter().all(|line| line.len() + 2 <= 512));
+        let listed: Vec<_> = names[..names.len() - 1]
+            .iter()
+            .flat_map(|line| line.split(" :").nth(1).unwrap().split(' '))
+            .collect();
+        assert_eq!(listed.len(), 42);
+        assert_eq!(listed[41], "member39!member39@host");
+    }
+
+    #[test]
+    /// Test extended `WHO` queries, which send the fields asked for in a fixed
+    /// order with the query's token.
+    fn whox() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        server.clients.get_mut(&user).unwrap().account = Some(b"acct".to_vec());
+        send(&mut server, user, "JOIN #chan");
+        lines(&op_out);
+        lines(&user_out);
+
+        send(&mut server, op, "WHO #chan %rafnhuct,42");
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":irc.test 354 op 42 #chan op host op H@ 0 :Real op",
+                ":irc.test 354 op 42 #chan user host user H acct :Real user",
+                ":irc.test 315 op #chan :End of /WHO list",
+            ]
+        );
+        send(&mut server, op, "WHO user o%na,1234");
+        assert_eq!(lines(&op_out), [":irc.test 315 op user :End of /WHO list"]);
+        send(&mut server, op, "WHO user %tnido,x");
+        assert_eq!(
+            lines(&op_out)[0],
+            ":irc.test 354 op 0 255.255.255.255 user 0 n/a"
+        );
+        send(&mut server, op, "WHO user %nl");
+        assert_eq!(lines(&op_out)[0], ":irc.test 354 op user 0");
+    }
+
+    #[test]
+    /// Test monitoring nicknames as they come online, change and go offline,
+    /// and the limit on the monitor list.
+    fn monitor() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        server.limits.monitor = 3;
+        let (id, outbox) = register(&mut server, "watcher");
+        let (friend, _) = register(&mut server, "Friend");
+        lines(&outbox);
+
+        send(
+            &mut server,
+            id,
+            "MONITOR + friend,other,ghost,extra,bad!nick",
+        );
+        assert_eq!(
+            lines(&outbox),
+            [
+                ":irc.test 734 watcher 3 extra :Monitor list is full",
+                ":irc.test 730 watcher Friend!Friend@host",
+                ":irc.test 731 watcher other,ghost",
+            ]
+        );
+        send(&mut server, id, "MONITOR - GHOST");
+        send(&mut server, id, "MONITOR L");
+        assert_eq!(
+            lines(&outbox),
+            [
+                ":irc.test 732 watcher friend,other",
+                ":irc.test 733 watcher :End of MONITOR list",
+            ]
+        );
+
+        send(&mut server, friend, "NICK FRIEND");
+        assert!(lines(&outbox).is_empty());
+        send(&mut server, friend, "NICK other");
+        assert_eq!(
+            lines(&outbox),
+            [
+                ":irc.test 731 watcher FRIEND",
+                ":irc.test 730 watcher other!Friend@host",
+            ]
+        );
+        server.disconnect(friend, b"Bye");
+        assert_eq!(lines(&outbox), [":irc.test 731 watcher other"]);
+        let (_, _) = register(&mut server, "friend");
+        assert_eq!(lines(&outbox), [":irc.test 730 watcher friend!friend@host"]);
+        send(&mut server, id, "MONITOR S");
+        assert_eq!(
+            lines(&outbox),
+            [
+                ":irc.test 730 watcher friend!friend@host",
+                ":irc.test 731 watcher other",
+            ]
+        );
+
+        send(&mut server, id, "MONITOR C");
+        send(&mut server, id, "MONITOR L");
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test 733 watcher :End of MONITOR list"]
+        );
+        assert!(server.monitors.is_empty());
+    }
+
+    #[test]
+    /// Test that private message history is refused, with a reason, unless
+    /// both users are logged in to accounts.
+    fn chathistory_accounts() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        server.set_history(History::new(config::History::default()));
+        server.clients.get_mut(&op).unwrap().account = Some(b"Op".to_vec());
+        send(&mut server, op, "PRIVMSG user :not kept");
+        lines(&user_out);
+
+        send(&mut server, user, "CHATHISTORY LATEST op * 10");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test FAIL CHATHISTORY ACCOUNT_REQUIRED LATEST op :Private messages are only kept between users logged in to accounts"]
+        );
+        send(&mut server, op, "CHATHISTORY LATEST user * 10");
+        assert_eq!(
+            lines(&op_out),
+            [":irc.test FAIL CHATHISTORY INVALID_TARGET LATEST user :Private messages are only kept between users logged in to accounts"]
+        );
+    }
+
+    #[test]
+    /// Test playing back the history of channels and private conversations,
+    /// and that it is only shown to those who took part in them.
+    fn chathistory() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, op, "CHATHISTORY LATEST #chan * 10");
+        assert_eq!(
+            lines(&op_out),
+            [":irc.test 421 op CHATHISTORY :Unknown command"]
+        );
+        server.set_history(History::new(config::History::default()));
+        assert!(server.available_caps().contains(&Cap::Chathistory));
+        server.clients.get_mut(&op).unwrap().account = Some(b"Op".to_vec());
+        send(&mut server, op, "PRIVMSG #chan :one");
+        send(&mut server, op, "PRIVMSG #chan :two");
+        send(&mut server, op, "PRIVMSG @#chan :ops only");
+        send(&mut server, op, "PRIVMSG user :not kept");
+        server.clients.get_mut(&user).unwrap().account = Some(b"User".to_vec());
+        send(&mut server, op, "PRIVMSG user :kept");
+        lines(&user_out);
+
+        send(&mut server, user, "CHATHISTORY LATEST #chan * 10");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test FAIL CHATHISTORY INVALID_TARGET LATEST #chan :Messages could not be retrieved"]
+        );
+        send(&mut server, user, "JOIN #chan");
+        send(&mut server, user, "CAP REQ batch");
+        lines(&user_out);
+        send(&mut server, user, "CHATHISTORY LATEST #chan * 1");
+        assert_eq!(
+            lines(&user_out),
+            [
+                ":irc.test BATCH +0 chathistory #chan",
+                "@batch=0 :op!op@host PRIVMSG #chan two",
+                ":irc.test BATCH -0",
+            ]
+        );
+        send(
+            &mut server,
+            user,
+            "CHATHISTORY BETWEEN #CHAN timestamp=2000-01-01T00:00:00Z timestamp=2100-01-01T00:00:00Z 10",
+        );
+        assert_eq!(
+            lines(&user_out)[1..3],
+            [
+                "@batch=1 :op!op@host PRIVMSG #chan one",
+                "@batch=1 :op!op@host PRIVMSG #chan two",
+            ]
+        );
+        send(&mut server, user, "CHATHISTORY LATEST op * 10");
+        assert_eq!(
+            lines(&user_out),
+            [
+                ":irc.test BATCH +2 chathistory op",
+                "@batch=2 :op!op@host PRIVMSG user kept",
+                ":irc.test BATCH -2",
+            ]
+        );
+
+        send(
+            &mut server,
+            user,
+            "CHATHISTORY TARGETS timestamp=2000-01-01T00:00:00Z timestamp=2100-01-01T00:00:00Z 10",
+        );
+        let targets = lines(&user_out);
+        assert_eq!(targets.len(), 4);
+        assert!(targets[1].starts_with("@batch=3 :irc.test CHATHISTORY TARGETS #chan 20"));
+        assert!(targets[2].starts_with("@batch=3 :irc.test CHATHISTORY TARGETS op 20"));
+
+        for (command, error) in [
+            ("CHATHISTORY", "NEED_MORE_PARAMS :Insufficient parameters"),
+            (
+                "CHATHISTORY FOO #chan",
+                "UNKNOWN_COMMAND FOO :Unknown command",
+            ),
+            (
+                "CHATHISTORY BEFORE #chan 10",
+                "NEED_MORE_PARAMS BEFORE :Insufficient parameters",
+            ),
+            (
+                "CHATHISTORY AFTER #chan * 10",
+                "INVALID_PARAMS AFTER :Invalid message reference",
+            ),
+            (
+                "CHATHISTORY AROUND #chan msgid=x 0",
+                "INVALID_PARAMS AROUND :Invalid limit",
+            ),
+            (
+                "CHATHISTORY TARGETS msgid=x * 10",
+                "INVALID_PARAMS TARGETS :Invalid timestamp",
+            ),
+        ] {
+            send(&mut server, user, command);
+            assert_eq!(
+                lines(&user_out),
+                [format!(":irc.test FAIL CHATHISTORY {error}")]
+            );
+        }
+    }
+
+    #[test]
+    /// Test logging in with SASL, and the ways it can fail.
+    fn sasl() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        server.accounts = vec![config::Account {
+            name: b"Alice".to_vec(),
+            credentials: Some(crate::scram::Credentials::derive(b"secret")),
+            certfps: vec![b"c0ffee".to_vec()],
+        }];
+        let plain = |response: &[u8]| {
+            format!(
+                "AUTHENTICATE {}",
+                String::from_utf8(sasl::encode(response).remove(0)).unwrap()
+            )
+        };
+
+        let (id, outbox) = server.connect(b"host".to_vec());
+        send(&mut server, id, "AUTHENTICATE PLAIN");
+        assert_eq!(lines(&outbox), ["AUTHENTICATE +"]);
+        send(&mut server, id, &plain(b"\0alice\0wrong"));
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test 904 * :SASL authentication failed"]
+        );
+        send(&mut server, id, "AUTHENTICATE PLAIN");
+        send(&mut server, id, "AUTHENTICATE *");
+        assert_eq!(
+            lines(&outbox),
+            [
+                "AUTHENTICATE +",
+                ":irc.test 906 * :SASL authentication aborted"
+            ]
+        );
+        send(&mut server, id, "AUTHENTICATE CRAM-MD5");
+        assert_eq!(
+            lines(&outbox),
+            [
+                ":irc.test 908 * EXTERNAL,PLAIN,SCRAM-SHA-256 :are available SASL mechanisms",
+                ":irc.test 904 * :SASL authentication failed",
+            ]
+        );
+        send(&mut server, id, "AUTHENTICATE PLAIN");
+        send(
+            &mut server,
+            id,
+            &format!("AUTHENTICATE {}", "A".repeat(401)),
+        );
+        assert_eq!(
+            lines(&outbox),
+            ["AUTHENTICATE +", ":irc.test 905 * :SASL message too long"]
+        );
+
+        send(&mut server, id, "NICK nick");
+        send(&mut server, id, "AUTHENTICATE plain");
+        send(&mut server, id, &plain(b"\0alice\0secret"));
+        assert_eq!(
+            lines(&outbox),
+            [
+                "AUTHENTICATE +",
+                ":irc.test 900 nick nick!*@host Alice :You are now logged in as Alice",
+                ":irc.test 903 nick :SASL authentication successful",
+            ]
+        );
+        send(&mut server, id, "AUTHENTICATE PLAIN");
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test 907 nick :You have already authenticated using SASL"]
+        );
+
+        // registering while authenticating aborts the exchange
+        let (other, other_out) = server.connect_tls(b"host".to_vec(), Some(b"c0ffee".to_vec()));
+        send(&mut server, other, "AUTHENTICATE EXTERNAL");
+        send(&mut server, other, "NICK other");
+        send(&mut server, other, "USER other 0 * :Other");
+        assert!(lines(&other_out)
+            .contains(&":irc.test 906 other :SASL authentication aborted".to_owned()));
+        assert!(server.clients[&other].account.is_none());
+        send(&mut server, other, "AUTHENTICATE EXTERNAL");
+        send(&mut server, other, "AUTHENTICATE +");
+        assert_eq!(
+            lines(&other_out)[2],
+            ":irc.test 903 other :SASL authentication successful"
+        );
+        assert_eq!(
+            server.clients[&other].account.as_deref(),
+            Some(&b"Alice"[..])
+        );
+    }
+
+    #[test]
+    /// Test reloading the configuration with `REHASH`.
+    fn rehash() {
+        let path = std::env::temp_dir().join(format!("strigoi-rehash-{}.toml", std::process::id()));
+        let write = |text: &str| std::fs::write(&path, text).unwrap();
+        let base = "[server]\nname = \"irc.test\"\nnetwork = \"TestNet\"\n\
+                    [[listen]]\naddress = \"localhost:6667\"\n\
+                    [[oper]]\nname = \"admin\"\npassword = \"secret\"\nhosts = [\"*\"]\n";
+        write(base);
+        let mut server = Server::from_config(&Config::load(&path).unwrap());
+        server.set_config_path(path.clone());
+        let (user, user_out) = register(&mut server, "user");
+        let (oper, oper_out) = register(&mut server, "oper");
+        send(&mut server, oper, "OPER admin secret");
+        lines(&oper_out);
+
+        send(&mut server, user, "REHASH");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 481 user :Permission Denied- You're not an IRC operator"]
+        );
+
+        // live settings change, and the others are reported
+        write(
+            &base
+                .replace("6667", "6697")
+                .replace("[[oper]]", "[limits]\nnicklen = 9\n[[oper]]"),
+        );
+        send(&mut server, oper, "REHASH");
+        let display = path.display();
+        assert_eq!(
+            lines(&oper_out),
+            [
+                format!(":irc.test 382 oper {display} Rehashing"),
+                format!(":irc.test NOTICE oper :*** Notice -- Configuration reloaded by oper from {display}"),
+                ":irc.test WARN REHASH RESTART_REQUIRED :localhost:6667 will be listened on until a restart".to_owned(),
+                ":irc.test WARN REHASH RESTART_REQUIRED :localhost:6697 will not be listened on until a restart".to_owned(),
+            ]
+        );
+        assert_eq!(server.limits.nicklen, 9);
+
+        // history turned on by a rehash is only kept in memory
+        let history = base.replace("[[oper]]", "[limits]\nnicklen = 9\n[[oper]]")
+            + "[history]\nfile = \"history.log\"\n";
+        write(&history);
+        let log = path.with_file_name("history.log");
+        assert_eq!(
+            server.rehash(None).unwrap(),
+            [format!(
+                "history will be kept only in memory, not in {}, until a restart",
+                log.display()
+            )]
+        );
+        assert_eq!(server.history.as_ref().unwrap().settings().file, None);
+        // without a client to warn, the operators are told with notices
+        assert_eq!(
+            lines(&oper_out)[1],
+            format!(
+                ":irc.test NOTICE oper :*** Notice -- Rehash: history will be kept only in memory, not in {}, until a restart",
+                log.display()
+            )
+        );
+        server.set_history(History::new(config::History {
+            file: Some(log.clone()),
+            ..config::History::default()
+        }));
+        write(&history.replace("history.log", "other.log"));
+        assert_eq!(
+            server.rehash(None).unwrap(),
+            [format!(
+                "history will be kept in {} until a restart",
+                log.display()
+            )]
+        );
+        assert_eq!(
+            server.history.as_ref().unwrap().settings().file,
+            Some(log.clone())
+        );
+        lines(&oper_out);
+
+        // an invalid configuration is rejected, and the old one kept
+        write(&base.replace("network", "bogus = 1\nnetwork"));
+        assert!(server.rehash(None).is_err());
+        assert_eq!(
+            lines(&oper_out),
+            [format!(
+                ":irc.test NOTICE oper :*** Notice -- Rehash by the server failed: {display}: line 3: [server] has an unknown key `bogus`"
+            )]
+        );
+        assert_eq!(server.limits.nicklen, 9);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Test that banned clients cannot register, and that clients matching a
+    /// ban added by a rehash are disconnected.
+    fn bans() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, user, "JOIN #chan");
+        lines(&op_out);
+        lines(&user_out);
+
+        let mut config = Config::default();
+        config.bans = vec![config::Ban {
+            mask: b"user@*".to_vec(),
+            reason: b"No spam".to_vec(),
+        }];
+        server.configure(&config);
+        assert_eq!(
+            lines(&user_out),
+            [
+                ":irc.test 465 user :You are banned from this server",
+                "ERROR :No spam",
+            ]
+        );
+        assert!(user_out.is_closed());
+        assert!(!server.clients.contains_key(&user));
+        assert_eq!(lines(&op_out), [":user!user@host QUIT :No spam"]);
+
+        let (id, outbox) = server.connect(b"host".to_vec());
+        send(&mut server, id, "NICK other");
+        send(&mut server, id, "USER user 0 * :Real user");
+        assert_eq!(
+            lines(&outbox),
+            [
+                ":irc.test 465 other :You are banned from this server",
+                "ERROR :No spam",
+            ]
+        );
+        assert!(server.find_nick(b"other").is_none());
+        assert!(server.clients.contains_key(&op));
+    }
+
+    #[test]
+    /// Test kicking members from a channel.
+    fn kick() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, user, "KICK #chan op");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 442 user #chan :You're not on that channel"]
+        );
+        send(&mut server, user, "JOIN #chan");
+        lines(&op_out);
+        lines(&user_out);
+        send(&mut server, user, "KICK #chan op");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 482 user #chan :You're not channel operator"]
+        );
+
+        server.limits.kicklen = 5;
+        send(&mut server, op, "KICK #chan user,nobody :Goodbye, user");
+        let kick = ":op!op@host KICK #chan user Goodb";
+        assert_eq!(
+            lines(&op_out),
+            [
+                kick,
+                ":irc.test 441 op nobody #chan :They aren't on that channel"
+            ]
+        );
+        assert_eq!(lines(&user_out), [kick]);
+        assert!(!server
+            .channel(b"#chan")
+            .unwrap()
+            .members
+            .contains_key(&user));
+    }
+
+    #[test]
+    /// Test renaming channels, both for members which have enabled
+    /// `draft/channel-rename` and for those which have not.
+    fn rename() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, op, "CAP REQ draft/channel-rename");
+        send(&mut server, user, "JOIN #chan");
+        send(&mut server, op, "TOPIC #chan :Hello");
+        send(&mut server, op, "JOIN #taken");
+        lines(&op_out);
+        lines(&user_out);
+
+        send(&mut server, user, "RENAME #chan #new");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 482 user #chan :You're not channel operator"]
+        );
+        send(&mut server, op, "RENAME #chan #taken");
+        send(&mut server, op, "RENAME #chan new");
+        send(&mut server, op, "RENAME #nowhere #new");
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":irc.test FAIL RENAME CHANNEL_NAME_IN_USE #chan #taken :Channel already exists",
+                ":irc.test FAIL RENAME CANNOT_RENAME #chan new :Invalid channel name",
+                ":irc.test 403 op #nowhere :No such channel",
+            ]
+        );
+
+        send(&mut server, op, "RENAME #CHAN #New :Tidying up");
+        assert_eq!(
+            lines(&op_out),
+            [":op!op@host RENAME #chan #New :Tidying up"]
+        );
+        let rejoin = lines(&user_out);
+        assert_eq!(
+            rejoin[..3],
+            [
+                ":user!user@host PART #chan :Renamed to #New: Tidying up",
+                ":user!user@host JOIN #New",
+                ":irc.test 332 user #New Hello",
+            ]
+        );
+        assert_eq!(
+            rejoin[4..],
+            [
+                ":irc.test 353 user = #New :@op user",
+                ":irc.test 366 user #New :End of /NAMES list",
+            ]
+        );
+        send(&mut server, user, "PRIVMSG #new :hi");
+        assert_eq!(lines(&op_out), [":user!user@host PRIVMSG #new hi"]);
+        send(&mut server, user, "PART #chan");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 403 user #chan :No such channel"]
+        );
+    }
+
+    #[test]
+    /// Test that commands are limited to `maxtargets` targets.
+    fn targets() {
+        let (mut server, (op, op_out), (_, user_out)) = setup();
+        server.limits.maxtargets = 2;
+        send(&mut server, op, "PRIVMSG user,#chan,user :hi");
+        assert_eq!(lines(&op_out), [":irc.test 407 op user :Too many targets"]);
+        send(&mut server, op, "NOTICE user,#chan,user :hi");
+        assert!(lines(&op_out).is_empty());
+        assert!(lines(&user_out).is_empty());
+        send(&mut server, op, "PRIVMSG user,user :hi");
+        assert_eq!(lines(&user_out).len(), 2);
+    }
+
+    #[test]
+    /// Test that messages to `@#chan` and `+#chan` reach only members with
+    /// that status.
+    fn status_messages() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        let (voiced, voiced_out) = register(&mut server, "voiced");
+        send(&mut server, user, "JOIN #chan");
+        send(&mut server, voiced, "JOIN #chan");
+        send(&mut server, op, "MODE #chan +v voiced");
+        lines(&op_out);
+        lines(&user_out);
+        lines(&voiced_out);
+
+        send(&mut server, user, "PRIVMSG @#chan :ops only");
+        assert_eq!(lines(&op_out), [":user!user@host PRIVMSG @#chan :ops only"]);
+        assert!(lines(&voiced_out).is_empty());
+        send(&mut server, user, "PRIVMSG +#chan voices");
+        assert_eq!(lines(&op_out).len(), 1);
+        assert_eq!(
+            lines(&voiced_out),
+            [":user!user@host PRIVMSG +#chan voices"]
+        );
+        assert!(lines(&user_out).is_empty());
+    }
+
+    #[test]
+    /// Test adding to, removing from and listing the silence list.
+    fn silence() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        server.limits.silence = 1;
+        send(&mut server, user, "SILENCE +op");
+        assert_eq!(lines(&user_out), [":user!user@host SILENCE +op!*@*"]);
+        send(&mut server, user, "SILENCE *!*@elsewhere");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 511 user *!*@elsewhere :Your silence list is full"]
+        );
+        send(&mut server, user, "SILENCE");
+        assert_eq!(
+            lines(&user_out),
+            [
+                ":irc.test 271 user op!*@*",
+                ":irc.test 272 user :End of Silence List"
+            ]
+        );
+
+        send(&mut server, op, "PRIVMSG user :hello");
+        send(&mut server, op, "INVITE user #chan");
+        assert!(lines(&user_out).is_empty());
+        send(&mut server, user, "JOIN #chan");
+        lines(&op_out);
+        lines(&user_out);
+        send(&mut server, op, "PRIVMSG #chan :hello");
+        assert!(lines(&user_out).is_empty());
+
+        send(&mut server, user, "SILENCE -OP");
+        assert_eq!(lines(&user_out), [":user!user@host SILENCE -op!*@*"]);
+        send(&mut server, op, "PRIVMSG user :hello");
+        assert_eq!(lines(&user_out).len(), 1);
+    }
+
+    #[test]
+    /// Test the limits on list modes, key lengths and mode arguments per
+    /// command.
+    fn mode_limits() {
+        let (mut server, (op, op_out), _) = setup();
+        server.limits.modes = 2;
+        send(&mut server, op, "MODE #chan +bbb a!*@* b!*@* c!*@*");
+        assert_eq!(lines(&op_out), [":op!op@host MODE #chan +bb a!*@* b!*@*"]);
+
+        server.limits.maxlist = 3;
+        send(&mut server, op, "MODE #chan +eI d!*@* e!*@*");
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":irc.test 478 op #chan I :Channel list is full",
+                ":op!op@host MODE #chan +e d!*@*"
+            ]
+        );
+
+        server.limits.keylen = 4;
+        send(&mut server, op, "MODE #chan +k toolong");
+        assert!(lines(&op_out).is_empty());
+        send(&mut server, op, "MODE #chan +k key");
+        assert_eq!(lines(&op_out), [":op!op@host MODE #chan +k key"]);
+    }
+
+    #[test]
+    /// Test the `LIST` search conditions.
+    fn list_conditions() {
+        let (mut server, (op, op_out), (user, _)) = setup();
+        send(&mut server, op, "JOIN #chat,#other");
+        send(&mut server, user, "JOIN #chat");
+        lines(&op_out);
+
+        let listed = |server: &mut Server, params: &str| -> Vec<String> {
+            send(server, op, &format!("LIST {params}"));
+            let lines = lines(&op_out);
+            lines[1..lines.len() - 1]
+                .iter()
+                .map(|line| line.split(' ').nth(3).unwrap().to_owned())
+                .collect()
+        };
+        assert_eq!(listed(&mut server, ">1"), ["#chat"]);
+        assert_eq!(listed(&mut server, "<2"), ["#chan", "#other"]);
+        assert_eq!(listed(&mut server, "#ch*"), ["#chan", "#chat"]);
+        assert_eq!(listed(&mut server, "!#ch*,<2"), ["#other"]);
+        assert_eq!(listed(&mut server, "#chat,#other,>1"), ["#chat"]);
+    }
+
+    #[test]
+    /// Test that long usernames are truncated.
+    fn userlen() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        server.limits.userlen = 4;
+        let (id, _) = server.connect(b"host".to_vec());
+        send(&mut server, id, "NICK nick");
+        send(&mut server, id, "USER username 0 * :Real Name");
+        assert_eq!(server.hostmask(id), b"nick!user@host");
+    }
+
+    #[test]
+    /// Test that clients may only be in a limited number of channels.
+    fn chanlimit() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        server.limits.chanlimit = 2;
+        let (id, outbox) = register(&mut server, "nick");
+        send(&mut server, id, "JOIN #a,#b");
+        lines(&outbox);
+        send(&mut server, id, "JOIN #b,#c");
+        assert_eq!(
+            lines(&outbox),
+            [":irc.test 405 nick #c :You have joined too many channels"]
+        );
+    }
+
+    #[test]
+    /// Test querying the modes and creation time of a channel.
+    fn query_modes() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, op, "MODE #chan +kl key 5");
+        lines(&op_out);
+
+        send(&mut server, op, "MODE #chan");
+        let reply = lines(&op_out);
+        assert_eq!(reply[0], ":irc.test 324 op #chan +klnt key 5");
+        assert!(reply[1].starts_with(":irc.test 329 op #chan "));
+
+        send(&mut server, user, "MODE #CHAN");
+        assert_eq!(lines(&user_out)[0], ":irc.test 324 user #chan +klnt * 5");
+    }
+
+    #[test]
+    /// Test that only operators may change modes, and that changes are
+    /// announced to the channel.
+    fn set_modes() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, user, "JOIN #chan");
+        lines(&user_out);
+        lines(&op_out);
+
+        send(&mut server, user, "MODE #chan +m");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 482 user #chan :You're not channel operator"]
+        );
+
+        send(&mut server, op, "MODE #chan +mi-t+x");
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":irc.test 472 op x :is unknown mode char to me",
+                ":op!op@host MODE #chan +mi-t",
+            ]
+        );
+        assert_eq!(lines(&user_out), [":op!op@host MODE #chan +mi-t"]);
+    }
+
+    #[test]
+    /// Test that invite-only, keyed and limited channels refuse joins.
+    fn join_restrictions() {
+        let (mut server, (op, _), (user, user_out)) = setup();
+
+        send(&mut server, op, "MODE #chan +i");
+        send(&mut server, user, "JOIN #chan");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 473 user #chan :Cannot join channel (+i)"]
+        );
+
+        send(&mut server, op, "MODE #chan -i+k secret");
+        send(&mut server, user, "JOIN #chan wrong");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 475 user #chan :Cannot join channel (+k)"]
+        );
+
+        send(&mut server, op, "MODE #chan -k+l * 1");
+        send(&mut server, user, "JOIN #chan");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 471 user #chan :Cannot join channel (+l)"]
+        );
+
+        // an invitation overrides the limit
+        send(&mut server, op, "INVITE user #chan");
+        lines(&user_out);
+        send(&mut server, user, "JOIN #chan");
+        assert_eq!(lines(&user_out)[0], ":user!user@host JOIN #chan");
+    }
+
+    #[test]
+    /// Test that bans, invite exceptions and mutes are enforced.
+    fn ban_lists() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+
+        send(&mut server, op, "MODE #chan +b user");
+        assert_eq!(lines(&op_out), [":op!op@host MODE #chan +b user!*@*"]);
+        send(&mut server, user, "JOIN #chan");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 474 user #chan :Cannot join channel (+b)"]
+        );
+
+        send(&mut server, op, "MODE #chan -b+b+iI user $m:user user");
+        send(&mut server, user, "JOIN #chan");
+        assert_eq!(lines(&user_out)[0], ":user!user@host JOIN #chan");
+
+        send(&mut server, user, "PRIVMSG #chan :hello");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 404 user #chan :Cannot send to channel"]
+        );
+
+        lines(&op_out);
+        send(&mut server, op, "MODE #chan b");
+        let list = lines(&op_out);
+        assert!(list[0].starts_with(":irc.test 367 op #chan $m:user op!op@host "));
+        assert_eq!(list[1], ":irc.test 368 op #chan :End of channel ban list");
+    }
+
+    #[test]
+    /// Test that moderated and no-external channels refuse messages.
+    fn message_restrictions() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+
+        send(&mut server, user, "PRIVMSG #chan :hi");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 404 user #chan :Cannot send to channel"]
+        );
+
+        send(&mut server, user, "JOIN #chan");
+        send(&mut server, op, "MODE #chan +m");
+        lines(&user_out);
+        lines(&op_out);
+        send(&mut server, user, "PRIVMSG #chan :hi");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 404 user #chan :Cannot send to channel"]
+        );
+
+        send(&mut server, op, "MODE #chan +v user");
+        send(&mut server, user, "PRIVMSG #chan :hi");
+        assert_eq!(
+            lines(&op_out),
+            [
+                ":op!op@host MODE #chan +v user",
+                ":user!user@host PRIVMSG #chan hi",
+            ]
+        );
+    }
+
+    #[test]
+    /// Test setting, querying and clearing a topic, and that it is sent on
+    /// join.
+    fn topic() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, op, "TOPIC #chan");
+        assert_eq!(lines(&op_out), [":irc.test 331 op #chan :No topic is set"]);
+
+        send(&mut server, op, "TOPIC #chan :Welcome all");
+        assert_eq!(lines(&op_out), [":op!op@host TOPIC #chan :Welcome all"]);
+
+        send(&mut server, user, "JOIN #chan");
+        let joined = lines(&user_out);
+        assert_eq!(joined[1], ":irc.test 332 user #chan :Welcome all");
+        assert!(joined[2].starts_with(":irc.test 333 user #chan op!op@host "));
+
+        send(&mut server, user, "TOPIC #chan :mine now");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 482 user #chan :You're not channel operator"]
+        );
+
+        lines(&op_out);
+        send(&mut server, op, "TOPIC #chan :");
+        assert_eq!(lines(&op_out), [":op!op@host TOPIC #chan :"]);
+        assert!(server.channel(b"#chan").unwrap().topic.is_none());
+    }
+
+    #[test]
+    /// Test that long topics are truncated without splitting characters.
+    fn topic_truncation() {
+        let (mut server, (op, _), _) = setup();
+        let topiclen = config::Limits::default().topiclen;
+        let long = "é".repeat(topiclen);
+        send(&mut server, op, &format!("TOPIC #chan :{long}"));
+        let topic = &server.channel(b"#chan").unwrap().topic;
+        let text = &topic.as_ref().unwrap().text;
+        assert_eq!(text.len(), topiclen);
+        assert!(std::str::from_utf8(text).is_ok());
+    }
+
+    #[test]
+    /// Test that a silent client is pinged, and disconnected with a quit
+    /// broadcast if it does not answer.
+    fn ping_timeout() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        server.set_keepalive(Duration::from_secs(10), Duration::from_secs(5));
+        send(&mut server, user, "JOIN #chan");
+        lines(&user_out);
+        lines(&op_out);
+        let start = Instant::now();
+
+        server.check_keepalive(start + Duration::from_secs(11));
+        assert_eq!(lines(&user_out), ["PING irc.test"]);
+        assert_eq!(lines(&op_out), ["PING irc.test"]);
+
+        // the operator answers, the user does not
+        send(&mut server, op, "PONG irc.test");
+        assert!(server.client(op).unwrap().ping_sent.is_none());
+        server.check_keepalive(start + Duration::from_secs(16));
+        assert_eq!(lines(&user_out), ["ERROR :Ping timeout"]);
+        assert!(user_out.is_closed());
+        assert!(server.client(user).is_none());
+        assert!(lines(&op_out).contains(&":user!user@host QUIT :Ping timeout".to_owned()));
+    }
+
+    #[test]
+    /// Test that a client's `PING` is answered, even before registration.
+    fn answer_ping() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        let (id, outbox) = server.connect(b"host".to_vec());
+        send(&mut server, id, "PING :token here");
+        assert_eq!(lines(&outbox), [":irc.test PONG irc.test :token here"]);
+    }
+
+    #[test]
+    /// Test that a flooding client is delayed, then disconnected, unless it
+    /// is exempt.
+    fn flood_control() {
+        let (mut server, (op, _), (user, user_out)) = setup();
+        server.set_flood_limits(flood::Limits {
+            burst: 2,
+            excess: 1,
+            ..flood::Limits::default()
+        });
+        let now = Instant::now();
+        let delays: Vec<_> = ["PRIVMSG", "PONG", "PRIVMSG", "PRIVMSG", "PRIVMSG"]
+            .iter()
+            .map(|verb| server.throttle(user, verb.as_bytes(), now))
+            .collect();
+        assert_eq!(
+            delays,
+            [
+                Some(Duration::ZERO),
+                Some(Duration::ZERO),
+                Some(Duration::ZERO),
+                Some(Duration::from_secs(1)),
+                None
+            ]
+        );
+        assert_eq!(lines(&user_out), ["ERROR :Excess Flood"]);
+        assert!(server.client(user).is_none());
+
+        server.clients.get_mut(&op).unwrap().oper = true;
+        for _ in 0..10 {
+            assert_eq!(server.throttle(op, b"JOIN", now), Some(Duration::ZERO));
+        }
+    }
+
+    #[test]
+    /// Test that lines are queued until they are due, and that a client whose
+    /// queued lines exceed its receive queue limit is disconnected.
+    fn recvq_limit() {
+        let (mut server, _, (user, user_out)) = setup();
+        server.set_flood_limits(flood::Limits {
+            burst: 1,
+            excess: 100,
+            ..flood::Limits::default()
+        });
+        server.clients.get_mut(&user).unwrap().class.recvq = 100;
+        let ping = |token: &str| incoming::Message::parse_bytes(format!("PING {token}").as_bytes());
+        let now = Instant::now();
+
+        assert_eq!(server.receive(user, Ok(ping("a").unwrap()), 40, now), None);
+        assert_eq!(lines(&user_out), [":irc.test PONG irc.test a"]);
+        let due = server.receive(user, Ok(ping("b").unwrap()), 40, now);
+        assert_eq!(due, Some(now + Duration::from_secs(1)));
+        assert_eq!(server.receive(user, Ok(ping("c").unwrap()), 40, now), due);
+        assert!(lines(&user_out).is_empty());
+        assert_eq!(server.client(user).unwrap().recvq, 80);
+
+        assert_eq!(
+            server.process(user, due.unwrap()),
+            Some(now + Duration::from_secs(2))
+        );
+        assert_eq!(lines(&user_out), [":irc.test PONG irc.test b"]);
+
+        let err = Err(IrcError::UnknownCommand(b"FOO".to_vec()));
+        assert!(server.receive(user, err.clone(), 40, now).is_some());
+        assert_eq!(server.receive(user, err, 40, now), None);
+        assert_eq!(lines(&user_out), ["ERROR :Max RecvQ exceeded"]);
+        assert!(server.client(user).is_none());
+    }
+
+    #[test]
+    /// Test that a client whose send queue overflows is disconnected.
+    fn sendq_limit() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        server.set_classes(vec![
+            Class {
+                name: b"slow".to_vec(),
+                hosts: vec![b"slow.example.com".to_vec()],
+                sendq: 2000,
+                ..Class::default()
+            },
+            Class::default(),
+        ]);
+        let (op, op_out) = register(&mut server, "op");
+        let (slow, slow_out) = server.connect(b"slow.example.com".to_vec());
+        assert_eq!(server.client(slow).unwrap().class().name, b"slow");
+        send(&mut server, slow, "NICK slow");
+        send(&mut server, slow, "USER slow 0 * :Slow");
+        send(&mut server, op, "JOIN #chan");
+        send(&mut server, slow, "JOIN #chan");
+        lines(&op_out);
+
+        for _ in 0..100 {
+            send(&mut server, op, "PRIVMSG #chan :spam spam spam");
+        }
+        assert!(slow_out.queued() <= 2000);
+        assert!(slow_out.is_overflowed());
+        server.check_sendq();
+        assert!(server.client(slow).is_none());
+        assert!(slow_out.is_closed());
+        assert_eq!(
+            lines(&slow_out).last().unwrap(),
+            "ERROR :Max SendQ exceeded"
+        );
+        assert_eq!(
+            lines(&op_out),
+            [":slow!slow@slow.example.com QUIT :Max SendQ exceeded"]
+        );
+        assert!(server.client(op).is_some());
+    }
+
+    #[test]
+    /// Test that connected clients are moved to the class they match after
+    /// the classes are reconfigured, and given its limits.
+    fn reclassify() {
+        let mut server = Server::new(b"irc.test", b"TestNet");
+        let (user, user_out) = register(&mut server, "user");
+        server.clients.get_mut(&user).unwrap().host = b"vhost.example.com".to_vec();
+        assert_eq!(server.client(user).unwrap().class().name, b"default");
+
+        let mut config = Config::default();
+        config.classes.insert(
+            0,
+            Class {
+                name: b"small".to_vec(),
+                hosts: vec![b"host".to_vec()],
+                sendq: 600,
+                recvq: 600,
+            },
+        );
+        server.configure(&config);
+        let class = server.client(user).unwrap().class();
+        assert_eq!((&class.name[..], class.recvq), (&b"small"[..], 600));
+        user_out.push(vec![b'x'; 601]);
+        assert!(user_out.is_overflowed());
+    }
+
+    #[test]
+    /// Test that `STATS` shows the queues of a client's own connection, and
+    /// of every connection to operators.
+    fn stats() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        send(&mut server, user, "STATS l");
+        let reply = lines(&user_out);
+        assert_eq!(reply.len(), 2);
+        assert!(reply[0].starts_with(":irc.test 211 user user[user@host] 0 "));
+        assert!(reply[0].ends_with(" 0"));
+        assert_eq!(reply[1], ":irc.test 219 user l :End of /STATS report");
+
+        server.clients.get_mut(&op).unwrap().oper = true;
+        send(&mut server, op, "STATS L");
+        assert_eq!(lines(&op_out).len(), 3);
+
+        send(&mut server, user, "STATS y");
+        assert_eq!(
+            lines(&user_out),
+            [
+                ":irc.test 218 user Y default 120 0 1048576 16384",
+                ":irc.test 219 user y :End of /STATS report",
+            ]
+        );
+    }
+
+    #[test]
+    /// Test that shutting down closes every connection with an error and
+    /// forgets every channel.
+    fn shutdown() {
+        let (mut server, (_, op_out), (user, user_out)) = setup();
+        send(&mut server, user, "JOIN #chan");
+        lines(&op_out);
+        lines(&user_out);
+
+        server.shutdown(b"Server shutting down");
+        for outbox in [&op_out, &user_out] {
+            assert_eq!(lines(outbox), ["ERROR :Server shutting down"]);
+            assert!(outbox.is_closed());
+        }
+        assert!(server.client(user).is_none());
+        assert!(server.channel(b"#chan").is_none());
+    }
+
+    #[test]
+    /// Test that idle time is only reset by messages.
+    fn whois_idle() {
+        let (mut server, (op, op_out), (user, _)) = setup();
+        let long_ago = Instant::now()
+            .checked_sub(Duration::from_secs(100))
+            .unwrap();
+        server.clients.get_mut(&user).unwrap().last_message = long_ago;
+
+        send(&mut server, user, "JOIN #chan");
+        send(&mut server, op, "WHOIS user");
+        let idle = lines(&op_out)
+            .into_iter()
+            .find(|line| line.contains(" 317 "))
+            .unwrap();
+        assert!(idle.starts_with(":irc.test 317 op user 100 "));
+
+        send(&mut server, user, "PRIVMSG #chan :hi");
+        send(&mut server, op, "WHOIS user");
+        assert!(lines(&op_out)
+            .iter()
+            .any(|line| line.starts_with(":irc.test 317 op user 0 ")));
+    }
+
+    #[test]
+    /// Test that clients connected over TLS get user mode `+Z`, and that
+    /// `WHOIS` shows their certificate fingerprint only to themselves and to
+    /// operators.
+    fn secure_connections() {
+        let (mut server, (op, op_out), (user, user_out)) = setup();
+        server.clients.get_mut(&op).unwrap().oper = true;
+        let (id, outbox) = server.connect_tls(b"host".to_vec(), Some(b"c0ffee".to_vec()));
+        send(&mut server, id, "NICK secure");
+        send(&mut server, id, "USER secure 0 * :Real secure");
+        lines(&outbox);
+
+        send(&mut server, id, "MODE secure -Z");
+        send(&mut server, id, "MODE secure");
+        assert_eq!(lines(&outbox), [":irc.test 221 secure +Z"]);
+
+        let fingerprint = ":irc.test 276 secure secure :has client certificate fingerprint c0ffee";
+        send(&mut server, id, "WHOIS secure");
+        let whois = lines(&outbox);
+        assert!(
+            whois.contains(&":irc.test 671 secure secure :is using a secure connection".to_owned())
+        );
+        assert!(whois.contains(&fingerprint.to_owned()));
+        send(&mut server, op, "WHOIS secure");
+        assert!(lines(&op_out)
+            .iter()
+            .any(|line| line.starts_with(":irc.test 276 op secure ")));
+        send(&mut server, user, "WHOIS secure");
+        let whois = lines(&user_out);
+        assert!(whois
+            .iter()
+            .any(|line| line.starts_with(":irc.test 671 user secure ")));
+        assert!(!whois.iter().any(|line| line.contains(" 276 ")));
+
+        send(&mut server, id, "WHOIS user");
+        assert!(!lines(&outbox).iter().any(|line| line.contains(" 671 ")));
+    }
+
+    #[test]
+    /// Test that secret channels are hidden from `LIST`, `NAMES`, `WHOIS` and
+    /// `MODE` for users outside them.
+    fn secret_channels() {
+        let (mut server, (op, _), (user, user_out)) = setup();
+        send(&mut server, op, "MODE #chan +s");
+
+        send(&mut server, user, "LIST");
+        assert_eq!(
+            lines(&user_out),
+            [
+                ":irc.test 321 user Channel :Users  Name",
+                ":irc.test 323 user :End of /LIST",
+            ]
+        );
+
+        send(&mut server, user, "NAMES #chan");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 366 user #chan :End of /NAMES list"]
+        );
+
+        send(&mut server, user, "WHOIS op");
+        let whois = lines(&user_out);
+        assert!(!whois.iter().any(|line| line.contains(" 319 ")));
+
+        send(&mut server, user, "MODE #chan");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 403 user #chan :No such channel"]
+        );
+        send(&mut server, user, "MODE #chan beI");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 442 user #chan :You're not on that channel"; 3]
+        );
+
+        send(&mut server, user, "JOIN #chan");
+        lines(&user_out);
+        send(&mut server, user, "MODE #chan b");
+        assert_eq!(
+            lines(&user_out),
+            [":irc.test 368 user #chan :End of channel ban list"]
+        );
+        send(&mut server, user, "WHOIS op");
+        assert!(lines(&user_out).contains(&":irc.test 319 user op @#chan".to_owned()));
+    }
+}
@@ -0,0 +1,173 @@
+//! A module for message tags, which carry extra information about a
+//! message in a `@key=value;key=value` section before its source.
+//!
+//! Values are escaped so that they contain no spaces, semicolons or line
+//! endings. Each tag this server sends is only shown to clients which have
+//! enabled the capability it belongs to, given by
+//! [`Cap::for_tag`](crate::cap::Cap::for_tag).
+
+/// The tags of a message, as pairs of keys and unescaped values, in the order
+/// they were given. A tag without a value has an empty one.
+pub type Tags = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Parse the tags section of a message, without its leading `@`.
+///
+/// Empty tags are skipped, and when a key is given more than once only its
+/// last value is kept.
+#[must_use]
+pub fn parse(section: &[u8]) -> Tags {
+    let mut tags: Tags = Vec::new();
+    for tag in section.split(|&b| b == b';').filter(|tag| !tag.is_empty()) {
+        let (key, value) = match tag.iter().position(|&b| b == b'=') {
+            Some(equals) => (&tag[..equals], unescape(&tag[equals + 1..])),
+            None => (tag, Vec::new()),
+        };
+        match tags.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing)) => *existing = value,
+            None => tags.push((key.to_vec(), value)),
+        }
+    }
+    tags
+}
+
+/// Undo the escaping of a tag value. A backslash before any other character
+/// is dropped, as is a backslash at the very end.
+#[must_use]
+pub fn unescape(value: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(value.len());
+    let mut bytes = value.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'\\' {
+            unescaped.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b':') => unescaped.push(b';'),
+            Some(b's') => unescaped.push(b' '),
+            Some(b'r') => unescaped.push(b'\r'),
+            Some(b'n') => unescaped.push(b'\n'),
+            Some(&other) => unescaped.push(other),
+            None => (),
+        }
+    }
+    unescaped
+}
+
+/// Escape a tag value so that it can be sent on the wire.
+#[must_use]
+pub fn escape(value: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(value.len());
+    for &b in value {
+        match b {
+            b';' => escaped.extend_from_slice(b"\\:"),
+            b' ' => escaped.extend_from_slice(b"\\s"),
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            _ => escaped.push(b),
+        }
+    }
+    escaped
+}
+
+/// Serialize `tags` as the tags section of a message, including the leading
+/// `@` and trailing space, or nothing at all if there are no tags.
+#[must_use]
+pub fn serialize<'a>(tags: impl IntoIterator<Item = &'a (Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+    let mut section = Vec::new();
+    for (key, value) in tags {
+        section.push(if section.is_empty() { b'@' } else { b';' });
+        section.extend_from_slice(key);
+        if !value.is_empty() {
+            section.push(b'=');
+            section.extend(escape(value));
+        }
+    }
+    if !section.is_empty() {
+        section.push(b' ');
+    }
+    section
+}
+
+/// Add the tag `key` with the unescaped `value` to the start of `line`, a
+/// message which has already been serialized.
+#[must_use]
+pub fn add_to_line(line: &[u8], key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut tagged = serialize(&[(key.to_vec(), value.to_vec())]);
+    match line.strip_prefix(b"@") {
+        Some(rest) => {
+            tagged.pop();
+            tagged.push(b';');
+            tagged.extend_from_slice(rest);
+        }
+        None => tagged.extend_from_slice(line),
+    }
+    tagged
+}
+
+/// Determine whether `line`, a message which has already been serialized,
+/// has the tag `key`.
+#[must_use]
+pub fn line_has(line: &[u8], key: &[u8]) -> bool {
+    line.strip_prefix(b"@").is_some_and(|rest| {
+        let end = rest.iter().position(|&b| b == b' ').unwrap_or(rest.len());
+        rest[..end].split(|&b| b == b';').any(|tag| {
+            tag.strip_prefix(key)
+                .is_some_and(|after| after.is_empty() || after[0] == b'=')
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test parsing tags with escaped, missing and repeated values.
+    fn parse_tags() {
+        assert_eq!(
+            parse(b"a=b\\\\and\\nk;d=gh\\:764;;e;f=;a=c"),
+            [
+                (b"a".to_vec(), b"c".to_vec()),
+                (b"d".to_vec(), b"gh;764".to_vec()),
+                (b"e".to_vec(), Vec::new()),
+                (b"f".to_vec(), Vec::new()),
+            ]
+        );
+        assert_eq!(unescape(b"\\s\\x\\"), b" x");
+    }
+
+    #[test]
+    /// Test that serialized tags are parsed back to the same values.
+    fn round_trip() {
+        let tags = vec![
+            (b"time".to_vec(), b"2011-10-19T16:40:51.620Z".to_vec()),
+            (b"+example.com/x".to_vec(), b"a; b\\c\r\n".to_vec()),
+            (b"flag".to_vec(), Vec::new()),
+        ];
+        let section = serialize(&tags);
+        assert_eq!(
+            section,
+            b"@time=2011-10-19T16:40:51.620Z;+example.com/x=a\\:\\sb\\\\c\\r\\n;flag "
+        );
+        assert_eq!(parse(&section[1..section.len() - 1]), tags);
+        assert_eq!(serialize(&[]), b"");
+    }
+
+    #[test]
+    /// Test adding a tag to lines with and without tags of their own.
+    fn add_tags() {
+        assert_eq!(
+            add_to_line(b":a PING x\r\n", b"label", b"1 2"),
+            b"@label=1\\s2 :a PING x\r\n"
+        );
+        assert_eq!(
+            add_to_line(b"@time=t :a PING x\r\n", b"batch", b"ref"),
+            b"@batch=ref;time=t :a PING x\r\n"
+        );
+        assert!(line_has(b"@a;batch=1 :x PING\r\n", b"batch"));
+        assert!(line_has(b"@batch :x PING\r\n", b"batch"));
+        assert!(!line_has(b"@batches=1 :x PING batch=1\r\n", b"batch"));
+        assert!(!line_has(b":x PING batch\r\n", b"batch"));
+    }
+}
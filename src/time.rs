@@ -0,0 +1,160 @@
+//! A module for reading the current time and formatting timestamps.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Get the number of whole seconds since the Unix epoch.
+#[must_use]
+pub fn unix_time() -> u64 {
+    since_epoch(SystemTime::now()).as_secs()
+}
+
+/// Get the duration between the Unix epoch and `time`, clamping times before
+/// the epoch to zero.
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Format `time` as a UTC ISO 8601 timestamp with millisecond precision, such
+/// as `2011-10-19T16:40:51.620Z`.
+#[must_use]
+pub fn iso8601(time: SystemTime) -> String {
+    let since = since_epoch(time);
+    let secs = since.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let of_day = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60,
+        since.subsec_millis(),
+    )
+}
+
+/// Parse a UTC ISO 8601 timestamp such as `2011-10-19T16:40:51.620Z`, as
+/// formatted by [`iso8601`]. The fraction of a second may be left out, and
+/// is otherwise read to millisecond precision.
+#[must_use]
+pub fn parse_iso8601(text: &[u8]) -> Option<SystemTime> {
+    let text = std::str::from_utf8(text).ok()?.strip_suffix('Z')?;
+    let (date, clock) = text.split_once('T')?;
+    let (clock, fraction) = clock.split_once('.').unwrap_or((clock, ""));
+    let number = |field: &str, digits: usize| -> Option<u64> {
+        if field.len() != digits || !field.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        field.parse().ok()
+    };
+    let mut date = date.split('-');
+    let (year, month, day) = (
+        number(date.next()?, 4)?,
+        number(date.next()?, 2)?,
+        number(date.next()?, 2)?,
+    );
+    let mut clock = clock.split(':');
+    let (hour, minute, second) = (
+        number(clock.next()?, 2)?,
+        number(clock.next()?, 2)?,
+        number(clock.next()?, 2)?,
+    );
+    if date.next().is_some() || clock.next().is_some() {
+        return None;
+    }
+    if year < 1970
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // pad the fraction to milliseconds, and ignore anything more precise
+    let millis: u64 = format!("{fraction:0<3}")[..3].parse().ok()?;
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis))
+}
+
+/// Get the number of whole milliseconds between the Unix epoch and `time`,
+/// clamping times before the epoch to zero.
+#[must_use]
+pub fn unix_millis(time: SystemTime) -> u64 {
+    u64::try_from(since_epoch(time).as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Convert a date in the proleptic Gregorian calendar into the number of
+/// days since the Unix epoch, which the date must not be before.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // count years from 0000-03-01, as `civil_from_days` does
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Convert a number of days since the Unix epoch into a (year, month, day)
+/// date in the proleptic Gregorian calendar.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Shift the epoch to 0000-03-01, so that leap days fall at the end of each
+    // 400-year era.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test formatting the epoch, a leap day and a time with milliseconds.
+    fn format_iso8601() {
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let leap = UNIX_EPOCH + Duration::from_secs(951_868_799);
+        assert_eq!(iso8601(leap), "2000-02-29T23:59:59.000Z");
+        let precise = UNIX_EPOCH + Duration::from_millis(1_318_956_051_620);
+        assert_eq!(iso8601(precise), "2011-10-18T16:40:51.620Z");
+    }
+
+    #[test]
+    /// Test that parsing undoes formatting, and rejects malformed times.
+    fn parse_timestamps() {
+        for millis in [0, 951_868_799_000, 1_318_956_051_620, 4_102_444_800_001] {
+            let time = UNIX_EPOCH + Duration::from_millis(millis);
+            assert_eq!(parse_iso8601(iso8601(time).as_bytes()), Some(time));
+            assert_eq!(unix_millis(time), millis);
+        }
+        let parse = |text: &str| parse_iso8601(text.as_bytes()).map(unix_millis);
+        assert_eq!(parse("2000-03-01T00:00:00Z"), Some(951_868_800_000));
+        assert_eq!(parse("2011-10-18T16:40:51.62Z"), Some(1_318_956_051_620));
+        assert_eq!(parse("2011-10-18T16:40:51.6209Z"), Some(1_318_956_051_620));
+        for bad in [
+            "2011-10-18T16:40:51",
+            "2011-10-18 16:40:51Z",
+            "2011-13-18T16:40:51Z",
+            "1969-12-31T23:59:59Z",
+            "2011-10-18T16:40:51.6a0Z",
+            "2011-1-18T16:40:51Z",
+        ] {
+            assert_eq!(parse(bad), None, "{bad}");
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! A module for accepting connections over TLS, and for identifying clients
+//! by the fingerprints of the certificates they present.
+//!
+//! Each TLS listener serves the certificate held by a [`CertResolver`],
+//! which can be given a new certificate while the server is running, so that
+//! renewed certificates take effect on `REHASH` without dropping anyone.
+
+use std::{
+    fmt::Write,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use ring::digest::{self, SHA256};
+use rustls::{
+    client::danger::HandshakeSignatureValid,
+    crypto::{self, CryptoProvider, WebPkiSupportedAlgorithms},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, UnixTime},
+    server::{
+        danger::{ClientCertVerified, ClientCertVerifier},
+        ClientHello, NoClientAuth, ResolvesServerCert,
+    },
+    sign::CertifiedKey,
+    DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme,
+};
+
+use crate::config::Tls;
+
+/// Load the certificate chain and private key named by `tls`.
+///
+/// # Errors
+///
+/// Returns a description of the problem if either file cannot be read or
+/// parsed, or if the key does not belong to the certificate.
+pub fn load_key(tls: &Tls) -> Result<Arc<CertifiedKey>, String> {
+    let chain = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|err| format!("cannot read {}: {err}", tls.cert.display()))?;
+    if chain.is_empty() {
+        return Err(format!("{} contains no certificates", tls.cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .map_err(|err| format!("cannot read {}: {err}", tls.key.display()))?;
+    CertifiedKey::from_der(chain, key, &provider())
+        .map(Arc::new)
+        .map_err(|err| {
+            format!(
+                "{} cannot be used with {}: {err}",
+                tls.key.display(),
+                tls.cert.display()
+            )
+        })
+}
+
+/// Get the cryptography used for every TLS connection.
+fn provider() -> CryptoProvider {
+    crypto::ring::default_provider()
+}
+
+/// Build the configuration of a TLS listener which serves the certificate
+/// held by `resolver`, and which asks clients for certificates if `tls` says
+/// to.
+///
+/// # Errors
+///
+/// Returns an error if the default protocol versions are not supported,
+/// which should never happen.
+pub fn server_config(
+    tls: &Tls,
+    resolver: Arc<CertResolver>,
+) -> Result<ServerConfig, rustls::Error> {
+    let provider = Arc::new(provider());
+    let verifier: Arc<dyn ClientCertVerifier> = if tls.client_certs {
+        Arc::new(AnyClientCert {
+            algorithms: provider.signature_verification_algorithms,
+        })
+    } else {
+        Arc::new(NoClientAuth)
+    };
+    Ok(ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(resolver))
+}
+
+/// Get the fingerprint of `cert`, which is the lowercase hexadecimal SHA-256
+/// digest of its DER encoding.
+#[must_use]
+pub fn fingerprint(cert: &CertificateDer<'_>) -> Vec<u8> {
+    digest::digest(&SHA256, cert)
+        .as_ref()
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+        .into_bytes()
+}
+
+#[derive(Debug)]
+/// The certificate served by a TLS listener, which may be replaced while the
+/// server is running.
+pub struct CertResolver {
+    /// The current certificate chain and private key.
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    /// Construct a resolver which serves `key`.
+    #[must_use]
+    pub fn new(key: Arc<CertifiedKey>) -> CertResolver {
+        CertResolver {
+            key: RwLock::new(key),
+        }
+    }
+
+    /// Serve `key` to every client which connects from now on.
+    pub fn set(&self, key: Arc<CertifiedKey>) {
+        *self.key.write().unwrap_or_else(PoisonError::into_inner) = key;
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(
+            &self.key.read().unwrap_or_else(PoisonError::into_inner),
+        ))
+    }
+}
+
+#[derive(Debug)]
+/// Asks clients for a certificate without requiring one, and accepts any
+/// certificate at all, since it is only used to identify the client by its
+/// fingerprint. The client must still prove that it holds the certificate's
+/// private key.
+struct AnyClientCert {
+    /// The signature algorithms the client may prove that with.
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::*;
+
+    /// The digits of lowercase hexadecimal.
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    /// Generate a self-signed certificate for `localhost`, and write it and
+    /// its key to files named after `name` in `dir`.
+    fn generate(dir: &Path, name: &str) -> (rcgen::CertifiedKey<rcgen::KeyPair>, Tls) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let tls = Tls {
+            cert: dir.join(format!("{name}.crt")),
+            key: dir.join(format!("{name}.key")),
+            client_certs: true,
+        };
+        std::fs::write(&tls.cert, generated.cert.pem()).unwrap();
+        std::fs::write(&tls.key, generated.signing_key.serialize_pem()).unwrap();
+        (generated, tls)
+    }
+
+    /// Connect to `acceptor` with a client which trusts `roots` and presents
+    /// `client`, and return the certificate the server presented and the
+    /// fingerprint of the one the client presented.
+    async fn handshake(
+        acceptor: &TlsAcceptor,
+        roots: &[&CertificateDer<'static>],
+        client: &rcgen::CertifiedKey<rcgen::KeyPair>,
+    ) -> (CertificateDer<'static>, Option<Vec<u8>>) {
+        let mut store = RootCertStore::empty();
+        for root in roots {
+            store.add((*root).clone()).unwrap();
+        }
+        let key = PrivateKeyDer::try_from(client.signing_key.serialize_der()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(store)
+            .with_client_auth_cert(vec![client.cert.der().clone()], key)
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (client_io, server_io) = tokio::io::duplex(1 << 16);
+        let name = "localhost".try_into().unwrap();
+        let (client, server) = tokio::join!(
+            connector.connect(name, client_io),
+            acceptor.accept(server_io)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        client.write_all(b"PING x\r\n").await.unwrap();
+        let mut line = [0; 8];
+        server.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"PING x\r\n");
+
+        let presented = client.get_ref().1.peer_certificates().unwrap()[0].clone();
+        let certfp = server
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(fingerprint);
+        (presented, certfp)
+    }
+
+    #[tokio::test]
+    /// Test a handshake which identifies the client by its certificate, and
+    /// that a new certificate is served once the resolver is given one.
+    async fn handshake_and_reload() {
+        let dir = std::env::temp_dir().join(format!("strigoi-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first, tls) = generate(&dir, "first");
+        let (second, renewed) = generate(&dir, "second");
+        let (client, _) = generate(&dir, "client");
+
+        let resolver = Arc::new(CertResolver::new(load_key(&tls).unwrap()));
+        let config = server_config(&tls, Arc::clone(&resolver)).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let roots = [first.cert.der(), second.cert.der()];
+
+        let (presented, certfp) = handshake(&acceptor, &roots, &client).await;
+        assert_eq!(&presented, first.cert.der());
+        let expected: Vec<u8> = digest::digest(&SHA256, client.cert.der())
+            .as_ref()
+            .iter()
+            .flat_map(|byte| [HEX[usize::from(byte >> 4)], HEX[usize::from(byte & 15)]])
+            .collect();
+        assert_eq!(certfp, Some(expected));
+
+        resolver.set(load_key(&renewed).unwrap());
+        let (presented, _) = handshake(&acceptor, &roots, &client).await;
+        assert_eq!(&presented, second.cert.der());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    /// Test that unusable certificates and keys are reported.
+    fn load_errors() {
+        let dir = std::env::temp_dir().join(format!("strigoi-tls-errors-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (_, tls) = generate(&dir, "server");
+        let (_, other) = generate(&dir, "other");
+
+        let missing = Tls {
+            cert: PathBuf::from("/nonexistent.crt"),
+            ..tls.clone()
+        };
+        assert!(load_key(&missing)
+            .unwrap_err()
+            .starts_with("cannot read /nonexistent.crt"));
+        let mismatched = Tls {
+            key: other.key.clone(),
+            ..tls.clone()
+        };
+        assert!(load_key(&mismatched)
+            .unwrap_err()
+            .contains("cannot be used with"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
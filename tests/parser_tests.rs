@@ -9,8 +9,8 @@
 mod mask_match {
     // TODO: remname all of these tests to match the name of the function they're testing
     // As right now they say nothing descriptive
-    fn mask_match_todo(_mask: &str, _pattern: &str) -> bool {
-        todo!()
+    fn mask_match_todo(mask: &str, pattern: &str) -> bool {
+        strigoi::mask::matches(mask.as_bytes(), pattern.as_bytes())
     }
 
     #[test]
@@ -23,7 +23,7 @@ mod mask_match {
         assert!(mask_match_todo(mask, "cooldud3!~bc@127.0.0.1"));
 
         // should not match
-        assert!(!mask_match_todo(mask, "coolguy!ab@127.0.0.1"));
+        assert!(!mask_match_todo(mask, "coolguy!ab@127.0.0.5"));
         assert!(!mask_match_todo(mask, "cooldud3!~d@124.0.0.1"));
     }
 
@@ -781,8 +781,14 @@ mod userhost_split {
         host: Option<&'a str>,
     }
 
-    fn userhost_split_todo(_source: &str) -> UserHostAtoms {
-        todo!()
+    fn userhost_split_todo(source: &str) -> UserHostAtoms {
+        let (nick, user, host) = strigoi::mask::split_userhost(source.as_bytes());
+        let as_str = |part| std::str::from_utf8(part).unwrap();
+        UserHostAtoms {
+            nick: Some(as_str(nick)),
+            user: user.map(as_str),
+            host: host.map(as_str),
+        }
     }
 
     #[test]
@@ -859,8 +865,8 @@ mod validating_hostnames {
     //  commonly contain weird chars, and oper masks can omit periods altogether and
     //  include things like formatting characters.
 
-    fn valid_hostname_todo(_host: &str) -> bool {
-        todo!()
+    fn valid_hostname_todo(host: &str) -> bool {
+        strigoi::config::is_valid_hostname(host)
     }
 
     #[test]